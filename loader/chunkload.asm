@@ -0,0 +1,67 @@
+; Loader for kz80_action --format chunks, for sjasmplus or z80asm.
+;
+; Reads chunks from the console UART and puts each at its address, then starts the
+; program at the address in the last chunk. Each chunk is its address and length
+; (little-endian), that many bytes and a checksum byte that makes all of them add up
+; to zero; the last chunk has no bytes. A bad checksum prints ? and halts.
+;
+; It needs only a stack, so it runs from any monitor: assemble it somewhere the
+; program does not load (change the ORG), start it there and send the .chk file.
+; get_byte and put_byte are for the UART the compiler's simple console uses, with
+; data on port $00 and receive ready in bit 0 of port $01; replace them with calls
+; to the monitor's own routines for any other.
+
+    ORG $FF00
+
+load:
+    LD E,$00            ; Sum of the chunk so far
+    CALL get_byte
+    LD L,A
+    CALL get_byte
+    LD H,A              ; HL = address
+    CALL get_byte
+    LD C,A
+    CALL get_byte
+    LD B,A              ; BC = length
+    LD A,B
+    OR C
+    JR Z,last
+next:
+    CALL get_byte
+    LD (HL),A
+    INC HL
+    DEC BC
+    LD A,B
+    OR C
+    JR NZ,next
+    CALL get_byte       ; Checksum
+    LD A,E
+    OR A
+    JR Z,load
+bad:
+    LD A,$3F            ; ?
+    CALL put_byte
+    HALT
+last:
+    CALL get_byte       ; Checksum
+    LD A,E
+    OR A
+    JR NZ,bad
+    JP (HL)             ; Start the program
+
+; A byte from the UART in A, added to E. Changes D
+get_byte:
+    IN A,($01)
+    AND $01
+    JR Z,get_byte
+    IN A,($00)
+    LD D,A
+    ADD A,E
+    LD E,A
+    LD A,D
+    RET
+
+; Write A to the UART
+put_byte:
+    OUT ($00),A
+    RET
@@ -1,5 +1,11 @@
 // Abstract Syntax Tree types for Action! language
 
+pub mod arena;
+pub mod calls;
+pub mod modules;
+
+use crate::token::CaseMode;
+
 #[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)]
 pub enum DataType {
@@ -43,6 +49,20 @@ pub struct Variable {
 pub struct Parameter {
     pub name: String,
     pub data_type: DataType,
+    pub by_ref: bool,  // VAR parameter: the caller's variable, passed by address
+}
+
+impl Parameter {
+    /// Type of the argument as passed: the variable's address for a VAR parameter, and
+    /// the array's for an ARRAY one
+    pub fn passed_type(&self) -> DataType {
+        let is_array = matches!(self.data_type, DataType::ByteArray(_) | DataType::CardArray(_) | DataType::IntArray(_));
+        if self.by_ref || is_array {
+            DataType::Pointer(Box::new(self.data_type.clone()))
+        } else {
+            self.data_type.clone()
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -52,6 +72,7 @@ pub enum Expression {
     Number(i32),
     String(String),
     Char(char),
+    Bytes(Vec<u8>),  // A file's contents, from INCBIN
 
     // Variables
     Variable(String),
@@ -100,6 +121,123 @@ pub enum Expression {
     },
 }
 
+impl Expression {
+    /// Evaluate an expression made only of literals, if possible
+    pub fn const_value(&self) -> Option<i32> {
+        self.fold(&|_| None)
+    }
+
+    /// When the expression is TABLE(index, entry), an ARRAY's initial value computed
+    /// for each element, the index's name and the entry
+    pub fn table(&self, case_mode: CaseMode) -> Option<(&str, &Expression)> {
+        match self {
+            Expression::FunctionCall { name, args } if case_mode.matches("TABLE", name) => match args.as_slice() {
+                [Expression::Variable(index), entry] => Some((index, entry)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The value of a TABLE entry for one element: a constant expression that may use
+    /// the index, and Sin(angle, amplitude) and Cos(angle, amplitude) with angles in
+    /// 256ths of a turn, rounded to the nearest whole number
+    pub fn table_value(&self, index: &str, at: i32, case_mode: CaseMode) -> Option<i32> {
+        self.fold(&|expr| match expr {
+            Expression::Variable(name) if case_mode.matches(index, name) => Some(Some(at)),
+            Expression::FunctionCall { name, args } if args.len() == 2
+                && (case_mode.matches("Sin", name) || case_mode.matches("Cos", name)) =>
+            {
+                let value = |arg: &Expression| arg.table_value(index, at, case_mode);
+                let (angle, amplitude) = (value(&args[0])?, value(&args[1])?);
+                let turns = angle as f64 / 256.0 + if case_mode.matches("Cos", name) { 0.25 } else { 0.0 };
+                Some(Some((amplitude as f64 * (turns * std::f64::consts::TAU).sin()).round() as i32))
+            }
+            _ => None,
+        })
+    }
+
+    // The constant value, with leaf giving the value of any expression it knows (Some)
+    // before the operators are tried
+    fn fold(&self, leaf: &dyn Fn(&Expression) -> Option<Option<i32>>) -> Option<i32> {
+        if let Some(value) = leaf(self) {
+            return value;
+        }
+        let binary = |l: &Expression, r: &Expression, f: fn(i32, i32) -> Option<i32>| {
+            f(l.fold(leaf)?, r.fold(leaf)?)
+        };
+        match self {
+            Expression::Number(n) => Some(*n),
+            Expression::Char(c) => Some(*c as i32),
+            Expression::Negate(e) => e.fold(leaf).map(|v| v.wrapping_neg()),
+            Expression::Add(l, r) => binary(l, r, |a, b| Some(a.wrapping_add(b))),
+            Expression::Subtract(l, r) => binary(l, r, |a, b| Some(a.wrapping_sub(b))),
+            Expression::Multiply(l, r) => binary(l, r, |a, b| Some(a.wrapping_mul(b))),
+            Expression::Divide(l, r) => binary(l, r, |a, b| a.checked_div(b)),
+            Expression::Modulo(l, r) => binary(l, r, |a, b| a.checked_rem(b)),
+            Expression::LeftShift(l, r) => binary(l, r, |a, b| a.checked_shl(b as u32)),
+            Expression::RightShift(l, r) => binary(l, r, |a, b| a.checked_shr(b as u32)),
+            Expression::BitAnd(l, r) | Expression::And(l, r) => binary(l, r, |a, b| Some(a & b)),
+            Expression::BitOr(l, r) | Expression::Or(l, r) => binary(l, r, |a, b| Some(a | b)),
+            Expression::BitXor(l, r) | Expression::Xor(l, r) => binary(l, r, |a, b| Some(a ^ b)),
+            Expression::Equal(l, r) => binary(l, r, |a, b| Some((a == b) as i32)),
+            Expression::NotEqual(l, r) => binary(l, r, |a, b| Some((a != b) as i32)),
+            Expression::Less(l, r) => binary(l, r, |a, b| Some((a < b) as i32)),
+            Expression::LessEqual(l, r) => binary(l, r, |a, b| Some((a <= b) as i32)),
+            Expression::Greater(l, r) => binary(l, r, |a, b| Some((a > b) as i32)),
+            Expression::GreaterEqual(l, r) => binary(l, r, |a, b| Some((a >= b) as i32)),
+            _ => None,
+        }
+    }
+
+    /// The variable, array or function the expression itself names, if any
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Expression::Variable(name) | Expression::AddressOf(name)
+            | Expression::ArrayAccess { array: name, .. } | Expression::FunctionCall { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Operands, indexes and arguments, left to right, to change in place
+    pub fn children_mut(&mut self) -> Vec<&mut Expression> {
+        match self {
+            Expression::Number(_) | Expression::String(_) | Expression::Char(_) | Expression::Bytes(_)
+            | Expression::Variable(_) | Expression::AddressOf(_) => vec![],
+            Expression::ArrayAccess { index, .. } => vec![index],
+            Expression::Negate(e) | Expression::Not(e) | Expression::Dereference(e) => vec![e],
+            Expression::Add(l, r) | Expression::Subtract(l, r) | Expression::Multiply(l, r)
+            | Expression::Divide(l, r) | Expression::Modulo(l, r)
+            | Expression::LeftShift(l, r) | Expression::RightShift(l, r)
+            | Expression::Equal(l, r) | Expression::NotEqual(l, r)
+            | Expression::Less(l, r) | Expression::LessEqual(l, r)
+            | Expression::Greater(l, r) | Expression::GreaterEqual(l, r)
+            | Expression::And(l, r) | Expression::Or(l, r) | Expression::Xor(l, r)
+            | Expression::BitAnd(l, r) | Expression::BitOr(l, r) | Expression::BitXor(l, r) => vec![l, r],
+            Expression::FunctionCall { args, .. } => args.iter_mut().collect(),
+        }
+    }
+
+    /// Operands, indexes and arguments, left to right
+    pub fn children(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Number(_) | Expression::String(_) | Expression::Char(_) | Expression::Bytes(_)
+            | Expression::Variable(_) | Expression::AddressOf(_) => vec![],
+            Expression::ArrayAccess { index, .. } => vec![index],
+            Expression::Negate(e) | Expression::Not(e) | Expression::Dereference(e) => vec![e],
+            Expression::Add(l, r) | Expression::Subtract(l, r) | Expression::Multiply(l, r)
+            | Expression::Divide(l, r) | Expression::Modulo(l, r)
+            | Expression::LeftShift(l, r) | Expression::RightShift(l, r)
+            | Expression::Equal(l, r) | Expression::NotEqual(l, r)
+            | Expression::Less(l, r) | Expression::LessEqual(l, r)
+            | Expression::Greater(l, r) | Expression::GreaterEqual(l, r)
+            | Expression::And(l, r) | Expression::Or(l, r) | Expression::Xor(l, r)
+            | Expression::BitAnd(l, r) | Expression::BitOr(l, r) | Expression::BitXor(l, r) => vec![l, r],
+            Expression::FunctionCall { args, .. } => args.iter().collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub enum Statement {
@@ -155,6 +293,87 @@ pub enum Statement {
 
     // Block of statements
     Block(Vec<Statement>),
+
+    // Source line of the statement that follows, for listings
+    Line(usize),
+}
+
+impl Statement {
+    /// Expressions the statement evaluates itself, not counting nested statements
+    pub fn expressions(&self) -> Vec<&Expression> {
+        match self {
+            Statement::VarDecl(var) => var.initial_value.iter().collect(),
+            Statement::Assignment { value, .. } => vec![value],
+            Statement::ArrayAssignment { index, value, .. } => vec![index, value],
+            Statement::PointerAssignment { pointer, value } => vec![pointer, value],
+            Statement::If { condition, .. } | Statement::While { condition, .. }
+            | Statement::Until { condition, .. } => vec![condition],
+            Statement::For { start, end, step, .. } => [Some(start), Some(end), step.as_ref()].into_iter().flatten().collect(),
+            Statement::Return(value) => value.iter().collect(),
+            Statement::ProcCall { args, .. } => args.iter().collect(),
+            Statement::Exit | Statement::Block(_) | Statement::Line(_) => vec![],
+        }
+    }
+
+    /// Variables, arrays and procedures the statement names itself, outside its expressions
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Statement::Assignment { target: name, .. } | Statement::ArrayAssignment { array: name, .. }
+            | Statement::For { var: name, .. } | Statement::ProcCall { name, .. } => vec![name],
+            _ => vec![],
+        }
+    }
+
+    /// Statements nested in this one, in source order
+    pub fn nested(&self) -> Vec<&Statement> {
+        match self {
+            Statement::If { then_block, else_block, .. } => {
+                then_block.iter().chain(else_block.iter().flatten()).collect()
+            }
+            Statement::While { body, .. } | Statement::Until { body, .. }
+            | Statement::For { body, .. } | Statement::Block(body) => body.iter().collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Drop the line markers from stmts and the statements nested in them
+pub fn strip_lines(stmts: &mut Vec<Statement>) {
+    stmts.retain(|stmt| !matches!(stmt, Statement::Line(_)));
+    for stmt in stmts {
+        match stmt {
+            Statement::If { then_block, else_block, .. } => {
+                strip_lines(then_block);
+                if let Some(else_block) = else_block {
+                    strip_lines(else_block);
+                }
+            }
+            Statement::While { body, .. } | Statement::Until { body, .. }
+            | Statement::For { body, .. } | Statement::Block(body) => strip_lines(body),
+            _ => {}
+        }
+    }
+}
+
+/// Every name stmts and their expressions use, in order
+pub fn collect_names<'a>(stmts: &'a [Statement], names: &mut Vec<&'a str>) {
+    for stmt in stmts {
+        names.extend(stmt.names());
+        for expr in stmt.expressions() {
+            collect_expression_names(expr, names);
+        }
+        for nested in stmt.nested() {
+            collect_names(std::slice::from_ref(nested), names);
+        }
+    }
+}
+
+/// Every name expr uses, in order
+pub fn collect_expression_names<'a>(expr: &'a Expression, names: &mut Vec<&'a str>) {
+    names.extend(expr.name());
+    for child in expr.children() {
+        collect_expression_names(child, names);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -165,12 +384,42 @@ pub struct Procedure {
     pub return_type: Option<DataType>,  // None for PROC, Some for FUNC
     pub locals: Vec<Variable>,
     pub body: Vec<Statement>,
+    pub address: Option<u16>,  // Fixed placement: PROC Name = $F000()
+    pub fast_call: bool,       // Arguments in registers: PROC Name(BYTE b) FASTCALL
+    pub interrupt: bool,       // Run by an interrupt: PROC Tick() INTERRUPT
+    pub module: usize,         // MODULE section it is in, counting from 0 before the first
+}
+
+impl Procedure {
+    /// Whether other takes the same arguments the same way and returns the same type,
+    /// whatever its parameters are called
+    pub fn same_signature(&self, other: &Procedure) -> bool {
+        self.return_type == other.return_type
+            && self.address == other.address
+            && self.fast_call == other.fast_call
+            && self.interrupt == other.interrupt
+            && self.params.len() == other.params.len()
+            && self.params.iter().zip(&other.params).all(|(a, b)| a.data_type == b.data_type && a.by_ref == b.by_ref)
+    }
 }
 
+/// Where a global or a procedure body is in the source
 #[derive(Debug, Clone)]
+pub struct Definition {
+    pub name: String,
+    pub line: usize,
+    pub module: usize,
+    pub global: bool,  // The next of program.globals, rather than the next of program.procedures
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Program {
     pub globals: Vec<Variable>,
     pub procedures: Vec<Procedure>,
+    pub declarations: Vec<Procedure>,  // FORWARD headings, without locals or body
+    pub modules: Vec<usize>,           // Line of each MODULE; module n + 1 starts at modules[n]
+    pub private: Vec<(String, usize)>, // PRIVATE globals and procedures, with their module
+    pub definitions: Vec<Definition>,  // Globals and procedures in source order
 }
 
 impl Program {
@@ -178,6 +427,18 @@ impl Program {
         Program {
             globals: Vec::new(),
             procedures: Vec::new(),
+            declarations: Vec::new(),
+            modules: Vec::new(),
+            private: Vec::new(),
+            definitions: Vec::new(),
+        }
+    }
+
+    /// Where module starts, for messages
+    pub fn module_name(&self, module: usize) -> String {
+        match module {
+            0 => "the start of the program".to_string(),
+            n => format!("the MODULE at line {}", self.modules[n - 1]),
         }
     }
 }
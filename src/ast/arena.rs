@@ -0,0 +1,162 @@
+// Typed IDs for every expression of a program.
+//
+// The arena indexes the tree in one Vec, so analysis passes can walk it without
+// recursion and keep their results in side tables indexed by ID rather than in maps
+// keyed by name or address. IDs follow source order, each expression before its
+// operands, so a pass going through them in reverse meets operands first.
+
+use super::{Expression, Procedure, Program, Statement};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// An expression's place in an ExprArena
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExprId(u32);
+
+impl ExprId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+struct Node<'a> {
+    expr: &'a Expression,
+    parent: Option<ExprId>,
+    children: Vec<ExprId>,
+}
+
+/// Every expression of a program, borrowed from its tree
+pub struct ExprArena<'a> {
+    nodes: Vec<Node<'a>>,
+    roots: Vec<ExprId>,
+    ids: HashMap<*const Expression, ExprId>,
+    procedures: Vec<Range<u32>>,  // The IDs in each procedure, after the globals'
+}
+
+impl<'a> ExprArena<'a> {
+    /// The expressions of the globals' initial values, then of each procedure in turn
+    pub fn new(program: &'a Program) -> Self {
+        let mut arena = ExprArena { nodes: Vec::new(), roots: Vec::new(), ids: HashMap::new(), procedures: Vec::new() };
+        for var in &program.globals {
+            if let Some(value) = &var.initial_value {
+                arena.add_root(value);
+            }
+        }
+        for proc in &program.procedures {
+            let start = arena.nodes.len() as u32;
+            arena.add_procedure(proc);
+            arena.procedures.push(start..arena.nodes.len() as u32);
+        }
+        arena
+    }
+
+    fn add_procedure(&mut self, proc: &'a Procedure) {
+        for var in &proc.locals {
+            if let Some(value) = &var.initial_value {
+                self.add_root(value);
+            }
+        }
+        self.add_statements(&proc.body);
+    }
+
+    fn add_statements(&mut self, stmts: &'a [Statement]) {
+        for stmt in stmts {
+            for expr in stmt.expressions() {
+                self.add_root(expr);
+            }
+            for nested in stmt.nested() {
+                self.add_statements(std::slice::from_ref(nested));
+            }
+        }
+    }
+
+    fn add_root(&mut self, expr: &'a Expression) {
+        let id = self.add(expr, None);
+        self.roots.push(id);
+    }
+
+    // Add expr and its operands, depth first with a stack of its own rather than by
+    // recursion, however deep the tree
+    fn add(&mut self, expr: &'a Expression, parent: Option<ExprId>) -> ExprId {
+        let root = ExprId(self.nodes.len() as u32);
+        let mut pending = vec![(expr, parent)];
+        while let Some((expr, parent)) = pending.pop() {
+            let id = ExprId(self.nodes.len() as u32);
+            self.nodes.push(Node { expr, parent, children: Vec::new() });
+            self.ids.insert(expr as *const Expression, id);
+            if let Some(parent) = parent {
+                self.nodes[parent.index()].children.push(id);
+            }
+            pending.extend(expr.children().into_iter().rev().map(|child| (child, Some(id))));
+        }
+        root
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn get(&self, id: ExprId) -> &'a Expression {
+        self.nodes[id.index()].expr
+    }
+
+    /// The expression this one is an operand, index or argument of
+    pub fn parent(&self, id: ExprId) -> Option<ExprId> {
+        self.nodes[id.index()].parent
+    }
+
+    pub fn children(&self, id: ExprId) -> &[ExprId] {
+        &self.nodes[id.index()].children
+    }
+
+    /// Expressions evaluated directly by statements and declarations, in source order
+    pub fn roots(&self) -> &[ExprId] {
+        &self.roots
+    }
+
+    /// ID of an expression in the tree the arena was built from
+    pub fn id(&self, expr: &Expression) -> Option<ExprId> {
+        self.ids.get(&(expr as *const Expression)).copied()
+    }
+
+    pub fn ids(&self) -> impl DoubleEndedIterator<Item = ExprId> {
+        (0..self.nodes.len() as u32).map(ExprId)
+    }
+
+    /// IDs in the globals' initial values
+    pub fn globals(&self) -> impl DoubleEndedIterator<Item = ExprId> {
+        let end = self.procedures.first().map_or(self.nodes.len() as u32, |ids| ids.start);
+        (0..end).map(ExprId)
+    }
+
+    /// IDs in the locals' initial values and the body of the program's procedure at index
+    pub fn procedure(&self, index: usize) -> impl DoubleEndedIterator<Item = ExprId> {
+        self.procedures[index].clone().map(ExprId)
+    }
+}
+
+/// One value per expression of an arena, filled in by an analysis pass
+pub struct SideTable<T> {
+    values: Vec<Option<T>>,
+}
+
+impl<T> SideTable<T> {
+    pub fn new(arena: &ExprArena) -> Self {
+        SideTable { values: std::iter::repeat_with(|| None).take(arena.len()).collect() }
+    }
+
+    pub fn get(&self, id: ExprId) -> Option<&T> {
+        self.values[id.index()].as_ref()
+    }
+
+    pub fn insert(&mut self, id: ExprId, value: T) {
+        self.values[id.index()] = Some(value);
+    }
+}
+
+#[cfg(test)]
+mod tests;
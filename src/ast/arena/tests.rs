@@ -0,0 +1,50 @@
+// Expression IDs over parsed programs
+
+use super::*;
+use crate::test_support::parse;
+
+#[test]
+fn ids_follow_source_order() {
+    let program = parse("BYTE b = 2\nPROC main()\nb = b + 3 * b\nIF b THEN PrintB(1) FI\nRETURN\n").unwrap();
+    let arena = ExprArena::new(&program);
+    let shown: Vec<String> = arena.ids().map(|id| match arena.get(id) {
+        Expression::Number(n) => n.to_string(),
+        Expression::Variable(name) => name.clone(),
+        Expression::Add(..) => "+".to_string(),
+        Expression::Multiply(..) => "*".to_string(),
+        other => format!("{:?}", other),
+    }).collect();
+    assert_eq!(shown, ["2", "+", "b", "*", "3", "b", "b", "1"]);
+    assert_eq!(arena.roots().len(), 4);
+}
+
+#[test]
+fn parents_children_and_side_tables() {
+    let program = parse("BYTE b\nPROC main()\nb = b + 1\nRETURN\n").unwrap();
+    let arena = ExprArena::new(&program);
+    let [sum] = arena.roots() else { panic!("one root expected") };
+    let operands = arena.children(*sum);
+    assert_eq!(operands.len(), 2);
+    assert!(operands.iter().all(|&id| arena.parent(id) == Some(*sum)));
+
+    let mut constants = SideTable::new(&arena);
+    for id in arena.ids() {
+        if let Some(value) = arena.get(id).const_value() {
+            constants.insert(id, value);
+        }
+    }
+    assert_eq!(constants.get(operands[1]), Some(&1));
+    assert_eq!(constants.get(*sum), None);
+    assert_eq!(arena.id(arena.get(operands[0])), Some(operands[0]));
+}
+
+#[test]
+fn ids_by_procedure() {
+    let program = parse("BYTE b = 2\nPROC first()\nb = 1\nRETURN\nPROC main()\nBYTE c = 3\nb = c + 1\nRETURN\n").unwrap();
+    let arena = ExprArena::new(&program);
+    let shown = |ids: Vec<ExprId>| -> Vec<String> { ids.into_iter().map(|id| format!("{:?}", arena.get(id))).collect() };
+    assert_eq!(shown(arena.globals().collect()), ["Number(2)"]);
+    assert_eq!(shown(arena.procedure(0).collect()), ["Number(1)"]);
+    assert_eq!(shown(arena.procedure(1).rev().take(2).collect()), ["Number(1)", "Variable(\"c\")"]);
+    assert_eq!(arena.procedure(1).count(), 4);
+}
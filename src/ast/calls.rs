@@ -0,0 +1,142 @@
+// Which procedures call which, from the calls in their bodies.
+//
+// Only calls to the program's own procedures are edges: built-ins, and array element
+// accesses that look like calls, are left out. Procedures are identified by their
+// symbol key under the compiler's case policy.
+
+use super::{Expression, Program, Statement};
+use std::collections::HashSet;
+
+/// The call graph of a program
+pub struct CallGraph {
+    names: Vec<String>,        // Procedure keys, in program order
+    callees: Vec<Vec<usize>>,  // Procedures each one calls, in order of first call
+}
+
+impl CallGraph {
+    pub fn new(program: &Program, key: impl Fn(&str) -> String) -> Self {
+        let names: Vec<String> = program.procedures.iter().map(|p| key(&p.name)).collect();
+        let callees = program.procedures.iter()
+            .map(|proc| {
+                let mut called = Vec::new();
+                add_calls(&proc.body, &mut |name| {
+                    if let Some(i) = names.iter().position(|n| *n == key(name)) {
+                        if !called.contains(&i) {
+                            called.push(i);
+                        }
+                    }
+                });
+                called
+            })
+            .collect();
+        CallGraph { names, callees }
+    }
+
+    fn index(&self, key: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == key)
+    }
+
+    /// Procedures the procedure with key calls directly
+    pub fn callees(&self, key: &str) -> Vec<&str> {
+        self.index(key).map_or_else(Vec::new, |i| self.callees[i].iter().map(|&c| self.names[c].as_str()).collect())
+    }
+
+    /// A procedure that can call itself, directly or through others, if there is one
+    pub fn recursive(&self) -> Option<&str> {
+        // Depth-first search for an edge back to a procedure still being searched
+        #[derive(Clone, Copy, PartialEq)]
+        enum State { New, Open, Done }
+        fn search(graph: &CallGraph, i: usize, state: &mut [State]) -> Option<usize> {
+            state[i] = State::Open;
+            for &c in &graph.callees[i] {
+                match state[c] {
+                    State::Open => return Some(c),
+                    State::New => {
+                        if let Some(found) = search(graph, c, state) {
+                            return Some(found);
+                        }
+                    }
+                    State::Done => {}
+                }
+            }
+            state[i] = State::Done;
+            None
+        }
+        let mut state = vec![State::New; self.names.len()];
+        (0..self.names.len())
+            .find_map(|i| if state[i] == State::New { search(self, i, &mut state) } else { None })
+            .map(|i| self.names[i].as_str())
+    }
+
+    /// Every procedure after all of its callers, or None if the program is recursive
+    pub fn callers_first(&self) -> Option<Vec<&str>> {
+        let mut callers = vec![0; self.names.len()];
+        for called in &self.callees {
+            for &c in called {
+                callers[c] += 1;
+            }
+        }
+        let mut ready: Vec<usize> = (0..self.names.len()).rev().filter(|&i| callers[i] == 0).collect();
+        let mut order = Vec::new();
+        while let Some(i) = ready.pop() {
+            order.push(self.names[i].as_str());
+            for &c in self.callees[i].iter().rev() {
+                callers[c] -= 1;
+                if callers[c] == 0 {
+                    ready.push(c);
+                }
+            }
+        }
+        (order.len() == self.names.len()).then_some(order)
+    }
+
+    /// The procedures with the keys given and every procedure they can call
+    pub fn reachable<'a>(&self, from: impl IntoIterator<Item = &'a str>) -> HashSet<&str> {
+        let mut seen = HashSet::new();
+        let mut todo: Vec<usize> = from.into_iter().filter_map(|key| self.index(key)).collect();
+        while let Some(i) = todo.pop() {
+            if seen.insert(self.names[i].as_str()) {
+                todo.extend(&self.callees[i]);
+            }
+        }
+        seen
+    }
+}
+
+/// The names stmts call, built-ins included, each once in order of first call
+pub fn called(stmts: &[Statement]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    add_calls(stmts, &mut |name| {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    });
+    names
+}
+
+// Call found for the name of every procedure or function called in stmts
+fn add_calls(stmts: &[Statement], found: &mut dyn FnMut(&str)) {
+    for stmt in stmts {
+        if let Statement::ProcCall { name, .. } = stmt {
+            found(name);
+        }
+        for expr in stmt.expressions() {
+            add_expression_calls(expr, found);
+        }
+        for nested in stmt.nested() {
+            add_calls(std::slice::from_ref(nested), found);
+        }
+    }
+}
+
+fn add_expression_calls(expr: &Expression, found: &mut dyn FnMut(&str)) {
+    if let Expression::FunctionCall { name, .. } = expr {
+        found(name);
+    }
+    for child in expr.children() {
+        add_expression_calls(child, found);
+    }
+}
+
+#[cfg(test)]
+mod tests;
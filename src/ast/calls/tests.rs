@@ -0,0 +1,40 @@
+// Call graphs of parsed programs
+
+use super::*;
+use crate::test_support::parse;
+
+fn graph(source: &str) -> CallGraph {
+    CallGraph::new(&parse(source).unwrap(), |name| name.to_ascii_uppercase())
+}
+
+#[test]
+fn calls_in_statements_and_expressions() {
+    let g = graph("\
+BYTE ARRAY a(4)
+FUNC BYTE f()
+RETURN (a(1))
+PROC p()
+PrintB(f())
+RETURN
+PROC main()
+IF f() THEN p() FI
+p()
+RETURN
+");
+    assert_eq!(g.callees("F"), Vec::<&str>::new());
+    assert_eq!(g.callees("P"), ["F"]);
+    assert_eq!(g.callees("MAIN"), ["F", "P"]);
+    assert_eq!(g.recursive(), None);
+    assert_eq!(g.callers_first(), Some(vec!["MAIN", "P", "F"]));
+    let mut reached: Vec<&str> = g.reachable(["P"]).into_iter().collect();
+    reached.sort();
+    assert_eq!(reached, ["F", "P"]);
+}
+
+#[test]
+fn recursion_through_other_procedures() {
+    let g = graph("PROC a()\nb()\nRETURN\nPROC b()\nc()\nRETURN\nPROC c()\nWHILE 1 DO a() OD\nRETURN\nPROC main()\na()\nRETURN\n");
+    assert_eq!(g.recursive(), Some("A"));
+    assert_eq!(g.callers_first(), None);
+    assert_eq!(graph("PROC a()\na()\nRETURN\n").recursive(), Some("A"));
+}
@@ -0,0 +1,162 @@
+// Names defined in more than one MODULE section.
+//
+// A PRIVATE global or procedure may share its name with those of other modules. Each
+// such private one is renamed with its module's number in front, in its definition and
+// in the uses in its own module, so every use finds the one it can see: its module's
+// own, or else the public one. Two definitions of a name in one module, or two public
+// ones, are an error. Names are compared by their symbol key under the compiler's case
+// policy.
+
+use super::{Expression, Program, Statement};
+use std::collections::{HashMap, HashSet};
+
+/// The name a PRIVATE name of module gets when another definition shares it
+pub fn mangle(name: &str, module: usize) -> String {
+    format!("M{}.{}", module, name)
+}
+
+/// The program with its shared PRIVATE names mangled, or why two definitions of a name
+/// cannot both stand
+pub fn separate(program: &Program, key: impl Fn(&str) -> String) -> Result<Program, String> {
+    let private: HashSet<(String, usize)> = program.private.iter().map(|(name, module)| (key(name), *module)).collect();
+
+    // Every definition against the earlier ones of its name
+    let mut seen: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, def) in program.definitions.iter().enumerate() {
+        let k = key(&def.name);
+        let public = !private.contains(&(k.clone(), def.module));
+        for &j in seen.get(&k).into_iter().flatten() {
+            let earlier = &program.definitions[j];
+            if earlier.module == def.module {
+                return Err(format!("{} is defined twice in {}, at line {} and at line {}",
+                                   def.name, program.module_name(def.module), earlier.line, def.line));
+            }
+            if public && !private.contains(&(k.clone(), earlier.module)) {
+                return Err(format!("{} is public at line {} and at line {}; all but one must be PRIVATE",
+                                   def.name, earlier.line, def.line));
+            }
+        }
+        seen.entry(k).or_default().push(i);
+    }
+    let mangled: HashSet<(String, usize)> = private.into_iter()
+        .filter(|(k, _)| seen.get(k).is_some_and(|defs| defs.len() > 1))
+        .collect();
+
+    let mut program = program.clone();
+    if mangled.is_empty() {
+        return Ok(program);
+    }
+    let rename = |name: &mut String, module: usize| {
+        if mangled.contains(&(key(name.as_str()), module)) {
+            *name = mangle(name, module);
+        }
+    };
+
+    let modules: Vec<usize> = program.definitions.iter().filter(|d| d.global).map(|d| d.module).collect();
+    for (var, &module) in program.globals.iter_mut().zip(&modules) {
+        rename(&mut var.name, module);
+        if let Some(value) = &mut var.initial_value {
+            rename_expression(value, &mut |name| rename(name, module));
+        }
+    }
+    for proc in program.procedures.iter_mut().chain(program.declarations.iter_mut()) {
+        let module = proc.module;
+        rename(&mut proc.name, module);
+
+        // A procedure's own locals and parameters hide the module's names
+        let own: HashSet<String> = proc.locals.iter().map(|v| key(&v.name))
+            .chain(proc.params.iter().map(|p| key(&p.name)))
+            .collect();
+        let mut uses = |name: &mut String| {
+            if !own.contains(&key(name.as_str())) {
+                rename(name, module);
+            }
+        };
+        for local in &mut proc.locals {
+            if let Some(value) = &mut local.initial_value {
+                rename_expression(value, &mut uses);
+            }
+        }
+        rename_statements(&mut proc.body, &mut uses);
+    }
+    for (name, module) in &mut program.private {
+        rename(name, *module);
+    }
+    for def in &mut program.definitions {
+        rename(&mut def.name, def.module);
+    }
+    Ok(program)
+}
+
+// Pass every name stmts use to rename
+fn rename_statements(stmts: &mut [Statement], rename: &mut dyn FnMut(&mut String)) {
+    for stmt in stmts {
+        match stmt {
+            Statement::VarDecl(var) => {
+                if let Some(value) = &mut var.initial_value {
+                    rename_expression(value, rename);
+                }
+            }
+            Statement::Assignment { target, value } => {
+                rename(target);
+                rename_expression(value, rename);
+            }
+            Statement::ArrayAssignment { array, index, value } => {
+                rename(array);
+                rename_expression(index, rename);
+                rename_expression(value, rename);
+            }
+            Statement::PointerAssignment { pointer, value } => {
+                rename_expression(pointer, rename);
+                rename_expression(value, rename);
+            }
+            Statement::If { condition, then_block, else_block } => {
+                rename_expression(condition, rename);
+                rename_statements(then_block, rename);
+                if let Some(else_block) = else_block {
+                    rename_statements(else_block, rename);
+                }
+            }
+            Statement::While { condition, body } | Statement::Until { condition, body } => {
+                rename_expression(condition, rename);
+                rename_statements(body, rename);
+            }
+            Statement::For { var, start, end, step, body } => {
+                rename(var);
+                rename_expression(start, rename);
+                rename_expression(end, rename);
+                if let Some(step) = step {
+                    rename_expression(step, rename);
+                }
+                rename_statements(body, rename);
+            }
+            Statement::Return(value) => {
+                if let Some(value) = value {
+                    rename_expression(value, rename);
+                }
+            }
+            Statement::ProcCall { name, args } => {
+                rename(name);
+                for arg in args {
+                    rename_expression(arg, rename);
+                }
+            }
+            Statement::Block(body) => rename_statements(body, rename),
+            Statement::Exit | Statement::Line(_) => {}
+        }
+    }
+}
+
+fn rename_expression(expr: &mut Expression, rename: &mut dyn FnMut(&mut String)) {
+    match expr {
+        Expression::Variable(name) | Expression::AddressOf(name)
+        | Expression::ArrayAccess { array: name, .. } | Expression::FunctionCall { name, .. } => rename(name),
+        _ => {}
+    }
+    for child in expr.children_mut() {
+        rename_expression(child, rename);
+    }
+}
+
+#[cfg(test)]
+mod tests;
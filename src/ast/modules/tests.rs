@@ -0,0 +1,81 @@
+// Shared names across MODULE sections
+
+use super::*;
+use crate::test_support::parse;
+
+fn separated(source: &str) -> Result<Program, String> {
+    separate(&parse(source).unwrap(), |name| name.to_ascii_uppercase())
+}
+
+#[test]
+fn shared_private_names_take_their_module() {
+    let program = separated("\
+MODULE
+PRIVATE BYTE count
+PROC bump()
+BYTE total
+count = count + 1
+total = count
+RETURN
+MODULE
+PRIVATE BYTE Count
+PROC reset(BYTE count)
+count = 0
+RETURN
+PROC clear()
+count = 0
+RETURN
+MODULE
+PROC main()
+bump()
+RETURN
+").unwrap();
+    let globals: Vec<&str> = program.globals.iter().map(|v| v.name.as_str()).collect();
+    assert_eq!(globals, ["M1.count", "M2.Count"]);
+    let body = format!("{:?}", program.procedures[0].body);
+    assert!(body.contains("\"M1.count\"") && !body.contains("\"count\""), "{}", body);
+    // The parameter hides the module's own
+    assert!(format!("{:?}", program.procedures[1].body).contains("target: \"count\""));
+    assert!(format!("{:?}", program.procedures[2].body).contains("target: \"M2.count\""));
+    assert_eq!(program.private, [("M1.count".to_string(), 1), ("M2.Count".to_string(), 2)]);
+}
+
+#[test]
+fn names_defined_once_stay() {
+    let source = "MODULE\nPRIVATE BYTE count\nMODULE\nBYTE total\nPROC main()\nRETURN\n";
+    let program = separated(source).unwrap();
+    assert_eq!(program.globals[0].name, "count");
+    assert_eq!(program.private, [("count".to_string(), 1)]);
+}
+
+#[test]
+fn public_names_are_left_for_other_modules() {
+    let program = separated("\
+MODULE
+PRIVATE PROC show()
+RETURN
+PROC first()
+show()
+RETURN
+MODULE
+PROC show()
+RETURN
+PROC main()
+show()
+RETURN
+").unwrap();
+    let names: Vec<&str> = program.procedures.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["M1.show", "first", "show", "main"]);
+    assert!(format!("{:?}", program.procedures[1].body).contains("\"M1.show\""));
+    assert!(format!("{:?}", program.procedures[3].body).contains("name: \"show\""));
+}
+
+#[test]
+fn duplicates_name_both_lines() {
+    assert_eq!(separated("BYTE count\nPROC main()\nRETURN\nCARD COUNT\n").unwrap_err(),
+               "COUNT is defined twice in the start of the program, at line 1 and at line 4");
+    assert_eq!(separated("MODULE\nPRIVATE BYTE count\nPROC count()\nRETURN\n").unwrap_err(),
+               "count is defined twice in the MODULE at line 1, at line 2 and at line 3");
+    assert_eq!(separated("MODULE\nBYTE count\nMODULE\nPRIVATE BYTE x\nBYTE count\n").unwrap_err(),
+               "count is public at line 2 and at line 5; all but one must be PRIVATE");
+}
@@ -0,0 +1,131 @@
+// Benchmark harness: compile a directory of programs and report size and cycle counts
+// Results can be saved as a baseline and later compared against it
+
+use crate::compile::{compile_source, CompileOptions};
+use crate::emulator::{Console, Cpu, StopReason};
+use crate::error::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const ORG: u16 = 0x4200;
+const MAX_CYCLES: u64 = 100_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    pub size: usize,            // Whole binary
+    pub runtime: usize,         // Runtime library part of it
+    pub cycles: Option<u64>,    // None if the program did not halt in time
+}
+
+pub struct BenchResult {
+    pub name: String,
+    pub metrics: std::result::Result<Metrics, String>,
+}
+
+// The image of source and the size of the runtime library in it
+fn compile(source: &str) -> Result<(Vec<u8>, usize)> {
+    let output = compile_source(source, CompileOptions { origin: ORG, ..CompileOptions::default() })?;
+    Ok((output.binary, output.runtime_size))
+}
+
+// Run a binary with no console input and count cycles until HALT
+fn measure(binary: &[u8]) -> Option<u64> {
+    let mut cpu = Cpu::new();
+    cpu.load(ORG, binary);
+    cpu.pc = ORG;
+    let mut console = Console::new();
+    match cpu.run(&mut console, Some(MAX_CYCLES)) {
+        StopReason::Halted => Some(cpu.cycles),
+        StopReason::CycleLimit => None,
+    }
+}
+
+/// Compile and run every .act file in a directory
+pub fn run(dir: &Path) -> io::Result<Vec<BenchResult>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "act"))
+        .collect();
+    paths.sort();
+
+    let mut results = Vec::new();
+    for path in paths {
+        let source = fs::read_to_string(&path)?;
+        let metrics = compile(&source)
+            .map(|(binary, runtime)| Metrics {
+                size: binary.len(),
+                runtime,
+                cycles: measure(&binary),
+            })
+            .map_err(|e| e.to_string());
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        results.push(BenchResult { name, metrics });
+    }
+    Ok(results)
+}
+
+/// Save results as a baseline, one tab-separated line per program that compiled
+pub fn save(path: &Path, results: &[BenchResult]) -> io::Result<()> {
+    let mut text = String::from("# program\tsize\truntime\tcycles\n");
+    for r in results {
+        if let Ok(m) = &r.metrics {
+            let cycles = m.cycles.map_or("-".to_string(), |c| c.to_string());
+            text.push_str(&format!("{}\t{}\t{}\t{}\n", r.name, m.size, m.runtime, cycles));
+        }
+    }
+    fs::write(path, text)
+}
+
+/// Load a baseline written by `save`
+pub fn load(path: &Path) -> io::Result<HashMap<String, Metrics>> {
+    let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Bad baseline line: {}", line));
+    let mut baseline = HashMap::new();
+    for line in fs::read_to_string(path)?.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [name, size, runtime, cycles] = fields[..] else {
+            return Err(invalid(line));
+        };
+        let metrics = Metrics {
+            size: size.parse().map_err(|_| invalid(line))?,
+            runtime: runtime.parse().map_err(|_| invalid(line))?,
+            cycles: if cycles == "-" { None } else { Some(cycles.parse().map_err(|_| invalid(line))?) },
+        };
+        baseline.insert(name.to_string(), metrics);
+    }
+    Ok(baseline)
+}
+
+// A value with its change from the baseline, e.g. "190 (+4)"
+fn with_delta(value: Option<u64>, base: Option<u64>) -> String {
+    match (value, base) {
+        (None, _) => "-".to_string(),
+        (Some(v), Some(b)) if v != b => format!("{} ({:+})", v, v as i64 - b as i64),
+        (Some(v), _) => v.to_string(),
+    }
+}
+
+/// Format results as a table, with changes from the baseline if one is given
+pub fn report(results: &[BenchResult], baseline: Option<&HashMap<String, Metrics>>) -> String {
+    let mut out = format!("{:<20} {:>14} {:>14} {:>22}\n", "Program", "Size", "Runtime", "Cycles");
+    for r in results {
+        match &r.metrics {
+            Ok(m) => {
+                let base = baseline.and_then(|b| b.get(&r.name));
+                out.push_str(&format!(
+                    "{:<20} {:>14} {:>14} {:>22}\n",
+                    r.name,
+                    with_delta(Some(m.size as u64), base.map(|b| b.size as u64)),
+                    with_delta(Some(m.runtime as u64), base.map(|b| b.runtime as u64)),
+                    with_delta(m.cycles, base.and_then(|b| b.cycles)),
+                ));
+            }
+            Err(e) => out.push_str(&format!("{:<20} error: {}\n", r.name, e)),
+        }
+    }
+    out
+}
@@ -0,0 +1,350 @@
+// Control-flow graphs of procedures, as basic blocks of their statements.
+//
+// A block is a run of statements that is entered only at its first and left only
+// after its last: an IF, a loop's test and the end of a loop body each end one, and
+// a loop's test and whatever follows an IF, a loop or an EXIT start one. Statements
+// after an EXIT start a block that nothing reaches, which is left in the graph since
+// it is there in the code too. Each statement is given with its source line, as the
+// parser marked it; the test of DO ... UNTIL and the step of FOR with the loop's.
+
+use crate::ast::*;
+
+/// Where a block goes once its statements are done
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub to: Target,
+    pub label: Option<&'static str>,  // "true" or "false" from a test
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Block(usize),
+    Return,
+}
+
+/// Statements that run one after the other, as "line: statement"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Block {
+    pub statements: Vec<String>,
+    pub edges: Vec<Edge>,
+}
+
+/// A procedure's blocks, starting with the one it is entered at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Graph {
+    pub name: String,
+    pub blocks: Vec<Block>,
+}
+
+impl Graph {
+    /// The graph of proc's body
+    pub fn new(proc: &Procedure) -> Self {
+        let mut builder = Builder { blocks: vec![Block::default()], current: Some(0), line: 0, exits: Vec::new() };
+        builder.statements(&proc.body);
+        if let Some(block) = builder.current {
+            builder.blocks[block].edges.push(Edge { to: Target::Return, label: None });
+        }
+        let mut graph = Graph { name: proc.name.clone(), blocks: builder.blocks };
+        graph.skip_empty();
+        graph
+    }
+
+    // Send edges into an empty block with a single way out straight on, and drop it
+    fn skip_empty(&mut self) {
+        let forward = |blocks: &[Block], block: usize| match blocks[block].edges.as_slice() {
+            [Edge { to, label: None }] if block != 0 && blocks[block].statements.is_empty() && *to != Target::Block(block) => Some(*to),
+            _ => None,
+        };
+        let mut index = Vec::new();
+        let mut kept = 0;
+        for block in 0..self.blocks.len() {
+            index.push(kept);
+            kept += forward(&self.blocks, block).is_none() as usize;
+        }
+        let resolve = |mut to: Target| {
+            let mut steps = 0;
+            while let Target::Block(block) = to {
+                match forward(&self.blocks, block) {
+                    Some(next) if steps < self.blocks.len() => to = next,
+                    _ => return Target::Block(index[block]),
+                }
+                steps += 1;
+            }
+            to
+        };
+        let blocks = self.blocks.iter().enumerate()
+            .filter(|&(block, _)| forward(&self.blocks, block).is_none())
+            .map(|(_, block)| Block {
+                statements: block.statements.clone(),
+                edges: block.edges.iter().map(|edge| Edge { to: resolve(edge.to), label: edge.label }).collect(),
+            })
+            .collect();
+        self.blocks = blocks;
+    }
+
+    /// The graph in Graphviz's DOT language, as a cluster of a larger graph, with the
+    /// nodes named after the procedure's place among the graphs
+    pub fn dot_cluster(&self, number: usize) -> String {
+        let node = |target: Target| match target {
+            Target::Block(block) => format!("p{}_{}", number, block),
+            Target::Return => format!("p{}_return", number),
+        };
+        let mut dot = format!("  subgraph cluster_{} {{\n    label=\"{}\";\n", number, escape(&self.name));
+        dot.push_str(&format!("    {} [shape=oval, label=\"exit\"];\n", node(Target::Return)));
+        for (block, contents) in self.blocks.iter().enumerate() {
+            let label: String = contents.statements.iter().map(|s| format!("{}\\l", escape(s))).collect();
+            dot.push_str(&format!("    {} [label=\"{}\"];\n", node(Target::Block(block)), label));
+        }
+        for (block, contents) in self.blocks.iter().enumerate() {
+            for edge in &contents.edges {
+                let label = edge.label.map_or(String::new(), |label| format!(" [label=\"{}\"]", label));
+                dot.push_str(&format!("    {} -> {}{};\n", node(Target::Block(block)), node(edge.to), label));
+            }
+        }
+        dot.push_str("  }\n");
+        dot
+    }
+}
+
+/// Graphs of procs as one DOT file, each in a box named after it
+pub fn dot<'a>(procs: impl IntoIterator<Item = &'a Procedure>) -> String {
+    let mut dot = String::from("digraph cfg {\n  node [shape=box, fontname=\"monospace\"];\n");
+    for (number, proc) in procs.into_iter().enumerate() {
+        dot.push_str(&Graph::new(proc).dot_cluster(number));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+struct Builder {
+    blocks: Vec<Block>,
+    current: Option<usize>,  // Block being filled, or None after an EXIT or RETURN
+    line: usize,
+    exits: Vec<Vec<(usize, Option<&'static str>)>>,  // Per loop, the blocks that leave it for the one after
+}
+
+impl Builder {
+    fn new_block(&mut self) -> usize {
+        self.blocks.push(Block::default());
+        self.blocks.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: Option<&'static str>) {
+        self.blocks[from].edges.push(Edge { to: Target::Block(to), label });
+    }
+
+    // Add a statement to the current block, starting one if the code cannot get here
+    fn add(&mut self, text: String) -> usize {
+        let block = match self.current {
+            Some(block) => block,
+            None => self.new_block(),
+        };
+        self.blocks[block].statements.push(format!("{}: {}", self.line, text));
+        self.current = Some(block);
+        block
+    }
+
+    // A block that starts here, for a loop to come back to
+    fn start(&mut self) -> usize {
+        match self.current {
+            Some(block) if self.blocks[block].statements.is_empty() => block,
+            current => {
+                let block = self.new_block();
+                if let Some(from) = current {
+                    self.edge(from, block, None);
+                }
+                self.current = Some(block);
+                block
+            }
+        }
+    }
+
+    // Carry on in a block that the edges go to, if there are any
+    fn join(&mut self, edges: Vec<(usize, Option<&'static str>)>) {
+        if edges.is_empty() {
+            self.current = None;
+            return;
+        }
+        let block = self.new_block();
+        for (from, label) in edges {
+            self.edge(from, block, label);
+        }
+        self.current = Some(block);
+    }
+
+    // A block the test in from leads to when it comes out label
+    fn branch(&mut self, from: usize, label: &'static str) {
+        let block = self.new_block();
+        self.edge(from, block, Some(label));
+        self.current = Some(block);
+    }
+
+    fn statements(&mut self, stmts: &[Statement]) {
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Line(line) => self.line = *line,
+            Statement::VarDecl(var) => {
+                if let Some(value) = &var.initial_value {
+                    self.add(format!("{} = {}", var.name, text(value)));
+                }
+            }
+            Statement::Assignment { target, value } => {
+                self.add(format!("{} = {}", target, text(value)));
+            }
+            Statement::ArrayAssignment { array, index, value } => {
+                self.add(format!("{}({}) = {}", array, text(index), text(value)));
+            }
+            Statement::PointerAssignment { pointer, value } => {
+                self.add(format!("^{} = {}", operand(pointer), text(value)));
+            }
+            Statement::ProcCall { name, args } => {
+                self.add(format!("{}({})", name, arguments(args)));
+            }
+            Statement::Block(body) => self.statements(body),
+
+            Statement::If { condition, then_block, else_block } => {
+                let test = self.add(format!("IF {}", text(condition)));
+                let mut ends = Vec::new();
+                self.branch(test, "true");
+                self.statements(then_block);
+                ends.extend(self.current.map(|block| (block, None)));
+                match else_block {
+                    Some(else_block) => {
+                        self.branch(test, "false");
+                        self.statements(else_block);
+                        ends.extend(self.current.map(|block| (block, None)));
+                    }
+                    None => ends.push((test, Some("false"))),
+                }
+                self.join(ends);
+            }
+            Statement::While { condition, body } => {
+                let head = self.start();
+                self.add(format!("WHILE {}", text(condition)));
+                self.exits.push(vec![(head, Some("false"))]);
+                self.branch(head, "true");
+                self.statements(body);
+                self.back_to(head, None);
+                self.after_loop();
+            }
+            Statement::Until { condition, body } => {
+                let line = self.line;
+                let head = self.start();
+                self.exits.push(Vec::new());
+                self.statements(body);
+                if self.current.is_some() {
+                    self.line = line;
+                    let test = self.add(format!("UNTIL {}", text(condition)));
+                    self.back_to(head, Some("false"));
+                    self.exits.last_mut().unwrap().push((test, Some("true")));
+                }
+                self.after_loop();
+            }
+            Statement::For { var, start, end, step, body } => {
+                let line = self.line;
+                self.add(format!("{} = {}", var, text(start)));
+                let head = self.start();
+                self.add(format!("FOR {} <= {}", var, text(end)));
+                self.exits.push(vec![(head, Some("false"))]);
+                self.branch(head, "true");
+                self.statements(body);
+                if self.current.is_some() {
+                    self.line = line;
+                    let step = step.as_ref().map_or("1".to_string(), operand);
+                    self.add(format!("{} = {} + {}", var, var, step));
+                }
+                self.back_to(head, None);
+                self.after_loop();
+            }
+            Statement::Exit => {
+                if let (Some(block), Some(exits)) = (self.current, self.exits.last_mut()) {
+                    exits.push((block, None));
+                }
+                self.current = None;
+            }
+            Statement::Return(value) => {
+                let block = match value {
+                    Some(value) => self.add(format!("RETURN({})", text(value))),
+                    None => self.add("RETURN".to_string()),
+                };
+                self.blocks[block].edges.push(Edge { to: Target::Return, label: None });
+                self.current = None;
+            }
+        }
+    }
+
+    // Go round a loop again from the current block, if the code gets here
+    fn back_to(&mut self, head: usize, label: Option<&'static str>) {
+        if let Some(block) = self.current {
+            self.edge(block, head, label);
+        }
+    }
+
+    // Carry on after the innermost loop, from wherever it is left
+    fn after_loop(&mut self) {
+        let exits = self.exits.pop().unwrap_or_default();
+        self.join(exits);
+    }
+}
+
+// An expression as it would be written, with operands that are operations themselves
+// in parentheses
+fn text(expr: &Expression) -> String {
+    let binary = |l: &Expression, op: &str, r: &Expression| format!("{} {} {}", operand(l), op, operand(r));
+    match expr {
+        Expression::Number(n) => n.to_string(),
+        Expression::String(s) => format!("\"{}\"", s.replace('"', "\"\"")),
+        Expression::Char(c) => format!("'{}", c),
+        Expression::Bytes(bytes) => format!("[{} bytes]", bytes.len()),
+        Expression::Variable(name) => name.clone(),
+        Expression::ArrayAccess { array, index } => format!("{}({})", array, text(index)),
+        Expression::Negate(e) => format!("-{}", operand(e)),
+        Expression::Not(e) => format!("NOT {}", operand(e)),
+        Expression::AddressOf(name) => format!("@{}", name),
+        Expression::Dereference(e) => format!("^{}", operand(e)),
+        Expression::Add(l, r) => binary(l, "+", r),
+        Expression::Subtract(l, r) => binary(l, "-", r),
+        Expression::Multiply(l, r) => binary(l, "*", r),
+        Expression::Divide(l, r) => binary(l, "/", r),
+        Expression::Modulo(l, r) => binary(l, "MOD", r),
+        Expression::LeftShift(l, r) => binary(l, "LSH", r),
+        Expression::RightShift(l, r) => binary(l, "RSH", r),
+        Expression::Equal(l, r) => binary(l, "=", r),
+        Expression::NotEqual(l, r) => binary(l, "<>", r),
+        Expression::Less(l, r) => binary(l, "<", r),
+        Expression::LessEqual(l, r) => binary(l, "<=", r),
+        Expression::Greater(l, r) => binary(l, ">", r),
+        Expression::GreaterEqual(l, r) => binary(l, ">=", r),
+        Expression::And(l, r) => binary(l, "AND", r),
+        Expression::Or(l, r) => binary(l, "OR", r),
+        Expression::Xor(l, r) => binary(l, "XOR", r),
+        Expression::BitAnd(l, r) => binary(l, "&", r),
+        Expression::BitOr(l, r) => binary(l, "%", r),
+        Expression::BitXor(l, r) => binary(l, "!", r),
+        Expression::FunctionCall { name, args } => format!("{}({})", name, arguments(args)),
+    }
+}
+
+fn operand(expr: &Expression) -> String {
+    match expr {
+        Expression::FunctionCall { .. } => text(expr),
+        _ if expr.children().len() == 2 => format!("({})", text(expr)),
+        _ => text(expr),
+    }
+}
+
+fn arguments(args: &[Expression]) -> String {
+    args.iter().map(text).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,124 @@
+// Basic blocks and their edges for each kind of statement
+
+use super::*;
+use crate::compile::{compile_source, CompileOptions};
+use crate::test_support::parse;
+
+// Each block as its statements, then where it goes
+fn blocks(source: &str) -> Vec<String> {
+    let program = parse(source).unwrap();
+    let graph = Graph::new(&program.procedures[0]);
+    graph.blocks.iter().map(|block| {
+        let edges: Vec<String> = block.edges.iter().map(|edge| {
+            let to = match edge.to {
+                Target::Block(block) => block.to_string(),
+                Target::Return => "return".to_string(),
+            };
+            edge.label.map_or(to.clone(), |label| format!("{} {}", label, to))
+        }).collect();
+        format!("{} -> {}", block.statements.join("; "), edges.join(", "))
+    }).collect()
+}
+
+#[test]
+fn if_splits_and_joins() {
+    let source = "\
+PROC main()
+BYTE x
+x = 1
+IF x = 1 THEN
+  PrintE(\"one\")
+ELSE
+  x = 2
+FI
+RETURN
+";
+    assert_eq!(blocks(source), [
+        "3: x = 1; 4: IF x = 1 -> true 1, false 2",
+        "5: PrintE(\"one\") -> 3",
+        "7: x = 2 -> 3",
+        "9: RETURN -> return",
+    ]);
+}
+
+#[test]
+fn exit_leaves_the_innermost_loop() {
+    let source = "\
+PROC main()
+BYTE n
+n = 0
+WHILE n < 10 DO
+  n = n + 1
+  IF n = 5 THEN EXIT FI
+  PrintB(n)
+OD
+RETURN
+";
+    assert_eq!(blocks(source), [
+        "3: n = 0 -> 1",
+        "4: WHILE n < 10 -> true 2, false 4",
+        "5: n = n + 1; 6: IF n = 5 -> true 4, false 3",
+        "7: PrintB(n) -> 1",
+        "9: RETURN -> return",
+    ]);
+}
+
+#[test]
+fn until_and_for_test_at_their_ends() {
+    let source = "\
+PROC main()
+BYTE i
+DO
+  i = i + 1
+UNTIL i > 3 OD
+FOR i = 1 TO 5 STEP 2 DO
+  PrintB(i)
+OD
+RETURN
+";
+    assert_eq!(blocks(source), [
+        "4: i = i + 1; 3: UNTIL i > 3 -> false 0, true 1",
+        "6: i = 1 -> 2",
+        "6: FOR i <= 5 -> true 3, false 4",
+        "7: PrintB(i); 6: i = i + 2 -> 2",
+        "9: RETURN -> return",
+    ]);
+}
+
+#[test]
+fn code_after_exit_is_kept_unreached() {
+    let source = "\
+PROC main()
+WHILE 1 DO
+  EXIT
+  PrintE(\"never\")
+OD
+RETURN
+";
+    assert_eq!(blocks(source), [
+        "2: WHILE 1 -> true 2, false 2",
+        "4: PrintE(\"never\") -> 0",
+        "6: RETURN -> return",
+    ]);
+}
+
+#[test]
+fn dot_has_a_cluster_for_each_procedure_in_the_image() {
+    let source = "\
+PROC unused()
+RETURN
+PROC greet()
+PrintE(\"hi\")
+RETURN
+PROC main()
+greet()
+RETURN
+";
+    let program = parse(source).unwrap();
+    let dot = compile_source(source, CompileOptions::default()).unwrap().control_flow(&program);
+    assert!(dot.starts_with("digraph cfg {\n"), "{}", dot);
+    assert!(!dot.contains("\"unused\""), "{}", dot);
+    assert!(dot.contains("label=\"greet\""), "{}", dot);
+    assert!(dot.contains("p0_0 [label=\"4: PrintE(\\\"hi\\\")\\l5: RETURN\\l\"];"), "{}", dot);
+    assert!(dot.contains("p1_0 -> p1_return;"), "{}", dot);
+}
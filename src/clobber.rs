@@ -0,0 +1,274 @@
+// Registers each procedure and runtime routine changes, found by following its code.
+//
+// Every path from a routine's entry is decoded up to its returns, and calls are followed
+// into the routines they call, so a procedure's set includes its callees'. Anything that
+// cannot be followed (a jump through a register, a call outside the image) counts as
+// changing every register. Flags and the index registers are not tracked: any routine
+// may change flags, and the compiler's code puts back the IX it uses.
+//
+// The listing shows the sets, and codegen uses them to save only the pairs a replaced
+// PutD or GetD changes.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A set of the 8-bit registers A, B, C, D, E, H and L
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers(u8);
+
+impl Registers {
+    pub const NONE: Registers = Registers(0);
+    pub const ALL: Registers = Registers(0xBF);
+    pub(crate) const BC: Registers = Registers(0x03);
+    pub(crate) const DE: Registers = Registers(0x0C);
+    pub(crate) const HL: Registers = Registers(0x30);
+
+    // Bit numbers follow the Z80's register encoding: B C D E H L (HL) A
+    const NAMES: [(u8, char); 7] = [(7, 'A'), (0, 'B'), (1, 'C'), (2, 'D'), (3, 'E'), (4, 'H'), (5, 'L')];
+
+    // Register r of an instruction; (HL) is memory, not a register
+    fn r(code: u8) -> Registers {
+        if code == 6 { Registers::NONE } else { Registers(1 << code) }
+    }
+
+    // Register pair rp of an instruction; SP is not tracked
+    fn rp(code: u8) -> Registers {
+        [Registers(0x03), Registers(0x0C), Registers(0x30), Registers::NONE][code as usize]
+    }
+
+    /// Whether the two sets have a register in common
+    pub fn intersects(self, other: Registers) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    // Register pair rp2 of PUSH and POP, where AF replaces SP
+    fn rp2(code: u8) -> Registers {
+        if code == 3 { Registers(0x80) } else { Registers::rp(code) }
+    }
+}
+
+impl std::ops::BitOr for Registers {
+    type Output = Registers;
+    fn bitor(self, other: Registers) -> Registers {
+        Registers(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Registers {
+    fn bitor_assign(&mut self, other: Registers) {
+        self.0 |= other.0;
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == Registers::NONE {
+            return write!(f, "none");
+        }
+        let names: Vec<String> = Registers::NAMES.iter()
+            .filter(|(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, name)| name.to_string())
+            .collect();
+        write!(f, "{}", names.join(" "))
+    }
+}
+
+// Where execution goes after an instruction
+pub(crate) enum Flow {
+    Next,
+    Jump(u16),    // Only to the target
+    Branch(u16),  // To the target or the next instruction
+    Call(u16),    // Into the target, then on to the next instruction
+    Return,       // Out of the routine
+    Unknown,      // Somewhere that cannot be followed
+}
+
+// Length, registers written and flow of the instruction at pc
+pub(crate) fn decode(fetch: impl Fn(u16) -> Option<u8>, pc: u16) -> Option<(u16, Registers, Flow)> {
+    let op = fetch(pc)?;
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (p, q) = (y >> 1, y & 1);
+    let word = || Some(u16::from_le_bytes([fetch(pc.wrapping_add(1))?, fetch(pc.wrapping_add(2))?]));
+    let relative = || Some(pc.wrapping_add(2).wrapping_add(fetch(pc.wrapping_add(1))? as i8 as u16));
+    let none = Registers::NONE;
+    let a = Registers(0x80);
+    let hl = Registers::rp(2);
+
+    Some(match (x, z) {
+        (0, 0) => match y {
+            0 => (1, none, Flow::Next),               // NOP
+            1 => (1, a, Flow::Next),                  // EX AF, AF'
+            2 => (2, Registers::r(0), Flow::Branch(relative()?)),  // DJNZ
+            3 => (2, none, Flow::Jump(relative()?)),  // JR
+            _ => (2, none, Flow::Branch(relative()?)),  // JR cc
+        },
+        (0, 1) if q == 0 => (3, Registers::rp(p), Flow::Next),  // LD rp, nn
+        (0, 1) => (1, hl, Flow::Next),                          // ADD HL, rp
+        (0, 2) => match (q, p) {
+            (0, 0 | 1) => (1, none, Flow::Next),  // LD (BC), A / LD (DE), A
+            (0, _) => (3, none, Flow::Next),      // LD (nn), HL / LD (nn), A
+            (_, 0 | 1) => (1, a, Flow::Next),     // LD A, (BC) / LD A, (DE)
+            (_, 2) => (3, hl, Flow::Next),        // LD HL, (nn)
+            _ => (3, a, Flow::Next),              // LD A, (nn)
+        },
+        (0, 3) => (1, Registers::rp(p), Flow::Next),  // INC rp / DEC rp
+        (0, 4 | 5) => (1, Registers::r(y), Flow::Next),  // INC r / DEC r
+        (0, 6) => (2, Registers::r(y), Flow::Next),  // LD r, n
+        (0, _) => (1, if y < 6 { a } else { none }, Flow::Next),  // Rotates, DAA, CPL, SCF, CCF
+        (1, _) if op == 0x76 => (1, none, Flow::Return),  // HALT
+        (1, _) => (1, Registers::r(y), Flow::Next),  // LD r, r'
+        (2, _) => (1, if y == 7 { none } else { a }, Flow::Next),  // ALU A, r (CP changes only flags)
+        (3, 0) => (1, none, Flow::Branch(pc.wrapping_add(1))),  // RET cc: the next instruction follows
+        (3, 1) => match (q, p) {
+            (0, _) => (1, Registers::rp2(p), Flow::Next),  // POP rp2
+            (_, 0) => (1, none, Flow::Return),             // RET
+            (_, 1) => (1, Registers::rp(0) | Registers::rp(1) | hl, Flow::Next),  // EXX
+            (_, 2) => (1, none, Flow::Unknown),            // JP (HL)
+            _ => (1, none, Flow::Next),                    // LD SP, HL
+        },
+        (3, 2) => (3, none, Flow::Branch(word()?)),  // JP cc, nn
+        (3, 3) => match y {
+            0 => (3, none, Flow::Jump(word()?)),  // JP nn
+            1 => {
+                // CB prefix: rotates, shifts, BIT, RES and SET
+                let op = fetch(pc.wrapping_add(1))?;
+                let written = if op >> 6 == 1 { none } else { Registers::r(op & 7) };
+                (2, written, Flow::Next)
+            }
+            2 => (2, none, Flow::Next),  // OUT (n), A
+            3 => (2, a, Flow::Next),     // IN A, (n)
+            4 => (1, hl, Flow::Next),    // EX (SP), HL
+            5 => (1, Registers::rp(1) | hl, Flow::Next),  // EX DE, HL
+            _ => (1, none, Flow::Next),  // DI / EI
+        },
+        (3, 4) => (3, none, Flow::Call(word()?)),  // CALL cc, nn
+        (3, 5) if q == 0 => (1, none, Flow::Next),  // PUSH rp2
+        (3, 5) if p == 0 => (3, none, Flow::Call(word()?)),  // CALL nn
+        (3, 5) if p == 2 => decode_ed(fetch(pc.wrapping_add(1))?),
+        (3, 5) => decode_indexed(&fetch, pc)?,  // IX and IY instructions
+        (3, 6) => (2, if y == 7 { none } else { a }, Flow::Next),  // ALU A, n
+        _ => (1, none, Flow::Call(u16::from(y) * 8)),  // RST
+    })
+}
+
+// Length, registers written and flow of a DD- or FD-prefixed instruction: the HL form
+// after the prefix, with a displacement when it reads or writes (HL), and IX or IY in
+// place of HL, H and L otherwise. IX and IY are not tracked.
+fn decode_indexed(fetch: &dyn Fn(u16) -> Option<u8>, pc: u16) -> Option<(u16, Registers, Flow)> {
+    let op = fetch(pc.wrapping_add(1))?;
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    match op {
+        0xCB => return Some((4, Registers::NONE, Flow::Next)),  // Bit operations on (IX+d)
+        0xDD | 0xED | 0xFD => return Some((1, Registers::NONE, Flow::Next)),  // A prefix that changes nothing
+        0xE9 => return Some((2, Registers::NONE, Flow::Unknown)),  // JP (IX)
+        _ => {}
+    }
+    let (len, written, flow) = decode(fetch, pc.wrapping_add(1))?;
+    let memory = match x {
+        0 => (4..=6).contains(&z) && y == 6,  // INC, DEC and LD (HL), n
+        1 => (y == 6 || z == 6) && op != 0x76,  // LD r, (HL) and LD (HL), r
+        2 => z == 6,  // ALU A, (HL)
+        _ => false,
+    };
+    Some(if memory {
+        (len + 2, written, flow)
+    } else {
+        (len + 1, Registers(written.0 & !Registers::rp(2).0), flow)
+    })
+}
+
+// Length, registers written and flow of an ED-prefixed instruction
+fn decode_ed(op: u8) -> (u16, Registers, Flow) {
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (p, q) = (y >> 1, y & 1);
+    let a = Registers(0x80);
+    let (bc, de, hl) = (Registers::rp(0), Registers::rp(1), Registers::rp(2));
+    match (x, z) {
+        (1, 0) => (2, Registers::r(y), Flow::Next),  // IN r, (C)
+        (1, 1) | (1, 6) => (2, Registers::NONE, Flow::Next),  // OUT (C), r / IM
+        (1, 2) => (2, hl, Flow::Next),  // SBC HL, rp / ADC HL, rp
+        (1, 3) if q == 1 => (4, Registers::rp(p), Flow::Next),  // LD rp, (nn)
+        (1, 3) => (4, Registers::NONE, Flow::Next),  // LD (nn), rp
+        (1, 4) => (2, a, Flow::Next),  // NEG
+        (1, 5) => (2, Registers::NONE, Flow::Return),  // RETN / RETI
+        (1, _) => (2, if (2..=5).contains(&y) { a } else { Registers::NONE }, Flow::Next),  // LD A, I/R, RRD, RLD
+        (2, 0) if y >= 4 => (2, bc | de | hl, Flow::Next),  // LDI, LDIR and friends
+        (2, 1) if y >= 4 => (2, bc | hl, Flow::Next),  // CPI, CPIR and friends
+        (2, 2 | 3) if y >= 4 => (2, Registers::r(0) | hl, Flow::Next),  // INI, OUTI and friends
+        _ => (2, Registers::NONE, Flow::Unknown),
+    }
+}
+
+/// Registers changed by routines in an image, remembered per entry address
+pub struct Analysis<'a> {
+    image: &'a [u8],
+    base: u16,
+    done: HashMap<u16, Registers>,
+    in_progress: HashSet<u16>,
+}
+
+impl<'a> Analysis<'a> {
+    /// Analysis of an image loaded at base
+    pub fn new(image: &'a [u8], base: u16) -> Self {
+        Analysis { image, base, done: HashMap::new(), in_progress: HashSet::new() }
+    }
+
+    fn fetch(&self, addr: u16) -> Option<u8> {
+        self.image.get(addr.checked_sub(self.base)? as usize).copied()
+    }
+
+    /// Registers the routine at entry may change before it returns
+    pub fn clobbered(&mut self, entry: u16) -> Registers {
+        self.follow(entry).0
+    }
+
+    // The routine's registers, and whether they are final: a routine that calls one
+    // still being followed (recursion) is followed again when next asked for
+    fn follow(&mut self, entry: u16) -> (Registers, bool) {
+        if let Some(&registers) = self.done.get(&entry) {
+            return (registers, true);
+        }
+        if !self.in_progress.insert(entry) {
+            return (Registers::NONE, false);
+        }
+        let mut registers = Registers::NONE;
+        let mut complete = true;
+        let mut seen = HashSet::new();
+        let mut pending = vec![entry];
+        while let Some(pc) = pending.pop() {
+            if !seen.insert(pc) {
+                continue;
+            }
+            let Some((len, written, flow)) = decode(|addr| self.fetch(addr), pc) else {
+                registers = Registers::ALL;
+                break;
+            };
+            registers |= written;
+            let next = pc.wrapping_add(len);
+            match flow {
+                Flow::Next => pending.push(next),
+                Flow::Jump(target) => pending.push(target),
+                Flow::Branch(target) => pending.extend([target, next]),
+                Flow::Call(target) => {
+                    let (called, final_) = self.follow(target);
+                    registers |= called;
+                    complete &= final_;
+                    pending.push(next);
+                }
+                Flow::Return => {}
+                Flow::Unknown => registers = Registers::ALL,
+            }
+            if registers == Registers::ALL {
+                break;
+            }
+        }
+        self.in_progress.remove(&entry);
+        if complete {
+            self.done.insert(entry, registers);
+        }
+        (registers, complete)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,70 @@
+// Register sets of hand-assembled routines and compiled procedures
+
+use super::*;
+use crate::test_support::{compile_program, ORG};
+
+#[test]
+fn follows_branches_and_calls() {
+    let code = [
+        0x3E, 0x01,        // 4200: LD A, 1
+        0x28, 0x03,        // 4202: JR Z, +3
+        0xCD, 0x0A, 0x42,  // 4204: CALL 420A
+        0xC9,              // 4207: RET
+        0x06, 0x02,        // 4208: (skipped)
+        0x21, 0x00, 0x00,  // 420A: LD HL, 0
+        0xEB,              // 420D: EX DE, HL
+        0xC9,              // 420E: RET
+    ];
+    let mut analysis = Analysis::new(&code, 0x4200);
+    assert_eq!(analysis.clobbered(0x420A).to_string(), "D E H L");
+    assert_eq!(analysis.clobbered(0x4200).to_string(), "A D E H L");
+}
+
+#[test]
+fn calls_outside_the_image_change_everything() {
+    let code = [0xCD, 0x00, 0x00, 0xC9];
+    assert_eq!(Analysis::new(&code, 0x4200).clobbered(0x4200), Registers::ALL);
+}
+
+#[test]
+fn index_register_instructions_keep_their_operands() {
+    let code = [
+        0xDD, 0xE5,              // 4200: PUSH IX
+        0xDD, 0x21, 0x00, 0x00,  // 4202: LD IX, 0
+        0xDD, 0x66, 0xFE,        // 4206: LD H, (IX-2)
+        0xDD, 0x36, 0xFF, 0x05,  // 4209: LD (IX-1), 5
+        0xDD, 0x86, 0xFF,        // 420D: ADD A, (IX-1)
+        0xDD, 0xE1,              // 4210: POP IX
+        0xC9,                    // 4212: RET
+    ];
+    assert_eq!(Analysis::new(&code, 0x4200).clobbered(0x4200).to_string(), "A H");
+}
+
+#[test]
+fn recursive_procedures_are_finished() {
+    let code = [
+        0x05,              // 4200: DEC B
+        0xC8,              // 4201: RET Z
+        0xCD, 0x00, 0x42,  // 4202: CALL 4200
+        0x0C,              // 4205: INC C
+        0xC9,              // 4206: RET
+    ];
+    assert_eq!(Analysis::new(&code, 0x4200).clobbered(0x4200).to_string(), "B C");
+}
+
+#[test]
+fn procedures_include_their_runtime_calls() {
+    let source = "BYTE b\nPROC quiet()\nb = 1\nRETURN\nPROC noisy()\nPutD('x')\nRETURN\nPROC main()\nquiet()\nRETURN\nPROC SysInit()\nnoisy()\nRETURN\n";
+    let (_, symbols) = crate::runtime::generate_runtime(ORG + 3);
+    let image = compile_program(source, ORG).unwrap();
+    let mut codegen = crate::codegen::CodeGenerator::new(symbols.end_address);
+    codegen.set_runtime_symbols(&symbols);
+    codegen.generate(&crate::test_support::parse(source).unwrap()).unwrap();
+
+    let mut analysis = Analysis::new(&image, ORG);
+    let quiet = analysis.clobbered(codegen.procedure_address("quiet").unwrap());
+    let noisy = analysis.clobbered(codegen.procedure_address("noisy").unwrap());
+    assert_eq!(quiet.to_string(), "A");
+    assert!(noisy | quiet == noisy && noisy != quiet);
+    assert_eq!(analysis.clobbered(codegen.procedure_address("main").unwrap()), quiet);
+}
@@ -1,9 +1,17 @@
 // Z80 Code Generator for Action! language
 
+use crate::ast::calls::CallGraph;
+use crate::ast::modules;
 use crate::ast::*;
+use crate::clobber::{self, Registers};
 use crate::error::{CompileError, Result};
-use crate::runtime::RuntimeSymbols;
-use std::collections::HashMap;
+use crate::runtime::{RuntimeSymbols, DEVICE_PRINTER, RAM_START};
+use crate::symbols::SymbolFile;
+use crate::token::CaseMode;
+use std::collections::{HashMap, HashSet};
+
+// Most bytes of locals a stack frame holds, as (IX+d) reaches down to IX-128
+const MAX_FRAME_SIZE: u16 = 128;
 
 // Z80 opcodes (many reserved for future use)
 #[allow(dead_code)]
@@ -23,6 +31,11 @@ mod opcodes {
 
     pub const LD_A_HL: u8 = 0x7E;
     pub const LD_HL_A: u8 = 0x77;
+    pub const LD_HL_E: u8 = 0x73;
+    pub const LD_HL_D: u8 = 0x72;
+    pub const LD_H_HL: u8 = 0x66;
+    pub const LD_E_HL: u8 = 0x5E;
+    pub const LD_D_HL: u8 = 0x56;
     pub const LD_A_DE: u8 = 0x1A;
     pub const LD_DE_A: u8 = 0x12;
     pub const LD_A_BC: u8 = 0x0A;
@@ -59,6 +72,7 @@ mod opcodes {
     pub const POP_AF: u8 = 0xF1;
 
     pub const ADD_A_N: u8 = 0xC6;
+    pub const ADD_A_A: u8 = 0x87;
     pub const ADD_A_B: u8 = 0x80;
     pub const ADD_A_C: u8 = 0x81;
     pub const ADD_A_D: u8 = 0x82;
@@ -124,6 +138,7 @@ mod opcodes {
     pub const JR_NZ_N: u8 = 0x20;
     pub const JR_C_N: u8 = 0x38;
     pub const JR_NC_N: u8 = 0x30;
+    pub const DJNZ_N: u8 = 0x10;
 
     pub const CALL_NN: u8 = 0xCD;
     pub const RET: u8 = 0xC9;
@@ -136,12 +151,29 @@ mod opcodes {
 
     pub const EX_DE_HL: u8 = 0xEB;
 
+    pub const ADD_HL_SP: u8 = 0x39;
+    pub const LD_SP_HL: u8 = 0xF9;
+
+    // IX, for stack frames; the (IX+d) forms take the displacement after the opcode
+    pub const PUSH_IX: [u8; 2] = [0xDD, 0xE5];
+    pub const POP_IX: [u8; 2] = [0xDD, 0xE1];
+    pub const LD_IX_NN: [u8; 2] = [0xDD, 0x21];
+    pub const ADD_IX_SP: [u8; 2] = [0xDD, 0x39];
+    pub const LD_SP_IX: [u8; 2] = [0xDD, 0xF9];
+    pub const LD_A_IX: [u8; 2] = [0xDD, 0x7E];
+    pub const LD_L_IX: [u8; 2] = [0xDD, 0x6E];
+    pub const LD_H_IX: [u8; 2] = [0xDD, 0x66];
+    pub const LD_IX_A: [u8; 2] = [0xDD, 0x77];
+    pub const LD_IX_L: [u8; 2] = [0xDD, 0x75];
+    pub const LD_IX_H: [u8; 2] = [0xDD, 0x74];
+
     pub const SLA_A: [u8; 2] = [0xCB, 0x27];
     pub const SRA_A: [u8; 2] = [0xCB, 0x2F];
     pub const SRL_A: [u8; 2] = [0xCB, 0x3F];
 
     pub const CPL: u8 = 0x2F;
     pub const NEG: [u8; 2] = [0xED, 0x44];
+    pub const RETI: [u8; 2] = [0xED, 0x4D];
 }
 
 #[derive(Debug, Clone)]
@@ -150,15 +182,82 @@ struct SymbolInfo {
     address: u16,
     data_type: DataType,
     is_param: bool,
-    stack_offset: Option<i16>,  // For local variables/params
+    stack_offset: Option<i16>,  // Offset from IX of a local in a stack frame
+    in_data: bool,              // Address is an offset into the data section
+    by_ref: bool,               // VAR parameter: holds the address of the variable
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct ListingEntry {
-    address: u16,
-    bytes: Vec<u8>,
-    source: String,
+// Where a runtime call's I/O device comes from
+#[derive(Debug, Clone, Copy)]
+enum DeviceArg {
+    First,      // The call's first argument
+    Fixed(u8),  // Always this device
+}
+
+// A run of PrintF output: bytes printed as they are, or an argument and its directive
+enum FormatPiece<'a> {
+    Text(Vec<u8>),
+    Arg(char, &'a Expression),
+}
+
+// A place in the code that jumps can refer to before it is defined
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Label(usize);
+
+// Output sections the generator writes into
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Code,
+    Data,
+}
+
+/// Code generated for one source line, or for compiler-generated glue when line is None
+#[derive(Debug, Clone)]
+pub struct ListingEntry {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub line: Option<usize>,
+    pub procedure: Option<String>,
+}
+
+/// What goes into the text listing
+#[derive(Debug, Clone, Copy)]
+pub struct ListingOptions {
+    pub code: bool,         // Program code and the runtime library
+    pub data: bool,         // Initialized data
+    pub symbols: bool,      // Procedures, variables, the jump table and registers changed
+    pub hex: bool,          // Hex dump of the program code
+    pub disassembly: bool,  // Disassembly of the program and the runtime library
+}
+
+impl Default for ListingOptions {
+    fn default() -> Self {
+        ListingOptions { code: true, data: true, symbols: true, hex: true, disassembly: true }
+    }
+}
+
+/// Which way to lean where code can be made either smaller or faster
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptFor {
+    Size,
+    Speed,
+}
+
+/// What arithmetic that overflows its type does
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overflow {
+    #[default]
+    Wrap,   // Keep the low bits, as the hardware does
+    Check,  // Stop the program with a message giving where
+}
+
+/// How the program ends once Main returns
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Exit {
+    #[default]
+    Halt,
+    Return,     // To whatever called the image, such as BASIC's USR
+    Jump(u16),  // To the system, such as CP/M's warm start at 0
 }
 
 #[allow(dead_code)]
@@ -167,14 +266,57 @@ pub struct CodeGenerator {
     code: Vec<u8>,
     pc: u16,
     globals: HashMap<String, SymbolInfo>,
-    locals: HashMap<String, SymbolInfo>,
     procedures: HashMap<String, u16>,
-    label_counter: usize,
-    loop_stack: Vec<(u16, u16)>,  // (loop_start, loop_end)
-    listing: Vec<ListingEntry>,
+    labels: Vec<Option<u16>>,       // Address of each label, once defined
+    label_refs: Vec<(usize, Label)>,  // (code offset, label) of words waiting for a label
+    loop_stack: Vec<Label>,         // End of each enclosing loop, for EXIT
+    line_marks: Vec<(usize, Option<usize>, Option<String>)>,  // (code offset, line, procedure)
+    current_line: Option<usize>,
+    current_proc: Option<String>,
     data_section: Vec<u8>,
+    data_base: Option<u16>,  // Address of data_section once placed
+    data_address: Option<u16>,  // Requested run address for the data section
+    data_in_ram: bool,          // Run the data section in RAM after the variables
+    data_loaded: bool,          // The loader puts the data section where it runs
+    data_fixups: Vec<(usize, u16)>,  // (code offset, data offset) of data references
     data_offset: u16,
     runtime: Option<RuntimeSymbols>,
+    runtime_code: Vec<u8>,          // The runtime, ending where the program starts, for following calls into it
+    case_mode: CaseMode,
+    entry_point: Option<String>,
+    main_address: Option<u16>,      // The procedure the startup code calls, once placed
+    init_proc: Option<String>,
+    held: Option<(usize, String)>,  // (code length, variable) while A or HL still holds the variable
+    tail_call: Option<usize>,       // Code length right after the last CALL
+    jumps: Vec<usize>,              // Code offsets of jumps to addresses in the code
+    opt_for: Option<OptFor>,
+    overflow: Overflow,
+    overflow_calls: Vec<usize>,     // Code offsets of the overflow trap's address in each check
+    keep_unused: bool,
+    drop_write_only: bool,
+    external: Vec<String>,          // Procedures called from outside the program
+    verify: bool,
+    exit: Exit,
+    overlay_locals: bool,
+    stack_locals: bool,
+    frame_procs: HashSet<String>,     // Keys of procedures whose locals are in a stack frame
+    in_frame: bool,                   // The procedure being generated has a stack frame
+    in_interrupt: bool,               // It is an INTERRUPT procedure, which saves every register
+    local_frames: HashMap<String, u16>,  // Procedure key -> address of its overlaid locals
+    frame_sizes: HashMap<String, u16>,   // Procedure key -> bytes of its stack frame's locals
+    register_procs: HashMap<String, Vec<Parameter>>,  // Procedure key -> parameters passed in A and HL
+    stack_params: HashMap<String, Vec<Parameter>>,    // Procedure key -> parameters passed on the stack
+    proc_params: HashMap<String, Vec<Parameter>>,     // Procedure key -> parameters, for every procedure
+    return_types: HashMap<String, DataType>,          // Procedure key -> return type, for every FUNC
+    private: HashMap<String, usize>,                  // Key of each PRIVATE name -> its module
+    module_lines: Vec<usize>,                         // Line of each MODULE, as in the program
+    module_code: Vec<(usize, String, u16, u16)>,      // (module, procedure, start, end) of the code
+    module_globals: Vec<usize>,                       // Bytes of globals in each module
+    overrides: Vec<(u16, u16)>,                       // (runtime JP, its new target) for replaced routines
+    call_fixups: Vec<(usize, String)>,                // (code offset, procedure) of calls made before it was placed
+    imports: Vec<SymbolFile>,                         // Symbols of separately built images
+    imported: HashMap<String, u16>,                   // Procedure key -> address, for imported procedures
+    warnings: Vec<String>,
 }
 
 impl CodeGenerator {
@@ -184,14 +326,57 @@ impl CodeGenerator {
             code: Vec::new(),
             pc: origin,
             globals: HashMap::new(),
-            locals: HashMap::new(),
             procedures: HashMap::new(),
-            label_counter: 0,
+            labels: Vec::new(),
+            label_refs: Vec::new(),
             loop_stack: Vec::new(),
-            listing: Vec::new(),
+            line_marks: Vec::new(),
+            current_line: None,
+            current_proc: None,
             data_section: Vec::new(),
+            data_base: None,
+            data_address: None,
+            data_in_ram: false,
+            data_loaded: false,
+            data_fixups: Vec::new(),
             data_offset: 0,
             runtime: None,
+            runtime_code: Vec::new(),
+            case_mode: CaseMode::default(),
+            entry_point: None,
+            main_address: None,
+            init_proc: None,
+            held: None,
+            tail_call: None,
+            jumps: Vec::new(),
+            opt_for: None,
+            overflow: Overflow::default(),
+            overflow_calls: Vec::new(),
+            keep_unused: false,
+            drop_write_only: false,
+            external: Vec::new(),
+            verify: false,
+            exit: Exit::default(),
+            overlay_locals: false,
+            stack_locals: false,
+            frame_procs: HashSet::new(),
+            in_frame: false,
+            in_interrupt: false,
+            local_frames: HashMap::new(),
+            frame_sizes: HashMap::new(),
+            register_procs: HashMap::new(),
+            stack_params: HashMap::new(),
+            proc_params: HashMap::new(),
+            return_types: HashMap::new(),
+            private: HashMap::new(),
+            module_lines: Vec::new(),
+            module_code: Vec::new(),
+            module_globals: Vec::new(),
+            overrides: Vec::new(),
+            call_fixups: Vec::new(),
+            imports: Vec::new(),
+            imported: HashMap::new(),
+            warnings: Vec::new(),
         }
     }
 
@@ -199,9 +384,109 @@ impl CodeGenerator {
         self.runtime = Some(symbols.clone());
     }
 
+    /// The runtime's code, so what the program's routines change can be followed into it
+    pub fn set_runtime_code(&mut self, code: &[u8]) {
+        self.runtime_code = code.to_vec();
+    }
+
+    pub fn set_case_mode(&mut self, mode: CaseMode) {
+        self.case_mode = mode;
+    }
+
+    /// Call the named procedure at startup instead of Main
+    pub fn set_entry_point(&mut self, name: &str) {
+        self.entry_point = Some(name.to_string());
+    }
+
+    /// Call the named procedure at startup before Main, instead of SysInit
+    pub fn set_init_proc(&mut self, name: &str) {
+        self.init_proc = Some(name.to_string());
+    }
+
+    /// Run the data section at a fixed address, copied there from the image at startup
+    pub fn set_data_address(&mut self, addr: u16) {
+        self.data_address = Some(addr);
+    }
+
+    /// Run the data section in RAM after the variables, copied there from the image at
+    /// startup, for images that run from ROM (a fixed data address takes precedence)
+    pub fn set_data_in_ram(&mut self) {
+        self.data_in_ram = true;
+    }
+
+    /// Leave the data section out of the image, for a loader that puts it at its run
+    /// address itself, so the startup code need not copy it there
+    pub fn set_data_loaded(&mut self) {
+        self.data_loaded = true;
+    }
+
+    /// Prefer smaller or faster code where there is a choice
+    pub fn set_opt_for(&mut self, opt_for: OptFor) {
+        self.opt_for = Some(opt_for);
+    }
+
+    /// Generate every procedure, not only those the program can reach, for debugging
+    pub fn set_keep_unused(&mut self) {
+        self.keep_unused = true;
+    }
+
+    /// Leave out variables the program writes but never reads, with their stores
+    pub fn set_drop_write_only(&mut self) {
+        self.drop_write_only = true;
+    }
+
+    /// Procedures reached from outside the program, such as through the jump table,
+    /// which are kept though nothing in it calls them
+    pub fn set_external(&mut self, names: &[String]) {
+        self.external = names.to_vec();
+    }
+
+    /// Wrap arithmetic that overflows, or check for it and stop the program
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Check the finished code for signs of generator bugs, failing with an internal error
+    pub fn set_verify(&mut self) {
+        self.verify = true;
+    }
+
+    /// End the program some other way than HALT once Main returns
+    pub fn set_exit(&mut self, exit: Exit) {
+        self.exit = exit;
+    }
+
+    /// Share RAM between the locals of procedures that are never active at the same
+    /// time, when the program is not recursive
+    pub fn set_overlay_locals(&mut self) {
+        self.overlay_locals = true;
+    }
+
+    /// Give every procedure with locals a stack frame, not only those that can call
+    /// themselves, so each call has locals of its own
+    pub fn set_stack_locals(&mut self) {
+        self.stack_locals = true;
+    }
+
+    /// Let the program call the procedures and use the globals of separately built
+    /// images, where their symbol files say they are
+    pub fn set_imports(&mut self, imports: &[SymbolFile]) {
+        self.imports = imports.to_vec();
+    }
+
+    /// Things worth knowing about the last generate that did not stop it
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // Symbol table key for a name under the active case policy
+    fn key(&self, name: &str) -> String {
+        self.case_mode.key(name)
+    }
+
     fn emit(&mut self, byte: u8) {
         self.code.push(byte);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
     }
 
     fn emit_bytes(&mut self, bytes: &[u8]) {
@@ -215,22 +500,390 @@ impl CodeGenerator {
         self.emit((word >> 8) as u8);
     }
 
-    fn current_address(&self) -> u16 {
+    // Call a procedure or runtime routine
+    fn emit_call(&mut self, addr: u16) {
+        self.emit(opcodes::CALL_NN);
+        self.emit_word(addr);
+        self.tail_call = Some(self.code.len());
+    }
+
+    // Call one of the program's procedures, patched once they are all placed if it
+    // comes later
+    fn emit_proc_call(&mut self, name: &str) {
+        let key = self.key(name);
+        let addr = self.procedures.get(&key).or_else(|| self.imported.get(&key)).copied();
+        if addr.is_none() {
+            self.call_fixups.push((self.code.len() + 1, name.to_string()));
+        }
+        self.emit_call(addr.unwrap_or(0x0000));
+    }
+
+    // Return from a procedure. A CALL right before the return becomes a JP, so the
+    // callee returns for us.
+    fn emit_return(&mut self) {
+        if self.in_interrupt {
+            // Restore what the entry saved and let the next interrupt in, as part of no
+            // line, like the entry, so each line still pops what it pushes
+            self.mark_line(None);
+            if self.in_frame {
+                self.emit_bytes(&opcodes::LD_SP_IX);
+                self.emit_bytes(&opcodes::POP_IX);
+            }
+            self.emit_bytes(&opcodes::POP_IX);
+            for pop in [opcodes::POP_HL, opcodes::POP_DE, opcodes::POP_BC, opcodes::POP_AF] {
+                self.emit(pop);
+            }
+            self.emit(opcodes::EI);
+            self.emit_bytes(&opcodes::RETI);
+        } else if self.in_frame {
+            // Drop the frame: nothing after a call to tail-call into. Its POP IX belongs
+            // to no line, like the PUSH IX that built it
+            self.mark_line(None);
+            self.emit_bytes(&opcodes::LD_SP_IX);
+            self.emit_bytes(&opcodes::POP_IX);
+            self.emit(opcodes::RET);
+        } else if self.tail_call == Some(self.code.len()) {
+            let at = self.code.len() - 3;
+            self.code[at] = opcodes::JP_NN;
+            self.tail_call = None;
+        } else {
+            self.emit(opcodes::RET);
+        }
+    }
+
+    // Jump to an address in the code, returning the jump's address
+    fn emit_jump(&mut self, opcode: u8, target: u16) -> u16 {
+        let at = self.current_address();
+        self.jumps.push(self.code.len());
+        self.emit(opcode);
+        self.emit_word(target);
+        at
+    }
+
+    // Jump to a label, returning the jump's address
+    fn emit_jump_to(&mut self, opcode: u8, label: Label) -> u16 {
+        let at = self.current_address();
+        self.jumps.push(self.code.len());
+        self.emit(opcode);
+        self.refer(label);
+        at
+    }
+
+    // Jump back to an earlier address: a JR when optimizing for size and it reaches,
+    // as a taken JR is 2 T-states slower than a JP
+    fn emit_jump_back(&mut self, target: u16) {
+        if self.opt_for == Some(OptFor::Size) && jr_offset(self.pc.wrapping_add(2), target).is_some() {
+            self.emit_jr(opcodes::JR_N, target).expect("offset checked");
+        } else {
+            self.emit_jump(opcodes::JP_NN, target);
+        }
+    }
+
+    // Relative jump (JR, JR cc or DJNZ) to an address already known
+    fn emit_jr(&mut self, opcode: u8, target: u16) -> Result<()> {
+        let offset = jr_offset(self.pc.wrapping_add(2), target).ok_or_else(|| CompileError::CodeGenError {
+            message: format!("relative jump at ${:04X} cannot reach ${:04X}", self.pc, target),
+        })?;
+        self.emit(opcode);
+        self.emit(offset);
+        Ok(())
+    }
+
+    // Relative jump to code not generated yet, returning the offset byte for patch_jr
+    fn emit_jr_forward(&mut self, opcode: u8) -> usize {
+        self.emit(opcode);
+        self.emit(0);
+        self.code.len() - 1
+    }
+
+    // Point a forward relative jump at the current address
+    fn patch_jr(&mut self, at: usize) -> Result<()> {
+        let from = self.origin.wrapping_add(at as u16 + 1);
+        let target = self.current_address();
+        self.code[at] = jr_offset(from, target).ok_or_else(|| CompileError::CodeGenError {
+            message: format!("relative jump at ${:04X} cannot reach ${:04X}", from - 2, target),
+        })?;
+        Ok(())
+    }
+
+    // Send jumps that land on a JP straight to where that JP goes
+    fn thread_jumps(&mut self) {
+        let unconditional: HashSet<usize> = self.jumps.iter().copied()
+            .filter(|&at| self.code[at] == opcodes::JP_NN)
+            .collect();
+        let target_of = |code: &[u8], at: usize| u16::from_le_bytes([code[at + 1], code[at + 2]]);
+        for &at in &self.jumps {
+            let mut target = target_of(&self.code, at);
+            // Bounded, in case the jumps form a loop
+            for _ in 0..unconditional.len() {
+                match target.checked_sub(self.origin).map(usize::from) {
+                    Some(next) if unconditional.contains(&next) && next != at => {
+                        target = target_of(&self.code, next);
+                    }
+                    _ => break,
+                }
+            }
+            self.code[at + 1..at + 3].copy_from_slice(&target.to_le_bytes());
+        }
+    }
+
+    // Taking the address may make it a jump target, so registers can hold anything here
+    fn current_address(&mut self) -> u16 {
+        self.held = None;
+        self.tail_call = None;
         self.pc
     }
 
-    #[allow(dead_code)]
-    fn new_label(&mut self) -> usize {
-        let label = self.label_counter;
-        self.label_counter += 1;
-        label
+    // Whether A or HL holds a variable's value, because it was loaded or stored with
+    // nothing emitted or jumping in since
+    fn holds(&self, key: &str) -> bool {
+        self.held.as_ref().is_some_and(|(at, held)| *at == self.code.len() && held == key)
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    // Put label at the current address, filling in the words that refer to it
+    fn define(&mut self, label: Label) -> u16 {
+        let addr = self.current_address();
+        self.labels[label.0] = Some(addr);
+        for (at, _) in self.label_refs.extract_if(.., |(_, l)| *l == label) {
+            self.code[at..at + 2].copy_from_slice(&addr.to_le_bytes());
+        }
+        addr
+    }
+
+    // Emit the address of label, filled in later if it is not defined yet
+    fn refer(&mut self, label: Label) {
+        match self.labels[label.0] {
+            Some(addr) => self.emit_word(addr),
+            None => {
+                self.label_refs.push((self.code.len(), label));
+                self.emit_word(0x0000);
+            }
+        }
+    }
+
+    // Find the section and offset holding a 16-bit word at addr
+    fn locate_word(&self, addr: u16) -> Option<(Section, usize)> {
+        let within = |base: u16, len: usize| {
+            let offset = addr.checked_sub(base)? as usize;
+            (offset + 2 <= len).then_some(offset)
+        };
+        if let Some(offset) = within(self.origin, self.code.len()) {
+            return Some((Section::Code, offset));
+        }
+        let offset = within(self.data_base?, self.data_section.len())?;
+        Some((Section::Data, offset))
+    }
+
+    // Patch a 16-bit value into the code or data section at a given address
+    fn patch_word(&mut self, addr: u16, value: u16) -> Result<()> {
+        let (section, offset) = self.locate_word(addr).ok_or_else(|| CompileError::InternalError {
+            message: format!("Patch address ${:04X} is outside the code and data sections", addr),
+        })?;
+        self.patch_section_word(section, offset, value)
+    }
+
+    // Patch a 16-bit value at a section-relative offset
+    fn patch_section_word(&mut self, section: Section, offset: usize, value: u16) -> Result<()> {
+        let bytes = match section {
+            Section::Code => &mut self.code,
+            Section::Data => &mut self.data_section,
+        };
+        match bytes.get_mut(offset..offset + 2) {
+            Some(slot) => {
+                slot.copy_from_slice(&value.to_le_bytes());
+                Ok(())
+            }
+            None => Err(CompileError::InternalError {
+                message: format!("Patch offset {} is outside the {:?} section", offset, section),
+            }),
+        }
+    }
+
+    // Append bytes to the data section, returning their offset
+    fn add_data(&mut self, bytes: &[u8]) -> u16 {
+        let offset = self.data_section.len() as u16;
+        self.data_section.extend_from_slice(bytes);
+        offset
+    }
+
+    // Emit the address of a data section offset, fixed up once the section is placed
+    fn emit_data_address(&mut self, offset: u16) {
+        self.data_fixups.push((self.code.len(), offset));
+        self.emit_word(0x0000);
+    }
+
+    // Emit the address of a variable
+    fn emit_symbol_address(&mut self, info: &SymbolInfo) {
+        if info.in_data {
+            self.emit_data_address(info.address);
+        } else {
+            self.emit_word(info.address);
+        }
+    }
+
+    // An (IX+d) instruction for a local in the stack frame
+    fn emit_indexed(&mut self, opcode: [u8; 2], offset: i16) {
+        self.emit_bytes(&opcode);
+        self.emit(offset as i8 as u8);
+    }
+
+    // Address of a variable into HL
+    fn emit_address_hl(&mut self, info: &SymbolInfo) {
+        if let Some(offset) = info.stack_offset {
+            self.emit_bytes(&opcodes::PUSH_IX);
+            self.emit(opcodes::POP_HL);
+            self.emit(opcodes::LD_DE_NN);
+            self.emit_word(offset as u16);
+            self.emit(opcodes::ADD_HL_DE);
+        } else {
+            self.emit(opcodes::LD_HL_NN);
+            self.emit_symbol_address(info);
+        }
+    }
+
+    // Address of a variable into HL, which a VAR or ARRAY parameter holds
+    fn emit_variable_address(&mut self, info: &SymbolInfo) {
+        if info.by_ref {
+            self.emit_load_hl(info);
+        } else {
+            self.emit_address_hl(info);
+        }
+    }
+
+    // Word variable into HL
+    fn emit_load_hl(&mut self, info: &SymbolInfo) {
+        if let Some(offset) = info.stack_offset {
+            self.emit_indexed(opcodes::LD_L_IX, offset);
+            self.emit_indexed(opcodes::LD_H_IX, offset + 1);
+        } else {
+            self.emit(opcodes::LD_HL_NN_IND);
+            self.emit_symbol_address(info);
+        }
+    }
+
+    // Byte variable into A
+    fn emit_load_a(&mut self, info: &SymbolInfo) {
+        if let Some(offset) = info.stack_offset {
+            self.emit_indexed(opcodes::LD_A_IX, offset);
+        } else {
+            self.emit(opcodes::LD_A_NN);
+            self.emit_symbol_address(info);
+        }
+    }
+
+    // HL into a word variable
+    fn emit_store_hl(&mut self, info: &SymbolInfo) {
+        if let Some(offset) = info.stack_offset {
+            self.emit_indexed(opcodes::LD_IX_L, offset);
+            self.emit_indexed(opcodes::LD_IX_H, offset + 1);
+        } else {
+            self.emit(opcodes::LD_NN_HL);
+            self.emit_symbol_address(info);
+        }
+    }
+
+    // A into a byte variable
+    fn emit_store_a(&mut self, info: &SymbolInfo) {
+        if let Some(offset) = info.stack_offset {
+            self.emit_indexed(opcodes::LD_IX_A, offset);
+        } else {
+            self.emit(opcodes::LD_NN_A);
+            self.emit_symbol_address(info);
+        }
+    }
+
+    // Allocate a local below IX in the procedure's stack frame, which is size bytes so far
+    fn allocate_frame_variable(&self, var: &Variable, size: &mut u16) -> Result<SymbolInfo> {
+        let is_array = matches!(var.data_type, DataType::ByteArray(_) | DataType::CardArray(_) | DataType::IntArray(_));
+        if is_array && var.initial_value.is_some() {
+            return Err(CompileError::CodeGenError {
+                message: format!("ARRAY '{}' cannot have an initial value in a stack frame", var.name),
+            });
+        }
+        *size += var.data_type.size() as u16;
+        Ok(SymbolInfo {
+            address: 0,
+            data_type: var.data_type.clone(),
+            is_param: false,
+            stack_offset: Some(-(*size as i32) as i16),
+            in_data: false,
+            by_ref: false,
+        })
+    }
+
+    // Allocate a variable, placing constant-initialized ones in the data section
+    fn allocate_variable(&mut self, var: &Variable, ram_addr: &mut u16) -> Result<SymbolInfo> {
+        let size = var.data_type.size();
+        let initial = match &var.initial_value {
+            None => None,
+            Some(Expression::String(s)) if !var.data_type.is_word() && size > 1 => {
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.resize(size, 0);
+                Some(bytes)
+            }
+            Some(Expression::Bytes(bytes)) => Some(bytes.clone()),
+            Some(value) if value.table(self.case_mode).is_some() => Some(self.table_bytes(var, value)?),
+            Some(expr) => {
+                let value = expr.const_value().ok_or_else(|| CompileError::CodeGenError {
+                    message: format!("Initial value of '{}' must be a constant", var.name),
+                })?;
+                let mut bytes = (value as u16).to_le_bytes().to_vec();
+                bytes.resize(size, 0);
+                Some(bytes)
+            }
+        };
+
+        let mut info = SymbolInfo {
+            address: *ram_addr,
+            data_type: var.data_type.clone(),
+            is_param: false,
+            stack_offset: None,
+            in_data: false,
+            by_ref: false,
+        };
+        match initial {
+            Some(bytes) => {
+                info.address = self.add_data(&bytes);
+                info.in_data = true;
+            }
+            None => *ram_addr += size as u16,
+        }
+        Ok(info)
     }
 
-    // Patch a 16-bit address at a given location
-    fn patch_word(&mut self, addr: u16, value: u16) {
-        let offset = (addr - self.origin) as usize;
-        self.code[offset] = (value & 0xFF) as u8;
-        self.code[offset + 1] = (value >> 8) as u8;
+    // The elements of an ARRAY initialized with TABLE(index, entry), each the entry for
+    // its index
+    fn table_bytes(&self, var: &Variable, value: &Expression) -> Result<Vec<u8>> {
+        let (index, entry) = value.table(self.case_mode).expect("a TABLE");
+        let (count, word, range) = match var.data_type {
+            DataType::ByteArray(n) => (n, false, -128..=255),
+            DataType::CardArray(n) | DataType::IntArray(n) => (n, true, -32768..=65535),
+            _ => return Err(CompileError::CodeGenError {
+                message: format!("TABLE only fills an ARRAY, and '{}' is not one", var.name),
+            }),
+        };
+        let mut bytes = Vec::new();
+        for i in 0..count {
+            let value = entry.table_value(index, i as i32, self.case_mode).ok_or_else(|| CompileError::CodeGenError {
+                message: format!("TABLE entry {} of '{}' is not a constant", i, var.name),
+            })?;
+            if !range.contains(&value) {
+                return Err(CompileError::CodeGenError {
+                    message: format!("TABLE entry {} of '{}' is {}, which does not fit in an element", i, var.name, value),
+                });
+            }
+            if word {
+                bytes.extend((value as u16).to_le_bytes());
+            } else {
+                bytes.push(value as u8);
+            }
+        }
+        Ok(bytes)
     }
 
     // Load a byte value into A
@@ -247,50 +900,143 @@ impl CodeGenerator {
 
     // Load variable into A (byte) or HL (word)
     fn emit_load_var(&mut self, name: &str) -> Result<DataType> {
-        if let Some(_info) = self.locals.get(name).cloned() {
-            // Local variable - loaded from stack
-            // TODO: Implement stack-relative addressing
-            return Err(CompileError::CodeGenError {
-                message: "Local variables not yet fully implemented".to_string(),
-            });
-        }
-
-        if let Some(info) = self.globals.get(name).cloned() {
+        let key = self.key(name);
+        if let Some(info) = self.globals.get(&key).cloned() {
+            if matches!(info.data_type, DataType::ByteArray(_) | DataType::CardArray(_) | DataType::IntArray(_)) {
+                // An array's value is its address, in HL
+                self.emit_variable_address(&info);
+                return Ok(DataType::Pointer(Box::new(info.data_type)));
+            }
+            if let (true, DataType::Pointer(target)) = (info.by_ref, &info.data_type) {
+                if self.holds(&key) {
+                    return Ok((**target).clone());
+                }
+                // Load the variable's address, then the value from it
+                self.emit_load_hl(&info);
+                self.emit(opcodes::LD_A_HL);
+                if target.is_word() {
+                    self.emit(opcodes::INC_HL);
+                    self.emit(opcodes::LD_H_HL);
+                    self.emit(opcodes::LD_L_A);
+                }
+                self.held = Some((self.code.len(), key));
+                return Ok((**target).clone());
+            }
+            if self.holds(&key) {
+                return Ok(info.data_type);
+            }
             if info.data_type.is_word() {
                 // Load 16-bit value into HL
-                self.emit(opcodes::LD_HL_NN_IND);
-                self.emit_word(info.address);
+                self.emit_load_hl(&info);
             } else {
                 // Load 8-bit value into A
-                self.emit(opcodes::LD_A_NN);
-                self.emit_word(info.address);
+                self.emit_load_a(&info);
             }
+            self.held = Some((self.code.len(), key));
             return Ok(info.data_type);
         }
 
         Err(CompileError::UndefinedVariable { name: name.to_string() })
     }
 
-    // Store A (byte) or HL (word) to variable
+    // Store A (byte) or HL (word) to variable, converting to the variable's width
     fn emit_store_var(&mut self, name: &str, is_word: bool) -> Result<()> {
-        if let Some(info) = self.globals.get(name).cloned() {
-            if is_word || info.data_type.is_word() {
+        let key = self.key(name);
+        if let Some(info) = self.globals.get(&key).cloned() {
+            if let (true, DataType::Pointer(target)) = (info.by_ref, &info.data_type) {
+                if target.is_word() {
+                    if !is_word {
+                        self.emit(opcodes::LD_L_A);
+                        self.emit(opcodes::LD_H_N);
+                        self.emit(0);
+                    }
+                    // Store HL through the variable's address, keeping it in HL
+                    self.emit(opcodes::EX_DE_HL);
+                    self.emit_load_hl(&info);
+                    self.emit(opcodes::LD_HL_E);
+                    self.emit(opcodes::INC_HL);
+                    self.emit(opcodes::LD_HL_D);
+                    self.emit(opcodes::EX_DE_HL);
+                } else {
+                    if is_word {
+                        self.emit(opcodes::LD_A_L);
+                    }
+                    self.emit_load_hl(&info);
+                    self.emit(opcodes::LD_HL_A);
+                }
+                self.held = Some((self.code.len(), key));
+                return Ok(());
+            }
+            if info.data_type.is_word() {
+                if !is_word {
+                    // Zero-extend A into HL
+                    self.emit(opcodes::LD_L_A);
+                    self.emit(opcodes::LD_H_N);
+                    self.emit(0);
+                }
                 // Store HL to 16-bit variable
-                self.emit(opcodes::LD_NN_HL);
-                self.emit_word(info.address);
+                self.emit_store_hl(&info);
             } else {
+                if is_word {
+                    // Truncate HL to its low byte
+                    self.emit(opcodes::LD_A_L);
+                }
                 // Store A to 8-bit variable
-                self.emit(opcodes::LD_NN_A);
-                self.emit_word(info.address);
+                self.emit_store_a(&info);
             }
+            // The value stays in A or HL, in the variable's width
+            self.held = Some((self.code.len(), key));
             return Ok(());
         }
 
         Err(CompileError::UndefinedVariable { name: name.to_string() })
     }
 
-    // Generate code for expression, result in A (byte) or HL (word)
+    // Whether an expression is a string: a literal, or a BYTE or CHAR ARRAY holding one
+    fn is_string(&self, expr: &Expression) -> bool {
+        match expr {
+            Expression::String(_) => true,
+            Expression::Variable(name) => self.globals.get(&self.key(name))
+                .is_some_and(|info| matches!(info.data_type, DataType::ByteArray(_))),
+            _ => false,
+        }
+    }
+
+    // Strings compared with = or <> through SCompare, giving 1 when equal is true
+    fn gen_string_compare(&mut self, left: &Expression, right: &Expression, equal: bool) -> Result<bool> {
+        let s_compare = self.runtime.as_ref().map_or(0, |r| r.s_compare);
+        if s_compare == 0 {
+            return Err(CompileError::CodeGenError {
+                message: "comparing strings needs the runtime library".to_string(),
+            });
+        }
+        self.gen_expression(left)?;
+        self.emit_push_temp();
+        self.gen_expression(right)?;
+        self.emit(opcodes::EX_DE_HL);
+        self.emit_pop_temp(opcodes::POP_HL);
+        self.emit_call(s_compare);
+        // A is 0 when the strings are the same
+        self.emit(opcodes::OR_A);
+        self.emit(opcodes::LD_A_N);
+        self.emit(0);
+        let skip = self.emit_jr_forward(if equal { opcodes::JR_NZ_N } else { opcodes::JR_Z_N });
+        self.emit(opcodes::INC_A);
+        self.patch_jr(skip)?;
+        Ok(false)
+    }
+
+    // Generate code for expression, result in A (byte) or HL (word). Operations go to
+    // functions of their own, keeping this frame, which every level of nesting adds,
+    // small
     fn gen_expression(&mut self, expr: &Expression) -> Result<bool> {
+        // Operations on constants are worked out here, leaving one load of the value
+        if !matches!(expr, Expression::Number(_) | Expression::Char(_)) {
+            if let Some(n) = expr.const_value() {
+                return self.gen_expression(&Expression::Number(n));
+            }
+        }
+
         match expr {
             Expression::Number(n) => {
                 if *n >= 0 && *n <= 255 {
@@ -307,708 +1053,2198 @@ impl CodeGenerator {
                 Ok(false)
             }
 
+            Expression::String(text) => {
+                // Null-terminated string in the data section, address in HL
+                let mut bytes = text.as_bytes().to_vec();
+                bytes.push(0);
+                let offset = self.add_data(&bytes);
+                self.emit(opcodes::LD_HL_NN);
+                self.emit_data_address(offset);
+                Ok(true)
+            }
+
+            Expression::Bytes(bytes) => {
+                // The bytes as they are in the data section, address in HL
+                let offset = self.add_data(bytes);
+                self.emit(opcodes::LD_HL_NN);
+                self.emit_data_address(offset);
+                Ok(true)
+            }
+
             Expression::Variable(name) => {
                 let dt = self.emit_load_var(name)?;
                 Ok(dt.is_word())
             }
 
-            Expression::Add(left, right) => {
-                let left_word = self.gen_expression(left)?;
+            Expression::Add(left, right) => self.gen_add(expr, left, right),
+            Expression::Subtract(left, right) => self.gen_subtract(expr, left, right),
 
-                if left_word {
-                    // 16-bit addition
-                    self.emit(opcodes::PUSH_HL);
-                    let right_word = self.gen_expression(right)?;
-                    if !right_word {
-                        // Promote right to 16-bit
-                        self.emit(opcodes::LD_L_A);
-                        self.emit(opcodes::LD_H_N);
-                        self.emit(0);
-                    }
-                    self.emit(opcodes::POP_DE);
-                    self.emit(opcodes::ADD_HL_DE);
-                    Ok(true)
-                } else {
-                    // 8-bit addition
-                    self.emit(opcodes::LD_B_A);
-                    let right_word = self.gen_expression(right)?;
-                    if right_word {
-                        // Promote to 16-bit
-                        self.emit(opcodes::LD_C_A); // Save low byte
-                        self.emit(opcodes::LD_A_B);
-                        self.emit(opcodes::LD_L_A);
-                        self.emit(opcodes::LD_H_N);
-                        self.emit(0);
-                        self.emit(opcodes::LD_D_N);
-                        self.emit(0);
-                        self.emit(opcodes::LD_E_A);
-                        self.emit(opcodes::ADD_HL_DE);
-                        Ok(true)
-                    } else {
-                        self.emit(opcodes::ADD_A_B);
-                        Ok(false)
-                    }
-                }
+            Expression::Multiply(left, right) if self.opt_for == Some(OptFor::Speed)
+                && right.const_value().is_some_and(|n| (1..=128).contains(&n) && (n as u8).is_power_of_two()) =>
+            {
+                self.gen_shifted_multiply(left, right)
             }
-
-            Expression::Subtract(left, right) => {
-                let left_word = self.gen_expression(left)?;
-
-                if left_word {
-                    // 16-bit subtraction using SBC or manual
-                    self.emit(opcodes::PUSH_HL);
-                    let _right_word = self.gen_expression(right)?;
-                    // For simplicity, convert to 16-bit subtraction
-                    self.emit(opcodes::LD_D_H);
-                    self.emit(opcodes::LD_E_L);
-                    self.emit(opcodes::POP_HL);
-                    // HL = HL - DE (manual subtract)
-                    self.emit(opcodes::AND_A); // Clear carry
-                    self.emit(opcodes::LD_A_L);
-                    self.emit(opcodes::SUB_E);
-                    self.emit(opcodes::LD_L_A);
-                    self.emit(opcodes::LD_A_H);
-                    self.emit(0x9A); // SBC A, D
-                    self.emit(opcodes::LD_H_A);
-                    Ok(true)
-                } else {
-                    self.emit(opcodes::LD_B_A);
-                    self.gen_expression(right)?;
-                    self.emit(opcodes::LD_C_A);
-                    self.emit(opcodes::LD_A_B);
-                    self.emit(opcodes::SUB_C);
-                    Ok(false)
-                }
+            Expression::Multiply(left, right) | Expression::Divide(left, right) | Expression::Modulo(left, right) => {
+                self.gen_runtime_arithmetic(expr, left, right)
             }
 
-            Expression::Multiply(left, right) => {
-                // Simple 8-bit multiply using repeated addition
-                // For 16-bit, would need a runtime routine
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::LD_C_A);
-                // Call multiply routine
-                self.emit(opcodes::CALL_NN);
-                // Placeholder - needs runtime library
-                self.emit_word(0x0000);
-                Ok(false)
+            Expression::Equal(left, right) | Expression::NotEqual(left, right)
+                if self.is_string(left) && self.is_string(right) =>
+            {
+                self.gen_string_compare(left, right, matches!(expr, Expression::Equal(..)))
             }
 
-            Expression::Equal(left, right) => {
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::CP_B);
-                // Set A to 1 if equal, 0 otherwise
-                self.emit(opcodes::LD_A_N);
-                self.emit(0);
-                self.emit(opcodes::JR_NZ_N);
-                self.emit(1);
-                self.emit(opcodes::INC_A);
-                Ok(false)
+            Expression::Equal(left, right) | Expression::NotEqual(left, right)
+            | Expression::Less(left, right) | Expression::Greater(left, right)
+            | Expression::LessEqual(left, right) | Expression::GreaterEqual(left, right) => {
+                self.gen_comparison(expr, left, right)
             }
 
-            Expression::NotEqual(left, right) => {
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::CP_B);
-                // Set A to 1 if not equal, 0 otherwise
-                self.emit(opcodes::LD_A_N);
-                self.emit(0);
-                self.emit(opcodes::JR_Z_N);
-                self.emit(1);
-                self.emit(opcodes::INC_A);
-                Ok(false)
+            Expression::And(left, right) | Expression::Or(left, right)
+            | Expression::BitAnd(left, right) | Expression::BitOr(left, right) | Expression::BitXor(left, right) => {
+                self.gen_logical(expr, left, right)
             }
 
-            Expression::Less(left, right) => {
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::LD_C_A);
-                self.emit(opcodes::LD_A_B);
-                self.emit(opcodes::CP_C);
-                // Set A to 1 if less (carry set), 0 otherwise
-                self.emit(opcodes::LD_A_N);
-                self.emit(0);
-                self.emit(opcodes::JR_NC_N);
-                self.emit(1);
-                self.emit(opcodes::INC_A);
+            Expression::Negate(inner) => {
+                self.gen_expression(inner)?;
+                self.emit_bytes(&opcodes::NEG);
                 Ok(false)
             }
 
-            Expression::Greater(left, right) => {
-                // a > b is the same as b < a
-                self.gen_expression(right)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_C_A);
-                self.emit(opcodes::LD_A_B);
-                self.emit(opcodes::CP_C);
-                self.emit(opcodes::LD_A_N);
-                self.emit(0);
-                self.emit(opcodes::JR_NC_N);
-                self.emit(1);
-                self.emit(opcodes::INC_A);
+            Expression::Not(inner) => {
+                self.gen_expression(inner)?;
+                self.emit(opcodes::CPL);
                 Ok(false)
             }
 
-            Expression::LessEqual(left, right) => {
-                // a <= b is the same as !(a > b) = !(b < a) = b >= a
-                // Or simpler: a <= b if a < b OR a == b
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::LD_C_A);
-                self.emit(opcodes::LD_A_B);
-                self.emit(opcodes::CP_C);
-                // A <= C means carry set (A < C) or zero (A == C)
-                self.emit(opcodes::LD_A_N);
-                self.emit(1);  // Assume true
-                self.emit(opcodes::JR_Z_N);  // If equal, skip JR C and XOR A
-                self.emit(3);  // Skip 2 bytes (JR C) + 1 byte (XOR A)
-                self.emit(opcodes::JR_C_N);  // If less, skip XOR A
-                self.emit(1);
-                self.emit(opcodes::XOR_A);  // Otherwise false
-                Ok(false)
+            Expression::FunctionCall { name, args } => self.gen_function_call(name, args),
+
+            Expression::AddressOf(name) => {
+                if let Some(info) = self.globals.get(&self.key(name)).cloned() {
+                    self.emit_variable_address(&info);
+                    Ok(true)
+                } else {
+                    Err(CompileError::UndefinedVariable { name: name.clone() })
+                }
             }
 
-            Expression::GreaterEqual(left, right) => {
-                // a >= b if a > b OR a == b
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::LD_C_A);
+            Expression::ArrayAccess { array, index } => self.gen_array_access(array, index),
+            Expression::Dereference(pointer) => self.gen_dereference(pointer),
+
+            _ => Err(CompileError::CodeGenError {
+                message: format!("Unsupported expression: {:?}", expr),
+            }),
+        }
+    }
+
+    // left + right, in 16 bits if either is a word
+    fn gen_add(&mut self, expr: &Expression, left: &Expression, right: &Expression) -> Result<bool> {
+        let left_word = self.gen_expression(left)?;
+
+        if left_word {
+            // 16-bit addition
+            self.emit_push_temp();
+            let right_word = self.gen_expression(right)?;
+            if !right_word {
+                // Promote right to 16-bit
+                self.emit(opcodes::LD_L_A);
+                self.emit(opcodes::LD_H_N);
+                self.emit(0);
+            }
+            self.emit_pop_temp(opcodes::POP_DE);
+            if self.overflow == Overflow::Check && self.is_int(expr) {
+                // ADD HL,DE leaves the overflow flag as it was
+                self.emit(opcodes::AND_A);
+                self.emit_bytes(&[0xED, 0x5A]);  // ADC HL,DE
+            } else {
+                self.emit(opcodes::ADD_HL_DE);
+            }
+            self.emit_overflow_check(expr);
+            Ok(true)
+        } else {
+            // 8-bit addition
+            self.emit(opcodes::LD_B_A);
+            let right_word = self.gen_expression(right)?;
+            if right_word {
+                // Promote to 16-bit
+                self.emit(opcodes::LD_C_A); // Save low byte
                 self.emit(opcodes::LD_A_B);
-                self.emit(opcodes::CP_C);
-                // A >= C means no carry (A >= C)
-                self.emit(opcodes::LD_A_N);
+                self.emit(opcodes::LD_L_A);
+                self.emit(opcodes::LD_H_N);
+                self.emit(0);
+                self.emit(opcodes::LD_D_N);
                 self.emit(0);
-                self.emit(opcodes::JR_C_N);  // If carry (A < C), result is 0
-                self.emit(1);
-                self.emit(opcodes::INC_A);   // Otherwise 1
+                self.emit(opcodes::LD_E_A);
+                self.emit(opcodes::ADD_HL_DE);
+                self.emit_overflow_check(expr);
+                Ok(true)
+            } else {
+                self.emit(opcodes::ADD_A_B);
+                self.emit_overflow_check(expr);
                 Ok(false)
             }
+        }
+    }
 
-            Expression::And(left, right) => {
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::AND_B);
-                Ok(false)
+    // left - right, in 16 bits if left is a word
+    fn gen_subtract(&mut self, expr: &Expression, left: &Expression, right: &Expression) -> Result<bool> {
+        let left_word = self.gen_expression(left)?;
+
+        if left_word {
+            // 16-bit subtraction using SBC or manual
+            self.emit_push_temp();
+            let _right_word = self.gen_expression(right)?;
+            // For simplicity, convert to 16-bit subtraction
+            self.emit(opcodes::LD_D_H);
+            self.emit(opcodes::LD_E_L);
+            self.emit_pop_temp(opcodes::POP_HL);
+            // HL = HL - DE (manual subtract)
+            self.emit(opcodes::AND_A); // Clear carry
+            self.emit(opcodes::LD_A_L);
+            self.emit(opcodes::SUB_E);
+            self.emit(opcodes::LD_L_A);
+            self.emit(opcodes::LD_A_H);
+            self.emit(0x9A); // SBC A, D
+            self.emit(opcodes::LD_H_A);
+            self.emit_overflow_check(expr);
+            Ok(true)
+        } else {
+            self.emit(opcodes::LD_B_A);
+            self.gen_expression(right)?;
+            self.emit(opcodes::LD_C_A);
+            self.emit(opcodes::LD_A_B);
+            self.emit(opcodes::SUB_C);
+            self.emit_overflow_check(expr);
+            Ok(false)
+        }
+    }
+
+    // left * right for a power of two right, by shifting
+    fn gen_shifted_multiply(&mut self, left: &Expression, right: &Expression) -> Result<bool> {
+        // Shift left instead of calling the multiply routine
+        let shifts = (right.const_value().unwrap() as u8).trailing_zeros();
+        let is_word = self.gen_expression(left)?;
+        let int_word = is_word && self.overflow == Overflow::Check && self.is_int(left);
+        for _ in 0..shifts {
+            if int_word {
+                self.emit(opcodes::AND_A);
+                self.emit_bytes(&[0xED, 0x6A]);  // ADC HL,HL, which sets the overflow flag
+            } else {
+                self.emit(if is_word { opcodes::ADD_HL_HL } else { opcodes::ADD_A_A });
             }
+            self.emit_overflow_check(left);
+        }
+        Ok(is_word)
+    }
 
-            Expression::Or(left, right) => {
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
+    // left * right, left / right or left MOD right through the runtime
+    fn gen_runtime_arithmetic(&mut self, expr: &Expression, left: &Expression, right: &Expression) -> Result<bool> {
+        let (routine, needs) = match expr {
+            Expression::Multiply(..) => (self.runtime.as_ref().map_or(0, |r| r.multiply), "* needs"),
+            _ => (self.runtime.as_ref().map_or(0, |r| r.div16), "/ and MOD need"),
+        };
+        if routine == 0 {
+            return Err(CompileError::CodeGenError {
+                message: format!("{} the runtime library", needs),
+            });
+        }
+        // Left in HL and right in DE, both widened to words
+        let left_word = self.gen_expression(left)?;
+        if !left_word {
+            self.emit(opcodes::LD_L_A);
+            self.emit(opcodes::LD_H_N);
+            self.emit(0);
+        }
+        self.emit_push_temp();
+        let right_word = self.gen_expression(right)?;
+        if right_word {
+            self.emit(opcodes::EX_DE_HL);
+        } else {
+            self.emit(opcodes::LD_E_A);
+            self.emit(opcodes::LD_D_N);
+            self.emit(0);
+        }
+        self.emit_pop_temp(opcodes::POP_HL);
+        self.emit_call(routine);
+        if matches!(expr, Expression::Modulo(..)) {
+            self.emit(opcodes::EX_DE_HL);
+        }
+        // Bytes give a byte, the low one of a product
+        if left_word || right_word {
+            Ok(true)
+        } else {
+            self.emit(opcodes::LD_A_L);
+            Ok(false)
+        }
+    }
+
+    // A comparison of bytes, giving 1 in A when it holds and 0 when not
+    fn gen_comparison(&mut self, expr: &Expression, left: &Expression, right: &Expression) -> Result<bool> {
+        let (first, second) = match expr {
+            Expression::Greater(..) => (right, left),  // a > b is the same as b < a
+            _ => (left, right),
+        };
+        self.gen_expression(first)?;
+        self.emit(opcodes::LD_B_A);
+        self.gen_expression(second)?;
+        if matches!(expr, Expression::Equal(..) | Expression::NotEqual(..)) {
+            self.emit(opcodes::CP_B);
+        } else {
+            // Carry when first < second
+            self.emit(opcodes::LD_C_A);
+            self.emit(opcodes::LD_A_B);
+            self.emit(opcodes::CP_C);
+        }
+        if let Expression::LessEqual(..) = expr {
+            // A <= C means carry set (A < C) or zero (A == C)
+            self.emit(opcodes::LD_A_N);
+            self.emit(1);  // Assume true
+            let equal = self.emit_jr_forward(opcodes::JR_Z_N);  // If equal, skip JR C and XOR A
+            let less = self.emit_jr_forward(opcodes::JR_C_N);  // If less, skip XOR A
+            self.emit(opcodes::XOR_A);  // Otherwise false
+            self.patch_jr(equal)?;
+            self.patch_jr(less)?;
+            return Ok(false);
+        }
+        // Set A to 1, unless the flags say the comparison fails
+        let fails = match expr {
+            Expression::Equal(..) => opcodes::JR_NZ_N,
+            Expression::NotEqual(..) => opcodes::JR_Z_N,
+            Expression::GreaterEqual(..) => opcodes::JR_C_N,
+            _ => opcodes::JR_NC_N,  // < and the swapped >
+        };
+        self.emit(opcodes::LD_A_N);
+        self.emit(0);
+        let skip = self.emit_jr_forward(fails);
+        self.emit(opcodes::INC_A);
+        self.patch_jr(skip)?;
+        Ok(false)
+    }
+
+    // AND, OR and the bitwise operations on bytes
+    fn gen_logical(&mut self, expr: &Expression, left: &Expression, right: &Expression) -> Result<bool> {
+        self.gen_expression(left)?;
+        self.emit(opcodes::LD_B_A);
+        self.gen_expression(right)?;
+        match expr {
+            Expression::And(..) | Expression::BitAnd(..) => self.emit(opcodes::AND_B),
+            Expression::Or(..) => {
                 self.emit(opcodes::OR_A);
                 self.emit(opcodes::OR_N);
                 self.emit(0); // OR with B would be: LD C,A; LD A,B; OR C
                 // Actually need to fix this
-                Ok(false)
             }
+            _ => {
+                self.emit(opcodes::LD_C_A);
+                self.emit(opcodes::LD_A_B);
+                self.emit(if matches!(expr, Expression::BitOr(..)) { 0xB1 } else { 0xA9 });  // OR C or XOR C
+            }
+        }
+        Ok(false)
+    }
 
-            Expression::BitAnd(left, right) => {
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::AND_B);
-                Ok(false)
+    // A call to a FUNC or built-in for its value, or name(i) on an array
+    fn gen_function_call(&mut self, name: &str, args: &[Expression]) -> Result<bool> {
+        // name(i) on an array is element access
+        let is_array = self.globals.get(&self.key(name))
+            .is_some_and(|info| matches!(info.data_type, DataType::ByteArray(_) | DataType::CardArray(_) | DataType::IntArray(_)));
+        if is_array && args.len() == 1 {
+            return self.gen_expression(&Expression::ArrayAccess {
+                array: name.to_string(),
+                index: Box::new(args[0].clone()),
+            });
+        }
+
+        if let Some(is_word) = self.gen_runtime_call(name, args)? {
+            return Ok(is_word);
+        }
+
+        let pushed = self.gen_call_args(name, args)?;
+
+        // Call the function
+        self.emit_proc_call(name);
+
+        // Clean up stack (caller cleanup)
+        for _ in 0..pushed {
+            self.emit(opcodes::POP_BC);
+        }
+
+        // A BYTE comes back in A, a CARD or INT in HL
+        Ok(self.returns_word(name))
+    }
+
+    // What pointer points to, in its width
+    fn gen_dereference(&mut self, pointer: &Expression) -> Result<bool> {
+        let target = self.pointee(pointer);
+        match self.pointer_variable(pointer) {
+            Some(info) => self.emit_load_hl(&info),
+            None => self.gen_pointer_address(pointer)?,
+        }
+        self.emit(opcodes::LD_A_HL);
+        if target.is_word() {
+            self.emit(opcodes::INC_HL);
+            self.emit(opcodes::LD_H_HL);
+            self.emit(opcodes::LD_L_A);
+        }
+        Ok(target.is_word())
+    }
+
+    // The address a pointer expression holds, in HL
+    fn gen_pointer_address(&mut self, pointer: &Expression) -> Result<()> {
+        if !self.gen_expression(pointer)? {
+            self.emit(opcodes::LD_L_A);
+            self.emit(opcodes::LD_H_N);
+            self.emit(0);
+        }
+        Ok(())
+    }
+
+    // A variable whose value is the address a pointer expression holds, loaded with
+    // emit_load_hl: a VAR parameter, a CARD or an INT
+    fn pointer_variable(&self, pointer: &Expression) -> Option<SymbolInfo> {
+        let Expression::Variable(name) = pointer else {
+            return None;
+        };
+        let info = self.globals.get(&self.key(name))?;
+        (info.by_ref || matches!(info.data_type, DataType::Card | DataType::Int)).then(|| info.clone())
+    }
+
+    // The type a pointer expression points to: that of a VAR parameter or an array's
+    // element, and otherwise a BYTE
+    fn pointee(&self, pointer: &Expression) -> DataType {
+        let info = match pointer {
+            Expression::Variable(name) => self.globals.get(&self.key(name)),
+            _ => None,
+        };
+        match info.map(|info| (info.by_ref, &info.data_type)) {
+            Some((true, DataType::Pointer(target))) => (**target).clone(),
+            Some((_, DataType::CardArray(_))) => DataType::Card,
+            Some((_, DataType::IntArray(_))) => DataType::Int,
+            _ => DataType::Byte,
+        }
+    }
+
+    // The byte at array(index)
+    fn gen_array_access(&mut self, array: &str, index: &Expression) -> Result<bool> {
+        // Get array base address
+        let info = self.globals.get(&self.key(array)).cloned()
+            .ok_or_else(|| CompileError::UndefinedVariable { name: array.to_string() })?;
+
+        // Calculate address: base + index
+        self.emit_variable_address(&info);
+        self.emit_push_temp();
+        self.gen_expression(index)?;
+        self.emit(opcodes::LD_E_A);
+        self.emit(opcodes::LD_D_N);
+        self.emit(0);
+        self.emit_pop_temp(opcodes::POP_HL);
+        self.emit(opcodes::ADD_HL_DE);
+
+        // Load value from (HL)
+        self.emit(opcodes::LD_A_HL);
+        Ok(false)
+    }
+
+    // Call a runtime library function, returning its result width, or None if name is not one
+    fn gen_runtime_call(&mut self, name: &str, args: &[Expression]) -> Result<Option<bool>> {
+        let Some(runtime) = self.runtime.clone() else {
+            return Ok(None);
+        };
+        if self.case_mode.matches("PrintF", name) {
+            self.gen_print_f(&runtime, args)?;
+            return Ok(Some(false));
+        }
+        if let Some(intrinsic) = intrinsic(name, self.case_mode) {
+            self.gen_intrinsic(intrinsic, args)?;
+            return Ok(Some(false));
+        }
+        let Some((builtin, addr)) = runtime.get_function(name, self.case_mode) else {
+            return Ok(None);
+        };
+        if addr == 0 {
+            let (module, flag) = match builtin {
+                "Crc16" | "CheckSum" => ("CRC", "--crc"),
+                _ => ("XMODEM", "--xmodem"),
+            };
+            return Err(CompileError::CodeGenError {
+                message: format!("{} needs the {} runtime module (compile with {})", name, module, flag),
+            });
+        }
+
+        // Device variants take a device number first, LPrint variants go to the printer,
+        // and otherwise they act like the console routine
+        let (routine, device) = match builtin {
+            "PrintD" => ("Print", Some(DeviceArg::First)),
+            "PrintBD" => ("PrintB", Some(DeviceArg::First)),
+            "PrintCD" => ("PrintC", Some(DeviceArg::First)),
+            "PrintED" => ("PrintE", Some(DeviceArg::First)),
+            "PutD" if args.len() == 2 => ("PutD", Some(DeviceArg::First)),
+            "GetD" if args.len() == 1 => ("GetD", Some(DeviceArg::First)),
+            "InputSD" => ("InputS", Some(DeviceArg::First)),
+            "LPrint" => ("Print", Some(DeviceArg::Fixed(DEVICE_PRINTER))),
+            "LPrintB" => ("PrintB", Some(DeviceArg::Fixed(DEVICE_PRINTER))),
+            "LPrintC" => ("PrintC", Some(DeviceArg::Fixed(DEVICE_PRINTER))),
+            "LPrintE" => ("PrintE", Some(DeviceArg::Fixed(DEVICE_PRINTER))),
+            "Put" => ("PutD", None),
+            "Get" => ("GetD", None),
+            other => (other, None),
+        };
+        let args = match device {
+            Some(DeviceArg::First) => {
+                let device = args.first().ok_or_else(|| CompileError::CodeGenError {
+                    message: format!("{} expects a device number as its first argument", name),
+                })?;
+                self.gen_expression(device)?;
+                self.emit_call(runtime.set_device);
+                &args[1..]
+            }
+            Some(DeviceArg::Fixed(device)) => {
+                self.emit_load_byte(device);
+                self.emit_call(runtime.set_device);
+                args
             }
+            None => args,
+        };
 
-            Expression::BitOr(left, right) => {
-                self.gen_expression(left)?;
+        match routine {
+            "PrintB" | "PrintBE" | "PutD" | "Print" if !args.is_empty() => {
+                // Byte argument in A, or string pointer in HL for Print
+                self.gen_expression(&args[0])?;
+            }
+            "PrintC" | "PrintCE" if !args.is_empty() => {
+                // PrintC expects CARD in HL
+                let is_word = self.gen_expression(&args[0])?;
+                if !is_word {
+                    // Zero-extend a BYTE in A
+                    self.emit(opcodes::LD_L_A);
+                    self.emit(opcodes::LD_H_N);
+                    self.emit(0);
+                }
+            }
+            "StrB" | "StrC" => {
+                // Value in HL, buffer in DE
+                self.emit_push_args(args, 2, name)?;
+                self.emit_pop_temp(opcodes::POP_DE);
+                self.emit_pop_temp(opcodes::POP_HL);
+            }
+            "ValB" | "ValC" if !args.is_empty() => {
+                // String pointer in HL
+                self.gen_expression(&args[0])?;
+            }
+            "SCompare" => {
+                // First string in HL, second in DE
+                self.emit_push_args(args, 2, name)?;
+                self.emit_pop_temp(opcodes::POP_DE);
+                self.emit_pop_temp(opcodes::POP_HL);
+            }
+            "SIndex" => {
+                // String in HL, character in C
+                self.emit_push_args(args, 2, name)?;
+                self.emit_pop_temp(opcodes::POP_BC);
+                self.emit_pop_temp(opcodes::POP_HL);
+            }
+            "InputS" => {
+                // Buffer in HL, size in C
+                self.emit_push_args(args, 2, name)?;
+                self.emit_pop_temp(opcodes::POP_BC);
+                self.emit_pop_temp(opcodes::POP_HL);
+            }
+            "XRecv" | "XSend" | "Crc16" | "CheckSum" => {
+                // Buffer in HL, size or length in BC
+                self.emit_push_args(args, 2, name)?;
+                self.emit_pop_temp(opcodes::POP_BC);
+                self.emit_pop_temp(opcodes::POP_HL);
+            }
+            "SSub" => {
+                // Source in HL, destination in DE, start in B, length in C
+                // Called as SSub(dest, source, start, length)
+                self.emit_push_args(args, 4, name)?;
+                self.emit_pop_temp(opcodes::POP_BC);
+                self.emit_pop_temp(opcodes::POP_HL);
+                self.emit(opcodes::LD_A_L);
+                self.emit_pop_temp(opcodes::POP_HL);
+                self.emit_pop_temp(opcodes::POP_DE);
                 self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::LD_C_A);
-                self.emit(opcodes::LD_A_B);
-                self.emit(0xB1); // OR C
-                Ok(false)
             }
+            _ => {
+                // No arguments (PrintE, GetD, or a call missing its argument)
+            }
+        }
+        let inline = routine == "PutD" && self.opt_for == Some(OptFor::Speed)
+            && !self.proc_params.contains_key(&self.key("PutD"));
+        if inline && !runtime.put_d_inline.is_empty() {
+            // A copy of out_char saves the CALL, the JP on to it and the RET, 37 T-states
+            // The runtime keeps its own out_char, which Print and the others call; it is
+            // one image with no pass to drop unused routines, so this trades size for speed
+            self.emit_bytes(&runtime.put_d_inline);
+        } else if let Some(rst) = runtime.rst_for(addr) {
+            self.emit(rst);
+        } else {
+            self.emit_call(addr);
+        }
+        if device.is_some() {
+            self.emit_call(runtime.reset_device);
+        }
+        // XRecv, ValC and Crc16 return a CARD in HL, the rest a byte in A
+        Ok(Some(matches!(routine, "XRecv" | "ValC" | "Crc16")))
+    }
 
-            Expression::BitXor(left, right) => {
-                self.gen_expression(left)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(right)?;
-                self.emit(opcodes::LD_C_A);
-                self.emit(opcodes::LD_A_B);
-                self.emit(0xA9); // XOR C
-                Ok(false)
+    // With --overflow check, a call to the trap if the operation just done went past
+    // the range of expr's type: out of 0..255 or 0..65535 by the carry, or for an INT
+    // out of -32768..32767 by the overflow flag
+    fn emit_overflow_check(&mut self, expr: &Expression) {
+        if self.overflow == Overflow::Check {
+            self.emit(if self.is_int(expr) { 0xEC } else { 0xDC });  // CALL PE or CALL C
+            self.overflow_calls.push(self.code.len());
+            self.emit_word(0x0000);  // Patched once the trap is placed
+        }
+    }
+
+    // Whether an expression gives an INT: an INT variable, element or FUNC result, or
+    // arithmetic with one, as an INT with a BYTE or CARD is an INT
+    fn is_int(&self, expr: &Expression) -> bool {
+        let data_type = |name: &str| self.globals.get(&self.key(name)).map(|info| match &info.data_type {
+            DataType::Pointer(target) if info.by_ref => (**target).clone(),
+            other => other.clone(),
+        });
+        match expr {
+            Expression::Variable(name) => data_type(name) == Some(DataType::Int),
+            Expression::ArrayAccess { array, .. } => matches!(data_type(array), Some(DataType::IntArray(_))),
+            Expression::FunctionCall { name, args } => matches!(data_type(name), Some(DataType::IntArray(_))) && args.len() == 1
+                || self.return_types.get(&self.key(name)) == Some(&DataType::Int),
+            Expression::Add(l, r) | Expression::Subtract(l, r) | Expression::Multiply(l, r) => self.is_int(l) || self.is_int(r),
+            _ => false,
+        }
+    }
+
+    // A character classification or case conversion on the character in A, in a few
+    // instructions in place of a call
+    fn gen_intrinsic(&mut self, name: &str, args: &[Expression]) -> Result<()> {
+        let [arg] = args else {
+            return Err(CompileError::CodeGenError {
+                message: format!("{} expects 1 argument, found {}", name, args.len()),
+            });
+        };
+        if self.gen_expression(arg)? {
+            self.emit(opcodes::LD_A_L);
+        }
+        // Characters in first..=last give 1 from the carry of the compare, others 0
+        let in_range = |first: u8, last: u8| [0xD6, first, 0xFE, last - first + 1, 0x3E, 0x00, 0x8F];  // SUB first  CP n  LD A,0  ADC A,A
+        // Characters in first..=last are moved by adding delta, others stay as they are
+        let shift = |first: u8, last: u8, delta: u8| [0xFE, first, 0x38, 0x06, 0xFE, last + 1, 0x30, 0x02, 0xC6, delta];  // CP  JR C  CP  JR NC  ADD A,delta
+        match name {
+            "IsDigit" => self.emit_bytes(&in_range(b'0', b'9')),
+            "IsAlpha" => {
+                self.emit_bytes(&[0xE6, 0xDF]);  // AND $DF: lowercase letters to uppercase
+                self.emit_bytes(&in_range(b'A', b'Z'));
             }
+            "ToUpper" => self.emit_bytes(&shift(b'a', b'z', 0u8.wrapping_sub(32))),
+            _ => self.emit_bytes(&shift(b'A', b'Z', 32)),  // ToLower
+        }
+        Ok(())
+    }
 
-            Expression::Negate(inner) => {
-                self.gen_expression(inner)?;
-                self.emit_bytes(&opcodes::NEG);
-                Ok(false)
+    // PrintF(format, args...) as Print, PrintB, PrintC, PutD and PrintE calls. Constant
+    // arguments are printed into the text around them at compile time, so a PrintF of
+    // constants is a single Print.
+    fn gen_print_f(&mut self, runtime: &RuntimeSymbols, args: &[Expression]) -> Result<()> {
+        let error = |message: String| CompileError::CodeGenError { message };
+        let Some((Expression::String(format), mut rest)) = args.split_first().map(|(f, r)| (f, r.iter())) else {
+            return Err(error("PrintF expects a string literal as its format".to_string()));
+        };
+
+        let mut pieces: Vec<FormatPiece> = Vec::new();
+        let mut text = Vec::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                let mut utf8 = [0; 4];
+                text.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                continue;
+            }
+            let directive = chars.next().map(|d| d.to_ascii_uppercase());
+            match directive {
+                Some('%') => text.push(b'%'),
+                Some('E') => text.extend_from_slice(b"\r\n"),
+                Some(d @ ('C' | 'S' | 'U')) => {
+                    let arg = rest.next().ok_or_else(|| error(format!("PrintF has no argument for %{}", d)))?;
+                    match (d, arg, arg.const_value()) {
+                        ('C', _, Some(value)) if value as u8 != 0 => text.push(value as u8),
+                        ('S', Expression::String(s), _) => text.extend_from_slice(s.as_bytes()),
+                        ('U', _, Some(value)) => text.extend_from_slice((value as u16).to_string().as_bytes()),
+                        _ => {
+                            if !text.is_empty() {
+                                pieces.push(FormatPiece::Text(std::mem::take(&mut text)));
+                            }
+                            pieces.push(FormatPiece::Arg(d, arg));
+                        }
+                    }
+                }
+                Some(d) => return Err(error(format!("PrintF has no directive %{}", d))),
+                None => return Err(error("PrintF format ends in %".to_string())),
             }
+        }
+        if !text.is_empty() {
+            pieces.push(FormatPiece::Text(text));
+        }
+        if rest.next().is_some() {
+            return Err(error("PrintF has more arguments than its format uses".to_string()));
+        }
 
-            Expression::Not(inner) => {
-                self.gen_expression(inner)?;
-                self.emit(opcodes::CPL);
-                Ok(false)
+        for piece in pieces {
+            let routine = match piece {
+                FormatPiece::Text(mut bytes) => {
+                    bytes.push(0);
+                    let offset = self.add_data(&bytes);
+                    self.emit(opcodes::LD_HL_NN);
+                    self.emit_data_address(offset);
+                    runtime.print
+                }
+                FormatPiece::Arg(directive, arg) => {
+                    let is_word = self.gen_expression(arg)?;
+                    match directive {
+                        'C' if is_word => {
+                            self.emit(opcodes::LD_A_L);
+                            runtime.put_d
+                        }
+                        'C' => runtime.put_d,
+                        'U' if is_word => runtime.print_c,
+                        'U' => runtime.print_b,
+                        _ => runtime.print,
+                    }
+                }
+            };
+            match runtime.rst_for(routine) {
+                Some(rst) => self.emit(rst),
+                None => self.emit_call(routine),
+            }
+        }
+        Ok(())
+    }
+
+    // Save HL as an expression temporary, on the runtime's evaluation stack if it has one
+    fn emit_push_temp(&mut self) {
+        match self.runtime.as_ref().map_or(0, |r| r.eval_push) {
+            0 => self.emit(opcodes::PUSH_HL),
+            push => self.emit_call(push),
+        }
+    }
+
+    // Take the last temporary back into the register pair pop (POP_HL, POP_DE or POP_BC) pops
+    fn emit_pop_temp(&mut self, pop: u8) {
+        let routine = self.runtime.as_ref().map_or(0, |r| match pop {
+            opcodes::POP_HL => r.eval_pop_hl,
+            opcodes::POP_DE => r.eval_pop_de,
+            _ => r.eval_pop_bc,
+        });
+        match routine {
+            0 => self.emit(pop),
+            routine => self.emit_call(routine),
+        }
+    }
+
+    // Evaluate arguments left to right and push each as a word, as temporaries
+    fn emit_push_args(&mut self, args: &[Expression], count: usize, name: &str) -> Result<()> {
+        if args.len() != count {
+            return Err(CompileError::CodeGenError {
+                message: format!("{} expects {} arguments, found {}", name, count, args.len()),
+            });
+        }
+        for arg in args {
+            if !self.gen_expression(arg)? {
+                self.emit(opcodes::LD_L_A);
+                self.emit(opcodes::LD_H_N);
+                self.emit(0);
+            }
+            self.emit_push_temp();
+        }
+        Ok(())
+    }
+
+    // Start a listing entry for the code that follows
+    fn mark_line(&mut self, line: Option<usize>) {
+        self.current_line = line;
+        self.line_marks.push((self.code.len(), line, self.current_proc.clone()));
+    }
+
+    // Generate code for statement, attributing code after nested statements back to its line
+    fn gen_statement(&mut self, stmt: &Statement) -> Result<()> {
+        if let Statement::Line(line) = stmt {
+            self.mark_line(Some(*line));
+            return Ok(());
+        }
+        let line = self.current_line;
+        self.gen_statement_inner(stmt)?;
+        if self.current_line != line {
+            self.mark_line(line);
+        }
+        Ok(())
+    }
+
+    // Statements go to functions of their own, keeping this frame, which every
+    // level of nesting adds, small
+    fn gen_statement_inner(&mut self, stmt: &Statement) -> Result<()> {
+        match stmt {
+            Statement::VarDecl(_var) => {
+                // Local variable - allocate on stack
+                // For now, skip - handled during procedure setup
+                Ok(())
+            }
+
+            Statement::Assignment { target, value } => {
+                let is_word = self.gen_expression(value)?;
+                self.emit_store_var(target, is_word)?;
+                Ok(())
+            }
+
+            Statement::ArrayAssignment { array, index, value } => self.gen_array_assignment(array, index, value),
+            Statement::If { condition, then_block, else_block } => self.gen_if(condition, then_block, else_block.as_deref()),
+            Statement::While { condition, body } => self.gen_while(condition, body),
+            Statement::Until { condition, body } => self.gen_until(condition, body),
+            Statement::For { var, start, end, step, body } => self.gen_for(var, start, end, step.as_ref(), body),
+
+            Statement::Exit => {
+                if let Some(&end) = self.loop_stack.last() {
+                    self.emit_jump_to(opcodes::JP_NN, end);
+                }
+                Ok(())
             }
 
-            Expression::FunctionCall { name, args } => {
-                // Push arguments in reverse order
-                for arg in args.iter().rev() {
-                    self.gen_expression(arg)?;
+            Statement::Return(value) => self.gen_return(value.as_ref()),
+            Statement::ProcCall { name, args } => self.gen_proc_call(name, args),
+
+            Statement::PointerAssignment { pointer, value } => self.gen_pointer_assignment(pointer, value),
+
+            Statement::Block(statements) => {
+                for stmt in statements {
+                    self.gen_statement(stmt)?;
+                }
+                Ok(())
+            }
+
+            // Marks the lines of the statements that follow, in gen_statement
+            Statement::Line(_) => Ok(()),
+        }
+    }
+
+    fn gen_array_assignment(&mut self, array: &str, index: &Expression, value: &Expression) -> Result<()> {
+        // Calculate destination address
+        let info = self.globals.get(&self.key(array)).cloned()
+            .ok_or_else(|| CompileError::UndefinedVariable { name: array.to_string() })?;
+
+        // Evaluate value first, save in B
+        self.gen_expression(value)?;
+        self.emit(opcodes::LD_B_A);
+
+        // Calculate address
+        self.emit_variable_address(&info);
+        self.emit_push_temp();
+        self.gen_expression(index)?;
+        self.emit(opcodes::LD_E_A);
+        self.emit(opcodes::LD_D_N);
+        self.emit(0);
+        self.emit_pop_temp(opcodes::POP_HL);
+        self.emit(opcodes::ADD_HL_DE);
+
+        // Store value
+        self.emit(opcodes::LD_A_B);
+        self.emit(opcodes::LD_HL_A);
+        Ok(())
+    }
+
+    // ^pointer = value: the value, then the address, which a pointer variable gives
+    // without disturbing it and anything else through a temporary
+    fn gen_pointer_assignment(&mut self, pointer: &Expression, value: &Expression) -> Result<()> {
+        let target = self.pointee(pointer);
+        let direct = self.pointer_variable(pointer);
+        if direct.is_none() {
+            self.gen_pointer_address(pointer)?;
+            self.emit_push_temp();
+        }
+        let is_word = self.gen_expression(value)?;
+        if target.is_word() {
+            if !is_word {
+                self.emit(opcodes::LD_L_A);
+                self.emit(opcodes::LD_H_N);
+                self.emit(0);
+            }
+            self.emit(opcodes::EX_DE_HL);
+            match &direct {
+                Some(info) => self.emit_load_hl(info),
+                None => self.emit_pop_temp(opcodes::POP_HL),
+            }
+            self.emit(opcodes::LD_HL_E);
+            self.emit(opcodes::INC_HL);
+            self.emit(opcodes::LD_HL_D);
+        } else {
+            if is_word {
+                self.emit(opcodes::LD_A_L);
+            }
+            match &direct {
+                Some(info) => self.emit_load_hl(info),
+                None => self.emit_pop_temp(opcodes::POP_HL),
+            }
+            self.emit(opcodes::LD_HL_A);
+        }
+        // The store may have changed any variable
+        self.held = None;
+        Ok(())
+    }
+
+    fn gen_if(&mut self, condition: &Expression, then_block: &[Statement], else_block: Option<&[Statement]>) -> Result<()> {
+        self.gen_expression(condition)?;
+        self.emit(opcodes::AND_A); // Set flags
+
+        let else_label = self.new_label();
+        let else_jump = self.emit_jump_to(opcodes::JP_Z_NN, else_label);
+
+        // Then block
+        for stmt in then_block {
+            self.gen_statement(stmt)?;
+        }
+
+        if let Some(else_stmts) = else_block.filter(|_| self.pc == else_jump + 3) {
+            // Nothing to do when the condition holds: jump over the else block
+            // then, rather than around a jump to the end
+            let at = (else_jump - self.origin) as usize;
+            self.code[at] = opcodes::JP_NZ_NN;
+            for stmt in else_stmts {
+                self.gen_statement(stmt)?;
+            }
+        } else if let Some(else_stmts) = else_block {
+            let end = self.new_label();
+            self.emit_jump_to(opcodes::JP_NN, end);
+            self.define(else_label);
+            for stmt in else_stmts {
+                self.gen_statement(stmt)?;
+            }
+            self.define(end);
+            return Ok(());
+        }
+        self.define(else_label);
+        Ok(())
+    }
+
+    fn gen_while(&mut self, condition: &Expression, body: &[Statement]) -> Result<()> {
+        let loop_start = self.current_address();
+        let loop_end = self.new_label();
+
+        self.gen_expression(condition)?;
+        self.emit(opcodes::AND_A);
+        self.emit_jump_to(opcodes::JP_Z_NN, loop_end);
+
+        self.loop_stack.push(loop_end);
+        for stmt in body {
+            self.gen_statement(stmt)?;
+        }
+        self.loop_stack.pop();
+
+        self.emit_jump_back(loop_start);
+        self.define(loop_end);
+        Ok(())
+    }
+
+    fn gen_until(&mut self, condition: &Expression, body: &[Statement]) -> Result<()> {
+        let loop_start = self.current_address();
+        let loop_end = self.new_label();
+
+        self.loop_stack.push(loop_end);
+        for stmt in body {
+            self.gen_statement(stmt)?;
+        }
+        self.loop_stack.pop();
+
+        // The body runs again while the condition is false
+        self.gen_expression(condition)?;
+        self.emit(opcodes::AND_A);
+        if self.opt_for == Some(OptFor::Size) && jr_offset(self.pc.wrapping_add(2), loop_start).is_some() {
+            self.emit_jr(opcodes::JR_Z_N, loop_start)?;
+        } else {
+            self.emit_jump(opcodes::JP_Z_NN, loop_start);
+        }
+        self.define(loop_end);
+        Ok(())
+    }
+
+    fn gen_for(&mut self, var: &str, start: &Expression, end: &Expression, step: Option<&Expression>, body: &[Statement]) -> Result<()> {
+        if let Some(count) = self.counted_loop(var, start, end, step, body) {
+            return self.gen_counted_for(var, start, count, body);
+        }
+
+        // Initialize loop variable
+        self.gen_expression(start)?;
+        self.emit_store_var(var, false)?;
+
+        let loop_start = self.current_address();
+        let loop_end = self.new_label();
+
+        // Check condition: var <= end, as end - var without a borrow
+        self.emit_load_var(var)?;
+        self.emit(opcodes::LD_B_A);
+        self.gen_expression(end)?;
+        self.emit(opcodes::CP_B);
+
+        // Exit if var > end
+        self.emit_jump_to(opcodes::JP_C_NN, loop_end);
+
+        self.loop_stack.push(loop_end);
+        for stmt in body {
+            self.gen_statement(stmt)?;
+        }
+        self.loop_stack.pop();
+
+        // Increment
+        self.emit_load_var(var)?;
+        if let Some(step_expr) = step {
+            self.emit(opcodes::LD_B_A);
+            self.gen_expression(step_expr)?;
+            self.emit(opcodes::ADD_A_B);
+        } else {
+            self.emit(opcodes::INC_A);
+        }
+        self.emit_store_var(var, false)?;
+
+        self.emit_jump_back(loop_start);
+        self.define(loop_end);
+        Ok(())
+    }
+
+    fn gen_return(&mut self, value: Option<&Expression>) -> Result<()> {
+        if let Some(expr) = value {
+            let is_word = self.gen_expression(expr)?;
+            // A FUNC returns its type: a BYTE in A, a CARD or INT in HL
+            let return_type = self.current_proc.as_ref().and_then(|name| self.return_types.get(&self.key(name)));
+            match return_type.map(|t| t.is_word()) {
+                Some(true) if !is_word => {
+                    self.emit(opcodes::LD_L_A);
+                    self.emit(opcodes::LD_H_N);
+                    self.emit(0);
+                }
+                Some(false) if is_word => self.emit(opcodes::LD_A_L),
+                _ => {}
+            }
+        }
+        self.emit_return();
+        Ok(())
+    }
+
+    fn gen_proc_call(&mut self, name: &str, args: &[Expression]) -> Result<()> {
+        // Runtime library functions take their arguments in registers
+        if self.gen_runtime_call(name, args)?.is_some() {
+            return Ok(());
+        }
+
+        // So do the program's, up to one of each width, and push the rest
+        let pushed = self.gen_call_args(name, args)?;
+
+        self.emit_proc_call(name);
+
+        // Clean up stack
+        for _ in 0..pushed {
+            self.emit(opcodes::POP_BC);
+        }
+
+        Ok(())
+    }
+
+    // Iterations of a FOR loop that can count down in B, when optimizing for size: constant
+    // bounds, a step of 1, a BYTE variable the body leaves alone and no way out of the body
+    // but its end
+    fn counted_loop(&self, var: &str, start: &Expression, end: &Expression, step: Option<&Expression>, body: &[Statement]) -> Option<u16> {
+        if self.opt_for != Some(OptFor::Size) || step.is_some_and(|s| s.const_value() != Some(1)) {
+            return None;
+        }
+        let key = self.key(var);
+        if self.globals.get(&key).is_none_or(|info| info.data_type != DataType::Byte) {
+            return None;
+        }
+        let (start, end) = (start.const_value()?, end.const_value()?);
+        if !(0..=255).contains(&start) || !(start..=255).contains(&end) {
+            return None;
+        }
+        self.loop_body_is_plain(&key, body).then_some((end - start + 1) as u16)
+    }
+
+    // Whether statements neither assign the variable with key nor leave early
+    fn loop_body_is_plain(&self, key: &str, stmts: &[Statement]) -> bool {
+        stmts.iter().all(|stmt| match stmt {
+            Statement::Assignment { target, .. } => self.key(target) != key,
+            Statement::For { var, body, .. } => self.key(var) != key && self.loop_body_is_plain(key, body),
+            Statement::If { then_block, else_block, .. } => {
+                self.loop_body_is_plain(key, then_block)
+                    && else_block.as_ref().is_none_or(|b| self.loop_body_is_plain(key, b))
+            }
+            Statement::While { body, .. } | Statement::Until { body, .. } | Statement::Block(body) => {
+                self.loop_body_is_plain(key, body)
+            }
+            // A store through a pointer may change the counter
+            Statement::Exit | Statement::Return(_) | Statement::PointerAssignment { .. } => false,
+            _ => true,
+        })
+    }
+
+    // Whether the FUNC name returns a CARD or INT, in HL
+    fn returns_word(&self, name: &str) -> bool {
+        self.return_types.get(&self.key(name)).is_some_and(|t| t.is_word())
+    }
+
+    // The procedures and globals of other images, called and used like the program's
+    // own; the program may not define them too
+    fn import_symbols(&mut self, program: &Program) -> Result<()> {
+        for import in self.imports.clone() {
+            let names = import.procedures.iter().map(|p| &p.name).chain(import.globals.iter().map(|g| &g.name));
+            for name in names {
+                if let Some(def) = program.definitions.iter().find(|d| self.key(&d.name) == self.key(name)) {
+                    return Err(CompileError::CodeGenError {
+                        message: format!("{} at line {} is also imported from another image", def.name, def.line),
+                    });
+                }
+            }
+            for proc in &import.procedures {
+                let key = self.key(&proc.name);
+                self.proc_params.insert(key.clone(), proc.parameters());
+                if let Some(return_type) = proc.return_type() {
+                    self.return_types.insert(key.clone(), return_type);
+                }
+                self.imported.insert(key, proc.address);
+            }
+            for var in &import.globals {
+                self.globals.insert(self.key(&var.name), SymbolInfo {
+                    address: var.address,
+                    data_type: var.data_type(),
+                    is_param: false,
+                    stack_offset: None,
+                    in_data: false,
+                    by_ref: false,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Every FORWARD declaration needs a body with the same signature
+    fn check_declarations(&self, program: &Program) -> Result<()> {
+        for decl in &program.declarations {
+            let kind = if decl.return_type.is_some() { "FUNC" } else { "PROC" };
+            match program.procedures.iter().find(|p| self.key(&p.name) == self.key(&decl.name)) {
+                None => {
+                    return Err(CompileError::CodeGenError {
+                        message: format!("{} {} is declared FORWARD but never defined", kind, decl.name),
+                    });
+                }
+                Some(proc) if !proc.same_signature(decl) => {
+                    return Err(CompileError::CodeGenError {
+                        message: format!("{} {} is defined differently from its FORWARD declaration", kind, decl.name),
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    // A PRIVATE name may only be used by procedures in its own module, unless they have
+    // a local or parameter of that name or another module has a public one
+    fn check_visibility(&self, program: &Program) -> Result<()> {
+        let mut private: HashMap<String, Vec<usize>> = HashMap::new();
+        for (name, module) in &program.private {
+            private.entry(self.key(name)).or_default().push(*module);
+        }
+        let public: HashSet<String> = program.definitions.iter()
+            .filter(|d| !private.get(&self.key(&d.name)).is_some_and(|modules| modules.contains(&d.module)))
+            .map(|d| self.key(&d.name))
+            .collect();
+        for proc in &program.procedures {
+            let own: HashSet<String> = proc.locals.iter().map(|v| self.key(&v.name))
+                .chain(proc.params.iter().map(|p| self.key(&p.name)))
+                .collect();
+            let mut names = Vec::new();
+            collect_names(&proc.body, &mut names);
+            for name in names {
+                let key = self.key(name);
+                match private.get(&key) {
+                    Some(modules) if !modules.contains(&proc.module) && !own.contains(&key) && !public.contains(&key) => {
+                        return Err(CompileError::CodeGenError {
+                            message: format!("{} is PRIVATE to {}, but {} uses it",
+                                             name, program.module_name(modules[0]), proc.name),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Pass the first byte argument in A, the first word one in HL and the others on the
+    // stack. FASTCALL procedures must take them all in registers. Procedures at fixed
+    // addresses may be called from machine code, so they take all of theirs on the stack
+    // unless marked.
+    fn plan_register_args(&mut self, program: &Program) -> Result<()> {
+        for proc in &program.procedures {
+            self.plan_args(&proc.name, &proc.params, proc.address.is_some(), proc.fast_call)?;
+        }
+        for import in self.imports.clone() {
+            for proc in &import.procedures {
+                self.plan_args(&proc.name, &proc.parameters(), proc.fixed, proc.fastcall)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Where one procedure takes its arguments; fixed says it has an address of its own
+    fn plan_args(&mut self, name: &str, params: &[Parameter], fixed: bool, fast_call: bool) -> Result<()> {
+        let mut registers: Vec<Parameter> = Vec::new();
+        let mut stack = Vec::new();
+        for param in params {
+            let is_word = param.passed_type().is_word();
+            let taken = registers.iter().any(|r| r.passed_type().is_word() == is_word);
+            if taken || (fixed && !fast_call) {
+                stack.push(param.clone());
+            } else {
+                registers.push(param.clone());
+            }
+        }
+        if fast_call && (registers.is_empty() || !stack.is_empty()) {
+            return Err(CompileError::CodeGenError {
+                message: format!("FASTCALL {} needs one BYTE parameter, one CARD or INT, or one of each", name),
+            });
+        }
+        let key = self.key(name);
+        if !registers.is_empty() {
+            self.register_procs.insert(key.clone(), registers);
+        }
+        if !stack.is_empty() {
+            self.stack_params.insert(key, stack);
+        }
+        Ok(())
+    }
+
+    // Arguments of a call to one of the program's procedures: those passed on the stack
+    // pushed last first, each taking a word with a byte in its high half, as PUSH AF
+    // leaves it; then those in registers. Returns how many words it pushed, which the
+    // caller drops after the call.
+    fn gen_call_args(&mut self, name: &str, args: &[Expression]) -> Result<usize> {
+        let key = self.key(name);
+        let Some(params) = self.proc_params.get(&key).cloned() else {
+            // Not one of the program's: everything on the stack
+            for arg in args.iter().rev() {
+                let is_word = self.gen_expression(arg)?;
+                self.emit(if is_word { opcodes::PUSH_HL } else { opcodes::PUSH_AF });
+            }
+            return Ok(args.len());
+        };
+        check_arg_count(name, &params, args)?;
+        let args = self.reference_args(name, &params, args)?;
+        let position = |param: &Parameter| params.iter().position(|p| p.name == param.name).unwrap_or_default();
+
+        let stack = self.stack_params.get(&key).cloned().unwrap_or_default();
+        for param in stack.iter().rev() {
+            let is_word = self.gen_expression(&args[position(param)])?;
+            match (param.passed_type().is_word(), is_word) {
+                (true, true) => self.emit(opcodes::PUSH_HL),
+                (true, false) => {
+                    self.emit(opcodes::LD_L_A);
+                    self.emit(opcodes::LD_H_N);
+                    self.emit(0);
+                    self.emit(opcodes::PUSH_HL);
+                }
+                (false, true) => {
+                    self.emit(opcodes::LD_A_L);
                     self.emit(opcodes::PUSH_AF);
                 }
+                (false, false) => self.emit(opcodes::PUSH_AF),
+            }
+        }
+
+        let registers = self.register_procs.get(&key).cloned().unwrap_or_default();
+        let register_args: Vec<Expression> = registers.iter().map(|p| args[position(p)].clone()).collect();
+        self.gen_register_args(&registers, &register_args)?;
+        Ok(stack.len())
+    }
 
-                // Call the function
-                if let Some(&addr) = self.procedures.get(name) {
-                    self.emit(opcodes::CALL_NN);
-                    self.emit_word(addr);
+    // Arguments passed in registers: the word one in HL and the byte one in A, the word
+    // one evaluated first
+    fn gen_register_args(&mut self, params: &[Parameter], args: &[Expression]) -> Result<()> {
+        let word = params.iter().position(|p| p.passed_type().is_word());
+        let byte = params.iter().position(|p| !p.passed_type().is_word());
+        if let Some(i) = word {
+            if !self.gen_expression(&args[i])? {
+                self.emit(opcodes::LD_L_A);
+                self.emit(opcodes::LD_H_N);
+                self.emit(0);
+            }
+            if byte.is_some() {
+                self.emit_push_temp();
+            }
+        }
+        if let Some(i) = byte {
+            if self.gen_expression(&args[i])? {
+                self.emit(opcodes::LD_A_L);
+            }
+            if word.is_some() {
+                self.emit_pop_temp(opcodes::POP_HL);
+            }
+        }
+        Ok(())
+    }
+
+    // Arguments as passed: the address of the variable given for each VAR parameter
+    fn reference_args(&self, name: &str, params: &[Parameter], args: &[Expression]) -> Result<Vec<Expression>> {
+        args.iter().zip(params.iter().map(Some).chain(std::iter::repeat(None)))
+            .map(|(arg, param)| match (arg, param) {
+                (Expression::Variable(var), Some(param)) if param.by_ref && self.globals.contains_key(&self.key(var)) => {
+                    Ok(Expression::AddressOf(var.clone()))
+                }
+                (_, Some(param)) if param.by_ref => Err(CompileError::CodeGenError {
+                    message: format!("{} needs a variable for VAR parameter {}", name, param.name),
+                }),
+                _ => Ok(arg.clone()),
+            })
+            .collect()
+    }
+
+    // Give a stack frame to each procedure with locals that can call itself, directly or
+    // through others, or to each one with locals when asked to
+    fn plan_stack_frames(&mut self, program: &Program, graph: &CallGraph) {
+        for proc in &program.procedures {
+            let key = self.key(&proc.name);
+            let has_locals = !proc.locals.is_empty() || !proc.params.is_empty();
+            let recursive = graph.reachable(graph.callees(&key)).contains(key.as_str());
+            if has_locals && (self.stack_locals || recursive) {
+                self.frame_procs.insert(key);
+            }
+        }
+    }
+
+    // Place each procedure's uninitialized locals after those of every procedure that can
+    // be active while it runs: after its callers', which the call graph gives once it has
+    // no cycles. Interrupt procedures, and those at fixed addresses that may be handlers
+    // or entry points for other programs, keep locals of their own, as does what they call.
+    fn plan_local_frames(&mut self, program: &Program, graph: &CallGraph) {
+        let Some(order) = graph.callers_first() else {
+            let name = graph.recursive().unwrap_or_default();
+            let proc = program.procedures.iter().find(|p| self.key(&p.name) == name).map_or(name, |p| &p.name);
+            self.warnings.push(format!("locals not overlaid: {} is recursive", proc));
+            return;
+        };
+        let placed: Vec<String> = program.procedures.iter()
+            .filter(|p| p.address.is_some() || p.interrupt)
+            .map(|p| self.key(&p.name))
+            .collect();
+        let pinned = graph.reachable(placed.iter().map(String::as_str));
+        let sizes: HashMap<String, u16> = program.procedures.iter()
+            .map(|p| {
+                let key = self.key(&p.name);
+                if self.frame_procs.contains(&key) {
+                    return (key, 0);
+                }
+                let locals: u16 = p.locals.iter().filter(|v| v.initial_value.is_none()).map(|v| v.data_type.size() as u16).sum();
+                let params: u16 = p.params.iter().map(|p| p.passed_type().size() as u16).sum();
+                (key, locals + params)
+            })
+            .collect();
+
+        let base = self.data_offset;
+        let mut starts: HashMap<&str, u16> = HashMap::new();
+        let mut end = 0;
+        for name in order.into_iter().filter(|name| !pinned.contains(name)) {
+            let start = starts.get(name).copied().unwrap_or(0);
+            let frame_end = start + sizes[name];
+            for callee in graph.callees(name) {
+                let callee_start = starts.entry(callee).or_insert(0);
+                *callee_start = (*callee_start).max(frame_end);
+            }
+            end = end.max(frame_end);
+            self.local_frames.insert(name.to_string(), base + start);
+        }
+        self.data_offset = base + end;
+    }
+
+    // FOR loop counted down with DJNZ. B is saved around the body, which may use it.
+    fn gen_counted_for(&mut self, var: &str, start: &Expression, count: u16, body: &[Statement]) -> Result<()> {
+        self.gen_expression(start)?;
+        self.emit_store_var(var, false)?;
+        self.emit(opcodes::LD_B_N);
+        self.emit(count as u8);  // 256 is 0
+
+        let loop_start = self.current_address();
+        let line = self.current_line;
+        self.emit(opcodes::PUSH_BC);
+        for stmt in body {
+            self.gen_statement(stmt)?;
+        }
+        // The step and the POP BC belong to the FOR line, with the PUSH BC
+        if self.current_line != line {
+            self.mark_line(line);
+        }
+        self.emit_load_var(var)?;
+        self.emit(opcodes::INC_A);
+        self.emit_store_var(var, false)?;
+        self.emit(opcodes::POP_BC);
+
+        if jr_offset(self.pc.wrapping_add(2), loop_start).is_some() {
+            self.emit_jr(opcodes::DJNZ_N, loop_start)?;
+        } else {
+            self.emit(opcodes::DEC_B);
+            self.emit_jump(opcodes::JP_NZ_NN, loop_start);
+        }
+        Ok(())
+    }
+
+    fn gen_procedure(&mut self, proc: &Procedure) -> Result<()> {
+        // Pad up to a fixed address
+        if let Some(address) = proc.address {
+            let here = self.current_address();
+            if address < here {
+                return Err(CompileError::CodeGenError {
+                    message: format!(
+                        "PROC {} must be at ${:04X}, but the code before it already reaches ${:04X}",
+                        proc.name, address, here,
+                    ),
+                });
+            }
+            self.mark_line(None);
+            for _ in here..address {
+                self.emit(0x00);  // NOP
+            }
+        }
+
+        let proc_addr = self.current_address();
+        self.procedures.insert(self.key(&proc.name), proc_addr);
+        self.current_proc = Some(proc.name.clone());
+        self.mark_line(None);
+
+        // An interrupt can come between any two instructions, so its procedure keeps
+        // every register the program uses
+        if proc.interrupt {
+            for push in [opcodes::PUSH_AF, opcodes::PUSH_BC, opcodes::PUSH_DE, opcodes::PUSH_HL] {
+                self.emit(push);
+            }
+            self.emit_bytes(&opcodes::PUSH_IX);
+            self.in_interrupt = true;
+        }
+
+        // Locals are static, like in Action!, and initialized ones live in the data
+        // section, unless the procedure has a stack frame: then they are below IX, and
+        // each call has its own
+        let in_frame = self.frame_procs.contains(&self.key(&proc.name));
+        let frame = self.local_frames.get(&self.key(&proc.name)).copied();
+        let mut ram_addr = frame.unwrap_or(self.data_offset);
+        let mut frame_size = 0;
+        for local in &proc.locals {
+            let info = if in_frame {
+                self.allocate_frame_variable(local, &mut frame_size)?
+            } else {
+                self.allocate_variable(local, &mut ram_addr)?
+            };
+            self.globals.insert(self.key(&local.name), info);
+        }
+
+        // Parameters passed in registers are stored with the locals on entry, and so are
+        // those on the stack, unless the procedure has a frame to find them in: above IX,
+        // past the caller's IX and the return address
+        let params = self.register_procs.get(&self.key(&proc.name)).cloned().unwrap_or_default();
+        let stack = self.stack_params.get(&self.key(&proc.name)).cloned().unwrap_or_default();
+        for param in &params {
+            let var = Variable { name: param.name.clone(), data_type: param.passed_type(), initial_value: None };
+            let info = if in_frame {
+                self.allocate_frame_variable(&var, &mut frame_size)?
+            } else {
+                self.allocate_variable(&var, &mut ram_addr)?
+            };
+            self.globals.insert(self.key(&param.name), info);
+        }
+        for (i, param) in stack.iter().enumerate() {
+            let var = Variable { name: param.name.clone(), data_type: param.passed_type(), initial_value: None };
+            let info = if in_frame {
+                let offset = 4 + 2 * i as i16 + if var.data_type.is_word() { 0 } else { 1 };
+                if offset > i8::MAX as i16 - 1 {
+                    return Err(CompileError::CodeGenError {
+                        message: format!("{} has more arguments on the stack than its stack frame can reach", proc.name),
+                    });
+                }
+                SymbolInfo { stack_offset: Some(offset), ..self.allocate_frame_variable(&var, &mut 0)? }
+            } else {
+                self.allocate_variable(&var, &mut ram_addr)?
+            };
+            self.globals.insert(self.key(&param.name), info);
+        }
+        if frame.is_none() {
+            self.data_offset = ram_addr;
+        }
+        if in_frame {
+            if frame_size > MAX_FRAME_SIZE {
+                return Err(CompileError::CodeGenError {
+                    message: format!("the locals of {} take {} bytes, more than the {} a stack frame can hold",
+                                     proc.name, frame_size, MAX_FRAME_SIZE),
+                });
+            }
+            self.emit_frame_entry(frame_size, params.iter().any(|p| p.passed_type().is_word()));
+            self.frame_sizes.insert(self.key(&proc.name), frame_size);
+            self.in_frame = true;
+        }
+        for param in &params {
+            self.emit_store_var(&param.name, param.passed_type().is_word())?;
+        }
+        if !in_frame && !stack.is_empty() {
+            self.emit_load_word(2);  // Past the return address
+            self.emit(opcodes::ADD_HL_SP);
+            for param in &stack {
+                if param.passed_type().is_word() {
+                    self.emit(opcodes::LD_E_HL);
+                    self.emit(opcodes::INC_HL);
+                    self.emit(opcodes::LD_D_HL);
+                    self.emit(opcodes::INC_HL);
+                    self.emit(opcodes::EX_DE_HL);
+                    self.emit_store_var(&param.name, true)?;
+                    self.emit(opcodes::EX_DE_HL);
                 } else {
-                    // Forward reference - will need to patch
-                    self.emit(opcodes::CALL_NN);
-                    self.emit_word(0x0000); // Placeholder
+                    self.emit(opcodes::INC_HL);
+                    self.emit(opcodes::LD_A_HL);
+                    self.emit(opcodes::INC_HL);
+                    self.emit_store_var(&param.name, false)?;
                 }
-
-                // Clean up stack (caller cleanup)
-                if !args.is_empty() {
-                    let _cleanup = args.len() * 2;
-                    for _ in 0..args.len() {
-                        self.emit(opcodes::POP_BC);
-                    }
+            }
+        }
+        // A frame's initialized locals get their values on each call
+        if in_frame {
+            for (local, value) in proc.locals.iter().filter_map(|v| v.initial_value.as_ref().map(|e| (v, e))) {
+                if value.const_value().is_none() {
+                    return Err(CompileError::CodeGenError {
+                        message: format!("Initial value of '{}' must be a constant", local.name),
+                    });
                 }
-
-                Ok(false) // Assume byte return for now
+                let is_word = self.gen_expression(value)?;
+                self.emit_store_var(&local.name, is_word)?;
             }
-
-            Expression::AddressOf(name) => {
-                if let Some(info) = self.globals.get(name) {
-                    self.emit_load_word(info.address);
-                    Ok(true)
-                } else {
-                    Err(CompileError::UndefinedVariable { name: name.clone() })
+        }
+        // From here on a VAR parameter's name stands for the variable it points to, and
+        // an ARRAY parameter's for the array
+        for param in params.iter().chain(&stack).filter(|p| p.passed_type() != p.data_type) {
+            let key = self.key(&param.name);
+            if let Some(info) = self.globals.get_mut(&key) {
+                info.by_ref = true;
+                if !param.by_ref {
+                    info.data_type = param.data_type.clone();
                 }
             }
+        }
+        self.held = None;
 
-            Expression::ArrayAccess { array, index } => {
-                // Get array base address
-                let info = self.globals.get(array).cloned()
-                    .ok_or_else(|| CompileError::UndefinedVariable { name: array.clone() })?;
-
-                // Calculate address: base + index
-                self.emit_load_word(info.address);
-                self.emit(opcodes::PUSH_HL);
-                self.gen_expression(index)?;
-                self.emit(opcodes::LD_E_A);
-                self.emit(opcodes::LD_D_N);
-                self.emit(0);
-                self.emit(opcodes::POP_HL);
-                self.emit(opcodes::ADD_HL_DE);
-
-                // Load value from (HL)
-                self.emit(opcodes::LD_A_HL);
-                Ok(false)
-            }
+        // Generate body
+        for stmt in &proc.body {
+            self.gen_statement(stmt)?;
+        }
 
-            _ => Err(CompileError::CodeGenError {
-                message: format!("Unsupported expression: {:?}", expr),
-            }),
+        // Ensure return at end; an INTERRUPT procedure's RETURN has restored everything
+        // already, at more cost than a RET
+        if !(proc.interrupt && matches!(proc.body.last(), Some(Statement::Return(_)))) {
+            self.mark_line(None);
+            self.emit_return();
         }
+        self.in_frame = false;
+        self.in_interrupt = false;
+
+        Ok(())
     }
 
-    // Generate code for statement
-    fn gen_statement(&mut self, stmt: &Statement) -> Result<()> {
-        match stmt {
-            Statement::VarDecl(_var) => {
-                // Local variable - allocate on stack
-                // For now, skip - handled during procedure setup
-                Ok(())
+    // Set up a stack frame of size bytes: the caller's IX is saved, IX points at it, and
+    // the locals are below. HL may hold an argument, kept in DE meanwhile.
+    fn emit_frame_entry(&mut self, size: u16, keep_hl: bool) {
+        self.emit_bytes(&opcodes::PUSH_IX);
+        self.emit_bytes(&opcodes::LD_IX_NN);
+        self.emit_word(0x0000);
+        self.emit_bytes(&opcodes::ADD_IX_SP);
+        if size > 0 {
+            if keep_hl {
+                self.emit(opcodes::EX_DE_HL);
             }
+            self.emit(opcodes::LD_HL_NN);
+            self.emit_word(size.wrapping_neg());
+            self.emit(opcodes::ADD_HL_SP);
+            self.emit(opcodes::LD_SP_HL);
+            if keep_hl {
+                self.emit(opcodes::EX_DE_HL);
+            }
+        }
+    }
 
-            Statement::Assignment { target, value } => {
-                let is_word = self.gen_expression(value)?;
-                if is_word {
-                    self.emit_store_var(target, true)?;
-                } else {
-                    self.emit_store_var(target, false)?;
+    // Procedures that replace the runtime's weak routines, each reached from the runtime
+    // through a piece of code that keeps the registers its callers rely on: those of BC,
+    // DE and HL the procedure changes, and AF for PutD, whose callers may rely on the
+    // flags too. PutD takes its character in A and GetD returns one there, so their
+    // signatures are fixed.
+    fn gen_overrides(&mut self, program: &Program) -> Result<()> {
+        let Some(runtime) = self.runtime.clone() else {
+            return Ok(());
+        };
+        self.mark_line(None);
+        let base = self.origin.wrapping_sub(self.runtime_code.len() as u16);
+        let image = [self.runtime_code.as_slice(), self.code.as_slice()].concat();
+        let mut analysis = clobber::Analysis::new(&image, base);
+        for (routine, stub) in runtime.weak() {
+            let Some(proc) = program.procedures.iter().find(|p| self.key(&p.name) == self.key(routine)) else {
+                continue;
+            };
+            let error = |signature: &str| CompileError::CodeGenError {
+                message: format!("{} replaces the runtime's, so it must be declared {}", proc.name, signature),
+            };
+            let byte = |t: &DataType| t.size() == 1 && !t.is_word();
+            let addr = self.procedures[&self.key(&proc.name)];
+            let thunk = self.current_address();
+            let mut saved = Vec::new();
+            if routine == "PutD" {
+                let takes_a = proc.params.len() == 1 && byte(&proc.params[0].passed_type())
+                    && proc.return_type.is_none() && self.register_procs.contains_key(&self.key(&proc.name));
+                if !takes_a {
+                    return Err(error("PROC PutD(BYTE c)"));
                 }
-                Ok(())
+                saved.push((opcodes::PUSH_AF, opcodes::POP_AF));
+            } else if !proc.params.is_empty() || !proc.return_type.as_ref().is_some_and(byte) {
+                return Err(error("FUNC BYTE GetD()"));
             }
+            let changed = analysis.clobbered(addr);
+            let pairs = [
+                (Registers::BC, opcodes::PUSH_BC, opcodes::POP_BC),
+                (Registers::DE, opcodes::PUSH_DE, opcodes::POP_DE),
+                (Registers::HL, opcodes::PUSH_HL, opcodes::POP_HL),
+            ];
+            saved.extend(pairs.iter().filter(|(pair, _, _)| changed.intersects(*pair)).map(|&(_, push, pop)| (push, pop)));
+            for &(push, _) in &saved {
+                self.emit(push);
+            }
+            self.emit_call(addr);
+            for &(_, pop) in saved.iter().rev() {
+                self.emit(pop);
+            }
+            self.emit(opcodes::RET);
+            self.overrides.push((stub, thunk));
+        }
+        Ok(())
+    }
 
-            Statement::ArrayAssignment { array, index, value } => {
-                // Calculate destination address
-                let info = self.globals.get(array).cloned()
-                    .ok_or_else(|| CompileError::UndefinedVariable { name: array.clone() })?;
-
-                // Evaluate value first, save in B
-                self.gen_expression(value)?;
-                self.emit(opcodes::LD_B_A);
-
-                // Calculate address
-                self.emit_load_word(info.address);
-                self.emit(opcodes::PUSH_HL);
-                self.gen_expression(index)?;
-                self.emit(opcodes::LD_E_A);
-                self.emit(opcodes::LD_D_N);
-                self.emit(0);
-                self.emit(opcodes::POP_HL);
-                self.emit(opcodes::ADD_HL_DE);
-
-                // Store value
-                self.emit(opcodes::LD_A_B);
-                self.emit(opcodes::LD_HL_A);
-                Ok(())
+    // The routine the overflow checks call: it prints ?Overflow at $ and the address of
+    // the check, then stops the program the way it would end
+    fn gen_overflow_trap(&mut self) -> Result<()> {
+        let Some(runtime) = self.runtime.clone().filter(|_| !self.overflow_calls.is_empty()) else {
+            return Ok(());
+        };
+        self.mark_line(None);
+        let trap = self.current_address();
+        self.emit(opcodes::POP_HL);
+        self.emit_bytes(&[opcodes::DEC_HL; 3]);  // Back to the CALL
+        self.emit(opcodes::PUSH_HL);
+        let message = self.add_data(b"?Overflow at $\0");
+        self.emit(opcodes::LD_HL_NN);
+        self.emit_data_address(message);
+        self.emit_call(runtime.print);
+        self.emit(opcodes::POP_HL);
+        let mut byte_calls = Vec::new();
+        for half in [opcodes::LD_A_H, opcodes::LD_A_L] {
+            self.emit(half);
+            byte_calls.push(self.current_address());
+            self.emit(opcodes::CALL_NN);
+            self.emit_word(0x0000);
+        }
+        self.emit_call(runtime.print_e);
+        match self.exit {
+            Exit::Jump(addr) => {
+                self.emit(opcodes::JP_NN);
+                self.emit_word(addr);
             }
+            Exit::Halt | Exit::Return => self.emit_bytes(&[0xF3, opcodes::HALT]),  // DI, with nowhere to return to
+        }
 
-            Statement::If { condition, then_block, else_block } => {
-                self.gen_expression(condition)?;
-                self.emit(opcodes::AND_A); // Set flags
+        // The byte in A as two hex digits
+        let print_byte = self.current_address();
+        self.emit_bytes(&[opcodes::PUSH_AF, 0x0F, 0x0F, 0x0F, 0x0F]);  // RRCA x4: high digit first
+        let high_digit = self.current_address();
+        self.emit(opcodes::CALL_NN);
+        self.emit_word(0x0000);
+        self.emit(opcodes::POP_AF);
+        let print_digit = self.current_address();
+        self.patch_word(high_digit + 1, print_digit)?;
+        self.emit_bytes(&[0xE6, 0x0F, 0xC6, 0x90, 0x27, 0xCE, 0x40, 0x27]);  // AND $0F  ADD A,$90  DAA  ADC A,$40  DAA
+        self.emit(opcodes::JP_NN);
+        self.emit_word(runtime.put_d);
+
+        for at in byte_calls {
+            self.patch_word(at + 1, print_byte)?;
+        }
+        for at in std::mem::take(&mut self.overflow_calls) {
+            self.code[at..at + 2].copy_from_slice(&trap.to_le_bytes());
+        }
+        Ok(())
+    }
 
-                let else_jump = self.current_address();
-                self.emit(opcodes::JP_Z_NN);
-                self.emit_word(0x0000); // Placeholder
+    // The program without the procedures it can never run: those not reached by calls
+    // from the entry procedure, the init procedure, replacements for runtime routines,
+    // procedures at fixed addresses, INTERRUPT ones or those called from outside
+    fn without_unused(&self, program: &Program) -> Program {
+        let graph = CallGraph::new(program, |name| self.key(name));
+        let named = |key: &str| program.procedures.iter().any(|p| self.key(&p.name) == key);
+        let entry = self.entry_point.as_deref().map(|name| self.key(name))
+            .or_else(|| Some(self.key("Main")).filter(|main| named(main)))
+            .or_else(|| program.procedures.first().map(|p| self.key(&p.name)));
+        let init = self.key(self.init_proc.as_deref().unwrap_or("SysInit"));
+        let weak = self.runtime.iter().flat_map(|runtime| runtime.weak()).map(|(name, _)| self.key(name));
+        let placed = program.procedures.iter().filter(|p| p.address.is_some() || p.interrupt).map(|p| self.key(&p.name));
+        let external = self.external.iter().map(|name| self.key(name));
+        let roots: Vec<String> = entry.into_iter().chain([init]).chain(weak).chain(placed).chain(external).collect();
+
+        let reached = graph.reachable(roots.iter().map(String::as_str));
+        let mut program = program.clone();
+        program.procedures.retain(|p| reached.contains(self.key(&p.name).as_str()));
+        program
+    }
 
-                // Then block
-                for stmt in then_block {
-                    self.gen_statement(stmt)?;
-                }
+    pub fn generate(&mut self, program: &Program) -> Result<Vec<u8>> {
+        // PRIVATE names other modules share get names of their own
+        let separated = modules::separate(program, |name| self.key(name))
+            .map_err(|message| CompileError::CodeGenError { message })?;
+        self.check_visibility(program)?;
+        let program = &separated;
+        self.private = program.private.iter().map(|(name, module)| (self.key(name), *module)).collect();
+
+        // Variables only ever written, left out with their stores when asked to and
+        // nothing their values call would go with them
+        let (write_only, droppable) = self.write_only(program);
+        let without_stores;
+        let program = if self.drop_write_only && !write_only.is_empty() {
+            let (dropped, kept): (Vec<String>, Vec<String>) = write_only.into_iter().partition(|name| droppable.contains(&self.key(name)));
+            if !dropped.is_empty() {
+                self.warnings.push(format!("written but never read, left out: {}", dropped.join(", ")));
+            }
+            if !kept.is_empty() {
+                self.warnings.push(format!("written but never read, kept for the calls in their values: {}", kept.join(", ")));
+            }
+            without_stores = self.without_stores(program, &droppable);
+            &without_stores
+        } else {
+            if !write_only.is_empty() {
+                self.warnings.push(format!("written but never read: {}", write_only.join(", ")));
+            }
+            program
+        };
 
-                if let Some(else_stmts) = else_block {
-                    let end_jump = self.current_address();
-                    self.emit(opcodes::JP_NN);
-                    self.emit_word(0x0000);
+        // First pass: allocate global variables
+        // Variables start at 0x2000 (RAM starts here, first 8KB is ROM), after the runtime's own
+        let mut var_addr: u16 = self.runtime.as_ref().map_or(RAM_START, |r| r.ram_end);
+
+        // Initialized globals go to the data section instead
+        self.module_lines = program.modules.clone();
+        self.module_globals = vec![0; program.modules.len() + 1];
+        let modules = program.definitions.iter().filter(|d| d.global).map(|d| d.module);
+        for (var, module) in program.globals.iter().zip(modules.chain(std::iter::repeat(0))) {
+            let info = self.allocate_variable(var, &mut var_addr)?;
+            self.module_globals[module] += var.data_type.size();
+            self.globals.insert(self.key(&var.name), info);
+        }
+        self.data_offset = var_addr;
+        for proc in &program.procedures {
+            self.proc_params.insert(self.key(&proc.name), proc.params.clone());
+            if let Some(return_type) = &proc.return_type {
+                self.return_types.insert(self.key(&proc.name), return_type.clone());
+            }
+        }
+        self.import_symbols(program)?;
+        self.check_declarations(program)?;
 
-                    // Patch else jump
-                    let else_addr = self.current_address();
-                    self.patch_word(else_jump + 1, else_addr);
+        // From here on, only the procedures the program can run
+        let reached;
+        let program = if self.keep_unused {
+            program
+        } else {
+            reached = self.without_unused(program);
+            &reached
+        };
+        let graph = CallGraph::new(program, |name| self.key(name));
+        self.plan_register_args(program)?;
+        self.plan_stack_frames(program, &graph);
+        if self.overlay_locals {
+            self.plan_local_frames(program, &graph);
+        }
 
-                    // Else block
-                    for stmt in else_stmts {
-                        self.gen_statement(stmt)?;
-                    }
+        // Startup code belongs to no line or procedure
+        self.mark_line(None);
 
-                    // Patch end jump
-                    let end_addr = self.current_address();
-                    self.patch_word(end_jump + 1, end_addr);
-                } else {
-                    // Patch else jump to end
-                    let end_addr = self.current_address();
-                    self.patch_word(else_jump + 1, end_addr);
-                }
+        // All of a cartridge must be mapped in before its data is copied or its code runs
+        if let Some(slot_init) = self.runtime.as_ref().map(|r| r.slot_init).filter(|&a| a != 0) {
+            self.emit(opcodes::CALL_NN);
+            self.emit_word(slot_init);
+        }
 
-                Ok(())
-            }
+        // Hardware setup in SysInit (or the --init procedure) runs with interrupts disabled
+        let init_proc = self.init_proc.clone().or_else(|| {
+            program.procedures.iter()
+                .find(|p| self.key(&p.name) == self.key("SysInit"))
+                .map(|p| p.name.clone())
+        });
+        if init_proc.is_some() {
+            self.emit(opcodes::DI);
+        }
 
-            Statement::While { condition, body } => {
-                let loop_start = self.current_address();
+        // When the data section runs elsewhere, copy it there from the image first
+        let data_copy = if (self.data_address.is_some() || self.data_in_ram) && !self.data_loaded {
+            self.emit(opcodes::LD_BC_NN);
+            let copy_at = self.current_address();
+            self.emit_word(0x0000);     // Data length
+            self.emit(opcodes::LD_A_B);
+            self.emit(0xB1);            // OR C
+            let no_data = self.emit_jr_forward(opcodes::JR_Z_N);  // Skip the copy when there is no data
+            self.emit(opcodes::LD_HL_NN);
+            self.emit_word(0x0000);     // Load address in the image
+            self.emit(opcodes::LD_DE_NN);
+            self.emit_word(0x0000);     // Run address
+            self.emit_bytes(&[0xED, 0xB0]);  // LDIR
+            self.patch_jr(no_data)?;
+            Some(copy_at)
+        } else {
+            None
+        };
 
-                self.gen_expression(condition)?;
-                self.emit(opcodes::AND_A);
+        // Empty the evaluation stack before anything can use it
+        if let Some(eval_init) = self.runtime.as_ref().map(|r| r.eval_init).filter(|&a| a != 0) {
+            self.emit(opcodes::CALL_NN);
+            self.emit_word(eval_init);
+        }
 
-                let exit_jump = self.current_address();
-                self.emit(opcodes::JP_Z_NN);
-                self.emit_word(0x0000);
+        // Start with output on the console
+        if let Some(runtime) = &self.runtime {
+            let reset_device = runtime.reset_device;
+            self.emit(opcodes::CALL_NN);
+            self.emit_word(reset_device);
+        }
 
-                // Push loop context for EXIT
-                self.loop_stack.push((loop_start, 0)); // End address TBD
+        // Initialized data is in place for SysInit, which comes before the UART setup
+        // so it can start the clocks the UART depends on
+        let init_call = if init_proc.is_some() {
+            let at = self.current_address();
+            self.emit(opcodes::CALL_NN);
+            self.emit_word(0x0000);  // Patched once procedures are placed
+            Some(at)
+        } else {
+            None
+        };
 
-                for stmt in body {
-                    self.gen_statement(stmt)?;
-                }
+        // Set up the console UART if the runtime needs to
+        if let Some(runtime) = &self.runtime {
+            let uart_init = runtime.uart_init;
+            if uart_init != 0 {
+                self.emit(opcodes::CALL_NN);
+                self.emit_word(uart_init);
+            }
+        }
 
-                // Jump back to start
+        // Generate CALL to Main (or first procedure) followed by HALT (or the chosen
+        // exit), once queued console output is out
+        let main_call = self.current_address();
+        self.emit(opcodes::CALL_NN);
+        self.emit_word(0x0000); // Will patch later
+        if let Some(tx_flush) = self.runtime.as_ref().map(|r| r.tx_flush).filter(|&a| a != 0) {
+            self.emit(opcodes::CALL_NN);
+            self.emit_word(tx_flush);
+        }
+        match self.exit {
+            Exit::Halt => self.emit(opcodes::HALT),
+            Exit::Return => self.emit(opcodes::RET),
+            Exit::Jump(addr) => {
                 self.emit(opcodes::JP_NN);
-                self.emit_word(loop_start);
-
-                // Patch exit jump
-                let loop_end = self.current_address();
-                self.patch_word(exit_jump + 1, loop_end);
+                self.emit_word(addr);
+            }
+        }
 
-                self.loop_stack.pop();
-                Ok(())
+        // Procedures with a fixed address can be called before they are generated
+        for proc in &program.procedures {
+            if let Some(address) = proc.address {
+                self.procedures.insert(self.key(&proc.name), address);
             }
+        }
 
-            Statement::For { var, start, end, step, body } => {
-                // Initialize loop variable
-                self.gen_expression(start)?;
-                self.emit_store_var(var, false)?;
+        // Generate procedures
+        for proc in &program.procedures {
+            self.gen_procedure(proc)?;
+            let start = self.procedures[&self.key(&proc.name)];
+            self.module_code.push((proc.module, proc.name.clone(), start, self.pc));
+        }
 
-                let loop_start = self.current_address();
+        // Point calls made before their procedure was placed at it, so the overrides
+        // can follow them
+        for (at, name) in std::mem::take(&mut self.call_fixups) {
+            let addr = *self.procedures.get(&self.key(&name))
+                .ok_or(CompileError::UndefinedProcedure { name })?;
+            self.code[at..at + 2].copy_from_slice(&addr.to_le_bytes());
+        }
+        self.gen_overrides(program)?;
+        self.gen_overflow_trap()?;
+
+        // Patch the init and main calls
+        if let (Some(at), Some(name)) = (init_call, init_proc) {
+            let addr = *self.procedures.get(&self.key(&name))
+                .ok_or(CompileError::UndefinedProcedure { name })?;
+            self.patch_word(at + 1, addr)?;
+        }
+        if let Some(entry) = self.entry_point.clone() {
+            let addr = *self.procedures.get(&self.key(&entry))
+                .ok_or(CompileError::UndefinedProcedure { name: entry })?;
+            self.patch_word(main_call + 1, addr)?;
+            self.main_address = Some(addr);
+        } else if let Some(&main_addr) = self.procedures.get(&self.key("Main")) {
+            self.patch_word(main_call + 1, main_addr)?;
+            self.main_address = Some(main_addr);
+        } else {
+            // No Main - call first procedure
+            if let Some(proc) = program.procedures.first() {
+                if let Some(&addr) = self.procedures.get(&self.key(&proc.name)) {
+                    self.patch_word(main_call + 1, addr)?;
+                    self.main_address = Some(addr);
+                }
+            }
+        }
 
-                // Check condition: var <= end
-                self.emit_load_var(var)?;
-                self.emit(opcodes::LD_B_A);
-                self.gen_expression(end)?;
-                self.emit(opcodes::LD_C_A);
-                self.emit(opcodes::LD_A_B);
-                self.emit(opcodes::CP_C);
+        self.thread_jumps();
+
+        // Shorten jumps that reach with JR, unless speed matters more: a taken JR is
+        // slower than a JP
+        let data_copy = if self.opt_for != Some(OptFor::Speed) {
+            let anchors: Vec<usize> = program.procedures.iter()
+                .filter_map(|proc| proc.address)
+                .filter_map(|address| address.checked_sub(self.origin).map(usize::from))
+                .filter(|&offset| offset < self.code.len())
+                .collect();
+            let moves = self.relax_jumps(&anchors)?;
+            data_copy.map(|copy_at| moves.address(copy_at))
+        } else {
+            data_copy
+        };
+
+        // Place the data section after the code and resolve references to it
+        let data_load = self.current_address();
+        let data_run = match self.data_address {
+            Some(addr) => addr,
+            None if self.data_in_ram => self.data_offset,  // First RAM address after the variables
+            None => data_load,
+        };
+        self.data_base = Some(data_run);
+        let data_fixups = std::mem::take(&mut self.data_fixups);
+        for &(at, offset) in &data_fixups {
+            self.patch_section_word(Section::Code, at, data_run.wrapping_add(offset))?;
+        }
+        if let Some(copy_at) = data_copy {
+            self.patch_word(copy_at, self.data_section.len() as u16)?;
+            self.patch_word(copy_at + 7, data_load)?;
+            self.patch_word(copy_at + 10, data_run)?;
+        }
 
-                // Exit if var > end
-                let exit_jump = self.current_address();
-                self.emit(opcodes::JP_Z_NN);  // Jump if equal (continue)
-                self.emit_word(0x0000);
-                self.emit(opcodes::JP_C_NN);  // Jump if less (continue)
-                let exit_jump2 = self.current_address() - 3;
-                self.emit_word(0x0000);
+        if self.verify {
+            self.verify_code(&data_fixups)?;
+        }
+        self.verify_interrupts(program)?;
 
-                // Exit point
-                let _real_exit = self.current_address();
-                self.emit(opcodes::JP_NN);
-                self.emit_word(0x0000);
-                let exit_patch = self.current_address() - 2;
+        let mut image = self.code.clone();
+        if !self.data_loaded {
+            image.extend_from_slice(&self.data_section);
+        }
+        Ok(image)
+    }
 
-                // Continue point
-                let continue_addr = self.current_address();
-                self.patch_word(exit_jump + 1, continue_addr);
-                self.patch_word(exit_jump2, continue_addr);
+    /// Generate one statement after the program and return its bytes (for tests)
+    #[cfg(test)]
+    pub(crate) fn snippet_statement(&mut self, stmt: &Statement) -> Result<Vec<u8>> {
+        let start = self.code.len();
+        self.gen_statement(stmt)?;
+        self.finish_snippet(start)
+    }
 
-                // Body
-                for stmt in body {
-                    self.gen_statement(stmt)?;
-                }
+    /// Generate one expression after the program and return its bytes and width (for tests)
+    #[cfg(test)]
+    pub(crate) fn snippet_expression(&mut self, expr: &Expression) -> Result<(Vec<u8>, bool)> {
+        let start = self.code.len();
+        let is_word = self.gen_expression(expr)?;
+        Ok((self.finish_snippet(start)?, is_word))
+    }
 
-                // Increment
-                self.emit_load_var(var)?;
-                if let Some(step_expr) = step {
-                    self.emit(opcodes::LD_B_A);
-                    self.gen_expression(step_expr)?;
-                    self.emit(opcodes::ADD_A_B);
-                } else {
-                    self.emit(opcodes::INC_A);
-                }
-                self.emit_store_var(var, false)?;
+    // Resolve data references made by a snippet and take its bytes
+    #[cfg(test)]
+    fn finish_snippet(&mut self, start: usize) -> Result<Vec<u8>> {
+        let data_base = self.data_base.unwrap_or(self.origin);
+        for (at, offset) in std::mem::take(&mut self.data_fixups) {
+            self.patch_section_word(Section::Code, at, data_base.wrapping_add(offset))?;
+        }
+        Ok(self.code[start..].to_vec())
+    }
 
-                // Loop back
-                self.emit(opcodes::JP_NN);
-                self.emit_word(loop_start);
+    /// Address of a generated procedure
+    pub fn procedure_address(&self, name: &str) -> Option<u16> {
+        self.procedures.get(&self.key(name)).copied()
+    }
 
-                // Patch exit
-                let loop_end = self.current_address();
-                self.patch_word(exit_patch, loop_end);
+    /// Bytes a generated procedure's stack frame takes for its locals, not counting the
+    /// saved IX; None if its locals are static
+    pub fn frame_size(&self, name: &str) -> Option<u16> {
+        self.frame_sizes.get(&self.key(name)).copied()
+    }
 
-                Ok(())
-            }
+    /// Bytes of code a generated procedure takes, not counting its data
+    pub fn procedure_size(&self, name: &str) -> Option<usize> {
+        let key = self.key(name);
+        self.module_code.iter()
+            .find(|(_, proc, _, _)| self.key(proc) == key)
+            .map(|&(_, _, start, end)| end.wrapping_sub(start) as usize)
+    }
 
-            Statement::Exit => {
-                if let Some(&(_, end)) = self.loop_stack.last() {
-                    if end != 0 {
-                        self.emit(opcodes::JP_NN);
-                        self.emit_word(end);
-                    } else {
-                        // Need forward reference - not fully implemented
-                        self.emit(opcodes::JP_NN);
-                        self.emit_word(0x0000);
-                    }
-                }
-                Ok(())
-            }
+    /// Point the runtime's weak routines at the program's replacements, in the runtime
+    /// code that ends where the program starts
+    pub fn link_runtime(&self, runtime_code: &mut [u8]) {
+        let start = self.origin.wrapping_sub(runtime_code.len() as u16);
+        for &(stub, target) in &self.overrides {
+            let at = stub.wrapping_sub(start) as usize + 1;  // After the JP
+            runtime_code[at..at + 2].copy_from_slice(&target.to_le_bytes());
+        }
+    }
 
-            Statement::Return(value) => {
-                if let Some(expr) = value {
-                    self.gen_expression(expr)?;
-                }
-                self.emit(opcodes::RET);
-                Ok(())
-            }
+    /// Address of the procedure the startup code calls, once the program has been generated
+    pub fn entry_address(&self) -> Option<u16> {
+        self.main_address
+    }
 
-            Statement::ProcCall { name, args } => {
-                // Check if this is a runtime library function
-                if let Some(ref runtime) = self.runtime {
-                    if let Some(addr) = runtime.get_function(name) {
-                        // Handle runtime functions specially
-                        match name.to_uppercase().as_str() {
-                            "PRINTB" => {
-                                // PrintB expects byte in A
-                                if !args.is_empty() {
-                                    self.gen_expression(&args[0])?;
-                                }
-                                self.emit(opcodes::CALL_NN);
-                                self.emit_word(addr);
-                                return Ok(());
-                            }
-                            "PRINTC" => {
-                                // PrintC expects CARD in HL
-                                if !args.is_empty() {
-                                    self.gen_expression(&args[0])?;
-                                    // Move to HL if in A
-                                    self.emit(opcodes::LD_L_A);
-                                    self.emit(opcodes::LD_H_N);
-                                    self.emit(0);
-                                }
-                                self.emit(opcodes::CALL_NN);
-                                self.emit_word(addr);
-                                return Ok(());
-                            }
-                            "PRINTE" | "GETD" => {
-                                // No arguments
-                                self.emit(opcodes::CALL_NN);
-                                self.emit_word(addr);
-                                return Ok(());
-                            }
-                            "PUTD" => {
-                                // PutD expects character in A
-                                if !args.is_empty() {
-                                    self.gen_expression(&args[0])?;
-                                }
-                                self.emit(opcodes::CALL_NN);
-                                self.emit_word(addr);
-                                return Ok(());
-                            }
-                            "PRINT" => {
-                                // Print expects string pointer in HL
-                                if !args.is_empty() {
-                                    // Generate address of string
-                                    self.gen_expression(&args[0])?;
-                                }
-                                self.emit(opcodes::CALL_NN);
-                                self.emit_word(addr);
-                                return Ok(());
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+    /// Where the data section is loaded in the image, where it runs and its size, once
+    /// the program has been generated
+    pub fn data_layout(&self) -> (u16, u16, usize) {
+        let load = self.origin.wrapping_add(self.code.len() as u16);
+        let run = self.data_base.unwrap_or(load);
+        (if self.data_loaded { run } else { load }, run, self.data_section.len())
+    }
 
-                // Push arguments
-                for arg in args.iter().rev() {
-                    self.gen_expression(arg)?;
-                    self.emit(opcodes::PUSH_AF);
-                }
+    /// Whether the data section is left out of the image for the loader
+    pub fn is_data_loaded(&self) -> bool {
+        self.data_loaded
+    }
 
-                if let Some(&addr) = self.procedures.get(name) {
-                    self.emit(opcodes::CALL_NN);
-                    self.emit_word(addr);
-                } else {
-                    // External or forward reference
-                    self.emit(opcodes::CALL_NN);
-                    self.emit_word(0x0000);
-                }
+    /// The bytes of the data section, once the program has been generated
+    pub fn data_section(&self) -> &[u8] {
+        &self.data_section
+    }
 
-                // Clean up stack
-                for _ in 0..args.len() {
-                    self.emit(opcodes::POP_BC);
-                }
+    /// First RAM address after the static variables, once the program has been generated
+    pub fn variables_end(&self) -> u16 {
+        self.data_offset
+    }
 
-                Ok(())
-            }
+    /// Whether name was declared PRIVATE, so nothing outside its module may refer to it
+    pub fn is_private(&self, name: &str) -> bool {
+        self.private.contains_key(&self.key(name))
+    }
 
-            Statement::Block(statements) => {
-                for stmt in statements {
-                    self.gen_statement(stmt)?;
-                }
-                Ok(())
-            }
+    /// Names and addresses of the generated procedures
+    pub fn procedure_addresses(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.procedures.iter().map(|(name, &addr)| (name.as_str(), addr))
+    }
 
-            _ => Ok(()), // Skip unimplemented statements
+    /// Run address of a global variable, once the program has been generated; None
+    /// for a local in a stack frame, which has none of its own
+    pub fn global_address(&self, name: &str) -> Option<u16> {
+        let info = self.globals.get(&self.key(name)).filter(|info| info.stack_offset.is_none())?;
+        match self.data_base {
+            Some(base) if info.in_data => Some(base.wrapping_add(info.address)),
+            _ => Some(info.address),
         }
     }
 
-    fn gen_procedure(&mut self, proc: &Procedure) -> Result<()> {
-        let proc_addr = self.current_address();
-        self.procedures.insert(proc.name.clone(), proc_addr);
+    pub fn generate_listing(&self, options: &ListingOptions) -> String {
+        let mut listing = String::new();
+        listing.push_str("; Action! Compiler Output\n");
+        listing.push_str(&format!("; Origin: ${:04X}\n", self.origin));
+        listing.push_str(&format!("; Code size: {} bytes\n", self.code.len()));
+        listing.push_str(&format!("; Data size: {} bytes\n", self.data_section.len()));
+
+        if options.symbols {
+            // Dump procedures
+            listing.push_str("\n; Procedures:\n");
+            for (name, addr) in &self.procedures {
+                listing.push_str(&format!(";   {} = ${:04X}\n", name, addr));
+            }
 
-        // Clear locals
-        self.locals.clear();
+            // Dump globals
+            listing.push_str("\n; Global variables:\n");
+            for (name, info) in &self.globals {
+                let address = match (self.global_address(name), info.stack_offset) {
+                    (Some(address), _) => format!("${:04X}", address),
+                    (None, Some(offset)) => format!("IX{}", offset),
+                    (None, None) => format!("${:04X}", info.address),
+                };
+                listing.push_str(&format!(";   {} = {} ({:?})\n", name, address, info.data_type));
+            }
 
-        // For now, allocate local variables as if they were globals
-        // This is a simplification that won't work for recursion
-        // but allows basic programs to work
-        for local in &proc.locals {
-            self.globals.insert(local.name.clone(), SymbolInfo {
-                address: self.data_offset,
-                data_type: local.data_type.clone(),
-                is_param: false,
-                stack_offset: None,
-            });
-            self.data_offset += local.data_type.size() as u16;
+            if !self.module_lines.is_empty() {
+                listing.push_str(&self.module_map());
+            }
         }
 
-        // Generate body
-        for stmt in &proc.body {
-            self.gen_statement(stmt)?;
+        // Hex dump
+        if options.code && options.hex {
+            listing.push_str("\n; Code:\n");
+            for (i, chunk) in self.code.chunks(16).enumerate() {
+                let addr = self.origin as usize + i * 16;
+                listing.push_str(&format!("{:04X}: ", addr));
+                for byte in chunk {
+                    listing.push_str(&format!("{:02X} ", byte));
+                }
+                listing.push('\n');
+            }
         }
 
-        // Ensure return at end
-        self.emit(opcodes::RET);
+        // Data section
+        if let Some(base) = self.data_base.filter(|_| options.data && !self.data_section.is_empty()) {
+            listing.push_str("\n; Data:\n");
+            for (i, chunk) in self.data_section.chunks(16).enumerate() {
+                let addr = base as usize + i * 16;
+                listing.push_str(&format!("{:04X}: ", addr));
+                for byte in chunk {
+                    listing.push_str(&format!("{:02X} ", byte));
+                }
+                listing.push('\n');
+            }
+        }
 
-        Ok(())
+        listing
     }
 
-    pub fn generate(&mut self, program: &Program) -> Result<Vec<u8>> {
-        // First pass: allocate global variables
-        // Variables start at 0x2000 (RAM starts here, first 8KB is ROM)
-        let mut var_addr: u16 = 0x2000;
-
-        for var in &program.globals {
-            self.globals.insert(var.name.clone(), SymbolInfo {
-                address: var_addr,
-                data_type: var.data_type.clone(),
-                is_param: false,
-                stack_offset: None,
-            });
-            var_addr += var.data_type.size() as u16;
+    // Which module each run of procedures came from, and how much each module takes
+    fn module_map(&self) -> String {
+        let module_name = |module: usize| match module {
+            0 => "start".to_string(),
+            n => format!("MODULE at line {}", self.module_lines[n - 1]),
+        };
+        let mut map = String::from("\n; Module map:\n");
+        let mut code = vec![0; self.module_lines.len() + 1];
+        let mut runs: Vec<(usize, u16, u16, Vec<&str>)> = Vec::new();
+        for (module, name, start, end) in &self.module_code {
+            code[*module] += (end - start) as usize;
+            match runs.last_mut() {
+                Some(run) if run.0 == *module && run.2 == *start => {
+                    run.2 = *end;
+                    run.3.push(name);
+                }
+                _ => runs.push((*module, *start, *end, vec![name])),
+            }
         }
-        self.data_offset = var_addr;
-
-        // Generate CALL to Main (or first procedure) followed by HALT
-        let main_call = self.current_address();
-        self.emit(opcodes::CALL_NN);
-        self.emit_word(0x0000); // Will patch later
-        self.emit(opcodes::HALT);
-
-        // Generate procedures
-        for proc in &program.procedures {
-            self.gen_procedure(proc)?;
+        for (module, start, end, names) in runs.into_iter().filter(|run| run.2 > run.1) {
+            map.push_str(&format!(";   ${:04X}-${:04X} {}: {}\n", start, end - 1, module_name(module), names.join(", ")));
         }
+        map.push_str("\n; Module sizes:\n");
+        for (module, (code, globals)) in code.iter().zip(&self.module_globals).enumerate() {
+            map.push_str(&format!(";   {}: {} bytes of code, {} bytes of globals\n", module_name(module), code, globals));
+        }
+        map
+    }
 
-        // Patch main call
-        if let Some(&main_addr) = self.procedures.get("Main") {
-            self.patch_word(main_call + 1, main_addr);
-        } else if let Some(&main_addr) = self.procedures.get("main") {
-            // Also check lowercase 'main'
-            self.patch_word(main_call + 1, main_addr);
-        } else {
-            // No Main - call first procedure
-            if let Some(proc) = program.procedures.first() {
-                if let Some(&addr) = self.procedures.get(&proc.name) {
-                    self.patch_word(main_call + 1, addr);
-                }
+    /// Code split into runs by source line, in address order
+    pub fn listing_entries(&self) -> Vec<ListingEntry> {
+        let mut entries = Vec::new();
+        for (i, (start, line, procedure)) in self.line_marks.iter().enumerate() {
+            let end = self.line_marks.get(i + 1).map_or(self.code.len(), |mark| mark.0);
+            if end > *start {
+                entries.push(ListingEntry {
+                    address: self.origin.wrapping_add(*start as u16),
+                    bytes: self.code[*start..end].to_vec(),
+                    line: *line,
+                    procedure: procedure.clone(),
+                });
             }
         }
+        entries
+    }
 
-        // Initialize global variables with values
-        // (In a more complete implementation, this would be done at runtime startup)
+    /// Machine-readable listing as a JSON array, with source text taken from source
+    pub fn generate_listing_json(&self, source: &str) -> String {
+        let lines: Vec<&str> = source.lines().collect();
+        let entries: Vec<String> = self.listing_entries().iter()
+            .map(|entry| {
+                let text = entry.line.and_then(|n| lines.get(n.wrapping_sub(1)));
+                format!(
+                    "  {{\"address\": {}, \"bytes\": \"{}\", \"line\": {}, \"procedure\": {}, \"source\": {}}}",
+                    entry.address,
+                    hex_bytes(&entry.bytes),
+                    entry.line.map_or("null".to_string(), |n| n.to_string()),
+                    entry.procedure.as_deref().map_or("null".to_string(), json_string),
+                    text.map_or("null".to_string(), |t| json_string(t.trim())),
+                )
+            })
+            .collect();
+        format!("[\n{}\n]\n", entries.join(",\n"))
+    }
 
-        Ok(self.code.clone())
+    /// Machine-readable listing as CSV, with source text taken from source
+    pub fn generate_listing_csv(&self, source: &str) -> String {
+        let lines: Vec<&str> = source.lines().collect();
+        let mut csv = String::from("address,bytes,line,procedure,source\n");
+        for entry in self.listing_entries() {
+            let text = entry.line.and_then(|n| lines.get(n.wrapping_sub(1))).map_or("", |t| t.trim());
+            csv.push_str(&format!(
+                "{:04X},{},{},{},{}\n",
+                entry.address,
+                hex_bytes(&entry.bytes),
+                entry.line.map_or(String::new(), |n| n.to_string()),
+                csv_field(entry.procedure.as_deref().unwrap_or("")),
+                csv_field(text),
+            ));
+        }
+        csv
     }
+}
 
-    pub fn generate_listing(&self) -> String {
-        let mut listing = String::new();
-        listing.push_str("; Action! Compiler Output\n");
-        listing.push_str(&format!("; Origin: ${:04X}\n", self.origin));
-        listing.push_str(&format!("; Code size: {} bytes\n\n", self.code.len()));
+/// The character intrinsic name stands for, if any; they take a character and give
+/// a BYTE, generated in place rather than called
+pub fn intrinsic(name: &str, case_mode: CaseMode) -> Option<&'static str> {
+    ["IsDigit", "IsAlpha", "ToUpper", "ToLower"].into_iter().find(|intrinsic| case_mode.matches(intrinsic, name))
+}
 
-        // Dump procedures
-        listing.push_str("; Procedures:\n");
-        for (name, addr) in &self.procedures {
-            listing.push_str(&format!(";   {} = ${:04X}\n", name, addr));
-        }
+// Offset byte of a relative jump to target from the instruction after it, if in range
+fn jr_offset(from: u16, target: u16) -> Option<u8> {
+    i8::try_from(target.wrapping_sub(from) as i16).ok().map(|offset| offset as u8)
+}
 
-        // Dump globals
-        listing.push_str("\n; Global variables:\n");
-        for (name, info) in &self.globals {
-            listing.push_str(&format!(";   {} = ${:04X} ({:?})\n", name, info.address, info.data_type));
-        }
+// A call to one of the program's procedures must give each parameter an argument
+fn check_arg_count(name: &str, params: &[Parameter], args: &[Expression]) -> Result<()> {
+    if args.len() != params.len() {
+        return Err(CompileError::CodeGenError {
+            message: format!("{} expects {} arguments, found {}", name, params.len(), args.len()),
+        });
+    }
+    Ok(())
+}
 
-        // Hex dump
-        listing.push_str("\n; Code:\n");
-        for (i, chunk) in self.code.chunks(16).enumerate() {
-            let addr = self.origin as usize + i * 16;
-            listing.push_str(&format!("{:04X}: ", addr));
-            for byte in chunk {
-                listing.push_str(&format!("{:02X} ", byte));
-            }
-            listing.push('\n');
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
+    }
+    out.push('"');
+    out
+}
 
-        listing
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
     }
 }
+
+mod relax;
+mod stores;
+mod verify;
+
+#[cfg(test)]
+mod tests;
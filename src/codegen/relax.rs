@@ -0,0 +1,172 @@
+// Jump relaxation: once the code is complete, each JP whose target a JR can reach
+// becomes one, two bytes instead of three. Shortening a jump brings others nearer
+// their targets, so jumps are shortened in rounds until no more reach; code only ever
+// shrinks, so a jump that reached in one round still does in the next. Then every
+// jump, call and record of a code address or offset is moved to match.
+//
+// Procedures at fixed addresses stay where they are, with NOPs making up for what the
+// code before them lost, so no jump is shortened across one: its target would not
+// come nearer.
+
+use super::{jr_offset, CodeGenerator};
+use crate::clobber::{decode, Flow};
+use crate::error::{CompileError, Result};
+
+// JP and JP cc with their JR forms; JR has no PO, PE, P or M
+const SHORT_FORMS: [(u8, u8); 5] = [(0xC3, 0x18), (0xC2, 0x20), (0xCA, 0x28), (0xD2, 0x30), (0xDA, 0x38)];
+
+// JR, JR cc and DJNZ
+const RELATIVE: [u8; 6] = [0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+
+struct Instruction {
+    at: usize,
+    len: usize,
+    target: Option<u16>,  // Where a jump or call goes
+    short: Option<u8>,    // The JR a JP could become
+}
+
+/// Where each instruction moved to, for whatever held its old offset or address
+pub(super) struct Moves {
+    origin: u16,
+    old: Vec<usize>,  // Offset of each instruction before, then the old code length
+    new: Vec<usize>,  // Offset of each instruction after, then the new code length
+}
+
+impl Moves {
+    /// New offset of a byte in the old code: the same byte of its instruction, or the
+    /// end of a shortened jump for its address byte that is gone
+    pub(super) fn offset(&self, offset: usize) -> usize {
+        let i = self.old.partition_point(|&at| at <= offset).saturating_sub(1);
+        let len = self.new.get(i + 1).map_or(0, |next| next - self.new[i]);
+        self.new[i] + (offset - self.old[i]).min(len)
+    }
+
+    /// New address of an address in the old code; others stay as they were
+    pub(super) fn address(&self, addr: u16) -> u16 {
+        match addr.checked_sub(self.origin).map(usize::from) {
+            Some(offset) if offset <= *self.old.last().unwrap_or(&0) => self.origin.wrapping_add(self.offset(offset) as u16),
+            _ => addr,
+        }
+    }
+}
+
+impl CodeGenerator {
+    /// Turn JPs that a JR can stand in for into JRs, keeping the code at anchors (the
+    /// offsets of procedures at fixed addresses) where it is
+    pub(super) fn relax_jumps(&mut self, anchors: &[usize]) -> Result<Moves> {
+        let instructions = self.instructions()?;
+        let segment = |offset: usize| anchors.iter().filter(|&&anchor| anchor <= offset).count();
+        let in_code = |addr: u16| addr.checked_sub(self.origin).map(usize::from).filter(|&offset| offset < self.code.len());
+        let anchored: Vec<bool> = instructions.iter().map(|i| anchors.contains(&i.at)).collect();
+
+        // Shorten in rounds; a jump may go back into the runtime, below the code, but
+        // not over a fixed address or up past the code, which do not come nearer
+        let mut short = vec![false; instructions.len()];
+        loop {
+            let moves = layout(self.origin, &instructions, &short, &anchored, self.code.len());
+            let mut more = false;
+            for (i, instruction) in instructions.iter().enumerate() {
+                let (Some(target), Some(_), false) = (instruction.target, instruction.short, short[i]) else { continue };
+                let reachable = match in_code(target) {
+                    Some(offset) => segment(offset) == segment(instruction.at),
+                    None => target < self.origin,
+                };
+                let from = self.origin.wrapping_add((moves.new[i] + 2) as u16);
+                if reachable && jr_offset(from, moves.address(target)).is_some() {
+                    short[i] = true;
+                    more = true;
+                }
+            }
+            if !more {
+                break;
+            }
+        }
+
+        // Write the code out again with the jumps and calls pointing where things went
+        let moves = layout(self.origin, &instructions, &short, &anchored, self.code.len());
+        let mut code = Vec::with_capacity(moves.new[instructions.len()]);
+        for (i, instruction) in instructions.iter().enumerate() {
+            code.resize(moves.new[i], 0x00);  // NOPs up to a fixed address
+            let bytes = &self.code[instruction.at..instruction.at + instruction.len];
+            let here = self.origin.wrapping_add(moves.new[i] as u16);
+            match (instruction.target.map(|t| moves.address(t)), instruction.short) {
+                (Some(target), Some(jr)) if short[i] => {
+                    code.extend([jr, jr_offset(here.wrapping_add(2), target).expect("checked as it was shortened")]);
+                }
+                (Some(target), _) if RELATIVE.contains(&bytes[0]) => {
+                    let offset = jr_offset(here.wrapping_add(2), target).ok_or_else(|| CompileError::InternalError {
+                        message: format!("relative jump at ${:04X} cannot reach ${:04X} once jumps are shortened", here, target),
+                    })?;
+                    code.extend([bytes[0], offset]);
+                }
+                (Some(target), _) => {
+                    code.push(bytes[0]);
+                    code.extend(target.to_le_bytes());
+                }
+                (None, _) => code.extend_from_slice(bytes),
+            }
+        }
+        self.code = code;
+        self.pc = self.origin.wrapping_add(self.code.len() as u16);
+
+        for addr in self.procedures.values_mut().chain(self.main_address.iter_mut()) {
+            *addr = moves.address(*addr);
+        }
+        for (_, _, start, end) in &mut self.module_code {
+            (*start, *end) = (moves.address(*start), moves.address(*end));
+        }
+        for (_, thunk) in &mut self.overrides {
+            *thunk = moves.address(*thunk);
+        }
+        for (at, ..) in &mut self.line_marks {
+            *at = moves.offset(*at);
+        }
+        for (at, _) in &mut self.data_fixups {
+            *at = moves.offset(*at);
+        }
+        self.jumps.retain(|&at| !instructions.iter().zip(&short).any(|(i, &short)| short && i.at == at));
+        for at in &mut self.jumps {
+            *at = moves.offset(*at);
+        }
+        Ok(moves)
+    }
+
+    // The code decoded an instruction at a time
+    fn instructions(&self) -> Result<Vec<Instruction>> {
+        let fetch = |addr: u16| self.code.get(addr.wrapping_sub(self.origin) as usize).copied();
+        let mut instructions = Vec::new();
+        let mut at = 0;
+        while at < self.code.len() {
+            let pc = self.origin.wrapping_add(at as u16);
+            let (len, _, flow) = decode(fetch, pc).ok_or_else(|| CompileError::InternalError {
+                message: format!("cannot decode the instruction at ${:04X} to shorten jumps", pc),
+            })?;
+            let target = match flow {
+                Flow::Jump(target) | Flow::Branch(target) | Flow::Call(target) => Some(target),
+                _ => None,
+            };
+            let short = SHORT_FORMS.iter().find(|&&(jp, _)| jp == self.code[at]).map(|&(_, jr)| jr);
+            instructions.push(Instruction { at, len: len as usize, target, short });
+            at += len as usize;
+        }
+        Ok(instructions)
+    }
+}
+
+// Where each instruction goes with the chosen jumps shortened, anchored ones staying put
+fn layout(origin: u16, instructions: &[Instruction], short: &[bool], anchored: &[bool], len: usize) -> Moves {
+    let mut old = Vec::with_capacity(instructions.len() + 1);
+    let mut new = Vec::with_capacity(instructions.len() + 1);
+    let mut at = 0;
+    for (i, instruction) in instructions.iter().enumerate() {
+        if anchored[i] {
+            at = instruction.at;
+        }
+        old.push(instruction.at);
+        new.push(at);
+        at += if short[i] { 2 } else { instruction.len };
+    }
+    old.push(len);
+    new.push(at);
+    Moves { origin, old, new }
+}
@@ -0,0 +1,125 @@
+// Variables the program writes but never reads. Any use of a name other than as the
+// target of an assignment reads it: in an expression, as an array element, with @ or
+// as a FOR counter. Names are taken program-wide, so a local read in one procedure
+// keeps a local of the same name in another.
+//
+// Leaving such a variable out takes its stores with it, which is only safe when none
+// of them calls anything: the value, and the index of an array element, must be
+// evaluated for nothing but their result.
+
+use super::CodeGenerator;
+use crate::ast::*;
+use std::collections::HashSet;
+
+impl CodeGenerator {
+    /// Names of the variables the program declares and assigns but never reads, and the
+    /// keys of those among them whose stores can go
+    pub(super) fn write_only(&self, program: &Program) -> (Vec<String>, HashSet<String>) {
+        let declared: Vec<&Variable> = program.globals.iter()
+            .chain(program.procedures.iter().flat_map(|p| &p.locals))
+            .collect();
+        let variables: HashSet<String> = declared.iter().map(|v| self.key(&v.name)).collect();
+
+        let mut read = HashSet::new();
+        let mut written = HashSet::new();
+        let mut calls = HashSet::new();  // Written somewhere with a call in the value
+        for var in &declared {
+            for name in var.initial_value.iter().flat_map(expression_names) {
+                read.insert(self.key(name));
+            }
+        }
+        for proc in &program.procedures {
+            self.find_stores(&proc.body, &variables, &mut read, &mut written, &mut calls);
+        }
+
+        let mut names = Vec::new();
+        let mut keys = HashSet::new();
+        for var in declared {
+            let key = self.key(&var.name);
+            if written.contains(&key) && !read.contains(&key) && keys.insert(key) {
+                names.push(var.name.clone());
+            }
+        }
+        let droppable = keys.into_iter().filter(|key| !calls.contains(key)).collect();
+        (names, droppable)
+    }
+
+    // Sort the names stmts use into reads and writes
+    fn find_stores(&self, stmts: &[Statement], variables: &HashSet<String>, read: &mut HashSet<String>,
+                   written: &mut HashSet<String>, calls: &mut HashSet<String>) {
+        for stmt in stmts {
+            let target = match stmt {
+                Statement::Assignment { target, .. } | Statement::ArrayAssignment { array: target, .. } => Some(target),
+                _ => None,
+            };
+            for name in stmt.names().into_iter().filter(|&name| Some(name) != target.map(String::as_str)) {
+                read.insert(self.key(name));
+            }
+            let exprs = stmt.expressions();
+            for name in exprs.iter().flat_map(|expr| expression_names(expr)) {
+                read.insert(self.key(name));
+            }
+            if let Some(target) = target {
+                let key = self.key(target);
+                if exprs.iter().any(|expr| self.has_call(expr, variables)) {
+                    calls.insert(key.clone());
+                }
+                written.insert(key);
+            }
+            for nested in stmt.nested() {
+                self.find_stores(std::slice::from_ref(nested), variables, read, written, calls);
+            }
+        }
+    }
+
+    // Whether evaluating expr calls a procedure or built-in: a call-like use of a
+    // variable's name is an array element
+    fn has_call(&self, expr: &Expression, variables: &HashSet<String>) -> bool {
+        let call = matches!(expr, Expression::FunctionCall { name, .. } if !variables.contains(&self.key(name)));
+        call || expr.children().into_iter().any(|child| self.has_call(child, variables))
+    }
+
+    /// The program without the variables of keys and their stores
+    pub(super) fn without_stores(&self, program: &Program, keys: &HashSet<String>) -> Program {
+        let mut program = program.clone();
+        let dropped = |var: &Variable| keys.contains(&self.key(&var.name));
+        let mut globals = program.globals.iter();
+        program.definitions.retain(|def| !def.global || !globals.next().is_some_and(dropped));
+        program.globals.retain(|var| !dropped(var));
+        for proc in &mut program.procedures {
+            proc.locals.retain(|var| !dropped(var));
+            self.drop_stores(&mut proc.body, keys);
+        }
+        program
+    }
+
+    fn drop_stores(&self, stmts: &mut Vec<Statement>, keys: &HashSet<String>) {
+        stmts.retain(|stmt| match stmt {
+            Statement::Assignment { target, .. } | Statement::ArrayAssignment { array: target, .. } => {
+                !keys.contains(&self.key(target))
+            }
+            Statement::VarDecl(var) => !keys.contains(&self.key(&var.name)),
+            _ => true,
+        });
+        for stmt in stmts {
+            match stmt {
+                Statement::If { then_block, else_block, .. } => {
+                    self.drop_stores(then_block, keys);
+                    if let Some(else_block) = else_block {
+                        self.drop_stores(else_block, keys);
+                    }
+                }
+                Statement::While { body, .. } | Statement::Until { body, .. }
+                | Statement::For { body, .. } | Statement::Block(body) => self.drop_stores(body, keys),
+                _ => {}
+            }
+        }
+    }
+}
+
+// Every name expr uses
+fn expression_names(expr: &Expression) -> Vec<&str> {
+    let mut names = Vec::new();
+    collect_expression_names(expr, &mut names);
+    names
+}
@@ -0,0 +1,882 @@
+// Byte-level snapshots of the code emitted for each expression and statement.
+// Run with INSTA_UPDATE=always (or `cargo insta review`) to accept intended changes.
+
+use crate::ast::{Expression, Statement};
+use crate::codegen::{Exit, OptFor, Overflow};
+use crate::test_support::*;
+use insta::assert_snapshot;
+
+const DECLS: &str = "\
+BYTE b
+CARD c
+INT i
+BYTE ARRAY(10) arr
+BYTE init = 7
+PROC callee(BYTE x)
+RETURN
+";
+
+fn show(bytes: Result<Vec<u8>, crate::error::CompileError>) -> String {
+    match bytes {
+        Ok(bytes) => hex_dump(&bytes),
+        Err(e) => format!("error: {}\n", e),
+    }
+}
+
+fn statement(source: &str) -> String {
+    show(statement_bytes(DECLS, source))
+}
+
+fn ast_statement(stmt: Statement) -> String {
+    show(ast_statement_bytes(DECLS, &stmt))
+}
+
+fn expression(source: &str) -> String {
+    show_expression(expression_bytes(DECLS, source))
+}
+
+fn ast_expression(expr: Expression) -> String {
+    show_expression(ast_expression_bytes(DECLS, &expr))
+}
+
+fn show_expression(result: Result<(Vec<u8>, bool), crate::error::CompileError>) -> String {
+    match result {
+        Ok((bytes, is_word)) => {
+            format!("{}\n{}", if is_word { "word" } else { "byte" }, hex_dump(&bytes))
+        }
+        Err(e) => format!("error: {}\n", e),
+    }
+}
+
+fn var(name: &str) -> Box<Expression> {
+    Box::new(Expression::Variable(name.to_string()))
+}
+
+fn num(n: i32) -> Box<Expression> {
+    Box::new(Expression::Number(n))
+}
+
+// Expressions
+
+#[test]
+fn number_byte() {
+    assert_snapshot!(expression("5"));
+}
+
+#[test]
+fn number_word() {
+    assert_snapshot!(expression("1000"));
+}
+
+#[test]
+fn string() {
+    assert_snapshot!(expression("\"hi\""));
+}
+
+#[test]
+fn char_literal() {
+    assert_snapshot!(expression("'A'"));
+}
+
+#[test]
+fn variable_byte() {
+    assert_snapshot!(expression("b"));
+}
+
+#[test]
+fn variable_word() {
+    assert_snapshot!(expression("c"));
+}
+
+#[test]
+fn variable_initialized() {
+    assert_snapshot!(expression("init"));
+}
+
+#[test]
+fn array_access() {
+    assert_snapshot!(expression("arr[3]"));
+}
+
+#[test]
+fn array_access_call_syntax() {
+    assert_snapshot!(expression("arr(3)"));
+}
+
+#[test]
+fn array_address() {
+    assert_snapshot!(expression("arr"));
+}
+
+#[test]
+fn negate() {
+    assert_snapshot!(expression("-b"));
+}
+
+#[test]
+fn not() {
+    assert_snapshot!(expression("NOT b"));
+}
+
+#[test]
+fn address_of() {
+    assert_snapshot!(expression("@b"));
+}
+
+#[test]
+fn dereference() {
+    assert_snapshot!(expression("^c"));
+}
+
+#[test]
+fn add_byte() {
+    assert_snapshot!(expression("b + 1"));
+}
+
+#[test]
+fn add_word() {
+    assert_snapshot!(expression("c + 1"));
+}
+
+#[test]
+fn subtract_byte() {
+    assert_snapshot!(expression("b - 1"));
+}
+
+#[test]
+fn subtract_word() {
+    assert_snapshot!(expression("c - 1"));
+}
+
+#[test]
+fn multiply() {
+    assert_snapshot!(expression("b * 3"));
+}
+
+#[test]
+fn multiply_word() {
+    assert_snapshot!(expression("c * 300"));
+}
+
+#[test]
+fn divide() {
+    assert_snapshot!(expression("b / 3"));
+}
+
+#[test]
+fn modulo() {
+    assert_snapshot!(expression("b MOD 3"));
+}
+
+#[test]
+fn divide_word() {
+    assert_snapshot!(expression("c / 300"));
+}
+
+#[test]
+fn modulo_word() {
+    assert_snapshot!(expression("c MOD b"));
+}
+
+#[test]
+fn left_shift() {
+    assert_snapshot!(expression("b LSH 1"));
+}
+
+#[test]
+fn right_shift() {
+    assert_snapshot!(expression("b RSH 1"));
+}
+
+#[test]
+fn equal() {
+    assert_snapshot!(expression("b = 1"));
+}
+
+#[test]
+fn not_equal() {
+    assert_snapshot!(expression("b <> 1"));
+}
+
+#[test]
+fn less() {
+    assert_snapshot!(expression("b < 1"));
+}
+
+#[test]
+fn less_equal() {
+    assert_snapshot!(expression("b <= 1"));
+}
+
+#[test]
+fn greater() {
+    assert_snapshot!(expression("b > 1"));
+}
+
+#[test]
+fn greater_equal() {
+    assert_snapshot!(expression("b >= 1"));
+}
+
+#[test]
+fn and() {
+    assert_snapshot!(expression("b AND 1"));
+}
+
+#[test]
+fn or() {
+    assert_snapshot!(expression("b OR 1"));
+}
+
+#[test]
+fn xor() {
+    assert_snapshot!(expression("b XOR 1"));
+}
+
+#[test]
+fn bit_and() {
+    assert_snapshot!(ast_expression(Expression::BitAnd(var("b"), num(15))));
+}
+
+#[test]
+fn bit_or() {
+    assert_snapshot!(ast_expression(Expression::BitOr(var("b"), num(15))));
+}
+
+#[test]
+fn bit_xor() {
+    assert_snapshot!(ast_expression(Expression::BitXor(var("b"), num(15))));
+}
+
+#[test]
+fn function_call() {
+    assert_snapshot!(expression("callee(2)"));
+}
+
+#[test]
+fn constant_arithmetic_folds() {
+    // One load of 25, and 300 is a word although both sides are bytes
+    assert_snapshot!(expression("3 * 8 + 1"));
+    assert_snapshot!(expression("200 + 100"));
+}
+
+#[test]
+fn constant_comparison_folds() {
+    assert_snapshot!(expression("2 < 1"));
+    assert_snapshot!(expression("1 LSH 9 = 512"));
+}
+
+#[test]
+fn constant_part_folds() {
+    // Only the constant side is worked out
+    assert_snapshot!(expression("b + (256 RSH 4)"));
+}
+
+// Statements
+
+#[test]
+fn var_decl() {
+    assert_snapshot!(statement("BYTE local"));
+}
+
+#[test]
+fn assignment_byte() {
+    assert_snapshot!(statement("b = 5"));
+}
+
+#[test]
+fn assignment_widening() {
+    assert_snapshot!(statement("c = b"));
+}
+
+#[test]
+fn assignment_narrowing() {
+    assert_snapshot!(statement("b = c"));
+}
+
+#[test]
+fn array_assignment() {
+    assert_snapshot!(statement("arr[2] = b"));
+}
+
+#[test]
+fn array_assignment_call_syntax() {
+    assert_snapshot!(statement("arr(2) = b"));
+}
+
+#[test]
+fn pointer_assignment() {
+    assert_snapshot!(statement("^c = 1"));
+}
+
+#[test]
+fn if_then() {
+    assert_snapshot!(statement("IF b = 1 THEN b = 2 FI"));
+}
+
+#[test]
+fn if_else() {
+    assert_snapshot!(statement("IF b = 1 THEN b = 2 ELSE b = 3 FI"));
+}
+
+#[test]
+fn while_loop() {
+    assert_snapshot!(statement("WHILE b < 10 DO b = b + 1 OD"));
+}
+
+#[test]
+fn for_loop() {
+    assert_snapshot!(statement("FOR b = 1 TO 3 DO PutD(b) OD"));
+}
+
+#[test]
+fn for_loop_step() {
+    assert_snapshot!(statement("FOR b = 1 TO 9 STEP 2 DO PutD(b) OD"));
+}
+
+#[test]
+fn until_loop() {
+    assert_snapshot!(statement("DO b = b + 1 UNTIL b = 10 OD"));
+}
+
+#[test]
+fn exit() {
+    assert_snapshot!(statement("WHILE b DO EXIT OD"));
+}
+
+#[test]
+fn return_value() {
+    assert_snapshot!(ast_statement(Statement::Return(Some(Expression::Variable("b".to_string())))));
+}
+
+#[test]
+fn proc_call() {
+    assert_snapshot!(statement("callee(b)"));
+}
+
+#[test]
+fn builtin_calls() {
+    assert_snapshot!(statement("PrintB(b) PrintC(c) PrintE() Print(\"x\") PutD(65) GetD()"));
+}
+
+#[test]
+fn print_with_end_of_line() {
+    assert_snapshot!(statement("PrintBE(b) PrintCE(c)"));
+}
+
+#[test]
+fn print_f() {
+    // Constant arguments go into the text; the others are printed by the runtime
+    assert_snapshot!(statement("PrintF(\"b=%U c=%U%E\", b, 7)"));
+    assert_snapshot!(statement("PrintF(\"%C%S\", b, \"x\")"));
+}
+
+#[test]
+fn print_f_of_constants() {
+    let source = "PROC main()\nPrintF(\"%S %U%C 100%%%E\", \"sum\", 1000, '!')\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn print_f_errors() {
+    assert_snapshot!(statement("PrintF(\"%U\")"));
+    assert_snapshot!(statement("PrintF(\"%X\", b)"));
+    assert_snapshot!(statement("PrintF(\"x\", b)"));
+}
+
+#[test]
+fn device_calls() {
+    assert_snapshot!(statement("PutD(2, b) PrintD(1, \"x\") Put(65) b = GetD(2)"));
+}
+
+#[test]
+fn printer_calls() {
+    assert_snapshot!(statement("LPrint(\"x\") LPrintB(b) LPrintE()"));
+}
+
+#[test]
+fn string_helpers() {
+    assert_snapshot!(statement("b = SIndex(arr, 'x') SSub(arr, \"hello\", 1, 3)"));
+}
+
+#[test]
+fn string_comparisons() {
+    assert_snapshot!(statement("b = arr = \"hi\" b = arr <> arr b = SCompare(arr, \"x\")"));
+}
+
+#[test]
+fn character_intrinsics() {
+    assert_snapshot!(statement("b = IsDigit(b) b = IsAlpha(b) b = ToUpper(b) b = ToLower(c)"));
+}
+
+#[test]
+fn number_conversions() {
+    assert_snapshot!(statement("StrB(b, arr) StrC(c, arr) b = ValB(arr) c = ValC(\"42\")"));
+}
+
+#[test]
+fn line_input() {
+    assert_snapshot!(statement("b = InputS(arr, 10) b = InputSD(2, arr, 10)"));
+}
+
+#[test]
+fn xmodem_without_module() {
+    assert_snapshot!(statement("c = XRecv(arr, 10)"));
+}
+
+#[test]
+fn crc_without_module() {
+    assert_snapshot!(statement("c = Crc16(arr, 10)"));
+}
+
+#[test]
+fn block() {
+    assert_snapshot!(ast_statement(Statement::Block(vec![
+        Statement::Assignment { target: "b".to_string(), value: Expression::Number(1) },
+        Statement::Assignment { target: "c".to_string(), value: Expression::Number(300) },
+    ])));
+}
+
+#[test]
+fn stored_value_reused() {
+    assert_snapshot!(statement("b = b + 1 i = b PrintB(b) c = 300 PrintC(c)"));
+}
+
+#[test]
+fn reload_at_loop_start() {
+    assert_snapshot!(statement("b = 0 WHILE b < 3 DO b = b + 1 OD"));
+}
+
+#[test]
+fn if_with_empty_then() {
+    assert_snapshot!(statement("IF b = 1 THEN ELSE b = 3 FI"));
+}
+
+#[test]
+fn jump_to_jump_is_threaded() {
+    // The IF's jump past its body goes straight back to the loop condition
+    let source = "BYTE b\nPROC main()\nWHILE b < 5 DO b = b + 1 IF b = 2 THEN PutD('x') FI OD\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+// Size and speed preferences
+
+#[test]
+fn counted_for_loop_for_size() {
+    let source = "BYTE b\nPROC main()\nFOR b = 1 TO 3 DO PutD(b) OD\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_opt_for(OptFor::Size))));
+}
+
+#[test]
+fn loop_back_with_jr_for_size() {
+    let source = "BYTE b\nPROC main()\nWHILE b < 10 DO b = b + 1 OD\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_opt_for(OptFor::Size))));
+}
+
+#[test]
+fn jumps_stay_long_for_speed() {
+    // A taken JR is slower than a JP, so none are shortened
+    let source = "BYTE b\nPROC main()\nWHILE b < 10 DO b = b + 1 OD\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_opt_for(OptFor::Speed))));
+}
+
+#[test]
+fn put_d_inline_for_speed() {
+    // out_char's body in place of each call, but not in place of the program's own PutD
+    let source = "PROC main()\nPutD('a')\nPutD(2, 'b')\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_opt_for(OptFor::Speed))));
+    let replaced = "PROC PutD(BYTE c) FASTCALL\nRETURN\nPROC main()\nPutD('a')\nRETURN\n";
+    assert_snapshot!(show(program_bytes(replaced, |g| g.set_opt_for(OptFor::Speed))));
+}
+
+#[test]
+fn multiply_by_power_of_two_for_speed() {
+    let source = "BYTE b\nCARD c\nPROC main()\nb = b * 4\nc = c * 2\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_opt_for(OptFor::Speed))));
+}
+
+// Overflow checks
+
+#[test]
+fn overflow_checks_after_arithmetic() {
+    // CALL C for the BYTE and CARD, CALL PE after ADC HL,DE for the INT, and the trap
+    let source = "BYTE b\nCARD c\nINT i\nPROC main()\nb = b - 1\nc = c + b\ni = i + c\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_overflow(Overflow::Check))));
+}
+
+// Verification
+
+#[test]
+fn verify_accepts_generated_code() {
+    let source = "\
+BYTE b
+BYTE ARRAY msg = \"hi\"
+PROC show()
+PrintB(b)
+RETURN
+PROC main()
+FOR b = 1 TO 3 DO show() Print(msg) OD
+WHILE b DO b = b - 1 IF b = 2 THEN PrintE() FI OD
+RETURN
+";
+    for opt_for in [OptFor::Size, OptFor::Speed] {
+        let bytes = program_bytes(source, |g| {
+            g.set_verify();
+            g.set_opt_for(opt_for);
+        });
+        assert!(bytes.is_ok(), "{:?}", bytes.err());
+    }
+}
+
+#[test]
+fn verify_accepts_counted_loops() {
+    // The DJNZ loop's PUSH BC and POP BC are both the FOR line's, around a body on
+    // lines of its own
+    let source = "PROC main()\nBYTE i\nFOR i = 1 TO 3\nDO\nPrintB(i)\nOD\nRETURN\n";
+    let bytes = program_bytes(source, |g| {
+        g.set_verify();
+        g.set_opt_for(OptFor::Size);
+    });
+    assert!(bytes.is_ok(), "{:?}", bytes.err());
+}
+
+#[test]
+fn verify_accepts_frame_locals_passed_by_reference() {
+    // PUSH IX; POP HL takes a frame local's address, and the frame's PUSH IX and
+    // POP IX are part of no line
+    let source = "\
+PROC Swap(BYTE VAR a, BYTE VAR b)
+BYTE t
+t = a
+a = b
+b = t
+RETURN
+PROC main()
+BYTE p
+BYTE q
+p = 1
+q = 2
+Swap(p, q)
+PrintB(p)
+RETURN
+";
+    let bytes = program_bytes(source, |g| {
+        g.set_verify();
+        g.set_stack_locals();
+    });
+    assert!(bytes.is_ok(), "{:?}", bytes.err());
+}
+
+#[test]
+fn calls_to_procedures_defined_later_are_patched() {
+    // main's tail call goes to later, and --verify finds nothing wrong with it
+    let source = "PROC main()\nlater()\nRETURN\nPROC later()\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_verify())));
+    let undefined = "PROC main()\nlater()\nRETURN\n";
+    assert!(matches!(program_bytes(undefined, |_| {}), Err(crate::error::CompileError::UndefinedProcedure { .. })));
+}
+
+// Startup code
+
+#[test]
+fn startup_with_sys_init() {
+    let source = "PROC SysInit()\nPutD('i')\nRETURN\nPROC main()\nPutD('m')\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn startup_with_init_option() {
+    let source = "PROC setup()\nRETURN\nPROC main()\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_init_proc("setup"))));
+}
+
+#[test]
+fn startup_with_missing_init() {
+    let source = "PROC main()\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_init_proc("setup"))));
+}
+
+#[test]
+fn startup_returning_to_the_system() {
+    let source = "PROC main()\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_exit(Exit::Return))));
+    assert_snapshot!(show(program_bytes(source, |g| g.set_exit(Exit::Jump(0x0000)))));
+}
+
+// Procedure placement
+
+#[test]
+fn placed_procedure() {
+    let source = "PROC main()\nhandler()\nRETURN\nPROC handler = $4300()\nPutD('h')\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn placed_procedure_behind_code() {
+    let source = "PROC main()\nRETURN\nPROC handler = $4200()\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn placed_procedure_after_shortened_jumps() {
+    // main's loop shrinks, NOPs keep handler at $4400, and its own loop shrinks too
+    let source = "\
+BYTE b
+PROC main()
+WHILE b < 5 DO b = b + 1 OD
+handler()
+RETURN
+PROC handler = $4400()
+WHILE b DO b = b - 1 OD
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn interrupt_procedure() {
+    // Tick keeps every register and returns with EI; RETI, though nothing calls it
+    let source = "BYTE ticks\nPROC Tick() INTERRUPT\nticks = ticks + 1\nRETURN\nPROC main()\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_verify())));
+}
+
+#[test]
+fn interrupt_epilogues_are_checked() {
+    // Tick's jumps stay inside it and its RETURN restores the registers, with its
+    // locals static or in a frame
+    let source = "\
+BYTE ticks
+PROC Tick() INTERRUPT
+BYTE n
+n = ticks
+IF n THEN ticks = n - 1 FI
+RETURN
+PROC main()
+RETURN
+";
+    let bytes = program_bytes(source, |_| {});
+    assert!(bytes.is_ok(), "{:?}", bytes.err());
+    assert!(program_bytes(source, |g| g.set_stack_locals()).is_ok());
+
+    // A plain RET in place of the first RETI is caught
+    let program = parse(source).unwrap();
+    let mut codegen = crate::codegen::CodeGenerator::new(ORG);
+    codegen.generate(&program).unwrap();
+    let reti = codegen.code.windows(2).position(|w| w == [0xED, 0x4D]).unwrap();
+    codegen.code[reti..reti + 2].copy_from_slice(&[0xC9, 0x00]);
+    let err = codegen.verify_interrupts(&program).unwrap_err();
+    assert!(err.to_string().contains(&format!("INTERRUPT Tick returns at ${:04X} without restoring", ORG as usize + reti)), "{}", err);
+}
+
+// Overlaid locals
+
+#[test]
+fn overlaid_locals() {
+    // a and b share their locals' RAM; c's go after a's, since a calls it
+    let source = "\
+PROC c()
+BYTE z
+z = 3
+RETURN
+PROC a()
+CARD x
+x = 1
+c()
+RETURN
+PROC b()
+BYTE y
+y = 2
+RETURN
+PROC main()
+a()
+b()
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_overlay_locals())));
+}
+
+#[test]
+fn recursive_programs_keep_separate_locals() {
+    let source = "PROC a()\nBYTE x\nx = 1\na()\nRETURN\nPROC main()\nBYTE y\ny = 2\na()\nRETURN\n";
+    assert_eq!(program_bytes(source, |g| g.set_overlay_locals()).unwrap(), program_bytes(source, |_| {}).unwrap());
+}
+
+// Stack frames
+
+#[test]
+fn stack_frames() {
+    // With every procedure in a frame: a byte register parameter, a local array and
+    // the address of one
+    let source = "\
+PROC fill(BYTE c)
+BYTE ARRAY buf(4)
+CARD p = 300
+buf(1) = c
+p = @buf
+RETURN
+PROC main()
+fill(7)
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |g| g.set_stack_locals())));
+}
+
+#[test]
+fn stack_frame_limits() {
+    assert_snapshot!(show(program_bytes("PROC f()\nBYTE ARRAY big(200)\nf()\nRETURN\n", |_| {})));
+    assert_snapshot!(show(program_bytes("PROC f()\nBYTE ARRAY s = \"hi\"\nf()\nRETURN\n", |_| {})));
+}
+
+// Register arguments
+
+#[test]
+fn stack_arguments() {
+    // The first BYTE and CARD come in A and HL, the others are pushed last first and
+    // copied to the parameters on entry, or read above IX in a stack frame
+    let source = "\
+PROC show(BYTE c, CARD n, BYTE d, CARD m)
+PrintC(n + m)
+PutD(c + d)
+RETURN
+PROC main()
+show('x', 300, 1, 2)
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+    assert_snapshot!(show(program_bytes(source, |g| g.set_stack_locals())));
+}
+
+#[test]
+fn register_arguments() {
+    // show is a leaf and twice asks for FASTCALL: neither takes its arguments on the stack
+    let source = "\
+PROC show(BYTE c, CARD n)
+PutD(c)
+RETURN
+PROC twice(BYTE c) FASTCALL
+show(c, 300)
+show(c, 1)
+RETURN
+PROC main()
+twice('x')
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn fastcall_needs_parameters_that_fit() {
+    let source = "PROC f(BYTE a, BYTE b) FASTCALL\nRETURN\nPROC main()\nf(1, 2)\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn var_parameters_need_variables() {
+    let source = "PROC f(BYTE VAR x)\nx = 1\nRETURN\nPROC main()\nf(2)\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+    let source = "PROC f(BYTE ARRAY VAR x)\nRETURN\nPROC main()\nRETURN\n";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn function_results() {
+    // widen returns its BYTE in HL and low its CARD in A; main adds a word result
+    let source = "\
+CARD c
+FUNC CARD widen(BYTE n)
+RETURN (n)
+FUNC BYTE low(CARD n)
+RETURN (n)
+PROC main()
+c = widen(low(c)) + 1
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+// Forward declarations
+
+#[test]
+fn forward_declarations_change_nothing() {
+    let source = "PROC main()\nshow(1)\nRETURN\nPROC show(BYTE n)\nPutD(n)\nRETURN\n";
+    let declared = format!("PROC Show(BYTE count) FORWARD\n{}", source);
+    assert_eq!(program_bytes(&declared, |_| {}).unwrap(), program_bytes(source, |_| {}).unwrap());
+}
+
+#[test]
+fn forward_declaration_errors() {
+    let body = "PROC main()\nRETURN\nPROC show(BYTE n)\nRETURN\n";
+    assert_snapshot!(show(program_bytes(&format!("PROC hide() FORWARD\n{}", body), |_| {})));
+    assert_snapshot!(show(program_bytes(&format!("PROC show(CARD n) FORWARD\n{}", body), |_| {})));
+    assert_snapshot!(show(program_bytes(&format!("FUNC BYTE show(BYTE n) FORWARD\n{}", body), |_| {})));
+    assert_snapshot!(show(program_bytes("PROC f(BYTE a, BYTE b)\nRETURN\nPROC main()\nf(1)\nf(1, 2)\nRETURN\n", |_| {})));
+}
+
+// Modules
+
+#[test]
+fn private_names_stay_in_their_module() {
+    let source = "\
+MODULE
+PRIVATE BYTE count
+PRIVATE PROC bump()
+count = count + 1
+RETURN
+PUBLIC PROC tick()
+bump()
+RETURN
+MODULE
+PROC main()
+BYTE count
+count = 1
+tick()
+RETURN
+";
+    let bytes = program_bytes(source, |_| {});
+    assert!(bytes.is_ok(), "{:?}", bytes.err());
+    assert_snapshot!(show(program_bytes(&source.replace("count = 1\ntick()", "count = 1\nbump()"), |_| {})));
+    assert_snapshot!(show(program_bytes(&source.replace("BYTE count\ncount = 1", "PutD(count)"), |_| {})));
+}
+
+// Tail calls
+
+#[test]
+fn tail_calls() {
+    // The call in guarded is kept: the IF jumps to its RETURN
+    let source = "\
+BYTE b
+PROC last()
+PutD('x')
+RETURN
+PROC guarded()
+IF b THEN last() FI
+RETURN
+PROC main()
+guarded()
+last()
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+}
+
+#[test]
+fn replaced_runtime_routines() {
+    let source = "\
+BYTE last
+PROC PutD(BYTE c)
+last = c
+RETURN
+FUNC BYTE GetD()
+RETURN (last)
+PROC main()
+PutD(GetD())
+RETURN
+";
+    assert_snapshot!(show(program_bytes(source, |_| {})));
+    // The runtime passes PutD a character in A, and nothing else
+    assert_snapshot!(show(program_bytes("PROC PutD(CARD c)\nRETURN\n", |_| {})));
+    assert_snapshot!(show(program_bytes("FUNC CARD GetD()\nRETURN (0)\n", |_| {})));
+    // Only the pairs the replacement changes are saved; here DE and HL, for the count
+    let counting = "CARD count\nPROC PutD(BYTE c)\ncount = count + 1\nRETURN\nPROC main()\nPutD('a')\nRETURN\n";
+    assert_snapshot!(show(program_bytes(counting, |_| {})));
+}
@@ -0,0 +1,145 @@
+// Checks on the finished code, for --verify: bad code from a generator bug becomes an
+// internal error instead of a crash on the target.
+//
+// The code is decoded from the start, so every byte must belong to an instruction.
+// Jumps and calls must land on an instruction, a runtime routine or an RST vector the
+// runtime fills, references to the data section must land inside it, and each source
+// line must pop what it pushes.
+//
+// INTERRUPT procedures are checked on every build, --verify or not: a handler that
+// returns without restoring what it saved is miserable to track down on the hardware.
+
+use super::CodeGenerator;
+use crate::ast::Program;
+use crate::clobber::{decode, Flow};
+use crate::error::{CompileError, Result};
+use std::collections::{HashMap, HashSet};
+
+impl CodeGenerator {
+    pub(super) fn verify_code(&self, data_fixups: &[(usize, u16)]) -> Result<()> {
+        let fail = |message: String| Err(CompileError::InternalError { message: format!("verify: {}", message) });
+        let fetch = |addr: u16| self.code.get(addr.checked_sub(self.origin)? as usize).copied();
+
+        // Instruction starts and where they go
+        let mut starts = HashSet::new();
+        let mut targets = Vec::new();
+        let mut pc = self.origin;
+        while ((pc - self.origin) as usize) < self.code.len() {
+            let Some((len, _, flow)) = decode(fetch, pc) else {
+                return fail(format!("instruction at ${:04X} runs past the end of the code", pc));
+            };
+            starts.insert(pc);
+            match flow {
+                Flow::Jump(target) | Flow::Branch(target) | Flow::Call(target) => targets.push((pc, target)),
+                Flow::Unknown if self.code[(pc - self.origin) as usize] != 0xE9 => {
+                    return fail(format!("cannot decode the instruction at ${:04X}", pc));
+                }
+                _ => {}
+            }
+            pc = pc.wrapping_add(len);
+        }
+
+        let mut entries: HashSet<u16> = HashSet::new();
+        if let Some(runtime) = &self.runtime {
+            entries.extend(runtime.routines().iter().map(|&(_, addr)| addr));
+            entries.extend(runtime.rst_vectors.iter().map(|&(vector, _)| u16::from(vector)));
+        }
+        entries.extend(self.imported.values());
+        for (at, target) in targets {
+            if !starts.contains(&target) && !entries.contains(&target) {
+                let place = if fetch(target).is_some() { "the middle of an instruction" } else { "outside the image" };
+                return fail(format!("jump or call at ${:04X} goes to ${:04X}, {}", at, target, place));
+            }
+        }
+
+        // Data references, against the data section where it runs
+        let data_base = self.data_base.unwrap_or(self.origin);
+        for &(at, offset) in data_fixups {
+            let word = self.code.get(at..at + 2).map(|w| u16::from_le_bytes([w[0], w[1]]));
+            if offset as usize > self.data_section.len() || word != Some(data_base.wrapping_add(offset)) {
+                return fail(format!("data reference at ${:04X} does not point into the data", self.origin as usize + at));
+            }
+        }
+
+        // Pushes and pops of each line, which may be split by nested statements
+        let mut depth: HashMap<(Option<&str>, usize), i32> = HashMap::new();
+        for (i, (start, line, procedure)) in self.line_marks.iter().enumerate() {
+            let Some(line) = line else { continue };
+            let end = self.line_marks.get(i + 1).map_or(self.code.len(), |&(next, _, _)| next);
+            let mut pc = self.origin + *start as u16;
+            while pc < self.origin + end as u16 {
+                // PUSH IX and PUSH IY are PUSH HL after a prefix, and likewise POP
+                let at = (pc - self.origin) as usize;
+                let op = match self.code[at] {
+                    0xDD | 0xFD => self.code.get(at + 1).copied().unwrap_or(0),
+                    op => op,
+                };
+                let change = match op & 0xCF {
+                    0xC5 => 1,   // PUSH
+                    0xC1 => -1,  // POP
+                    _ => 0,
+                };
+                *depth.entry((procedure.as_deref(), *line)).or_default() += change;
+                pc = pc.wrapping_add(decode(fetch, pc).map_or(1, |(len, _, _)| len));
+            }
+        }
+        let mut unbalanced: Vec<_> = depth.into_iter().filter(|&(_, pushed)| pushed != 0).collect();
+        unbalanced.sort();
+        if let Some(((_, line), pushed)) = unbalanced.first() {
+            let (more, less) = if *pushed > 0 { ("pushes", "pops") } else { ("pops", "pushes") };
+            return fail(format!("line {} {} {} more word(s) than it {}", line, more, pushed.abs(), less));
+        }
+        Ok(())
+    }
+
+    // Each INTERRUPT procedure saves the registers on entry, and may only leave through
+    // the epilogue that restores them and turns interrupts back on. A RET or RETN, a
+    // jump out of it or running off its end would hand the interrupted code changed
+    // registers with interrupts off.
+    pub(super) fn verify_interrupts(&self, program: &Program) -> Result<()> {
+        const ENTRY: [u8; 6] = [0xF5, 0xC5, 0xD5, 0xE5, 0xDD, 0xE5];  // PUSH AF, BC, DE, HL, IX
+        const EXIT: [u8; 9] = [0xDD, 0xE1, 0xE1, 0xD1, 0xC1, 0xF1, 0xFB, 0xED, 0x4D];  // POP IX, HL, DE, BC, AF; EI; RETI
+        for proc in program.procedures.iter().filter(|p| p.interrupt) {
+            let key = self.key(&proc.name);
+            let Some(&(_, _, start, end)) = self.module_code.iter().find(|(_, name, _, _)| self.key(name) == key) else {
+                continue;
+            };
+            let fail = |message: String| Err(CompileError::InternalError {
+                message: format!("verify: INTERRUPT {} {}", proc.name, message),
+            });
+            let fetch = |addr: u16| {
+                (start..end).contains(&addr).then(|| self.code[(addr - self.origin) as usize])
+            };
+            let offset = |addr: u16| (addr - self.origin) as usize;
+            if !self.code[offset(start)..offset(end)].starts_with(&ENTRY) {
+                return fail("does not save the registers on entry".to_string());
+            }
+
+            let mut pc = start;
+            let mut falls_through = true;
+            while pc < end {
+                let Some((len, _, flow)) = decode(fetch, pc) else {
+                    return fail(format!("has an instruction at ${:04X} that runs past its end", pc));
+                };
+                let op = self.code[offset(pc)];
+                let returns = op & 0xC7 == 0xC0 || (matches!(flow, Flow::Return) && op != 0x76);  // RET cc, RET, RETN, RETI
+                if returns && !self.code[..offset(pc + len)].ends_with(&EXIT) {
+                    return fail(format!("returns at ${:04X} without restoring the registers it saved", pc));
+                }
+                match flow {
+                    Flow::Jump(target) | Flow::Branch(target) if !returns && !(start..end).contains(&target) => {
+                        return fail(format!("jumps at ${:04X} to ${:04X}, past the code that restores the registers", pc, target));
+                    }
+                    Flow::Unknown => return fail(format!("jumps at ${:04X} somewhere that cannot be followed", pc)),
+                    _ => {}
+                }
+                falls_through = !matches!(flow, Flow::Jump(_) | Flow::Return) || op == 0x76;
+                pc = pc.wrapping_add(len);
+            }
+            if falls_through {
+                return fail("runs off its end without returning".to_string());
+            }
+        }
+        Ok(())
+    }
+}
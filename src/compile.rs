@@ -0,0 +1,663 @@
+// The compiler from source to image: lexer, parser, runtime library and code generator
+// run one after the other, and the pieces put together the way the command line
+// writes them out.
+
+use crate::ast::{Definition, Procedure, Program};
+use crate::cfg;
+use crate::clobber;
+use crate::codegen::{self, CodeGenerator, ListingOptions};
+use crate::disasm;
+use crate::error::{CompileError, Result};
+use crate::format::Segment;
+use crate::lexer::Lexer;
+use crate::library::{self, Library};
+use crate::parser::{self, Parser};
+use crate::relocate;
+use crate::stack;
+use crate::runtime::{self, RuntimeOptions, RuntimeSymbols};
+use crate::semantics;
+use crate::symbols::{self, Area, SymbolFile};
+use crate::token::{CaseMode, TokenInfo};
+use std::path::PathBuf;
+
+/// How to compile a program, with the command line's defaults
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub origin: u16,                    // Load address, unless boot_rom
+    pub boot_rom: bool,                 // ROM image for 0x0000 with a reset stub and vectors
+    pub stack: u16,                     // Initial stack pointer of a boot ROM
+    pub relocatable: bool,              // Prefix the image with a stub that relocates it
+    pub max_size: Option<usize>,        // Largest image allowed
+    pub runtime: RuntimeOptions,
+    pub case_mode: CaseMode,
+    pub defines: Vec<(String, String)>, // (name, text), as DEFINE
+    pub default_array_size: usize,
+    pub max_nesting: usize,
+    pub compat: parser::Compat,         // Operator precedence: this compiler's or the original Action!'s
+    pub init: Option<String>,           // Procedure called before main (default: SysInit if defined)
+    pub entry: Option<String>,          // Procedure the image runs (default: main, else the first)
+    pub data_address: Option<u16>,      // Run address of initialized data
+    pub data_loaded: bool,              // Data is loaded at data_address, not copied from the image
+    pub exit: codegen::Exit,
+    pub opt_for: Option<codegen::OptFor>,
+    pub overflow: codegen::Overflow,
+    pub keep_unused: bool,              // Generate procedures nothing calls
+    pub drop_write_only: bool,          // Leave out variables that are written but never read
+    pub overlay_locals: bool,
+    pub stack_locals: bool,
+    pub verify: bool,
+    pub jump_table: Vec<String>,        // Procedures for the table of JPs at the start of the image
+    pub imports: Vec<SymbolFile>,       // Symbols of separately built images the program uses
+    pub include_dir: Option<PathBuf>,   // Where INCBIN paths start from (default: the current directory)
+    pub libraries: Vec<Library>,        // Where compile_source finds the procedures the program uses but lacks
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            origin: 0x4200,
+            boot_rom: false,
+            stack: 0x0000,
+            relocatable: false,
+            max_size: None,
+            runtime: RuntimeOptions::default(),
+            case_mode: CaseMode::Insensitive,
+            defines: Vec::new(),
+            default_array_size: parser::DEFAULT_ARRAY_SIZE,
+            max_nesting: parser::DEFAULT_MAX_DEPTH,
+            compat: parser::Compat::default(),
+            init: None,
+            entry: None,
+            data_address: None,
+            data_loaded: false,
+            exit: codegen::Exit::default(),
+            opt_for: None,
+            overflow: codegen::Overflow::default(),
+            keep_unused: false,
+            drop_write_only: false,
+            overlay_locals: false,
+            stack_locals: false,
+            verify: false,
+            jump_table: Vec::new(),
+            imports: Vec::new(),
+            include_dir: None,
+            libraries: Vec::new(),
+        }
+    }
+}
+
+impl CompileOptions {
+    /// The settings of --target test, each written out rather than taken from the
+    /// defaults, so golden images and snapshots built with them do not change when a
+    /// default does
+    pub fn pinned() -> Self {
+        CompileOptions {
+            origin: 0x4200,
+            boot_rom: false,
+            stack: 0x0000,
+            relocatable: false,
+            max_size: None,
+            runtime: RuntimeOptions::pinned(),
+            case_mode: CaseMode::Insensitive,
+            defines: Vec::new(),
+            default_array_size: 256,
+            max_nesting: 200,
+            compat: parser::Compat::Modern,
+            init: None,
+            entry: None,
+            data_address: None,
+            data_loaded: false,
+            exit: codegen::Exit::Halt,
+            opt_for: None,
+            overflow: codegen::Overflow::Wrap,
+            keep_unused: false,
+            drop_write_only: false,
+            overlay_locals: false,
+            stack_locals: false,
+            verify: false,
+            jump_table: Vec::new(),
+            imports: Vec::new(),
+            include_dir: None,
+            libraries: Vec::new(),
+        }
+    }
+}
+
+/// An entry of the jump table
+#[derive(Debug, Clone, PartialEq)]
+pub struct JumpTableEntry {
+    pub name: String,
+    pub entry: u16,   // Address of the JP
+    pub target: u16,  // Address of the procedure
+}
+
+/// A compiled program
+pub struct CompileOutput {
+    pub binary: Vec<u8>,                // The image to load at origin
+    pub origin: u16,
+    pub codegen: CodeGenerator,         // Procedure and variable addresses, and the listings
+    pub runtime_symbols: RuntimeSymbols,
+    pub runtime_start: u16,
+    pub runtime_size: usize,
+    pub jump_table: Vec<JumpTableEntry>,
+    pub warnings: Vec<String>,
+    pub program: Program,               // The program compiled, with the library members it uses
+    image: Vec<u8>,                     // The image as linked, before any relocation stub
+    image_origin: u16,
+}
+
+/// Tokens of source, with the options' case policy and definitions
+pub fn tokenize(source: &str, options: &CompileOptions) -> Result<Vec<TokenInfo>> {
+    let mut lexer = Lexer::new(source);
+    lexer.set_case_mode(options.case_mode);
+    for (name, text) in &options.defines {
+        lexer.define(name, text).map_err(|e| CompileError::DefineError {
+            name: name.clone(),
+            message: e.to_string(),
+        })?;
+    }
+    lexer.tokenize()
+}
+
+/// The program the tokens make up, with the parser's warnings by line
+pub fn parse(tokens: Vec<TokenInfo>, options: &CompileOptions) -> Result<(Program, Vec<(usize, String)>)> {
+    let mut parser = Parser::new(tokens);
+    parser.set_max_depth(options.max_nesting);
+    parser.set_compat(options.compat);
+    parser.set_default_array_size(options.default_array_size);
+    if let Some(dir) = &options.include_dir {
+        parser.set_include_dir(dir);
+    }
+    let program = parser.parse()?;
+    Ok((program, parser.warnings().to_vec()))
+}
+
+/// The program in source with the members of the options' libraries it uses, and the
+/// parser's warnings by line
+pub fn parse_source(source: &str, options: &CompileOptions) -> Result<(Program, Vec<(usize, String)>)> {
+    validate(options)?;
+    let (program, warnings) = parse(tokenize(source, options)?, options)?;
+    Ok((library::link(&program, &options.libraries, options)?, warnings))
+}
+
+/// Compile a parsed program into an image. A program with semantic errors gives them
+/// all, as `CompileError::Errors` when there are several.
+pub fn compile_program(program: &Program, options: &CompileOptions) -> Result<CompileOutput> {
+    validate(options)?;
+    let mut errors = semantics::check(program, options.case_mode, &options.imports);
+    match errors.len() {
+        0 => {}
+        1 => return Err(errors.remove(0)),
+        _ => return Err(CompileError::Errors { errors }),
+    }
+    let org = if options.boot_rom { 0x0000 } else { options.origin };
+
+    // A relocatable image runs after the stub that relocates it, and is linked a second
+    // time further up to find its addresses
+    let image_org = if options.relocatable { org.wrapping_add(relocate::STUB_SIZE) } else { org };
+    let mut output = link(program, options, image_org)?;
+    if options.relocatable {
+        let shifted = link(program, options, image_org.wrapping_add(relocate::PROBE_SHIFT))?;
+        output.binary = relocate::make_relocatable(org, &output.image, &shifted.image)
+            .map_err(|e| CompileError::LinkError { message: format!("cannot make the image relocatable: {}", e) })?;
+        output.origin = org;
+    }
+
+    // The image must end by the top of memory rather than wrap around to 0x0000
+    if org as usize + output.binary.len() > 0x10000 {
+        return Err(CompileError::LinkError {
+            message: format!("the image is {} bytes, too many to load at ${:04X}; the most that fits there is {}",
+                             output.binary.len(), org, 0x10000 - org as usize),
+        });
+    }
+
+    // The variables are not in the image, so nothing may be loaded over them, nor over
+    // the images and variables of the builds it imports from
+    let image = Area { start: org, size: output.binary.len() };
+    let variables = Area {
+        start: options.runtime.ram_start,
+        size: output.codegen.variables_end().wrapping_sub(options.runtime.ram_start) as usize,
+    };
+    let mut taken = vec![("the variables", variables)];
+    for import in &options.imports {
+        taken.push(("the imported image", import.image));
+        taken.push(("the imported variables", import.variables));
+    }
+    for (what, area) in [("the image", image), ("the variables", variables)] {
+        if let Some((other, other_area)) = taken.iter().find(|&&(other, a)| other != what && a.size > 0 && area.overlaps(&a)) {
+            return Err(CompileError::LinkError {
+                message: format!("{} at {} overlaps {} at {}; move one or the other with --org or --ram",
+                                 what, area.describe(), other, other_area.describe()),
+            });
+        }
+    }
+
+    output.warnings.extend(semantics::interrupt_warnings(program, options.case_mode));
+
+    if let Some(max_size) = options.max_size {
+        if output.binary.len() > max_size {
+            return Err(CompileError::LinkError {
+                message: format!("the image is {} bytes, more than the {} set aside for it",
+                                 output.binary.len(), max_size),
+            });
+        }
+    }
+    Ok(output)
+}
+
+/// Compile source, with the members of the options' libraries it uses, into an image;
+/// parser warnings come first in the output's warnings
+pub fn compile_source(source: &str, options: CompileOptions) -> Result<CompileOutput> {
+    let (program, parse_warnings) = parse_source(source, &options)?;
+    let mut output = compile_program(&program, &options)?;
+    let warnings = parse_warnings.into_iter().map(|(line, warning)| format!("line {}: {}", line, warning));
+    output.warnings.splice(0..0, warnings);
+    Ok(output)
+}
+
+// The settings the runtime and the parser can work with, whoever made the options
+fn validate(options: &CompileOptions) -> Result<()> {
+    let fail = |message: &str| Err(CompileError::OptionError { message: message.to_string() });
+    let runtime = &options.runtime;
+    if options.default_array_size == 0 {
+        return fail("--default-array-size must be at least 1");
+    }
+    if ![1, 16, 64].contains(&runtime.clock_divide) {
+        return fail(&format!("--uart-divide must be 1, 16 or 64, found {}", runtime.clock_divide));
+    }
+    if runtime.tx_buffer != 0 {
+        if !(2..=128).contains(&runtime.tx_buffer) || !runtime.tx_buffer.is_power_of_two() {
+            return fail(&format!("--tx-buffer must be a power of two from 2 to 128, found {}", runtime.tx_buffer));
+        }
+        if runtime.console != runtime::ConsoleBackend::Uart {
+            return fail("--tx-buffer only applies to the UART console");
+        }
+        if runtime.uart == runtime::Uart::Simple {
+            return fail("--tx-buffer needs a UART with a transmitter status (--uart acia, sio or 8251)");
+        }
+    }
+    if runtime.eval_stack != 0 && (runtime.eval_stack > 256 || !runtime.eval_stack.is_multiple_of(2)) {
+        return fail(&format!("--eval-stack must be an even number of bytes from 2 to 256, found {}", runtime.eval_stack));
+    }
+    // The BIOS looks for a cartridge's header at the start of 0x4000 or 0x8000
+    if runtime.cartridge && !matches!(options.origin, 0x4010 | 0x8010) {
+        return fail("--format msx-rom needs --org 0x4010 or 0x8010, after the cartridge header");
+    }
+    Ok(())
+}
+
+// One build of the image for an origin
+fn link(program: &Program, options: &CompileOptions, org: u16) -> Result<CompileOutput> {
+    // Generate runtime library first, leaving space for initial JP instruction,
+    // or for the reset stub and vectors of a boot ROM
+    let table_start = if options.boot_rom {
+        runtime::BOOT_RUNTIME_START
+    } else {
+        org.wrapping_add(3)  // JP instruction takes 3 bytes
+    };
+    let runtime_start = table_start + 3 * options.jump_table.len() as u16;
+    let (mut runtime_code, runtime_symbols) = runtime::generate_runtime_with_options(runtime_start, &options.runtime);
+    let code_start = runtime_symbols.end_address;
+
+    // Generate code
+    let mut codegen = code_generator(options, &runtime_symbols, code_start);
+    codegen.set_runtime_code(&runtime_code);
+    if let Some(data_address) = options.data_address {
+        codegen.set_data_address(data_address);
+    }
+    if options.boot_rom || options.runtime.cartridge {
+        codegen.set_data_in_ram();
+    }
+    if options.data_loaded && options.data_address.is_some() {
+        codegen.set_data_loaded();
+    }
+    let program_code = codegen.generate(program)?;
+    codegen.link_runtime(&mut runtime_code);
+
+    // Exported procedures
+    let mut jump_table = Vec::new();
+    for (i, name) in options.jump_table.iter().enumerate() {
+        let target = codegen.procedure_address(name).ok_or_else(|| CompileError::LinkError {
+            message: format!("the jump table names '{}', which is not a procedure", name),
+        })?;
+        if codegen.is_private(name) {
+            return Err(CompileError::LinkError {
+                message: format!("the jump table names '{}', which is PRIVATE", name),
+            });
+        }
+        jump_table.push(JumpTableEntry { name: name.clone(), entry: table_start + 3 * i as u16, target });
+    }
+
+    // Build final binary:
+    // 1. JP to code_start (entry point with CALL main, HALT), or the boot ROM's
+    //    reset stub and vectors
+    // 2. Jump table, if any
+    // 3. Runtime library
+    // 4. Program code, followed by its initialized data
+    let mut image = Vec::new();
+    if options.boot_rom {
+        image.extend(runtime::generate_boot_vectors(options.stack, code_start, &runtime_symbols));
+    } else {
+        image.push(0xC3);  // JP
+        image.push((code_start & 0xFF) as u8);
+        image.push((code_start >> 8) as u8);
+    }
+    for entry in &jump_table {
+        image.push(0xC3);  // JP
+        image.push((entry.target & 0xFF) as u8);
+        image.push((entry.target >> 8) as u8);
+    }
+    let runtime_size = runtime_code.len();
+    image.extend(runtime_code);
+    image.extend(program_code);
+
+    let warnings = codegen.warnings().to_vec();
+    Ok(CompileOutput {
+        binary: image.clone(),
+        origin: org,
+        codegen,
+        runtime_symbols,
+        runtime_start,
+        runtime_size,
+        jump_table,
+        warnings,
+        program: program.clone(),
+        image,
+        image_origin: org,
+    })
+}
+
+// A code generator for code at origin, set up as options say
+fn code_generator(options: &CompileOptions, runtime_symbols: &RuntimeSymbols, origin: u16) -> CodeGenerator {
+    let mut codegen = CodeGenerator::new(origin);
+    codegen.set_runtime_symbols(runtime_symbols);
+    codegen.set_case_mode(options.case_mode);
+    if let Some(init) = &options.init {
+        codegen.set_init_proc(init);
+    }
+    if let Some(entry) = &options.entry {
+        codegen.set_entry_point(entry);
+    }
+    if options.verify {
+        codegen.set_verify();
+    }
+    if options.overlay_locals {
+        codegen.set_overlay_locals();
+    }
+    if options.stack_locals {
+        codegen.set_stack_locals();
+    }
+    codegen.set_imports(&options.imports);
+    codegen.set_exit(options.exit);
+    if let Some(opt_for) = options.opt_for {
+        codegen.set_opt_for(opt_for);
+    }
+    codegen.set_overflow(options.overflow);
+    if options.keep_unused {
+        codegen.set_keep_unused();
+    }
+    if options.drop_write_only {
+        codegen.set_drop_write_only();
+    }
+    codegen.set_external(&options.jump_table);
+    codegen
+}
+
+/// The image with the procedure name of program compiled again and written over its
+/// old code, at the address the imported symbol file of the image gives. The options
+/// must be those the image was built with, so the runtime is where it was; the
+/// procedure's data follows its code, its locals go after the image's variables, and
+/// what is left of its old space is filled with NOPs.
+pub fn compile_patch(program: &Program, name: &str, image: &[u8], options: &CompileOptions) -> Result<Vec<u8>> {
+    let case = options.case_mode;
+    let link_error = |message: String| CompileError::LinkError { message };
+    let (symbols, old) = options.imports.iter()
+        .find_map(|file| Some((file, file.procedures.iter().find(|p| case.matches(&p.name, name))?)))
+        .ok_or_else(|| link_error(format!("no symbol file imported gives the address of {}", name)))?;
+    let proc = program.procedures.iter().find(|p| case.matches(&p.name, name))
+        .ok_or_else(|| link_error(format!("the source has no procedure {} to patch", name)))?;
+    let offset = old.address.wrapping_sub(symbols.image.start) as usize;
+    if symbols.image.size != image.len() || offset + old.size > image.len() {
+        return Err(link_error(format!("the image is {} bytes, not the {} its symbol file was written for",
+                                      image.len(), symbols.image.size)));
+    }
+
+    // The procedure on its own, calling the rest of the image through its symbols
+    let mut imports = options.imports.clone();
+    for file in &mut imports {
+        file.procedures.retain(|p| !case.matches(&p.name, name));
+    }
+    let options = CompileOptions { imports, ..options.clone() };
+    let mut patch = Program {
+        procedures: vec![Procedure { address: None, module: 0, ..proc.clone() }],
+        definitions: program.definitions.iter().filter(|d| !d.global && case.matches(&d.name, name))
+            .map(|d| Definition { module: 0, ..d.clone() })
+            .collect(),
+        ..Program::new()
+    };
+    semantics::check(&patch, case, &options.imports).into_iter().next().map_or(Ok(()), Err)?;
+
+    // The same runtime as the image's, with the locals after all its variables
+    let table_start = if options.boot_rom { runtime::BOOT_RUNTIME_START } else { symbols.image.start.wrapping_add(3) };
+    let runtime_start = table_start + 3 * options.jump_table.len() as u16;
+    let (_, mut runtime_symbols) = runtime::generate_runtime_with_options(runtime_start, &options.runtime);
+    let variables_end = symbols.variables.start as usize + symbols.variables.size;
+    runtime_symbols.ram_end = runtime_symbols.ram_end.max(variables_end as u16);
+
+    // Startup code comes before the procedure, so build once to find how much, then
+    // again with the procedure at its address
+    let mut codegen = code_generator(&options, &runtime_symbols, old.address);
+    codegen.generate(&patch)?;
+    let startup = codegen.procedure_address(name).unwrap_or(old.address).wrapping_sub(old.address);
+    if old.fixed {
+        patch.procedures[0].address = Some(old.address);
+    }
+    let mut codegen = code_generator(&options, &runtime_symbols, old.address.wrapping_sub(startup));
+    let code = codegen.generate(&patch)?;
+    let new = &code[startup as usize..];
+    if new.len() > old.size {
+        return Err(link_error(format!("{} now takes {} bytes with its data, more than the {} it has at ${:04X}",
+                                      name, new.len(), old.size, old.address)));
+    }
+
+    let mut patched = image.to_vec();
+    patched[offset..offset + new.len()].copy_from_slice(new);
+    patched[offset + new.len()..offset + old.size].fill(0x00);  // NOP
+    Ok(patched)
+}
+
+impl CompileOutput {
+    /// Address the image was linked for, after the relocation stub of a relocatable one
+    pub fn image_origin(&self) -> u16 {
+        self.image_origin
+    }
+
+    /// What to load where: the image up to its data section at origin, then the data
+    /// section, either where the startup code copies it from or, when the options leave
+    /// it for the loader, where it runs. A relocatable image is one segment
+    pub fn segments(&self) -> Vec<Segment> {
+        if self.image_origin != self.origin {
+            return vec![Segment { name: "image", address: self.origin, bytes: self.binary.clone(), run: None }];
+        }
+        let (load, run, _) = self.codegen.data_layout();
+        let data = self.codegen.data_section();
+        let code_size = if self.codegen.is_data_loaded() { self.binary.len() } else { self.binary.len() - data.len() };
+        let mut segments = vec![Segment { name: "code", address: self.origin, bytes: self.binary[..code_size].to_vec(), run: None }];
+        if !data.is_empty() {
+            segments.push(Segment { name: "data", address: load, bytes: data.to_vec(), run: Some(run).filter(|&run| run != load) });
+        }
+        segments
+    }
+
+    /// The text listing: the program's own, then the runtime library disassembled, the
+    /// jump table and the registers each procedure may change
+    pub fn listing(&self, options: &ListingOptions) -> String {
+        let mut listing = self.codegen.generate_listing(options);
+        let routines = self.runtime_symbols.routines();
+        let procedures: Vec<(&str, u16)> = self.codegen.procedure_addresses().collect();
+        let names = |addr: u16| routines.iter().chain(&procedures).find(|&&(_, a)| a == addr).map(|&(name, _)| name.to_string());
+
+        // The program disassembled a source line at a time, with what each line costs
+        if options.code && options.disassembly {
+            listing.push_str("\n; Program by source line, with T-states for one pass through each:\n");
+            for entry in self.codegen.listing_entries() {
+                let place = match (entry.line, entry.procedure) {
+                    (Some(line), Some(proc)) => format!("line {} in {}", line, proc),
+                    (Some(line), None) => format!("line {}", line),
+                    (None, Some(proc)) => proc,  // Its entry or exit
+                    (None, None) => "startup".to_string(),
+                };
+                let cost = disasm::cost(entry.bytes.len(), disasm::run_t_states(&entry.bytes));
+                listing.push_str(&format!("; {}: {}\n", place, cost));
+                listing.push_str(&disasm::listing(&entry.bytes, entry.address, &names, &[]));
+            }
+        }
+
+        // The runtime library, disassembled with its routines named
+        if options.code && options.disassembly {
+            let start = (self.runtime_start - self.image_origin) as usize;
+            listing.push_str(&format!("\n; Runtime library (${:04X}-${:04X}):\n",
+                                      self.runtime_start, self.runtime_symbols.end_address.wrapping_sub(1)));
+            listing.push_str(&disasm::listing(&self.image[start..start + self.runtime_size], self.runtime_start,
+                                               &names, &self.runtime_symbols.tables));
+        }
+        if options.symbols {
+            if !self.jump_table.is_empty() {
+                listing.push_str("\n; Jump table:\n");
+                for entry in &self.jump_table {
+                    listing.push_str(&format!(";   {} = ${:04X} (JP ${:04X})\n", entry.name, entry.entry, entry.target));
+                }
+            }
+            // What each procedure may change, for machine code that calls it
+            listing.push_str("\n; Registers changed (flags always may be):\n");
+            let mut analysis = clobber::Analysis::new(&self.image, self.image_origin);
+            let mut procedures: Vec<(&str, u16)> = self.codegen.procedure_addresses().collect();
+            procedures.sort_by_key(|&(_, addr)| addr);
+            for (name, addr) in procedures {
+                listing.push_str(&format!(";   {} = {}\n", name, analysis.clobbered(addr)));
+            }
+        }
+        listing
+    }
+
+    /// How much stack each procedure uses, found from its code as linked: the bytes of
+    /// its stack frame, the most its own code pushes, and the most with what the
+    /// routines it calls push too, then what the program needs in all
+    pub fn stack_report(&self) -> String {
+        let mut analysis = stack::Analysis::new(&self.image, self.image_origin);
+        for &(head, bytes) in &self.runtime_symbols.push_loops {
+            analysis.bound_loop(head, bytes);
+        }
+        let mut procedures: Vec<(&str, u16)> = self.codegen.procedure_addresses().collect();
+        procedures.sort_by_key(|&(_, addr)| addr);
+        let width = procedures.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max(9);
+        let mut report = String::from("; Bytes of stack each procedure uses below its return address. Frame is its\n\
+                                       ; locals and the saved IX when it has a stack frame; own counts what its code\n\
+                                       ; pushes and the return addresses of its calls; worst adds what those calls use.\n\n");
+        report.push_str(&format!("{:<width$}  Address  Frame    Own  Worst\n", "Procedure"));
+        for (name, addr) in procedures {
+            let usage = analysis.usage(addr);
+            let frame = self.codegen.frame_size(name).map_or("-".to_string(), |size| (size + 2).to_string());
+            report.push_str(&format!("{:<width$}  ${:04X}  {:>6} {:>6}  {}\n", name, addr, frame, usage.own, usage.worst));
+        }
+        if let Some(entry) = self.codegen.entry_address() {
+            report.push_str(&format!("\n{}\n", stack_needed(analysis.usage(entry).worst)));
+        }
+        report
+    }
+
+    /// Control-flow graphs of the procedures in the image, in Graphviz's DOT language:
+    /// their statements in basic blocks, with the tests' true and false edges
+    pub fn control_flow(&self, program: &Program) -> String {
+        cfg::dot(program.procedures.iter().filter(|proc| self.codegen.procedure_address(&proc.name).is_some()))
+    }
+
+    /// Assembler source for the image as linked, in the syntax sjasmplus and z80asm take
+    /// and assembling to the same bytes: the code disassembled, with labels for the
+    /// runtime routines, procedures and globals, and the runtime's tables and the data
+    /// section as DB. Globals outside the image, in RAM, are given by EQU
+    pub fn assembly(&self, program: &Program) -> String {
+        let mut labels = Labels::default();
+        labels.add("start", self.runtime_symbols.end_address);  // The startup code that calls main
+        for (name, addr) in self.runtime_symbols.routines() {
+            labels.add(name, addr);
+        }
+        for proc in &program.procedures {
+            if let Some(addr) = self.codegen.procedure_address(&proc.name) {
+                labels.add(&proc.name, addr);
+            }
+        }
+        let image = self.image_origin..self.image_origin.wrapping_add(self.image.len() as u16);
+        let mut equates = String::new();
+        for var in &program.globals {
+            let Some(addr) = self.codegen.global_address(&var.name) else { continue };
+            let label = labels.add(&var.name, addr);
+            if !image.contains(&addr) {
+                equates.push_str(&format!("{:<15} EQU ${:04X}  ; {}\n", label, addr, symbols::type_name(&var.data_type)));
+            }
+        }
+        labels.0.sort_by_key(|&(addr, _)| addr);
+
+        let (data_load, _, data_size) = self.codegen.data_layout();
+        let mut tables = self.runtime_symbols.tables.clone();
+        tables.push(data_load..data_load.wrapping_add(data_size as u16));
+        let mut source = format!("; Action! program, linked to run at ${:04X}\n\n", self.image_origin);
+        if !equates.is_empty() {
+            source.push_str(&equates);
+            source.push('\n');
+        }
+        source.push_str(&format!("    ORG ${:04X}\n", self.image_origin));
+        source.push_str(&disasm::source(&self.image, self.image_origin, &labels.0, &tables));
+        source
+    }
+}
+
+// What the program needs in all, from how deep main goes, with the startup code's call
+fn stack_needed(main: stack::Depth) -> String {
+    match main {
+        stack::Depth::Bytes(n) => format!("The program needs {} bytes of stack, and interrupt handlers more.", n + 2),
+        stack::Depth::AtLeast(n) => format!("The program needs {} bytes of stack, and system routines and interrupt handlers more.", n + 2),
+        stack::Depth::Recursive => "The program's stack has no bound: main reaches a recursive procedure.".to_string(),
+        stack::Depth::Unknown => "The program's stack could not be worked out.".to_string(),
+    }
+}
+
+// Registers, conditions, mnemonics and directives, which an assembler would not take
+// for a label
+const ASSEMBLER_WORDS: &[&str] = &[
+    "A", "B", "C", "D", "E", "H", "L", "I", "R", "F", "AF", "BC", "DE", "HL", "SP", "IX", "IY",
+    "IXH", "IXL", "IYH", "IYL", "XH", "XL", "YH", "YL", "NZ", "Z", "NC", "PO", "PE", "P", "M",
+    "ADC", "ADD", "AND", "BIT", "CALL", "CCF", "CP", "CPD", "CPDR", "CPI", "CPIR", "CPL", "DAA",
+    "DEC", "DI", "DJNZ", "EI", "EX", "EXX", "HALT", "IM", "IN", "INC", "IND", "INDR", "INI", "INIR",
+    "JP", "JR", "LD", "LDD", "LDDR", "LDI", "LDIR", "NEG", "NOP", "OR", "OTDR", "OTIR", "OUT",
+    "OUTD", "OUTI", "POP", "PUSH", "RES", "RET", "RETI", "RETN", "RL", "RLA", "RLC", "RLCA",
+    "RLD", "RR", "RRA", "RRC", "RRCA", "RRD", "RST", "SBC", "SCF", "SET", "SLA", "SLL", "SRA",
+    "SRL", "SUB", "XOR", "DB", "DW", "DS", "EQU", "ORG", "END",
+];
+
+// Labels for assembler source, each name used once
+#[derive(Default)]
+struct Labels(Vec<(u16, String)>);
+
+impl Labels {
+    // The label for name at addr: itself, after an underscore if an assembler word, and
+    // with a number after it if already used
+    fn add(&mut self, name: &str, addr: u16) -> String {
+        let base = match ASSEMBLER_WORDS.iter().any(|word| word.eq_ignore_ascii_case(name)) {
+            true => format!("_{}", name),
+            false => name.to_string(),
+        };
+        let mut label = base.clone();
+        for n in 2.. {
+            if !self.0.iter().any(|(_, used)| used.eq_ignore_ascii_case(&label)) {
+                break;
+            }
+            label = format!("{}_{}", base, n);
+        }
+        self.0.push((addr, label.clone()));
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,609 @@
+// The library's compile API against the pieces it is made of
+
+use super::*;
+use crate::emulator::{Console, Cpu, StopReason};
+use crate::format::{Format, Segment};
+use crate::runtime::{ConsoleBackend, Uart};
+use crate::test_support::{compile_program as compile_image, ORG};
+
+const SOURCE: &str = "\
+BYTE ARRAY buffer
+PROC show()
+PrintE()
+RETURN
+PROC main()
+show()
+RETURN
+";
+
+#[test]
+fn defaults_give_the_command_line_image() {
+    let output = compile_source(SOURCE, CompileOptions::default()).unwrap();
+    assert_eq!(output.origin, ORG);
+    assert_eq!(output.binary, compile_image(SOURCE, ORG).unwrap());
+    assert_eq!(output.warnings, ["line 1: ARRAY 'buffer' has no size; using the default of 256 elements"]);
+}
+
+#[test]
+fn jump_table_goes_before_the_runtime() {
+    let options = CompileOptions { jump_table: vec!["show".to_string()], ..Default::default() };
+    let output = compile_source(SOURCE, options).unwrap();
+    let show = output.codegen.procedure_address("show").unwrap();
+    assert_eq!(output.jump_table, [JumpTableEntry { name: "show".to_string(), entry: ORG + 3, target: show }]);
+    assert_eq!(output.binary[3..6], [0xC3, show as u8, (show >> 8) as u8]);
+    assert_eq!(output.runtime_start, ORG + 6);
+
+    let options = CompileOptions { jump_table: vec!["hide".to_string()], ..Default::default() };
+    assert!(matches!(compile_source(SOURCE, options), Err(CompileError::LinkError { .. })));
+
+    let options = CompileOptions { jump_table: vec!["show".to_string()], ..Default::default() };
+    let private = SOURCE.replace("PROC show", "PRIVATE PROC show");
+    assert!(matches!(compile_source(&private, options), Err(CompileError::LinkError { .. })));
+}
+
+#[test]
+fn relocatable_images_start_with_the_stub() {
+    let options = CompileOptions { relocatable: true, ..Default::default() };
+    let output = compile_source(SOURCE, options).unwrap();
+    assert_eq!(output.origin, ORG);
+    assert!(output.binary.len() > relocate::STUB_SIZE as usize + compile_image(SOURCE, ORG).unwrap().len());
+}
+
+#[test]
+fn images_over_the_size_limit_fail() {
+    let size = compile_source(SOURCE, CompileOptions::default()).unwrap().binary.len();
+    let options = CompileOptions { max_size: Some(size), ..Default::default() };
+    assert!(compile_source(SOURCE, options).is_ok());
+    let options = CompileOptions { max_size: Some(size - 1), ..Default::default() };
+    assert!(matches!(compile_source(SOURCE, options), Err(CompileError::LinkError { .. })));
+}
+
+#[test]
+fn images_past_the_top_of_memory_fail() {
+    let size = compile_source(SOURCE, CompileOptions::default()).unwrap().binary.len();
+    let top = (0x10000 - size) as u16;
+    assert!(compile_source(SOURCE, CompileOptions { origin: top, ..Default::default() }).is_ok());
+    for options in [
+        CompileOptions { origin: top + 1, ..Default::default() },
+        CompileOptions { origin: 0xFFFF, ..Default::default() },
+        CompileOptions { origin: top, relocatable: true, ..Default::default() },
+    ] {
+        assert!(matches!(compile_source(SOURCE, options), Err(CompileError::LinkError { .. })));
+    }
+}
+
+#[test]
+fn images_over_the_variables_fail() {
+    // buffer takes 0x2002-0x2101, after the runtime's two bytes
+    let size = compile_source(SOURCE, CompileOptions::default()).unwrap().binary.len() as u16;
+    for (origin, fits) in [(0x2000 - size, true), (0x2001 - size, false), (0x2101, false), (0x2102, true)] {
+        let options = CompileOptions { origin, ..Default::default() };
+        match compile_source(SOURCE, options) {
+            Ok(_) => assert!(fits, "{:04X}", origin),
+            Err(e) => {
+                assert!(!fits, "{:04X}", origin);
+                assert!(e.to_string().contains("overlaps the variables at $2000-$2101"), "{}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn definitions_apply_before_parsing() {
+    let options = CompileOptions { defines: vec![("Greet".to_string(), "PrintE".to_string())], ..Default::default() };
+    let source = "PROC main()\nGreet()\nRETURN\n";
+    assert_eq!(compile_source(source, options).unwrap().binary,
+               compile_image("PROC main()\nPrintE()\nRETURN\n", ORG).unwrap());
+
+    let options = CompileOptions { defines: vec![("Bad".to_string(), "\"open".to_string())], ..Default::default() };
+    assert!(matches!(compile_source(source, options), Err(CompileError::DefineError { .. })));
+}
+
+#[test]
+fn listing_maps_code_to_modules() {
+    let source = "\
+MODULE
+PRIVATE BYTE count
+PROC bump()
+count = count + 1
+RETURN
+MODULE
+CARD total
+PROC main()
+bump()
+RETURN
+";
+    let output = compile_source(source, CompileOptions::default()).unwrap();
+    let listing = output.listing(&ListingOptions::default());
+    let bump = output.codegen.procedure_address("bump").unwrap();
+    let main = output.codegen.procedure_address("main").unwrap();
+    assert!(listing.contains(&format!(";   ${:04X}-${:04X} MODULE at line 1: bump\n", bump, main - 1)), "{}", listing);
+    assert!(listing.contains(&format!(
+        "; Module sizes:\n;   start: 0 bytes of code, 0 bytes of globals\n;   MODULE at line 1: {} bytes of code, 1 bytes of globals\n",
+        main - bump)), "{}", listing);
+    assert!(listing.contains(";   MODULE at line 6: 3 bytes of code, 2 bytes of globals\n"), "{}", listing);
+
+    assert!(!compile_source(SOURCE, CompileOptions::default()).unwrap()
+        .listing(&ListingOptions::default()).contains("; Module map:"));
+}
+
+#[test]
+fn patches_replace_one_procedure_in_place() {
+    let game = "\
+BYTE count
+PROC Stars(BYTE n)
+WHILE n > 0 DO PutD('*') n = n - 1 OD
+RETURN
+PROC Later()
+Print(\"later\")
+count = count + 1
+PutD('.')
+PutD('.')
+RETURN
+PROC main()
+Stars(3)
+Later()
+RETURN
+";
+    let options = CompileOptions::default();
+    let (program, _) = parse(tokenize(game, &options).unwrap(), &options).unwrap();
+    let built = compile_program(&program, &options).unwrap();
+    let options = CompileOptions { imports: vec![SymbolFile::export(&program, &built, &options)], ..options };
+    let run = |image: &[u8]| {
+        let mut cpu = Cpu::new();
+        cpu.load(ORG, image);
+        cpu.pc = ORG;
+        let mut console = Console::new();
+        assert_eq!(cpu.run(&mut console, Some(1_000_000)), StopReason::Halted);
+        (String::from_utf8(console.output).unwrap(), cpu.read(built.codegen.global_address("count").unwrap()))
+    };
+    assert_eq!(run(&built.binary), ("***later..".to_string(), 1));
+
+    let patch = |source: &str| {
+        let (program, _) = parse(tokenize(source, &options).unwrap(), &options).unwrap();
+        compile_patch(&program, "Later", &built.binary, &options)
+    };
+    let patched = patch("PROC Later()\nPrint(\"now\")\ncount = 5\nStars(1)\nRETURN\n").unwrap();
+    assert_eq!(patched.len(), built.binary.len());
+    assert_eq!(run(&patched), ("***now*".to_string(), 5));
+
+    let too_big = patch("PROC Later()\nPrint(\"much longer than it was\")\nRETURN\n");
+    assert!(too_big.unwrap_err().to_string().contains("more than the"));
+    assert!(patch("PROC main()\nRETURN\n").is_err());
+}
+
+#[test]
+fn pinned_options_give_the_test_image() {
+    let output = compile_source(SOURCE, CompileOptions::pinned()).unwrap();
+    assert_eq!(output.binary, compile_image(SOURCE, ORG).unwrap());
+}
+
+#[test]
+fn cartridges_map_in_their_second_half_and_copy_data_to_ram() {
+    let options = CompileOptions {
+        origin: 0x4010,
+        runtime: RuntimeOptions { console: ConsoleBackend::Msx, ram_start: 0xC000, cartridge: true, ..Default::default() },
+        exit: codegen::Exit::Return,
+        ..Default::default()
+    };
+    let output = compile_source("PROC main()\nPrint(\"hi\")\nRETURN\n", options).unwrap();
+    let rom = Format::MsxRom.wrap(&output.binary, output.origin, "prog");
+
+    // The BIOS: a slot in page 1 and ENASLT noting what it was asked for
+    let mut cpu = Cpu::new();
+    cpu.load(0x0000, &[0x76]);  // HALT, where INIT returns to
+    cpu.load(0x0024, &[0x32, 0x00, 0xF0, 0x7C, 0x32, 0x01, 0xF0, 0xC9]);  // LD (F000),A  LD A,H  LD (F001),A  RET
+    cpu.load(0x00A2, &[0xD3, 0x00, 0xC9]);  // CHPUT: OUT (0),A  RET
+    cpu.load(0x0138, &[0x3E, 0x04, 0xC9]);  // RSLREG: LD A,04h  RET
+    cpu.load(0x4000, &rom);
+    cpu.sp = 0xF380 - 2;
+    cpu.pc = cpu.read_word(0x4002);
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(100_000)), StopReason::Halted);
+    assert_eq!(console.output, b"hi");
+    assert_eq!([cpu.read(0xF000), cpu.read(0xF001)], [0x01, 0x80]);
+    assert!(cpu.mem[0xC000..0xC100].windows(2).any(|w| w == b"hi"), "the string is not in RAM");
+}
+
+#[test]
+fn incbin_files_go_in_the_data_section() {
+    let dir = std::env::temp_dir().join(format!("kz80_action_incbin_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("sprite-1.bin"), [1, 2, 3, 4, 5]).unwrap();
+    std::fs::write(dir.join("pal.bin"), [9, 8]).unwrap();
+    let source = "\
+INCBIN \"sprite-1.bin\"
+INCBIN colors = \"pal.bin\"
+PROC main()
+PrintB(sprite_1(4) + colors(0))
+RETURN
+";
+    let options = CompileOptions { include_dir: Some(dir.clone()), ..CompileOptions::pinned() };
+    let output = compile_source(source, options.clone());
+    let missing = compile_source("INCBIN \"none.bin\"\nPROC main()\nRETURN\n", options).err().unwrap().to_string();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let output = output.unwrap();
+
+    let at = |name: &str| (output.codegen.global_address(name).unwrap() - output.origin) as usize;
+    assert_eq!(output.binary[at("sprite_1")..][..5], [1, 2, 3, 4, 5]);
+    assert_eq!(output.binary[at("sprite_1_len")..][..2], [5, 0]);
+    assert_eq!(output.binary[at("colors")..][..2], [9, 8]);
+    assert_eq!(output.binary[at("colors_len")..][..2], [2, 0]);
+    let mut cpu = Cpu::new();
+    cpu.load(output.origin, &output.binary);
+    cpu.pc = output.origin;
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(100_000)), StopReason::Halted);
+    assert_eq!(console.output, b"14");
+    assert!(missing.contains("cannot read INCBIN file"), "{}", missing);
+}
+
+#[test]
+fn assembly_names_what_the_image_holds() {
+    let source = "\
+BYTE count = 3
+CARD total
+PROC Add(BYTE x)
+total = total + x
+RETURN
+PROC main()
+Add(count)
+RETURN
+";
+    let options = CompileOptions::default();
+    let (program, _) = parse(tokenize(source, &options).unwrap(), &options).unwrap();
+    let output = compile_program(&program, &options).unwrap();
+    let asm = output.assembly(&program);
+    let total = output.codegen.global_address("total").unwrap();
+    assert!(asm.contains(&format!("total           EQU ${:04X}  ; CARD\n", total)), "{}", asm);
+    assert!(asm.contains(&format!("    ORG ${:04X}\n    JP start\n", ORG)), "{}", asm);
+    assert!(asm.contains("\nstart:\n"), "{}", asm);
+    assert!(asm.contains("\nPrintB:\n"), "{}", asm);
+    assert!(asm.contains("\n_Add:\n"), "{}", asm);           // ADD is a mnemonic
+    assert!(asm.contains("    LD HL,(total)\n"), "{}", asm);
+    assert!(asm.contains("\nmain:\n    LD A,(count)\n    JR _Add\n"), "{}", asm);
+    assert!(asm.ends_with("\ncount:\n    DB $03\n"), "{}", asm);
+}
+
+// loader/chunkload.asm, assembled
+const CHUNK_LOADER: [u8; 0x45] = [
+    0x1E, 0x00, 0xCD, 0x35, 0xFF, 0x6F, 0xCD, 0x35, 0xFF, 0x67, 0xCD, 0x35, 0xFF, 0x4F, 0xCD, 0x35,
+    0xFF, 0x47, 0x78, 0xB1, 0x28, 0x17, 0xCD, 0x35, 0xFF, 0x77, 0x23, 0x0B, 0x78, 0xB1, 0x20, 0xF6,
+    0xCD, 0x35, 0xFF, 0x7B, 0xB7, 0x28, 0xD9, 0x3E, 0x3F, 0xCD, 0x42, 0xFF, 0x76, 0xCD, 0x35, 0xFF,
+    0x7B, 0xB7, 0x20, 0xF3, 0xE9, 0xDB, 0x01, 0xE6, 0x01, 0x28, 0xFA, 0xDB, 0x00, 0x57, 0x83, 0x5F,
+    0x7A, 0xC9, 0xD3, 0x00, 0xC9,
+];
+const CHUNK_LOADER_ORG: u16 = 0xFF00;
+
+#[test]
+fn chunk_loader_source_gives_the_tested_bytes() {
+    let source = include_str!("../../loader/chunkload.asm");
+    let lines: Vec<&str> = source.lines()
+        .map(|line| line.split(';').next().unwrap().trim_end())
+        .filter(|line| !line.is_empty() && !line.trim_start().starts_with("ORG"))
+        .collect();
+    assert!(source.contains(&format!("    ORG ${:04X}\n", CHUNK_LOADER_ORG)));
+
+    // Labels are where the instructions before them end
+    let mut labels = Vec::new();
+    let mut offset = 0;
+    for line in &lines {
+        match line.strip_suffix(':') {
+            Some(label) => labels.push((CHUNK_LOADER_ORG + offset as u16, label.to_string())),
+            None => offset += disasm::disassemble(&CHUNK_LOADER[offset..], 0, &|_| None).1,
+        }
+    }
+    assert_eq!(offset, CHUNK_LOADER.len());
+
+    let names = |addr: u16| labels.iter().find(|&&(a, _)| a == addr).map(|(_, name)| name.clone());
+    let mut offset = 0;
+    for line in lines.iter().filter(|line| !line.ends_with(':')) {
+        let addr = CHUNK_LOADER_ORG + offset as u16;
+        let (text, len) = disasm::disassemble(&CHUNK_LOADER[offset..], addr, &names);
+        assert_eq!(line.trim(), text, "at ${:04X}", addr);
+        offset += len;
+    }
+}
+
+#[test]
+fn chunks_load_code_and_data_in_one_transfer() {
+    let source = "BYTE ARRAY msg = \"loaded\"\nPROC main()\nPrint(msg)\nRETURN\n";
+    let options = CompileOptions { data_address: Some(0x3000), data_loaded: true, ..CompileOptions::pinned() };
+    let output = compile_source(source, options).unwrap();
+    let segments = output.segments();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[1], Segment { name: "data", address: 0x3000, bytes: b"loaded\0".to_vec(), run: None });
+    assert!(!output.binary.windows(6).any(|w| w == b"loaded"), "the data is not in the image");
+    let file = crate::format::chunks(&segments, output.origin);
+
+    let load = |file: &[u8]| {
+        let mut cpu = Cpu::new();
+        cpu.load(CHUNK_LOADER_ORG, &CHUNK_LOADER);
+        cpu.pc = CHUNK_LOADER_ORG;
+        cpu.sp = 0xFE00;
+        let mut console = Console::new();
+        console.input.extend(file);
+        assert_eq!(cpu.run(&mut console, Some(1_000_000)), StopReason::Halted);
+        console.output
+    };
+    assert_eq!(load(&file), b"loaded");
+
+    let mut corrupt = file.clone();
+    corrupt[10] ^= 0x01;
+    assert_eq!(load(&corrupt), b"?");
+}
+
+#[test]
+fn listing_gives_the_cost_of_each_line() {
+    let source = "BYTE i\nPROC main()\ni = 5\nRETURN\n";
+    let output = compile_source(source, CompileOptions::pinned()).unwrap();
+    let listing = output.listing(&ListingOptions::default());
+    let main = output.codegen.procedure_address("main").unwrap();
+    assert!(listing.contains(&format!(
+        "; line 3 in main: 5 bytes, 20T\nMAIN:\n{:04X}: 3E 05        LD A,$05             ; 2 bytes, 7T\n", main)), "{}", listing);
+}
+
+#[test]
+fn inlined_put_d_prints_what_the_call_does() {
+    let source = "PROC main()\nBYTE c\nFOR c = 'a' TO 'e' DO PutD(c) OD\nPrintE()\nRETURN\n";
+    let options = CompileOptions { opt_for: Some(codegen::OptFor::Speed), ..Default::default() };
+    let output = compile_source(source, options).unwrap();
+    assert!(output.binary.len() > compile_source(source, CompileOptions::default()).unwrap().binary.len());
+    let mut cpu = Cpu::new();
+    cpu.load(output.origin, &output.binary);
+    cpu.pc = output.origin;
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(100_000)), StopReason::Halted);
+    assert_eq!(console.output, b"abcde\r\n");
+}
+
+#[test]
+fn overflow_checks_stop_at_the_operation() {
+    let source = "BYTE b = 250\nPROC main()\nWHILE 1 DO b = b + 1 PrintB(b) PrintE() OD\nRETURN\n";
+    let run = |overflow| {
+        let output = compile_source(source, CompileOptions { overflow, ..Default::default() }).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load(output.origin, &output.binary);
+        cpu.pc = output.origin;
+        let mut console = Console::new();
+        let stop = cpu.run(&mut console, Some(200_000));
+        (output, stop, String::from_utf8_lossy(&console.output).into_owned())
+    };
+
+    let (output, stop, text) = run(codegen::Overflow::Check);
+    assert_eq!(stop, StopReason::Halted);
+    let (printed, trap) = text.split_once("?Overflow at $").expect("the trap runs");
+    assert_eq!(printed, "251\r\n252\r\n253\r\n254\r\n255\r\n");
+    let at = u16::from_str_radix(trap.trim_end(), 16).unwrap();
+    let offset = (at - output.origin) as usize;
+    assert_eq!(output.binary[offset], 0xDC, "{}", text);  // CALL C
+    assert_eq!(output.binary[offset - 1], 0x80, "{}", text);  // after ADD A,B
+
+    let (_, stop, text) = run(codegen::Overflow::Wrap);
+    assert_ne!(stop, StopReason::Halted);
+    assert!(text.contains("255\r\n0\r\n1\r\n"), "{}", text);
+}
+
+#[test]
+fn procedures_nothing_calls_are_left_out() {
+    let source = "\
+PROC helper()
+RETURN
+PROC unused()
+helper()
+RETURN
+PROC SysInit()
+RETURN
+PROC PutD(BYTE c) FASTCALL
+RETURN
+PROC Exported()
+RETURN
+PROC handler = $5000()
+RETURN
+PROC main()
+RETURN
+";
+    let options = CompileOptions { jump_table: vec!["Exported".to_string()], ..Default::default() };
+    let output = compile_source(source, options.clone()).unwrap();
+    let placed = |name: &str| output.codegen.procedure_address(name).is_some();
+    assert!(!placed("unused") && !placed("helper"));
+    assert!(["main", "SysInit", "PutD", "Exported", "handler"].into_iter().all(placed));
+
+    let kept = compile_source(source, CompileOptions { keep_unused: true, ..options }).unwrap();
+    assert!(kept.codegen.procedure_address("unused").is_some() && kept.codegen.procedure_address("helper").is_some());
+}
+
+#[test]
+fn strict_compat_evaluates_with_the_original_precedence() {
+    let source = "BYTE a = 1\nPROC main()\nPrintB(1 + 2 LSH 1) PrintE()\nPrintB(a ! 1 % 1) PrintE()\nRETURN\n";
+    let run = |compat| {
+        let output = compile_source(source, CompileOptions { compat, ..Default::default() }).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load(output.origin, &output.binary);
+        cpu.pc = output.origin;
+        let mut console = Console::new();
+        assert_eq!(cpu.run(&mut console, Some(100_000)), StopReason::Halted);
+        String::from_utf8_lossy(&console.output).into_owned()
+    };
+    assert_eq!(run(parser::Compat::Modern), "6\r\n1\r\n");
+    assert_eq!(run(parser::Compat::Strict), "5\r\n0\r\n");
+}
+
+#[test]
+fn write_only_variables_are_reported_and_dropped_on_request() {
+    let source = "\
+BYTE ARRAY log(4)
+CARD total
+BYTE count
+BYTE last
+FUNC BYTE next()
+RETURN(7)
+PROC main()
+BYTE i
+FOR i = 0 TO 3 DO log(i) = i count = count + 1 OD
+total = 500
+last = next()
+PrintB(count)
+RETURN
+";
+    let kept = compile_source(source, CompileOptions::default()).unwrap();
+    assert_eq!(kept.warnings, ["written but never read: log, total, last"]);
+
+    let dropped = compile_source(source, CompileOptions { drop_write_only: true, ..Default::default() }).unwrap();
+    assert_eq!(dropped.warnings, [
+        "written but never read, left out: log, total",
+        "written but never read, kept for the calls in their values: last",
+    ]);
+    assert!(dropped.binary.len() < kept.binary.len());
+    assert!(dropped.codegen.global_address("log").is_none() && dropped.codegen.global_address("last").is_some());
+    assert_eq!(dropped.codegen.variables_end(), kept.codegen.variables_end() - 6);
+
+    let mut cpu = Cpu::new();
+    cpu.load(dropped.origin, &dropped.binary);
+    cpu.pc = dropped.origin;
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(100_000)), StopReason::Halted);
+    assert_eq!(console.output, b"4");
+}
+
+#[test]
+fn print_c_prints_all_sixteen_bits() {
+    let source = "PROC main()\nCARD c\nBYTE b = 200\nc = 1234\nPrintC(c) PrintE()\nPrintCE(1234)\nPrintC(b) PrintE()\nRETURN\n";
+    assert_eq!(printed(source), "1234\r\n1234\r\n200\r\n");
+}
+
+#[test]
+fn multiplies_call_the_runtime() {
+    let source = "\
+PROC main()
+BYTE n = 7
+CARD c = 1000
+BYTE r
+r = n * 3
+PrintB(r) PrintE()
+PrintB(n * 40) PrintE()
+c = c * 3
+PrintC(c) PrintE()
+PrintC(c * n) PrintE()
+RETURN
+";
+    assert_eq!(printed(source), "21\r\n24\r\n3000\r\n21000\r\n");
+    assert!(compile_source(source, CompileOptions { verify: true, ..Default::default() }).is_ok());
+}
+
+#[test]
+fn entry_names_the_procedure_to_run() {
+    let source = "PROC main()\nPrintB(1)\nRETURN\nPROC other()\nPrintB(2)\nRETURN\n";
+    let output = compile_source(source, CompileOptions { entry: Some("other".to_string()), ..Default::default() }).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load(output.origin, &output.binary);
+    cpu.pc = output.origin;
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(1_000_000)), StopReason::Halted);
+    assert_eq!(String::from_utf8_lossy(&console.output), "2");
+    assert!(output.codegen.procedure_address("main").is_none());
+}
+
+#[test]
+fn options_are_checked_whoever_builds_them() {
+    let error = |runtime: RuntimeOptions, origin: u16| {
+        let options = CompileOptions { origin, runtime, ..Default::default() };
+        compile_source("PROC main()\nRETURN\n", options).err().map(|e| e.to_string())
+    };
+    let uart = |runtime: RuntimeOptions| error(runtime, 0x4200);
+    assert_eq!(uart(RuntimeOptions { clock_divide: 8, ..Default::default() }).as_deref(),
+               Some("Error: --uart-divide must be 1, 16 or 64, found 8"));
+    assert!(uart(RuntimeOptions { tx_buffer: 12, uart: Uart::Acia, ..Default::default() }).is_some());
+    assert!(uart(RuntimeOptions { tx_buffer: 16, ..Default::default() }).is_some_and(|e| e.contains("transmitter status")));
+    assert_eq!(uart(RuntimeOptions { tx_buffer: 16, uart: Uart::Acia, ..Default::default() }), None);
+    assert!(uart(RuntimeOptions { eval_stack: 7, ..Default::default() }).is_some());
+    let cartridge = RuntimeOptions { console: ConsoleBackend::Msx, ram_start: 0xC000, cartridge: true, ..Default::default() };
+    assert!(error(cartridge.clone(), 0x4200).is_some_and(|e| e.contains("0x4010 or 0x8010")));
+    assert_eq!(error(cartridge, 0x4010), None);
+    let arrays = CompileOptions { default_array_size: 0, ..Default::default() };
+    assert!(compile_source("PROC main()\nRETURN\n", arrays).is_err());
+}
+
+#[test]
+fn every_semantic_error_is_given() {
+    let errors = match compile_source("PROC main()\nx = 1\ny = 2\nRETURN\n", CompileOptions::default()) {
+        Err(CompileError::Errors { errors }) => errors,
+        other => panic!("{:?}", other.err()),
+    };
+    let lines: Vec<Option<usize>> = errors.iter().map(CompileError::line).collect();
+    assert_eq!(lines, [Some(2), Some(3)]);
+    // One on its own is itself
+    let one = compile_source("PROC main()\nx = 1\nRETURN\n", CompileOptions::default());
+    assert!(matches!(one, Err(CompileError::SemanticError { line: 2, .. })));
+}
+
+#[test]
+fn stores_and_loads_go_through_pointers() {
+    // A CARD holding an address points at a byte, a VAR parameter at its own type
+    let source = "\
+BYTE x = 1
+CARD y = 2
+CARD p
+PROC Set(CARD VAR v)
+^v = 500
+RETURN
+PROC main()
+p = @x
+^p = 7
+PrintB(x) PrintE()
+PrintB(^p + 1) PrintE()
+Set(y)
+PrintC(y) PrintE()
+RETURN
+";
+    assert_eq!(printed(source), "7\r\n8\r\n500\r\n");
+}
+
+// What a program prints when run to its end
+fn printed(source: &str) -> String {
+    let output = compile_source(source, CompileOptions::default()).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load(output.origin, &output.binary);
+    cpu.pc = output.origin;
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(1_000_000)), StopReason::Halted);
+    String::from_utf8_lossy(&console.output).into_owned()
+}
+
+// A program nesting each kind of level, from a flat chain of operators to blocks, depth deep
+fn nested(depth: usize) -> Vec<String> {
+    let expression = |e: String| format!("PROC main()\nCARD c\nc = {}\nRETURN\n", e);
+    let block = |open: &str, close: &str| {
+        format!("PROC main()\nBYTE c\n{}c = 1\n{}RETURN\n", open.repeat(depth), close.repeat(depth))
+    };
+    vec![
+        expression(vec!["c"; depth + 1].join("+")),
+        expression(format!("{}c{}", "(".repeat(depth), ")".repeat(depth))),
+        expression(format!("{}c", "-".repeat(depth))),
+        block("IF c THEN\n", "FI\n"),
+        block("WHILE c DO\n", "OD\n"),
+        block("FOR c = 1 TO 2 DO\n", "OD\n"),
+    ]
+}
+
+#[test]
+fn nesting_counts_one_level_each() {
+    for source in nested(200) {
+        assert!(compile_source(&source, CompileOptions::default()).is_ok(), "{}", source);
+    }
+    for source in nested(201) {
+        match compile_source(&source, CompileOptions::default()) {
+            Err(CompileError::ParserError { .. }) => {}
+            other => panic!("{:?} for {}", other.map(|output| output.binary.len()), source),
+        }
+    }
+}
+
+#[test]
+fn a_long_flat_chain_is_an_error() {
+    let source = format!("PROC main()\nCARD c\nc = {}\nRETURN\n", vec!["1"; 10_000].join("+"));
+    match compile_source(&source, CompileOptions::default()) {
+        Err(CompileError::ParserError { line: 3, message }) => assert!(message.contains("limit of 200"), "{}", message),
+        other => panic!("{:?}", other.map(|output| output.binary.len())),
+    }
+}
@@ -0,0 +1,131 @@
+// Differential testing: run compiled programs on two Z80 cores and compare the results.
+//
+// The reference core is an external emulator named by the KZ80_REFERENCE_EMULATOR
+// environment variable. It is run as `<emulator> <image.bin> <memory.bin>` with console
+// input on stdin, must print console output on stdout, and may write a 64K memory dump
+// to <memory.bin> when the program halts. The comparison is ignored by a plain
+// `cargo test`; set the variable and run `cargo test -- --ignored` to make it.
+
+use crate::emulator::{Console, Cpu, StopReason};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const REFERENCE_ENV: &str = "KZ80_REFERENCE_EMULATOR";
+const ORG: u16 = 0x0000;
+const MAX_CYCLES: u64 = 50_000_000;
+
+#[derive(Debug)]
+pub struct RunResult {
+    pub output: Vec<u8>,
+    pub memory: Option<Vec<u8>>,  // Final 64K memory, if the core can provide it
+}
+
+/// A Z80 core that can run an image loaded at address 0 until it halts
+pub trait Z80Core {
+    fn name(&self) -> String;
+    fn run(&mut self, image: &[u8], input: &[u8]) -> Result<RunResult, String>;
+}
+
+/// The emulator built into the compiler
+pub struct Builtin;
+
+impl Z80Core for Builtin {
+    fn name(&self) -> String {
+        "built-in".to_string()
+    }
+
+    fn run(&mut self, image: &[u8], input: &[u8]) -> Result<RunResult, String> {
+        let mut cpu = Cpu::new();
+        cpu.load(ORG, image);
+        cpu.pc = ORG;
+        let mut console = Console::new();
+        console.input.extend(input);
+        match cpu.run(&mut console, Some(MAX_CYCLES)) {
+            StopReason::Halted => Ok(RunResult { output: console.output, memory: Some(cpu.mem) }),
+            StopReason::CycleLimit => Err(format!("no HALT after {} cycles", MAX_CYCLES)),
+        }
+    }
+}
+
+/// An emulator run as a separate process
+pub struct External {
+    pub command: PathBuf,
+}
+
+impl Z80Core for External {
+    fn name(&self) -> String {
+        self.command.display().to_string()
+    }
+
+    fn run(&mut self, image: &[u8], input: &[u8]) -> Result<RunResult, String> {
+        let dir = TempDir::new()?;
+        let image_path = dir.0.join("image.bin");
+        let memory_path = dir.0.join("memory.bin");
+        fs::write(&image_path, image).map_err(|e| e.to_string())?;
+
+        let mut child = Command::new(&self.command)
+            .arg(&image_path)
+            .arg(&memory_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("cannot run {}: {}", self.name(), e))?;
+        child.stdin.take().unwrap().write_all(input).map_err(|e| e.to_string())?;
+        let out = child.wait_with_output().map_err(|e| e.to_string())?;
+        if !out.status.success() {
+            return Err(format!("{} exited with {}", self.name(), out.status));
+        }
+        let memory = fs::read(&memory_path).ok();
+        Ok(RunResult { output: out.stdout, memory })
+    }
+}
+
+// A directory of its own for one run, removed however the run ends
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Result<TempDir, String> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let run = RUNS.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("kz80_diff_{}_{}", std::process::id(), run));
+        fs::create_dir_all(&path).map_err(|e| e.to_string())?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run an image on both cores and describe the first difference, if any
+pub fn compare(a: &mut dyn Z80Core, b: &mut dyn Z80Core, image: &[u8], input: &[u8]) -> Result<(), String> {
+    let ra = a.run(image, input)?;
+    let rb = b.run(image, input)?;
+    if ra.output != rb.output {
+        return Err(format!(
+            "console output differs\n  {}: {:?}\n  {}: {:?}",
+            a.name(), String::from_utf8_lossy(&ra.output),
+            b.name(), String::from_utf8_lossy(&rb.output),
+        ));
+    }
+    // Memory is only compared when both cores report all of it
+    if let (Some(ma), Some(mb)) = (&ra.memory, &rb.memory) {
+        if ma.len() == mb.len() {
+            if let Some(addr) = ma.iter().zip(mb).position(|(x, y)| x != y) {
+                return Err(format!(
+                    "memory differs at ${:04X}: {} has ${:02X}, {} has ${:02X}",
+                    addr, a.name(), ma[addr], b.name(), mb[addr],
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
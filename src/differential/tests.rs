@@ -0,0 +1,83 @@
+// The examples on the built-in core and the reference, and how differences are reported
+
+use super::*;
+use crate::test_support::compile_program;
+use std::path::Path;
+
+// Examples written in syntax the compiler does not take yet: declarations separated by
+// commas and RETURN inside a block. They are left out of the comparison, with a note;
+// any other example that does not compile fails it.
+const UNSUPPORTED: [&str; 3] = ["factorial.act", "fibonacci.act", "sieve.act"];
+
+// Example programs, as (name, image or why it does not compile)
+fn example_images() -> Vec<(String, Result<Vec<u8>, String>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "act"))
+        .collect();
+    paths.sort();
+    paths.into_iter()
+        .map(|p| {
+            let name = p.file_name().unwrap().to_string_lossy().into_owned();
+            let image = fs::read_to_string(&p)
+                .map_err(|e| e.to_string())
+                .and_then(|source| compile_program(&source, ORG).map_err(|e| e.to_string()));
+            (name, image)
+        })
+        .collect()
+}
+
+#[test]
+#[ignore = "needs a reference emulator named by KZ80_REFERENCE_EMULATOR"]
+fn examples_match_reference_emulator() {
+    let command = std::env::var_os(REFERENCE_ENV)
+        .unwrap_or_else(|| panic!("{} must name the reference emulator", REFERENCE_ENV));
+    let mut reference = External { command: command.into() };
+    let mut failures = Vec::new();
+    for (name, image) in example_images() {
+        let result = match image {
+            Ok(image) => compare(&mut Builtin, &mut reference, &image, b""),
+            Err(e) if UNSUPPORTED.contains(&name.as_str()) => {
+                eprintln!("{}: not compared, since it does not compile: {}", name, e);
+                continue;
+            }
+            Err(e) => Err(format!("does not compile: {}", e)),
+        };
+        if let Err(e) = result {
+            failures.push(format!("{}: {}", name, e));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn examples_compile() {
+    // Every example but the unsupported ones compiles, so all of them are compared
+    let failures: Vec<String> = example_images().into_iter()
+        .filter(|(name, _)| !UNSUPPORTED.contains(&name.as_str()))
+        .filter_map(|(name, image)| image.err().map(|e| format!("{}: {}", name, e)))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+// A core that prints fixed text, to check that differences are reported
+struct Fixed(&'static [u8]);
+
+impl Z80Core for Fixed {
+    fn name(&self) -> String {
+        "fixed".to_string()
+    }
+
+    fn run(&mut self, _image: &[u8], _input: &[u8]) -> Result<RunResult, String> {
+        Ok(RunResult { output: self.0.to_vec(), memory: None })
+    }
+}
+
+#[test]
+fn compare_reports_output_difference() {
+    let image = compile_program("PROC main()\nPutD('A')\nRETURN\n", ORG).unwrap();
+    assert_eq!(compare(&mut Builtin, &mut Fixed(b"A"), &image, b""), Ok(()));
+    let err = compare(&mut Builtin, &mut Fixed(b"B"), &image, b"").unwrap_err();
+    assert!(err.contains("console output differs"), "{}", err);
+}
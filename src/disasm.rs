@@ -0,0 +1,462 @@
+// A Z80 disassembler for listings. Instructions are decoded from the fields of the
+// opcode byte (x = bits 7-6, y = bits 5-3, z = bits 2-0, with p and q splitting y),
+// the same way for the unprefixed, CB, ED and IX/IY forms. Addresses that have a
+// name are shown by name; numbers are in $ hex as in Action! sources.
+
+use std::ops::Range;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+/// Names for addresses, such as routine entry points
+pub type Names<'a> = &'a dyn Fn(u16) -> Option<String>;
+
+/// The instruction at the start of bytes, which is at address pc, and its length.
+/// Bytes that end partway through an instruction are shown as DB
+pub fn disassemble(bytes: &[u8], pc: u16, names: Names) -> (String, usize) {
+    let mut decoder = Decoder { bytes, pos: 0, pc, index: None, names };
+    match decoder.decode() {
+        Some(text) => (text, decoder.pos),
+        None => (format!("DB ${:02X}", bytes.first().copied().unwrap_or(0)), 1),
+    }
+}
+
+/// Listing lines for code at origin: a label line for each named address, then the
+/// address, bytes and instruction with its size and T-states, or DB for the bytes of
+/// a table
+pub fn listing(code: &[u8], origin: u16, names: Names, tables: &[Range<u16>]) -> String {
+    let mut text = String::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let addr = origin.wrapping_add(offset as u16);
+        if let Some(name) = names(addr) {
+            text.push_str(&format!("{}:\n", name));
+        }
+        let (instruction, len) = match tables.iter().find(|t| t.contains(&addr)) {
+            Some(table) => {
+                let len = ((table.end - addr) as usize).min(8).min(code.len() - offset);
+                (db(&code[offset..offset + len]), len)
+            }
+            None => {
+                let (instruction, len) = disassemble(&code[offset..], addr, names);
+                (format!("{:<20} ; {}", instruction, cost(len, t_states(&code[offset..]))), len)
+            }
+        };
+        let bytes: Vec<String> = code[offset..offset + len].iter().map(|b| format!("{:02X}", b)).collect();
+        text.push_str(&format!("{:04X}: {:<12} {}\n", addr, bytes.join(" "), instruction));
+        offset += len;
+    }
+    text
+}
+
+/// Assembler source for code at origin, in the syntax sjasmplus and z80asm take: a label
+/// line for each of labels at an address, then the instruction indented, or DB for the
+/// bytes of a table. So that the source assembles to the same bytes, an instruction that
+/// would run over a label, or whose text has another encoding, is given as DB too
+pub fn source(code: &[u8], origin: u16, labels: &[(u16, String)], tables: &[Range<u16>]) -> String {
+    let labels = with_jump_targets(code, origin, labels, tables);
+    let labels = labels.as_slice();
+    let names = |addr: u16| labels.iter().find(|&&(a, _)| a == addr).map(|(_, name)| name.clone());
+    let mut text = String::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let addr = origin.wrapping_add(offset as u16);
+        for (_, name) in labels.iter().filter(|&&(a, _)| a == addr) {
+            text.push_str(&format!("{}:\n", name));
+        }
+        // Nothing may run past the next label
+        let next = labels.iter().map(|&(a, _)| a.wrapping_sub(addr) as usize).filter(|&d| d > 0).min();
+        let end = next.map_or(code.len(), |d| code.len().min(offset + d));
+        let (instruction, len) = match tables.iter().find(|t| t.contains(&addr)) {
+            Some(table) => {
+                let len = ((table.end - addr) as usize).min(8).min(end - offset);
+                (db(&code[offset..offset + len]), len)
+            }
+            None => {
+                let (instruction, len) = disassemble(&code[offset..end], addr, &names);
+                if is_alias(&code[offset..offset + len]) { (db(&code[offset..offset + len]), len) } else { (instruction, len) }
+            }
+        };
+        text.push_str(&format!("    {}\n", instruction));
+        offset += len;
+    }
+    text
+}
+
+/// Size and T-states for a listing: "3 bytes, 10T", or "2 bytes, 7/12T" for an
+/// instruction or run of them that may take more or less time
+pub fn cost(len: usize, (least, most): (u32, u32)) -> String {
+    let size = if len == 1 { "1 byte".to_string() } else { format!("{} bytes", len) };
+    match least == most {
+        true => format!("{}, {}T", size, least),
+        false => format!("{}, {}/{}T", size, least, most),
+    }
+}
+
+/// T-states the instruction at the start of bytes takes, the least and the most: a
+/// conditional jump, call or return takes longer when it goes, a repeated block
+/// instruction when it repeats, and DJNZ when it loops
+pub fn t_states(bytes: &[u8]) -> (u32, u32) {
+    let prefixes = bytes.iter().take_while(|&&b| b == 0xDD || b == 0xFD).count();
+    let op = bytes.get(prefixes).copied().unwrap_or(0x00);
+    let prefixed = 4 * prefixes as u32;
+    let next = bytes.get(prefixes + 1).copied().unwrap_or(0x00);
+    let (least, most) = match op {
+        // After a prefix the opcode follows the displacement, and the operand is (IX+d)
+        0xCB if prefixes > 0 => {
+            let op = bytes.get(prefixes + 2).copied().unwrap_or(0x00);
+            return if op >> 6 == 1 { (prefixed + 16, prefixed + 16) } else { (prefixed + 19, prefixed + 19) };
+        }
+        0xCB => match (next >> 6, next & 7 == 6) {
+            (1, true) => (12, 12),
+            (_, true) => (15, 15),
+            _ => (8, 8),
+        },
+        0xED => ed_t_states(next),
+        _ => {
+            // (HL) becomes (IX+d), which takes the displacement and the sum as well
+            let (least, most, memory) = unprefixed_t_states(op);
+            let indexed = match (prefixes > 0 && memory, op) {
+                (false, _) => 0,
+                (true, 0x36) => 5,  // LD (IX+d),n
+                (true, _) => 8,
+            };
+            (least + indexed, most + indexed)
+        }
+    };
+    (least + prefixed, most + prefixed)
+}
+
+/// T-states of the instructions in code, each run once, the least and the most
+pub fn run_t_states(code: &[u8]) -> (u32, u32) {
+    let (mut least, mut most) = (0, 0);
+    let mut offset = 0;
+    while offset < code.len() {
+        let (t_least, t_most) = t_states(&code[offset..]);
+        least += t_least;
+        most += t_most;
+        offset += disassemble(&code[offset..], 0, &|_| None).1;
+    }
+    (least, most)
+}
+
+// T-states of an unprefixed instruction, least and most, and whether it reads or
+// writes (HL)
+fn unprefixed_t_states(op: u8) -> (u32, u32, bool) {
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (p, q) = (y >> 1, y & 1);
+    let fixed = |t| (t, t, false);
+    match (x, z) {
+        (0, 0) => match y {
+            0 | 1 => fixed(4),
+            2 => (8, 13, false),   // DJNZ
+            3 => fixed(12),        // JR
+            _ => (7, 12, false),   // JR cc
+        },
+        (0, 1) => fixed(if q == 0 { 10 } else { 11 }),
+        (0, 2) => fixed(match p { 0 | 1 => 7, 2 => 16, _ => 13 }),
+        (0, 3) => fixed(6),
+        (0, 4 | 5) if y == 6 => (11, 11, true),
+        (0, 4 | 5) => fixed(4),
+        (0, 6) if y == 6 => (10, 10, true),
+        (0, 6) => fixed(7),
+        (0, _) => fixed(4),
+        (1, _) if op == 0x76 => fixed(4),  // HALT
+        (1, _) if y == 6 || z == 6 => (7, 7, true),
+        (1, _) => fixed(4),
+        (2, 6) => (7, 7, true),
+        (2, _) => fixed(4),
+        (_, 0) => (5, 11, false),  // RET cc
+        (_, 1) if q == 0 => fixed(10),
+        (_, 1) => fixed([10, 4, 4, 6][p as usize]),
+        (_, 2) => fixed(10),
+        (_, 3) => fixed([10, 0, 11, 11, 19, 4, 4, 4][y as usize]),
+        (_, 4) => (10, 17, false),  // CALL cc
+        (_, 5) => fixed(if q == 0 { 11 } else { 17 }),
+        (_, 6) => fixed(7),
+        _ => fixed(11),
+    }
+}
+
+// T-states of the instruction after an ED prefix
+fn ed_t_states(op: u8) -> (u32, u32) {
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let t = match (x, z) {
+        (1, 0 | 1) => 12,
+        (1, 2) => 15,
+        (1, 3) => 20,
+        (1, 5) => 14,
+        (1, 7) if y == 4 || y == 5 => 18,  // RRD, RLD
+        (1, 7) if y < 4 => 9,
+        (2, 0..=3) if y >= 6 => return (16, 21),  // LDIR and the rest repeat
+        (2, 0..=3) if y >= 4 => 16,
+        _ => 8,
+    };
+    (t, t)
+}
+
+// labels, with L and the address as a label for each instruction in the code that a
+// jump or call without a label goes to, so that code can be moved about in the source
+fn with_jump_targets(code: &[u8], origin: u16, labels: &[(u16, String)], tables: &[Range<u16>]) -> Vec<(u16, String)> {
+    let end = origin.wrapping_add(code.len() as u16);
+    let mut starts = Vec::new();
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let addr = origin.wrapping_add(offset as u16);
+        if let Some(table) = tables.iter().find(|t| t.contains(&addr)) {
+            offset += ((table.end - addr) as usize).min(code.len() - offset);
+            continue;
+        }
+        let seen = std::cell::RefCell::new(Vec::new());
+        let (text, len) = disassemble(&code[offset..], addr, &|target| {
+            seen.borrow_mut().push(target);
+            None
+        });
+        if ["JP ", "JR ", "DJNZ ", "CALL "].iter().any(|jump| text.starts_with(jump)) {
+            targets.extend(seen.into_inner());
+        }
+        starts.push(addr);
+        offset += len;
+    }
+
+    let mut labels = labels.to_vec();
+    targets.sort_unstable();
+    targets.dedup();
+    for target in targets {
+        if (origin..end).contains(&target) && starts.binary_search(&target).is_ok()
+            && !labels.iter().any(|&(a, _)| a == target) {
+            labels.push((target, format!("L{:04X}", target)));
+        }
+    }
+    labels
+}
+
+// DB for bytes of data
+fn db(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+    format!("DB {}", bytes.join(","))
+}
+
+// Whether the instruction in bytes is one an assembler would encode differently from
+// its text: a prefix that changes nothing, a copy of NEG, RETN, IM or NOP in the ED
+// page, LD HL,(nn) the long way, or a CB operation after a prefix that also copies
+// its result to a register
+fn is_alias(bytes: &[u8]) -> bool {
+    match bytes {
+        [0xDD | 0xFD, 0xDD | 0xFD | 0xED, ..] => true,
+        [0xDD | 0xFD, 0xCB, _, op] => op & 7 != 6,
+        [0xDD | 0xFD, ..] => {
+            let (text, _) = disassemble(bytes, 0, &|_| None);
+            !text.contains("IX") && !text.contains("IY")
+        }
+        [0xED, op, ..] => matches!(op, 0x4C | 0x54 | 0x5C | 0x64 | 0x6C | 0x74 | 0x7C  // NEG
+            | 0x55 | 0x5D | 0x65 | 0x6D | 0x75 | 0x7D                                   // RETN
+            | 0x4E | 0x66 | 0x6E | 0x76 | 0x7E                                          // IM
+            | 0x63 | 0x6B | 0x77 | 0x7F),
+        _ => false,
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    pc: u16,
+    index: Option<&'static str>,  // IX or IY in place of HL after a DD or FD prefix
+    names: Names<'a>,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = self.bytes.get(self.pos).copied();
+        self.pos += 1;
+        byte
+    }
+
+    fn n(&mut self) -> Option<String> {
+        Some(format!("${:02X}", self.byte()?))
+    }
+
+    fn address(&self, addr: u16) -> String {
+        (self.names)(addr).unwrap_or_else(|| format!("${:04X}", addr))
+    }
+
+    fn nn(&mut self) -> Option<String> {
+        let lo = self.byte()?;
+        let hi = self.byte()?;
+        Some(self.address(u16::from_le_bytes([lo, hi])))
+    }
+
+    // Target of a relative jump, from the offset that ends the instruction
+    fn relative(&mut self) -> Option<String> {
+        let offset = self.byte()? as i8;
+        let target = self.pc.wrapping_add(self.pos as u16).wrapping_add(offset as u16);
+        Some(self.address(target))
+    }
+
+    fn hl(&self) -> &'static str {
+        self.index.unwrap_or("HL")
+    }
+
+    // (HL), or (IX+d) with the displacement that follows
+    fn indirect(&mut self) -> Option<String> {
+        match self.index {
+            None => Some("(HL)".to_string()),
+            Some(index) => {
+                let d = self.byte()? as i8;
+                let sign = if d < 0 { '-' } else { '+' };
+                Some(format!("({}{}${:02X})", index, sign, d.unsigned_abs()))
+            }
+        }
+    }
+
+    // Register r, with H and L meaning the halves of IX or IY after a prefix
+    fn r(&mut self, code: u8) -> Option<String> {
+        match (code, self.index) {
+            (6, _) => self.indirect(),
+            (4 | 5, Some(index)) => Some(format!("{}{}", index, if code == 4 { 'H' } else { 'L' })),
+            _ => Some(R[code as usize].to_string()),
+        }
+    }
+
+    fn rp(&self, p: u8) -> &'static str {
+        if p == 2 { self.hl() } else { RP[p as usize] }
+    }
+
+    fn rp2(&self, p: u8) -> &'static str {
+        if p == 2 { self.hl() } else { RP2[p as usize] }
+    }
+
+    fn decode(&mut self) -> Option<String> {
+        let mut op = self.byte()?;
+        while op == 0xDD || op == 0xFD {
+            self.index = Some(if op == 0xDD { "IX" } else { "IY" });
+            op = self.byte()?;
+        }
+        match op {
+            0xCB => return self.decode_cb(),
+            0xED => {
+                self.index = None;
+                return self.decode_ed();
+            }
+            _ => {}
+        }
+
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        Some(match (x, z) {
+            (0, 0) => match y {
+                0 => "NOP".to_string(),
+                1 => "EX AF,AF'".to_string(),
+                2 => format!("DJNZ {}", self.relative()?),
+                3 => format!("JR {}", self.relative()?),
+                _ => format!("JR {},{}", CC[y as usize - 4], self.relative()?),
+            },
+            (0, 1) if q == 0 => format!("LD {},{}", self.rp(p), self.nn()?),
+            (0, 1) => format!("ADD {},{}", self.hl(), self.rp(p)),
+            (0, 2) => match (q, p) {
+                (0, 0) => "LD (BC),A".to_string(),
+                (0, 1) => "LD (DE),A".to_string(),
+                (0, 2) => format!("LD ({}),{}", self.nn()?, self.hl()),
+                (0, _) => format!("LD ({}),A", self.nn()?),
+                (_, 0) => "LD A,(BC)".to_string(),
+                (_, 1) => "LD A,(DE)".to_string(),
+                (_, 2) => format!("LD {},({})", self.hl(), self.nn()?),
+                _ => format!("LD A,({})", self.nn()?),
+            },
+            (0, 3) => format!("{} {}", if q == 0 { "INC" } else { "DEC" }, self.rp(p)),
+            (0, 4) => format!("INC {}", self.r(y)?),
+            (0, 5) => format!("DEC {}", self.r(y)?),
+            (0, 6) => {
+                let r = self.r(y)?;
+                format!("LD {},{}", r, self.n()?)
+            }
+            (0, _) => ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y as usize].to_string(),
+            (1, _) if op == 0x76 => "HALT".to_string(),
+            // With (IX+d) on one side, H and L on the other are the real H and L
+            (1, 6) => format!("LD {},{}", R[y as usize], self.r(6)?),
+            (1, _) if y == 6 => format!("LD {},{}", self.r(6)?, R[z as usize]),
+            (1, _) => {
+                let dst = self.r(y)?;
+                format!("LD {},{}", dst, self.r(z)?)
+            }
+            (2, _) => format!("{}{}", ALU[y as usize], self.r(z)?),
+            (_, 0) => format!("RET {}", CC[y as usize]),
+            (_, 1) if q == 0 => format!("POP {}", self.rp2(p)),
+            (_, 1) => match p {
+                0 => "RET".to_string(),
+                1 => "EXX".to_string(),
+                2 => format!("JP ({})", self.hl()),
+                _ => format!("LD SP,{}", self.hl()),
+            },
+            (_, 2) => format!("JP {},{}", CC[y as usize], self.nn()?),
+            (_, 3) => match y {
+                0 => format!("JP {}", self.nn()?),
+                2 => format!("OUT ({}),A", self.n()?),
+                3 => format!("IN A,({})", self.n()?),
+                4 => format!("EX (SP),{}", self.hl()),
+                5 => "EX DE,HL".to_string(),
+                6 => "DI".to_string(),
+                _ => "EI".to_string(),
+            },
+            (_, 4) => format!("CALL {},{}", CC[y as usize], self.nn()?),
+            (_, 5) if q == 0 => format!("PUSH {}", self.rp2(p)),
+            (_, 5) => format!("CALL {}", self.nn()?),
+            (_, 6) => format!("{}{}", ALU[y as usize], self.n()?),
+            _ => format!("RST ${:02X}", y * 8),
+        })
+    }
+
+    // Rotates, shifts and bit operations; after a prefix the displacement comes
+    // before the opcode
+    fn decode_cb(&mut self) -> Option<String> {
+        let (op, operand) = if self.index.is_some() {
+            let operand = self.indirect()?;
+            (self.byte()?, operand)
+        } else {
+            let op = self.byte()?;
+            (op, R[(op & 7) as usize].to_string())
+        };
+        let y = (op >> 3) & 7;
+        Some(match op >> 6 {
+            0 => format!("{} {}", ROT[y as usize], operand),
+            1 => format!("BIT {},{}", y, operand),
+            2 => format!("RES {},{}", y, operand),
+            _ => format!("SET {},{}", y, operand),
+        })
+    }
+
+    fn decode_ed(&mut self) -> Option<String> {
+        let op = self.byte()?;
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        Some(match (x, z) {
+            (1, 0) if y == 6 => "IN F,(C)".to_string(),
+            (1, 0) => format!("IN {},(C)", R[y as usize]),
+            (1, 1) if y == 6 => "OUT (C),0".to_string(),
+            (1, 1) => format!("OUT (C),{}", R[y as usize]),
+            (1, 2) => format!("{} HL,{}", if q == 0 { "SBC" } else { "ADC" }, RP[p as usize]),
+            (1, 3) if q == 0 => format!("LD ({}),{}", self.nn()?, RP[p as usize]),
+            (1, 3) => format!("LD {},({})", RP[p as usize], self.nn()?),
+            (1, 4) => "NEG".to_string(),
+            (1, 5) => if y == 1 { "RETI" } else { "RETN" }.to_string(),
+            (1, 6) => format!("IM {}", ["0", "0", "1", "2"][(y & 3) as usize]),
+            (1, _) => ["LD I,A", "LD R,A", "LD A,I", "LD A,R", "RRD", "RLD", "NOP", "NOP"][y as usize].to_string(),
+            (2, 0..=3) if y >= 4 => BLOCK[y as usize - 4][z as usize].to_string(),
+            _ => format!("DB $ED,${:02X}", op),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests;
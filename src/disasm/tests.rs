@@ -0,0 +1,151 @@
+// Instructions from each part of the opcode map
+
+use super::*;
+
+fn show(bytes: &[u8]) -> (String, usize) {
+    let names = |addr: u16| (addr == 0x1234).then(|| "target".to_string());
+    disassemble(bytes, 0x1000, &names)
+}
+
+#[test]
+fn unprefixed_instructions() {
+    for (bytes, text) in [
+        (&[0x00][..], "NOP"),
+        (&[0x3E, 0x2A], "LD A,$2A"),
+        (&[0x21, 0x00, 0x20], "LD HL,$2000"),
+        (&[0x32, 0x01, 0x20], "LD ($2001),A"),
+        (&[0x2A, 0x34, 0x12], "LD HL,(target)"),
+        (&[0x36, 0x07], "LD (HL),$07"),
+        (&[0x78], "LD A,B"),
+        (&[0x77], "LD (HL),A"),
+        (&[0x76], "HALT"),
+        (&[0x86], "ADD A,(HL)"),
+        (&[0xD6, 0x30], "SUB $30"),
+        (&[0xFE, 0x0D], "CP $0D"),
+        (&[0x19], "ADD HL,DE"),
+        (&[0x0B], "DEC BC"),
+        (&[0xF5], "PUSH AF"),
+        (&[0xCD, 0x34, 0x12], "CALL target"),
+        (&[0xC2, 0x00, 0x30], "JP NZ,$3000"),
+        (&[0xD8], "RET C"),
+        (&[0xE9], "JP (HL)"),
+        (&[0xEB], "EX DE,HL"),
+        (&[0xD3, 0x81], "OUT ($81),A"),
+        (&[0xDB, 0x80], "IN A,($80)"),
+        (&[0xD7], "RST $10"),
+    ] {
+        assert_eq!(show(bytes), (text.to_string(), bytes.len()), "{:02X?}", bytes);
+    }
+}
+
+#[test]
+fn relative_jumps_show_their_target() {
+    assert_eq!(show(&[0x18, 0xFE]).0, "JR $1000");
+    assert_eq!(show(&[0x28, 0x10]).0, "JR Z,$1012");
+    assert_eq!(show(&[0x10, 0xFC]).0, "DJNZ $0FFE");
+}
+
+#[test]
+fn prefixed_instructions() {
+    for (bytes, text) in [
+        (&[0xCB, 0x27][..], "SLA A"),
+        (&[0xCB, 0x6E], "BIT 5,(HL)"),
+        (&[0xCB, 0xAE], "RES 5,(HL)"),
+        (&[0xED, 0xB0], "LDIR"),
+        (&[0xED, 0x42], "SBC HL,BC"),
+        (&[0xED, 0x4B, 0x00, 0x20], "LD BC,($2000)"),
+        (&[0xED, 0x79], "OUT (C),A"),
+        (&[0xED, 0x40], "IN B,(C)"),
+        (&[0xED, 0x56], "IM 1"),
+        (&[0xED, 0x45], "RETN"),
+        (&[0xDD, 0x21, 0x00, 0x80], "LD IX,$8000"),
+        (&[0xDD, 0x7E, 0xFE], "LD A,(IX-$02)"),
+        (&[0xFD, 0x66, 0x03], "LD H,(IY+$03)"),
+        (&[0xDD, 0x36, 0x01, 0x09], "LD (IX+$01),$09"),
+        (&[0xDD, 0x7C], "LD A,IXH"),
+        (&[0xDD, 0xE9], "JP (IX)"),
+        (&[0xDD, 0xCB, 0x04, 0x46], "BIT 0,(IX+$04)"),
+    ] {
+        assert_eq!(show(bytes), (text.to_string(), bytes.len()), "{:02X?}", bytes);
+    }
+}
+
+#[test]
+fn a_cut_off_instruction_is_a_byte() {
+    assert_eq!(show(&[0xCD, 0x34]), ("DB $CD".to_string(), 1));
+}
+
+#[test]
+fn listing_labels_named_addresses_and_shows_tables_as_data() {
+    let names = |addr: u16| (addr == 0x1001).then(|| "loop".to_string());
+    let code = [0x00, 0x18, 0xFE, 0x01, 0x02, 0xC9];
+    let tables = vec![0x1003..0x1005, 0x2000..0x2001];
+    assert_eq!(listing(&code, 0x1000, &names, &tables), "\
+1000: 00           NOP                  ; 1 byte, 4T
+loop:
+1001: 18 FE        JR loop              ; 2 bytes, 12T
+1003: 01 02        DB $01,$02
+1005: C9           RET                  ; 1 byte, 10T
+");
+}
+
+#[test]
+fn t_states_of_each_kind_of_instruction() {
+    for (bytes, t) in [
+        (&[0x00][..], (4, 4)),
+        (&[0x3E, 0x2A], (7, 7)),
+        (&[0x7E], (7, 7)),
+        (&[0x34], (11, 11)),
+        (&[0x22, 0x00, 0x20], (16, 16)),
+        (&[0xCD, 0x34, 0x12], (17, 17)),
+        (&[0xC4, 0x34, 0x12], (10, 17)),
+        (&[0xC8], (5, 11)),
+        (&[0x28, 0x10], (7, 12)),
+        (&[0x10, 0xFE], (8, 13)),
+        (&[0xE3], (19, 19)),
+        (&[0xCB, 0x27], (8, 8)),
+        (&[0xCB, 0x46], (12, 12)),
+        (&[0xCB, 0xC6], (15, 15)),
+        (&[0xED, 0xB0], (16, 21)),
+        (&[0xED, 0x42], (15, 15)),
+        (&[0xED, 0x4B, 0x00, 0x20], (20, 20)),
+        (&[0xED, 0x6F], (18, 18)),
+        (&[0xDD, 0x21, 0x00, 0x80], (14, 14)),
+        (&[0xDD, 0x7E, 0xFE], (19, 19)),
+        (&[0xDD, 0x36, 0x01, 0x09], (19, 19)),
+        (&[0xDD, 0x34, 0x01], (23, 23)),
+        (&[0xDD, 0x7C], (8, 8)),
+        (&[0xDD, 0xE5], (15, 15)),
+        (&[0xDD, 0xCB, 0x04, 0x46], (20, 20)),
+        (&[0xDD, 0xCB, 0x04, 0xC6], (23, 23)),
+    ] {
+        assert_eq!(t_states(bytes), t, "{:02X?}", bytes);
+    }
+    assert_eq!(run_t_states(&[0x3E, 0x2A, 0x28, 0x10, 0xC9]), (24, 29));
+    assert_eq!(cost(2, (7, 12)), "2 bytes, 7/12T");
+}
+
+#[test]
+fn source_labels_jump_targets_and_keeps_bytes_exact() {
+    let labels = vec![(0x1004, "data".to_string()), (0x1009, "inside".to_string())];
+    let code = [
+        0x00,              // NOP
+        0x18, 0xFD,        // JR $1000
+        0xC9,              // RET
+        0x01, 0x02,        // A table
+        0xDD, 0x00,        // NOP with a prefix that changes nothing
+        0x01, 0x34, 0x12,  // LD BC,$1234 with a label after its opcode
+        0xED, 0x4C,        // A copy of NEG
+    ];
+    let tables = vec![0x1004..0x1006, 0x2000..0x2001];
+    assert_eq!(source(&code[..6], 0x1000, &labels, &tables), "\
+L1000:
+    NOP
+    JR L1000
+    RET
+data:
+    DB $01,$02
+");
+    assert_eq!(source(&code[6..], 0x1006, &labels, &[]),
+               "    DB $DD,$00\n    DB $01\ninside:\n    INC (HL)\n    LD (DE),A\n    DB $ED,$4C\n");
+}
@@ -0,0 +1,1327 @@
+// Z80 emulator for running compiled Action! programs on the host
+// Implements the documented instruction set with T-state counting
+
+// Flag bits in F
+const FLAG_C: u8 = 0x01;
+const FLAG_N: u8 = 0x02;
+const FLAG_PV: u8 = 0x04;
+const FLAG_X: u8 = 0x08;
+const FLAG_H: u8 = 0x10;
+const FLAG_Y: u8 = 0x20;
+const FLAG_Z: u8 = 0x40;
+const FLAG_S: u8 = 0x80;
+
+/// Port I/O as seen by the emulated CPU
+pub trait IoBus {
+    fn input(&mut self, port: u8) -> u8;
+    fn output(&mut self, port: u8, value: u8);
+    /// Called before each instruction with the T-states run so far
+    fn clock(&mut self, _cycles: u64) {}
+}
+
+/// Console UART model (data on port 0x00, status on port 0x01)
+#[derive(Debug, Default)]
+pub struct Console {
+    pub input: std::collections::VecDeque<u8>,
+    pub output: Vec<u8>,
+    /// Echo output bytes to the host's stdout as they are written
+    pub echo: bool,
+    /// Read a line from the host's stdin when the program polls an empty input
+    pub interactive: bool,
+    /// Scripted input, each entry typed once the program has run that many T-states
+    pub script: std::collections::VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Console {
+    pub const DATA_PORT: u8 = 0x00;
+    pub const STATUS_PORT: u8 = 0x01;
+
+    pub fn new() -> Self {
+        Console::default()
+    }
+}
+
+impl IoBus for Console {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            Console::DATA_PORT => self.input.pop_front().unwrap_or(0),
+            // Bit 0: receive data ready, bit 1: transmitter empty
+            Console::STATUS_PORT => {
+                if self.input.is_empty() && self.interactive {
+                    let mut line = String::new();
+                    if std::io::stdin().read_line(&mut line).is_ok_and(|n| n > 0) {
+                        self.input.extend(line.trim_end_matches(['\r', '\n']).bytes());
+                        self.input.push_back(b'\r');
+                    }
+                }
+                0x02 | u8::from(!self.input.is_empty())
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn clock(&mut self, cycles: u64) {
+        while self.script.front().is_some_and(|&(at, _)| at <= cycles) {
+            if let Some((_, keys)) = self.script.pop_front() {
+                self.input.extend(keys);
+            }
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port == Console::DATA_PORT {
+            self.output.push(value);
+            if self.echo {
+                use std::io::Write;
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(&[value]);
+                let _ = stdout.flush();
+            }
+        }
+    }
+}
+
+/// A device model mapped into memory, answering reads and writes to the addresses it
+/// claims in place of RAM
+pub trait MemoryDevice {
+    fn claims(&self, addr: u16) -> bool;
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+    fn as_any(&self) -> &dyn std::any::Any;
+}
+
+/// Memory-mapped text screen, one byte per character, rendered as plain text
+pub struct TextScreen {
+    pub base: u16,
+    cells: Vec<u8>,
+}
+
+impl TextScreen {
+    pub const COLUMNS: usize = 80;
+    pub const ROWS: usize = 25;
+
+    pub fn new(base: u16) -> Self {
+        TextScreen { base, cells: vec![b' '; Self::COLUMNS * Self::ROWS] }
+    }
+
+    // Offset of addr in the screen, if it is on it
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.base) as usize;
+        (offset < self.cells.len()).then_some(offset)
+    }
+
+    /// The screen as lines of text, without trailing blanks; unprintable characters
+    /// show as spaces
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for row in self.cells.chunks(Self::COLUMNS) {
+            let line: String = row.iter()
+                .map(|&c| if (0x20..0x7F).contains(&c) { c as char } else { ' ' })
+                .collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+}
+
+impl MemoryDevice for TextScreen {
+    fn claims(&self, addr: u16) -> bool {
+        self.offset(addr).is_some()
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        self.offset(addr).map_or(0xFF, |i| self.cells[i])
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if let Some(i) = self.offset(addr) {
+            self.cells[i] = value;
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// Banked memory: a window of RAM showing one of several banks, chosen by writing the
+/// bank number to a register (which reads back the current bank)
+pub struct BankSwitch {
+    pub register: u16,
+    pub window: u16,
+    banks: Vec<Vec<u8>>,
+    selected: usize,
+}
+
+impl BankSwitch {
+    pub fn new(register: u16, window: u16, size: u16, count: usize) -> Self {
+        BankSwitch { register, window, banks: vec![vec![0; size as usize]; count.max(1)], selected: 0 }
+    }
+
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.window) as usize;
+        (offset < self.banks[0].len()).then_some(offset)
+    }
+}
+
+impl MemoryDevice for BankSwitch {
+    fn claims(&self, addr: u16) -> bool {
+        addr == self.register || self.offset(addr).is_some()
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        if addr == self.register {
+            return self.selected as u8;
+        }
+        self.offset(addr).map_or(0xFF, |i| self.banks[self.selected][i])
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr == self.register {
+            // Bank numbers past the last bank wrap around, like unused select lines
+            self.selected = value as usize % self.banks.len();
+        } else if let Some(i) = self.offset(addr) {
+            self.banks[self.selected][i] = value;
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// Why a call to `Cpu::run` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Halted,
+    CycleLimit,
+}
+
+// Register pair used in place of HL by the current instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Index {
+    HL,
+    IX,
+    IY,
+}
+
+pub struct Cpu {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub alt: [u8; 8],  // A' F' B' C' D' E' H' L'
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: u8,
+    pub halted: bool,
+    pub cycles: u64,
+    pub mem: Vec<u8>,
+    devices: Vec<Box<dyn MemoryDevice>>,
+    index: Index,
+    ei_pending: bool,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        Cpu {
+            a: 0xFF,
+            f: 0xFF,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            alt: [0; 8],
+            ix: 0,
+            iy: 0,
+            sp: 0xFFFF,
+            pc: 0,
+            i: 0,
+            r: 0,
+            iff1: false,
+            iff2: false,
+            im: 0,
+            halted: false,
+            cycles: 0,
+            mem: vec![0; 0x10000],
+            devices: Vec::new(),
+            index: Index::HL,
+            ei_pending: false,
+        }
+    }
+
+    /// Copy bytes into memory starting at addr
+    pub fn load(&mut self, addr: u16, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.mem[addr.wrapping_add(i as u16) as usize] = b;
+        }
+    }
+
+    /// Map a device over memory; it takes precedence over RAM and earlier devices
+    pub fn map_device(&mut self, device: Box<dyn MemoryDevice>) {
+        self.devices.insert(0, device);
+    }
+
+    /// The first mapped device of type T
+    pub fn device<T: 'static>(&self) -> Option<&T> {
+        self.devices.iter().find_map(|d| d.as_any().downcast_ref())
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        if !self.devices.is_empty() {
+            if let Some(device) = self.devices.iter().find(|d| d.claims(addr)) {
+                return device.read(addr);
+            }
+        }
+        self.mem[addr as usize]
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        if !self.devices.is_empty() {
+            if let Some(device) = self.devices.iter_mut().find(|d| d.claims(addr)) {
+                return device.write(addr, value);
+            }
+        }
+        self.mem[addr as usize] = value;
+    }
+
+    pub fn read_word(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    pub fn write_word(&mut self, addr: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write(addr, lo);
+        self.write(addr.wrapping_add(1), hi);
+    }
+
+    pub fn bc(&self) -> u16 { u16::from_be_bytes([self.b, self.c]) }
+    pub fn de(&self) -> u16 { u16::from_be_bytes([self.d, self.e]) }
+    pub fn hl(&self) -> u16 { u16::from_be_bytes([self.h, self.l]) }
+    pub fn af(&self) -> u16 { u16::from_be_bytes([self.a, self.f]) }
+
+    pub fn set_bc(&mut self, v: u16) { [self.b, self.c] = v.to_be_bytes(); }
+    pub fn set_de(&mut self, v: u16) { [self.d, self.e] = v.to_be_bytes(); }
+    pub fn set_hl(&mut self, v: u16) { [self.h, self.l] = v.to_be_bytes(); }
+    pub fn set_af(&mut self, v: u16) { [self.a, self.f] = v.to_be_bytes(); }
+
+    /// Run until HALT, or until max_cycles T-states have elapsed
+    pub fn run(&mut self, io: &mut dyn IoBus, max_cycles: Option<u64>) -> StopReason {
+        let limit = max_cycles.map(|m| self.cycles.saturating_add(m));
+        while !self.halted {
+            if limit.is_some_and(|limit| self.cycles >= limit) {
+                return StopReason::CycleLimit;
+            }
+            io.clock(self.cycles);
+            self.step(io);
+        }
+        StopReason::Halted
+    }
+
+    /// Execute one instruction, returning the T-states it took
+    pub fn step(&mut self, io: &mut dyn IoBus) -> u32 {
+        let start = self.cycles;
+        self.ei_pending = false;
+        if self.halted {
+            // HALT executes NOPs until an interrupt arrives
+            self.bump_r();
+            self.cycles += 4;
+            return 4;
+        }
+
+        self.index = Index::HL;
+        let mut op = self.fetch_opcode();
+        loop {
+            match op {
+                0xDD => self.index = Index::IX,
+                0xFD => self.index = Index::IY,
+                _ => break,
+            }
+            self.cycles += 4;
+            op = self.fetch_opcode();
+        }
+
+        match op {
+            0xCB if self.index != Index::HL => self.exec_index_cb(),
+            0xCB => {
+                let op = self.fetch_opcode();
+                self.exec_cb(op);
+            }
+            0xED => {
+                self.index = Index::HL;
+                let op = self.fetch_opcode();
+                self.exec_ed(op, io);
+            }
+            _ => self.exec_main(op, io),
+        }
+        (self.cycles - start) as u32
+    }
+
+    fn bump_r(&mut self) {
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(1) & 0x7F);
+    }
+
+    fn fetch_opcode(&mut self) -> u8 {
+        self.bump_r();
+        self.fetch()
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let v = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let v = self.read_word(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        v
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.write_word(self.sp, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let v = self.read_word(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        v
+    }
+
+    fn flag(&self, mask: u8) -> bool {
+        self.f & mask != 0
+    }
+
+    fn set_flag(&mut self, mask: u8, on: bool) {
+        if on {
+            self.f |= mask;
+        } else {
+            self.f &= !mask;
+        }
+    }
+
+    // Condition codes NZ Z NC C PO PE P M
+    fn condition(&self, cc: u8) -> bool {
+        match cc {
+            0 => !self.flag(FLAG_Z),
+            1 => self.flag(FLAG_Z),
+            2 => !self.flag(FLAG_C),
+            3 => self.flag(FLAG_C),
+            4 => !self.flag(FLAG_PV),
+            5 => self.flag(FLAG_PV),
+            6 => !self.flag(FLAG_S),
+            _ => self.flag(FLAG_S),
+        }
+    }
+
+    // HL, IX or IY depending on prefix
+    fn index_reg(&self) -> u16 {
+        match self.index {
+            Index::HL => self.hl(),
+            Index::IX => self.ix,
+            Index::IY => self.iy,
+        }
+    }
+
+    fn set_index_reg(&mut self, v: u16) {
+        match self.index {
+            Index::HL => self.set_hl(v),
+            Index::IX => self.ix = v,
+            Index::IY => self.iy = v,
+        }
+    }
+
+    // Address of the (HL)/(IX+d)/(IY+d) operand, fetching the displacement
+    fn indirect_addr(&mut self) -> u16 {
+        match self.index {
+            Index::HL => self.hl(),
+            _ => {
+                let d = self.fetch() as i8;
+                self.cycles += 8;
+                self.index_reg().wrapping_add(d as u16)
+            }
+        }
+    }
+
+    // 8-bit register by encoding (6 is not handled here)
+    fn reg(&self, r: u8) -> u8 {
+        match r {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => match self.index {
+                Index::HL => self.h,
+                Index::IX => (self.ix >> 8) as u8,
+                Index::IY => (self.iy >> 8) as u8,
+            },
+            5 => match self.index {
+                Index::HL => self.l,
+                Index::IX => self.ix as u8,
+                Index::IY => self.iy as u8,
+            },
+            _ => self.a,
+        }
+    }
+
+    fn set_reg(&mut self, r: u8, v: u8) {
+        match r {
+            0 => self.b = v,
+            1 => self.c = v,
+            2 => self.d = v,
+            3 => self.e = v,
+            4 => match self.index {
+                Index::HL => self.h = v,
+                Index::IX => self.ix = (self.ix & 0x00FF) | ((v as u16) << 8),
+                Index::IY => self.iy = (self.iy & 0x00FF) | ((v as u16) << 8),
+            },
+            5 => match self.index {
+                Index::HL => self.l = v,
+                Index::IX => self.ix = (self.ix & 0xFF00) | v as u16,
+                Index::IY => self.iy = (self.iy & 0xFF00) | v as u16,
+            },
+            _ => self.a = v,
+        }
+    }
+
+    // Plain register access ignoring any index prefix
+    fn reg_plain(&self, r: u8) -> u8 {
+        match r {
+            4 => self.h,
+            5 => self.l,
+            _ => self.reg(r),
+        }
+    }
+
+    fn set_reg_plain(&mut self, r: u8, v: u8) {
+        match r {
+            4 => self.h = v,
+            5 => self.l = v,
+            _ => self.set_reg(r, v),
+        }
+    }
+
+    // Register pairs BC DE HL SP
+    fn rp(&self, p: u8) -> u16 {
+        match p {
+            0 => self.bc(),
+            1 => self.de(),
+            2 => self.index_reg(),
+            _ => self.sp,
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, v: u16) {
+        match p {
+            0 => self.set_bc(v),
+            1 => self.set_de(v),
+            2 => self.set_index_reg(v),
+            _ => self.sp = v,
+        }
+    }
+
+    // Register pairs BC DE HL AF
+    fn rp2(&self, p: u8) -> u16 {
+        match p {
+            3 => self.af(),
+            _ => self.rp(p),
+        }
+    }
+
+    fn set_rp2(&mut self, p: u8, v: u16) {
+        match p {
+            3 => self.set_af(v),
+            _ => self.set_rp(p, v),
+        }
+    }
+
+    fn sz_flags(v: u8) -> u8 {
+        let mut f = v & (FLAG_S | FLAG_X | FLAG_Y);
+        if v == 0 {
+            f |= FLAG_Z;
+        }
+        f
+    }
+
+    fn szp_flags(v: u8) -> u8 {
+        let mut f = Self::sz_flags(v);
+        if v.count_ones().is_multiple_of(2) {
+            f |= FLAG_PV;
+        }
+        f
+    }
+
+    fn add8(&mut self, v: u8, carry: bool) {
+        let c = u8::from(carry);
+        let a = self.a;
+        let result = a.wrapping_add(v).wrapping_add(c);
+        let full = a as u16 + v as u16 + c as u16;
+        let mut f = Self::sz_flags(result);
+        if (a & 0x0F) + (v & 0x0F) + c > 0x0F {
+            f |= FLAG_H;
+        }
+        if (a ^ v) & 0x80 == 0 && (a ^ result) & 0x80 != 0 {
+            f |= FLAG_PV;
+        }
+        if full > 0xFF {
+            f |= FLAG_C;
+        }
+        self.a = result;
+        self.f = f;
+    }
+
+    fn sub8(&mut self, v: u8, carry: bool, store: bool) {
+        let c = u8::from(carry);
+        let a = self.a;
+        let result = a.wrapping_sub(v).wrapping_sub(c);
+        let mut f = Self::sz_flags(result) | FLAG_N;
+        if (a & 0x0F) < (v & 0x0F) + c {
+            f |= FLAG_H;
+        }
+        if (a ^ v) & 0x80 != 0 && (a ^ result) & 0x80 != 0 {
+            f |= FLAG_PV;
+        }
+        if (a as u16) < v as u16 + c as u16 {
+            f |= FLAG_C;
+        }
+        if store {
+            self.a = result;
+        } else {
+            // CP takes the undocumented bits from the operand
+            f = (f & !(FLAG_X | FLAG_Y)) | (v & (FLAG_X | FLAG_Y));
+        }
+        self.f = f;
+    }
+
+    fn alu(&mut self, op: u8, v: u8) {
+        match op {
+            0 => self.add8(v, false),
+            1 => self.add8(v, self.flag(FLAG_C)),
+            2 => self.sub8(v, false, true),
+            3 => self.sub8(v, self.flag(FLAG_C), true),
+            4 => {
+                self.a &= v;
+                self.f = Self::szp_flags(self.a) | FLAG_H;
+            }
+            5 => {
+                self.a ^= v;
+                self.f = Self::szp_flags(self.a);
+            }
+            6 => {
+                self.a |= v;
+                self.f = Self::szp_flags(self.a);
+            }
+            _ => self.sub8(v, false, false),
+        }
+    }
+
+    fn inc8(&mut self, v: u8) -> u8 {
+        let result = v.wrapping_add(1);
+        let mut f = (self.f & FLAG_C) | Self::sz_flags(result);
+        if v & 0x0F == 0x0F {
+            f |= FLAG_H;
+        }
+        if v == 0x7F {
+            f |= FLAG_PV;
+        }
+        self.f = f;
+        result
+    }
+
+    fn dec8(&mut self, v: u8) -> u8 {
+        let result = v.wrapping_sub(1);
+        let mut f = (self.f & FLAG_C) | Self::sz_flags(result) | FLAG_N;
+        if v & 0x0F == 0 {
+            f |= FLAG_H;
+        }
+        if v == 0x80 {
+            f |= FLAG_PV;
+        }
+        self.f = f;
+        result
+    }
+
+    fn add16(&mut self, a: u16, b: u16) -> u16 {
+        let result = a.wrapping_add(b);
+        let mut f = self.f & (FLAG_S | FLAG_Z | FLAG_PV);
+        f |= ((result >> 8) as u8) & (FLAG_X | FLAG_Y);
+        if (a & 0x0FFF) + (b & 0x0FFF) > 0x0FFF {
+            f |= FLAG_H;
+        }
+        if a as u32 + b as u32 > 0xFFFF {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        result
+    }
+
+    fn adc16(&mut self, b: u16) {
+        let a = self.hl();
+        let c = u16::from(self.flag(FLAG_C));
+        let result = a.wrapping_add(b).wrapping_add(c);
+        let mut f = ((result >> 8) as u8) & (FLAG_S | FLAG_X | FLAG_Y);
+        if result == 0 {
+            f |= FLAG_Z;
+        }
+        if (a & 0x0FFF) + (b & 0x0FFF) + c > 0x0FFF {
+            f |= FLAG_H;
+        }
+        if (a ^ b) & 0x8000 == 0 && (a ^ result) & 0x8000 != 0 {
+            f |= FLAG_PV;
+        }
+        if a as u32 + b as u32 + c as u32 > 0xFFFF {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        self.set_hl(result);
+    }
+
+    fn sbc16(&mut self, b: u16) {
+        let a = self.hl();
+        let c = u16::from(self.flag(FLAG_C));
+        let result = a.wrapping_sub(b).wrapping_sub(c);
+        let mut f = ((result >> 8) as u8) & (FLAG_S | FLAG_X | FLAG_Y) | FLAG_N;
+        if result == 0 {
+            f |= FLAG_Z;
+        }
+        if (a & 0x0FFF) < (b & 0x0FFF) + c {
+            f |= FLAG_H;
+        }
+        if (a ^ b) & 0x8000 != 0 && (a ^ result) & 0x8000 != 0 {
+            f |= FLAG_PV;
+        }
+        if (a as u32) < b as u32 + c as u32 {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        self.set_hl(result);
+    }
+
+    // CB-prefixed rotate/shift by operation number
+    fn rotate(&mut self, op: u8, v: u8) -> u8 {
+        let carry_in = u8::from(self.flag(FLAG_C));
+        let (result, carry) = match op {
+            0 => (v.rotate_left(1), v & 0x80 != 0),            // RLC
+            1 => (v.rotate_right(1), v & 0x01 != 0),           // RRC
+            2 => ((v << 1) | carry_in, v & 0x80 != 0),         // RL
+            3 => ((v >> 1) | (carry_in << 7), v & 0x01 != 0),  // RR
+            4 => (v << 1, v & 0x80 != 0),                      // SLA
+            5 => ((v >> 1) | (v & 0x80), v & 0x01 != 0),       // SRA
+            6 => ((v << 1) | 1, v & 0x80 != 0),                // SLL (undocumented)
+            _ => (v >> 1, v & 0x01 != 0),                      // SRL
+        };
+        self.f = Self::szp_flags(result) | u8::from(carry);
+        result
+    }
+
+    fn bit(&mut self, n: u8, v: u8) {
+        let set = v & (1 << n) != 0;
+        let mut f = (self.f & FLAG_C) | FLAG_H | (v & (FLAG_X | FLAG_Y));
+        if !set {
+            f |= FLAG_Z | FLAG_PV;
+        }
+        if n == 7 && set {
+            f |= FLAG_S;
+        }
+        self.f = f;
+    }
+
+    fn daa(&mut self) {
+        let a = self.a;
+        let mut correction = 0u8;
+        let mut carry = self.flag(FLAG_C);
+        if self.flag(FLAG_H) || (a & 0x0F) > 9 {
+            correction |= 0x06;
+        }
+        if carry || a > 0x99 {
+            correction |= 0x60;
+            carry = true;
+        }
+        let result = if self.flag(FLAG_N) {
+            a.wrapping_sub(correction)
+        } else {
+            a.wrapping_add(correction)
+        };
+        let half = if self.flag(FLAG_N) {
+            self.flag(FLAG_H) && (a & 0x0F) < 6
+        } else {
+            (a & 0x0F) > 9
+        };
+        let mut f = Self::szp_flags(result) | (self.f & FLAG_N) | u8::from(carry);
+        if half {
+            f |= FLAG_H;
+        }
+        self.a = result;
+        self.f = f;
+    }
+
+    fn exec_main(&mut self, op: u8, io: &mut dyn IoBus) {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match x {
+            0 => match z {
+                0 => match y {
+                    0 => self.cycles += 4,  // NOP
+                    1 => {
+                        // EX AF, AF'
+                        let af = self.af();
+                        let alt = u16::from_be_bytes([self.alt[0], self.alt[1]]);
+                        self.set_af(alt);
+                        [self.alt[0], self.alt[1]] = af.to_be_bytes();
+                        self.cycles += 4;
+                    }
+                    2 => {
+                        // DJNZ d
+                        let d = self.fetch() as i8;
+                        self.b = self.b.wrapping_sub(1);
+                        if self.b != 0 {
+                            self.pc = self.pc.wrapping_add(d as u16);
+                            self.cycles += 13;
+                        } else {
+                            self.cycles += 8;
+                        }
+                    }
+                    3 => {
+                        // JR d
+                        let d = self.fetch() as i8;
+                        self.pc = self.pc.wrapping_add(d as u16);
+                        self.cycles += 12;
+                    }
+                    _ => {
+                        // JR cc, d
+                        let d = self.fetch() as i8;
+                        if self.condition(y - 4) {
+                            self.pc = self.pc.wrapping_add(d as u16);
+                            self.cycles += 12;
+                        } else {
+                            self.cycles += 7;
+                        }
+                    }
+                },
+                1 => {
+                    if q == 0 {
+                        let nn = self.fetch_word();
+                        self.set_rp(p, nn);
+                        self.cycles += 10;
+                    } else {
+                        let result = self.add16(self.index_reg(), self.rp(p));
+                        self.set_index_reg(result);
+                        self.cycles += 11;
+                    }
+                }
+                2 => match (q, p) {
+                    (0, 0) => { self.write(self.bc(), self.a); self.cycles += 7; }
+                    (0, 1) => { self.write(self.de(), self.a); self.cycles += 7; }
+                    (0, 2) => {
+                        let nn = self.fetch_word();
+                        self.write_word(nn, self.index_reg());
+                        self.cycles += 16;
+                    }
+                    (0, _) => {
+                        let nn = self.fetch_word();
+                        self.write(nn, self.a);
+                        self.cycles += 13;
+                    }
+                    (_, 0) => { self.a = self.read(self.bc()); self.cycles += 7; }
+                    (_, 1) => { self.a = self.read(self.de()); self.cycles += 7; }
+                    (_, 2) => {
+                        let nn = self.fetch_word();
+                        let v = self.read_word(nn);
+                        self.set_index_reg(v);
+                        self.cycles += 16;
+                    }
+                    (_, _) => {
+                        let nn = self.fetch_word();
+                        self.a = self.read(nn);
+                        self.cycles += 13;
+                    }
+                },
+                3 => {
+                    let v = self.rp(p);
+                    let v = if q == 0 { v.wrapping_add(1) } else { v.wrapping_sub(1) };
+                    self.set_rp(p, v);
+                    self.cycles += 6;
+                }
+                4 | 5 => {
+                    let inc = z == 4;
+                    if y == 6 {
+                        let addr = self.indirect_addr();
+                        let v = self.read(addr);
+                        let v = if inc { self.inc8(v) } else { self.dec8(v) };
+                        self.write(addr, v);
+                        self.cycles += 11;
+                    } else {
+                        let v = self.reg(y);
+                        let v = if inc { self.inc8(v) } else { self.dec8(v) };
+                        self.set_reg(y, v);
+                        self.cycles += 4;
+                    }
+                }
+                6 => {
+                    if y == 6 {
+                        let addr = self.indirect_addr();
+                        let n = self.fetch();
+                        self.write(addr, n);
+                        // LD (IX+d), n is 19 rather than 10 + 8
+                        self.cycles += if self.index == Index::HL { 10 } else { 7 };
+                    } else {
+                        let n = self.fetch();
+                        self.set_reg(y, n);
+                        self.cycles += 7;
+                    }
+                }
+                _ => {
+                    match y {
+                        0 => {
+                            // RLCA
+                            let carry = self.a >> 7;
+                            self.a = self.a.rotate_left(1);
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV)) | (self.a & (FLAG_X | FLAG_Y)) | carry;
+                        }
+                        1 => {
+                            // RRCA
+                            let carry = self.a & 1;
+                            self.a = self.a.rotate_right(1);
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV)) | (self.a & (FLAG_X | FLAG_Y)) | carry;
+                        }
+                        2 => {
+                            // RLA
+                            let carry = self.a >> 7;
+                            self.a = (self.a << 1) | (self.f & FLAG_C);
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV)) | (self.a & (FLAG_X | FLAG_Y)) | carry;
+                        }
+                        3 => {
+                            // RRA
+                            let carry = self.a & 1;
+                            self.a = (self.a >> 1) | ((self.f & FLAG_C) << 7);
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV)) | (self.a & (FLAG_X | FLAG_Y)) | carry;
+                        }
+                        4 => self.daa(),
+                        5 => {
+                            // CPL
+                            self.a = !self.a;
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV | FLAG_C))
+                                | FLAG_H | FLAG_N | (self.a & (FLAG_X | FLAG_Y));
+                        }
+                        6 => {
+                            // SCF
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV)) | (self.a & (FLAG_X | FLAG_Y)) | FLAG_C;
+                        }
+                        _ => {
+                            // CCF
+                            let carry = self.f & FLAG_C;
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV)) | (self.a & (FLAG_X | FLAG_Y))
+                                | if carry != 0 { FLAG_H } else { FLAG_C };
+                        }
+                    }
+                    self.cycles += 4;
+                }
+            },
+            1 => {
+                if y == 6 && z == 6 {
+                    // HALT: PC stays on the instruction until an interrupt
+                    self.halted = true;
+                    self.pc = self.pc.wrapping_sub(1);
+                    self.cycles += 4;
+                } else if y == 6 {
+                    let addr = self.indirect_addr();
+                    let v = self.reg_plain(z);
+                    self.write(addr, v);
+                    self.cycles += 7;
+                } else if z == 6 {
+                    let addr = self.indirect_addr();
+                    let v = self.read(addr);
+                    self.set_reg_plain(y, v);
+                    self.cycles += 7;
+                } else {
+                    let v = self.reg(z);
+                    self.set_reg(y, v);
+                    self.cycles += 4;
+                }
+            }
+            2 => {
+                if z == 6 {
+                    let addr = self.indirect_addr();
+                    let v = self.read(addr);
+                    self.alu(y, v);
+                    self.cycles += 7;
+                } else {
+                    let v = self.reg(z);
+                    self.alu(y, v);
+                    self.cycles += 4;
+                }
+            }
+            _ => match z {
+                0 => {
+                    // RET cc
+                    if self.condition(y) {
+                        self.pc = self.pop();
+                        self.cycles += 11;
+                    } else {
+                        self.cycles += 5;
+                    }
+                }
+                1 => {
+                    if q == 0 {
+                        let v = self.pop();
+                        self.set_rp2(p, v);
+                        self.cycles += 10;
+                    } else {
+                        match p {
+                            0 => {
+                                self.pc = self.pop();
+                                self.cycles += 10;
+                            }
+                            1 => {
+                                // EXX
+                                let regs = [self.b, self.c, self.d, self.e, self.h, self.l];
+                                [self.b, self.c, self.d, self.e, self.h, self.l] = [
+                                    self.alt[2], self.alt[3], self.alt[4], self.alt[5], self.alt[6], self.alt[7],
+                                ];
+                                self.alt[2..8].copy_from_slice(&regs);
+                                self.cycles += 4;
+                            }
+                            2 => {
+                                self.pc = self.index_reg();
+                                self.cycles += 4;
+                            }
+                            _ => {
+                                self.sp = self.index_reg();
+                                self.cycles += 6;
+                            }
+                        }
+                    }
+                }
+                2 => {
+                    let nn = self.fetch_word();
+                    if self.condition(y) {
+                        self.pc = nn;
+                    }
+                    self.cycles += 10;
+                }
+                3 => match y {
+                    0 => {
+                        self.pc = self.fetch_word();
+                        self.cycles += 10;
+                    }
+                    2 => {
+                        let n = self.fetch();
+                        io.output(n, self.a);
+                        self.cycles += 11;
+                    }
+                    3 => {
+                        let n = self.fetch();
+                        self.a = io.input(n);
+                        self.cycles += 11;
+                    }
+                    4 => {
+                        // EX (SP), HL
+                        let v = self.read_word(self.sp);
+                        self.write_word(self.sp, self.index_reg());
+                        self.set_index_reg(v);
+                        self.cycles += 19;
+                    }
+                    5 => {
+                        // EX DE, HL (never indexed)
+                        let de = self.de();
+                        self.set_de(self.hl());
+                        self.set_hl(de);
+                        self.cycles += 4;
+                    }
+                    6 => {
+                        self.iff1 = false;
+                        self.iff2 = false;
+                        self.cycles += 4;
+                    }
+                    _ => {
+                        self.iff1 = true;
+                        self.iff2 = true;
+                        self.ei_pending = true;
+                        self.cycles += 4;
+                    }
+                },
+                4 => {
+                    let nn = self.fetch_word();
+                    if self.condition(y) {
+                        self.push(self.pc);
+                        self.pc = nn;
+                        self.cycles += 17;
+                    } else {
+                        self.cycles += 10;
+                    }
+                }
+                5 => {
+                    if q == 0 {
+                        self.push(self.rp2(p));
+                        self.cycles += 11;
+                    } else {
+                        // CALL nn (prefixes are handled before dispatch)
+                        let nn = self.fetch_word();
+                        self.push(self.pc);
+                        self.pc = nn;
+                        self.cycles += 17;
+                    }
+                }
+                6 => {
+                    let n = self.fetch();
+                    self.alu(y, n);
+                    self.cycles += 7;
+                }
+                _ => {
+                    self.push(self.pc);
+                    self.pc = (y as u16) * 8;
+                    self.cycles += 11;
+                }
+            },
+        }
+    }
+
+    fn exec_cb(&mut self, op: u8) {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let (v, addr) = if z == 6 {
+            let addr = self.hl();
+            (self.read(addr), Some(addr))
+        } else {
+            (self.reg(z), None)
+        };
+        let result = match x {
+            0 => Some(self.rotate(y, v)),
+            1 => {
+                self.bit(y, v);
+                None
+            }
+            2 => Some(v & !(1 << y)),
+            _ => Some(v | (1 << y)),
+        };
+        if let Some(result) = result {
+            match addr {
+                Some(addr) => self.write(addr, result),
+                None => self.set_reg(z, result),
+            }
+        }
+        self.cycles += match (addr.is_some(), x) {
+            (false, _) => 8,
+            (true, 1) => 12,
+            (true, _) => 15,
+        };
+    }
+
+    // DDCB/FDCB d op
+    fn exec_index_cb(&mut self) {
+        let d = self.fetch() as i8;
+        let op = self.fetch();
+        let addr = self.index_reg().wrapping_add(d as u16);
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let v = self.read(addr);
+        let result = match x {
+            0 => Some(self.rotate(y, v)),
+            1 => {
+                self.bit(y, v);
+                None
+            }
+            2 => Some(v & !(1 << y)),
+            _ => Some(v | (1 << y)),
+        };
+        if let Some(result) = result {
+            self.write(addr, result);
+            if z != 6 {
+                // Undocumented: result is also copied to a register
+                self.set_reg_plain(z, result);
+            }
+        }
+        self.cycles += if x == 1 { 16 } else { 19 };
+    }
+
+    fn exec_ed(&mut self, op: u8, io: &mut dyn IoBus) {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match (x, z) {
+            (1, 0) => {
+                // IN r, (C)
+                let v = io.input(self.c);
+                self.f = (self.f & FLAG_C) | Self::szp_flags(v);
+                if y != 6 {
+                    self.set_reg(y, v);
+                }
+                self.cycles += 12;
+            }
+            (1, 1) => {
+                // OUT (C), r
+                let v = if y == 6 { 0 } else { self.reg(y) };
+                io.output(self.c, v);
+                self.cycles += 12;
+            }
+            (1, 2) => {
+                if q == 0 {
+                    self.sbc16(self.rp(p));
+                } else {
+                    self.adc16(self.rp(p));
+                }
+                self.cycles += 15;
+            }
+            (1, 3) => {
+                let nn = self.fetch_word();
+                if q == 0 {
+                    self.write_word(nn, self.rp(p));
+                } else {
+                    let v = self.read_word(nn);
+                    self.set_rp(p, v);
+                }
+                self.cycles += 20;
+            }
+            (1, 4) => {
+                // NEG
+                let v = self.a;
+                self.a = 0;
+                self.sub8(v, false, true);
+                self.cycles += 8;
+            }
+            (1, 5) => {
+                // RETN / RETI
+                self.pc = self.pop();
+                self.iff1 = self.iff2;
+                self.cycles += 14;
+            }
+            (1, 6) => {
+                self.im = match y & 3 {
+                    2 => 1,
+                    3 => 2,
+                    _ => 0,
+                };
+                self.cycles += 8;
+            }
+            (1, 7) => {
+                match y {
+                    0 => { self.i = self.a; self.cycles += 9; }
+                    1 => { self.r = self.a; self.cycles += 9; }
+                    2 | 3 => {
+                        self.a = if y == 2 { self.i } else { self.r };
+                        let mut f = (self.f & FLAG_C) | Self::sz_flags(self.a);
+                        if self.iff2 {
+                            f |= FLAG_PV;
+                        }
+                        self.f = f;
+                        self.cycles += 9;
+                    }
+                    4 => {
+                        // RRD
+                        let addr = self.hl();
+                        let m = self.read(addr);
+                        self.write(addr, (self.a << 4) | (m >> 4));
+                        self.a = (self.a & 0xF0) | (m & 0x0F);
+                        self.f = (self.f & FLAG_C) | Self::szp_flags(self.a);
+                        self.cycles += 18;
+                    }
+                    5 => {
+                        // RLD
+                        let addr = self.hl();
+                        let m = self.read(addr);
+                        self.write(addr, (m << 4) | (self.a & 0x0F));
+                        self.a = (self.a & 0xF0) | (m >> 4);
+                        self.f = (self.f & FLAG_C) | Self::szp_flags(self.a);
+                        self.cycles += 18;
+                    }
+                    _ => self.cycles += 8,
+                }
+            }
+            (2, 0..=3) if y >= 4 => self.exec_block(y, z, io),
+            _ => self.cycles += 8,  // Undefined ED opcodes act as NOPs
+        }
+    }
+
+    // LDI/CPI/INI/OUTI family and their repeating forms
+    fn exec_block(&mut self, y: u8, z: u8, io: &mut dyn IoBus) {
+        let decrement = y & 1 == 1;
+        let repeat = y >= 6;
+        let step = |v: u16| if decrement { v.wrapping_sub(1) } else { v.wrapping_add(1) };
+
+        let again = match z {
+            0 => {
+                // LDI/LDD
+                let v = self.read(self.hl());
+                self.write(self.de(), v);
+                self.set_hl(step(self.hl()));
+                self.set_de(step(self.de()));
+                self.set_bc(self.bc().wrapping_sub(1));
+                let n = v.wrapping_add(self.a);
+                self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_C))
+                    | (n & FLAG_X) | ((n << 4) & FLAG_Y);
+                self.set_flag(FLAG_PV, self.bc() != 0);
+                self.bc() != 0
+            }
+            1 => {
+                // CPI/CPD
+                let v = self.read(self.hl());
+                let carry = self.f & FLAG_C;
+                let a = self.a;
+                self.sub8(v, false, false);
+                self.a = a;
+                self.set_hl(step(self.hl()));
+                self.set_bc(self.bc().wrapping_sub(1));
+                self.f = (self.f & !FLAG_C) | carry;
+                self.set_flag(FLAG_PV, self.bc() != 0);
+                self.bc() != 0 && !self.flag(FLAG_Z)
+            }
+            2 => {
+                // INI/IND
+                let v = io.input(self.c);
+                self.write(self.hl(), v);
+                self.set_hl(step(self.hl()));
+                self.b = self.b.wrapping_sub(1);
+                self.f = Self::sz_flags(self.b) | FLAG_N;
+                self.b != 0
+            }
+            _ => {
+                // OUTI/OUTD
+                let v = self.read(self.hl());
+                self.b = self.b.wrapping_sub(1);
+                io.output(self.c, v);
+                self.set_hl(step(self.hl()));
+                self.f = Self::sz_flags(self.b) | FLAG_N;
+                self.b != 0
+            }
+        };
+
+        if repeat && again {
+            self.pc = self.pc.wrapping_sub(2);
+            self.cycles += 21;
+        } else {
+            self.cycles += 16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,49 @@
+// Memory-mapped devices, driven by small hand-assembled programs
+
+use super::*;
+
+fn run_code(cpu: &mut Cpu, code: &[u8]) {
+    cpu.load(0x0000, code);
+    cpu.pc = 0x0000;
+    assert_eq!(cpu.run(&mut Console::new(), Some(10_000)), StopReason::Halted);
+}
+
+#[test]
+fn text_screen_renders_what_is_written() {
+    let mut cpu = Cpu::new();
+    cpu.map_device(Box::new(TextScreen::new(0xF000)));
+    run_code(&mut cpu, &[
+        0x3E, b'H', 0x32, 0x00, 0xF0,  // LD A, 'H'; LD ($F000), A
+        0x3E, b'i', 0x32, 0x01, 0xF0,  // LD A, 'i'; LD ($F001), A
+        0x3E, b'!', 0x32, 0x52, 0xF0,  // LD A, '!'; LD ($F052), A (row 1, column 2)
+        0x76,                          // HALT
+    ]);
+
+    let screen = cpu.device::<TextScreen>().unwrap().render();
+    let lines: Vec<&str> = screen.lines().collect();
+    assert_eq!(lines.len(), TextScreen::ROWS);
+    assert_eq!(lines[0], "Hi");
+    assert_eq!(lines[1], "  !");
+    assert_eq!(cpu.mem[0xF000], 0, "the screen is not RAM");
+}
+
+#[test]
+fn bank_switch_selects_the_window_contents() {
+    let mut cpu = Cpu::new();
+    cpu.map_device(Box::new(BankSwitch::new(0x00FF, 0x8000, 0x4000, 4)));
+    run_code(&mut cpu, &[
+        0x3E, 0x01, 0x32, 0xFF, 0x00,  // LD A, 1; LD ($00FF), A (bank 1)
+        0x3E, 0x11, 0x32, 0x00, 0x80,  // LD A, $11; LD ($8000), A
+        0x3E, 0x06, 0x32, 0xFF, 0x00,  // LD A, 6; LD ($00FF), A (bank 2)
+        0x3E, 0x22, 0x32, 0x00, 0x80,  // LD A, $22; LD ($8000), A
+        0x76,                          // HALT
+    ]);
+
+    assert_eq!(cpu.read(0x00FF), 2, "bank numbers wrap around");
+    assert_eq!(cpu.read(0x8000), 0x22);
+    cpu.write(0x00FF, 1);
+    assert_eq!(cpu.read(0x8000), 0x11);
+    cpu.write(0x00FF, 0);
+    assert_eq!(cpu.read(0x8000), 0x00);
+    assert_eq!(cpu.read(0xC000), 0x00, "outside the window is RAM");
+}
@@ -18,6 +18,12 @@ pub enum CompileError {
         message: String,
     },
 
+    #[error("Error at line {line}: {message}")]
+    SemanticError {
+        line: usize,
+        message: String,
+    },
+
     #[error("Unexpected token: expected {expected}, found {found}")]
     UnexpectedToken {
         expected: String,
@@ -34,8 +40,9 @@ pub enum CompileError {
         name: String,
     },
 
-    #[error("Type mismatch: expected {expected}, found {found}")]
+    #[error("Type mismatch at line {line}: expected {expected}, found {found}")]
     TypeMismatch {
+        line: usize,
         expected: String,
         found: String,
     },
@@ -45,10 +52,43 @@ pub enum CompileError {
         message: String,
     },
 
+    #[error("Error in the definition of {name}: {message}")]
+    DefineError {
+        name: String,
+        message: String,
+    },
+
+    #[error("Link error: {message}")]
+    LinkError {
+        message: String,
+    },
+
     #[error("Internal compiler error: {message}")]
     InternalError {
         message: String,
     },
+
+    #[error("Error: {message}")]
+    OptionError {
+        message: String,
+    },
+
+    #[error("{}", errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+    Errors {
+        errors: Vec<CompileError>,
+    },
+}
+
+impl CompileError {
+    /// The source line the error is on, if it has one
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            CompileError::LexerError { line, .. } | CompileError::ParserError { line, .. }
+            | CompileError::SemanticError { line, .. } | CompileError::TypeMismatch { line, .. } => Some(*line),
+            CompileError::Errors { errors } => errors.first()?.line(),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, CompileError>;
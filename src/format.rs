@@ -0,0 +1,146 @@
+// Output files: the bare image, or the image with the header a system's loader wants
+// (MSX BASIC's BLOAD, a ZX Spectrum tape), an MSX ROM cartridge, Intel HEX for
+// monitors, or chunks for the loader in loader/chunkload.asm.
+
+/// How the image is written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Bin,  // The image as is
+    Com,  // The image as is, named for CP/M
+    Hex,  // Intel HEX, for monitors that load programs typed or pasted in
+    Msx,  // BLOAD file: BLOAD "NAME",R loads and starts it
+    MsxRom,  // ROM cartridge: the "AB" header 16 bytes before the origin, padded to 16 or 32KB
+    Tap,  // Tape with one CODE block: LOAD "" CODE, then RANDOMIZE USR with the origin
+    Chunks,  // Checksummed chunks, each loaded at its own address, then the start address
+}
+
+/// Bytes to load at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: &'static str,
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub run: Option<u16>,  // Where the startup code copies the bytes to, if anywhere
+}
+
+/// Most bytes in one chunk, so a bad checksum is found before much more is loaded
+pub const CHUNK_SIZE: usize = 256;
+
+impl Format {
+    /// File extension of output named after the source
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Bin | Format::Msx => "bin",
+            Format::Com => "com",
+            Format::Hex => "hex",
+            Format::MsxRom => "rom",
+            Format::Tap => "tap",
+            Format::Chunks => "chk",
+        }
+    }
+
+    /// The file for an image that loads and starts at origin; name is the file name
+    /// on a tape
+    pub fn wrap(self, image: &[u8], origin: u16, name: &str) -> Vec<u8> {
+        match self {
+            Format::Bin | Format::Com => image.to_vec(),
+            Format::Hex => intel_hex(image, origin),
+            Format::Msx => bload(image, origin),
+            Format::MsxRom => cartridge(image, origin),
+            Format::Tap => tap(image, origin, name),
+            Format::Chunks => chunks(&[Segment { name: "image", address: origin, bytes: image.to_vec(), run: None }], origin),
+        }
+    }
+}
+
+/// The chunks file for segments, started at entry. Each chunk is its address and
+/// length, little-endian, its bytes and a checksum that makes all of them add up to
+/// zero; a chunk of no bytes gives the entry address and ends the file
+pub fn chunks(segments: &[Segment], entry: u16) -> Vec<u8> {
+    let mut file = Vec::new();
+    let mut chunk = |address: u16, bytes: &[u8]| {
+        let mut record = address.to_le_bytes().to_vec();
+        record.extend((bytes.len() as u16).to_le_bytes());
+        record.extend_from_slice(bytes);
+        record.push(record.iter().fold(0u8, |sum, &b| sum.wrapping_sub(b)));
+        file.extend(record);
+    };
+    for segment in segments {
+        for (i, bytes) in segment.bytes.chunks(CHUNK_SIZE).enumerate() {
+            chunk(segment.address.wrapping_add((i * CHUNK_SIZE) as u16), bytes);
+        }
+    }
+    chunk(entry, &[]);
+    file
+}
+
+// Data records of up to 16 bytes, then the end of file record
+fn intel_hex(image: &[u8], origin: u16) -> Vec<u8> {
+    let mut text = String::new();
+    for (i, chunk) in image.chunks(16).enumerate() {
+        let addr = origin.wrapping_add(16 * i as u16);
+        let mut record = vec![chunk.len() as u8, (addr >> 8) as u8, addr as u8, 0x00];
+        record.extend_from_slice(chunk);
+        record.push(record.iter().fold(0u8, |sum, &b| sum.wrapping_sub(b)));
+        text.push(':');
+        for b in record {
+            text.push_str(&format!("{:02X}", b));
+        }
+        text.push_str("\r\n");
+    }
+    text.push_str(":00000001FF\r\n");
+    text.into_bytes()
+}
+
+// 0FEh, then the first and last load addresses and the start address
+fn bload(image: &[u8], origin: u16) -> Vec<u8> {
+    let last = origin.wrapping_add(image.len().max(1) as u16 - 1);
+    let mut file = vec![0xFE];
+    for word in [origin, last, origin] {
+        file.extend(word.to_le_bytes());
+    }
+    file.extend_from_slice(image);
+    file
+}
+
+/// Bytes of an MSX cartridge header, which the origin of a cartridge comes after
+pub const CARTRIDGE_HEADER: u16 = 16;
+
+// "AB" and the INIT address the BIOS calls at boot; no BASIC statement, device or BASIC
+// text. The rest is the blank of an unprogrammed EPROM, up to a 16KB bank
+fn cartridge(image: &[u8], origin: u16) -> Vec<u8> {
+    let mut file = vec![b'A', b'B'];
+    file.extend(origin.to_le_bytes());
+    file.resize(CARTRIDGE_HEADER as usize, 0x00);
+    file.extend_from_slice(image);
+    file.resize(file.len().next_multiple_of(0x4000), 0xFF);
+    file
+}
+
+// A header block naming a CODE file, then the data block
+fn tap(image: &[u8], origin: u16, name: &str) -> Vec<u8> {
+    let mut header = vec![3];  // CODE
+    let mut padded: Vec<u8> = name.bytes().filter(u8::is_ascii_graphic).take(10).collect();
+    padded.resize(10, b' ');
+    header.extend(padded);
+    header.extend((image.len() as u16).to_le_bytes());
+    header.extend(origin.to_le_bytes());
+    header.extend(32768u16.to_le_bytes());
+
+    let mut file = tape_block(0x00, &header);
+    file.extend(tape_block(0xFF, image));
+    file
+}
+
+// Length, flag byte, contents and the XOR of flag and contents
+fn tape_block(flag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut block = ((contents.len() + 2) as u16).to_le_bytes().to_vec();
+    block.push(flag);
+    block.extend_from_slice(contents);
+    block.push(contents.iter().fold(flag, |sum, &b| sum ^ b));
+    block
+}
+
+#[cfg(test)]
+mod tests;
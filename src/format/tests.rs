@@ -0,0 +1,74 @@
+// Headers of the wrapped output files
+
+use super::*;
+
+#[test]
+fn bin_and_com_are_the_bare_image() {
+    for format in [Format::Bin, Format::Com] {
+        assert_eq!(format.wrap(&[1, 2, 3], 0x0100, "prog"), [1, 2, 3]);
+    }
+}
+
+#[test]
+fn intel_hex_records() {
+    let image: Vec<u8> = (0..18).collect();
+    let file = String::from_utf8(Format::Hex.wrap(&image, 0x8000, "prog")).unwrap();
+    assert_eq!(file, "\
+:10800000000102030405060708090A0B0C0D0E0FF8\r
+:0280100010114D\r
+:00000001FF\r
+");
+}
+
+#[test]
+fn bload_header_gives_the_last_address() {
+    let file = Format::Msx.wrap(&[0xC3, 0x03, 0x90, 0xC9], 0x9000, "prog");
+    assert_eq!(file, [0xFE, 0x00, 0x90, 0x03, 0x90, 0x00, 0x90, 0xC3, 0x03, 0x90, 0xC9]);
+}
+
+#[test]
+fn tap_has_a_header_and_a_data_block() {
+    let file = Format::Tap.wrap(&[0x11, 0x22], 0x8000, "hello world");
+    let header: &[u8] = &[
+        0x13, 0x00, 0x00, 0x03,
+        b'h', b'e', b'l', b'l', b'o', b'w', b'o', b'r', b'l', b'd',
+        0x02, 0x00, 0x00, 0x80, 0x00, 0x80,
+    ];
+    assert_eq!(&file[..20], header);
+    let checksum = header[2..].iter().fold(0, |sum, &b| sum ^ b);
+    assert_eq!(file[20], checksum);
+    assert_eq!(&file[21..], [0x04, 0x00, 0xFF, 0x11, 0x22, 0xFF ^ 0x11 ^ 0x22]);
+}
+
+#[test]
+fn tap_pads_short_names() {
+    let file = Format::Tap.wrap(&[], 0x8000, "a");
+    assert_eq!(&file[4..14], b"a         ");
+}
+
+#[test]
+fn cartridges_start_with_the_header_and_fill_whole_banks() {
+    let file = Format::MsxRom.wrap(&[0xC3, 0x13, 0x40, 0xC9], 0x4010, "prog");
+    assert_eq!(file.len(), 0x4000);
+    assert_eq!(&file[..20], [b'A', b'B', 0x10, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xC3, 0x13, 0x40, 0xC9]);
+    assert!(file[20..].iter().all(|&b| b == 0xFF));
+    assert_eq!(Format::MsxRom.wrap(&[0; 0x4000 - 15], 0x4010, "prog").len(), 0x8000);
+    assert_eq!(Format::MsxRom.extension(), "rom");
+}
+
+#[test]
+fn chunks_add_up_to_zero_and_end_with_the_entry() {
+    let image: Vec<u8> = (0..=255).chain([7]).collect();
+    let file = chunks(&[
+        Segment { name: "code", address: 0x8000, bytes: image, run: None },
+        Segment { name: "data", address: 0x2000, bytes: vec![9], run: None },
+    ], 0x8000);
+    assert_eq!(&file[..4], [0x00, 0x80, 0x00, 0x01]);
+    assert_eq!(file[..261].iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), 0);
+    assert_eq!(&file[261..], [
+        0x00, 0x81, 0x01, 0x00, 0x07, 0x77,
+        0x00, 0x20, 0x01, 0x00, 0x09, 0xD6,
+        0x00, 0x80, 0x00, 0x00, 0x80,
+    ]);
+    assert_eq!(Format::Chunks.wrap(&[1], 0x8000, "prog"), [0x00, 0x80, 0x01, 0x00, 0x01, 0x7E, 0x00, 0x80, 0x00, 0x00, 0x80]);
+}
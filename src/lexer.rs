@@ -1,7 +1,8 @@
 // Lexer/Tokenizer for Action! language
 
-use crate::token::{Token, TokenInfo};
+use crate::token::{CaseMode, Token, TokenInfo};
 use crate::error::{CompileError, Result};
+use std::collections::HashMap;
 
 pub struct Lexer<'a> {
     #[allow(dead_code)]
@@ -10,6 +11,8 @@ pub struct Lexer<'a> {
     line: usize,
     column: usize,
     current_char: Option<char>,
+    case_mode: CaseMode,
+    defines: HashMap<String, Vec<Token>>,  // Defined names (by case key) and the tokens they stand for
 }
 
 impl<'a> Lexer<'a> {
@@ -22,9 +25,33 @@ impl<'a> Lexer<'a> {
             line: 1,
             column: 1,
             current_char,
+            case_mode: CaseMode::default(),
+            defines: HashMap::new(),
         }
     }
 
+    pub fn set_case_mode(&mut self, mode: CaseMode) {
+        self.case_mode = mode;
+    }
+
+    /// Replace the name, wherever it appears as an identifier, with the tokens of
+    /// text, as Action!'s DEFINE does. Set the case mode first
+    pub fn define(&mut self, name: &str, text: &str) -> Result<()> {
+        let mut lexer = Lexer::new(text);
+        lexer.set_case_mode(self.case_mode);
+        let mut tokens: Vec<Token> = lexer.tokenize()?.into_iter().map(|t| t.token).collect();
+        tokens.pop();  // Eof
+        if tokens.contains(&Token::Newline) {
+            return Err(CompileError::LexerError {
+                line: 1,
+                column: 1,
+                message: format!("Definition of {} must fit on one line", name),
+            });
+        }
+        self.defines.insert(self.case_mode.key(name), tokens);
+        Ok(())
+    }
+
     fn advance(&mut self) {
         if let Some(c) = self.current_char {
             if c == '\n' {
@@ -172,8 +199,8 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        // Check for keywords (case-insensitive in Action!)
-        match ident.to_uppercase().as_str() {
+        // Check for keywords (case-insensitive unless strict case is requested)
+        match self.case_mode.key(&ident).as_str() {
             "BYTE" => Token::Byte,
             "CARD" => Token::Card,
             "INT" => Token::Int,
@@ -295,19 +322,24 @@ impl<'a> Lexer<'a> {
     pub fn tokenize(&mut self) -> Result<Vec<TokenInfo>> {
         let mut tokens = Vec::new();
 
-        loop {
-            match self.next_token()? {
-                Some(token_info) => {
-                    let is_eof = token_info.token == Token::Eof;
-                    tokens.push(token_info);
-                    if is_eof {
-                        break;
-                    }
+        while let Some(token_info) = self.next_token()? {
+            if let Token::Identifier(name) = &token_info.token {
+                if let Some(replacement) = self.defines.get(&self.case_mode.key(name)) {
+                    let at = |token: &Token| TokenInfo::new(token.clone(), token_info.line, token_info.column);
+                    tokens.extend(replacement.iter().map(at));
+                    continue;
                 }
-                None => break,
+            }
+            let is_eof = token_info.token == Token::Eof;
+            tokens.push(token_info);
+            if is_eof {
+                break;
             }
         }
 
         Ok(tokens)
     }
 }
+
+#[cfg(test)]
+mod tests;
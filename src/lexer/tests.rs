@@ -0,0 +1,33 @@
+// Defined names standing for other tokens
+
+use super::*;
+
+fn tokens(source: &str, defines: &[(&str, &str)]) -> Result<Vec<Token>> {
+    let mut lexer = Lexer::new(source);
+    for (name, text) in defines {
+        lexer.define(name, text)?;
+    }
+    Ok(lexer.tokenize()?.into_iter().map(|t| t.token).collect())
+}
+
+#[test]
+fn defined_names_are_replaced() {
+    let found = tokens("x = width - 1", &[("WIDTH", "$28 * 2")]).unwrap();
+    assert_eq!(found, [
+        Token::Identifier("x".to_string()), Token::Equal,
+        Token::Number(0x28), Token::Star, Token::Number(2),
+        Token::Minus, Token::Number(1), Token::Eof,
+    ]);
+}
+
+#[test]
+fn definitions_are_not_expanded_again() {
+    let found = tokens("a", &[("a", "b"), ("b", "c")]).unwrap();
+    assert_eq!(found, [Token::Identifier("b".to_string()), Token::Eof]);
+}
+
+#[test]
+fn a_definition_can_be_empty_but_not_several_lines() {
+    assert_eq!(tokens("debug", &[("DEBUG", "")]).unwrap(), [Token::Eof]);
+    assert!(tokens("x", &[("x", "1\n2")]).is_err());
+}
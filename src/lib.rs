@@ -0,0 +1,46 @@
+//! Action! compiler for Z80
+//!
+//! Compiles Action! source to Z80 machine code. [`compile_source`] runs the whole
+//! compiler; [`tokenize`], [`parse`] and [`compile_program`] run its stages one at a
+//! time. The `kz80_action` command line tool is built on these.
+//!
+//! ```
+//! use kz80_action::{compile_source, CompileOptions};
+//!
+//! let output = compile_source("PROC main()\nPrintE()\nRETURN\n", CompileOptions::default()).unwrap();
+//! assert_eq!(output.origin, 0x4200);
+//! assert_eq!(output.binary[0], 0xC3);  // JP to the startup code
+//! ```
+
+pub mod lexer;
+pub mod token;
+pub mod ast;
+pub mod parser;
+pub mod semantics;
+pub mod symbols;
+pub mod codegen;
+pub mod runtime;
+pub mod error;
+pub mod emulator;
+pub mod repl;
+pub mod bench;
+pub mod relocate;
+pub mod clobber;
+pub mod stack;
+pub mod cfg;
+pub mod run;
+pub mod format;
+pub mod upload;
+pub mod manifest;
+pub mod project;
+pub mod disasm;
+pub mod library;
+pub mod meta;
+mod compile;
+#[cfg(test)]
+mod test_support;
+#[cfg(test)]
+mod differential;
+
+pub use compile::{compile_patch, compile_program, compile_source, parse, parse_source, tokenize, CompileOptions, CompileOutput, JumpTableEntry};
+pub use error::{CompileError, Result};
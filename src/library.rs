@@ -0,0 +1,211 @@
+// Libraries (.lib): Action! source files bundled into one, from which a program takes
+// only the members it needs. Each member starts with a line giving its file name and
+// the public globals and procedures it defines:
+//
+//     ;;; kz80_action library
+//     ;;; member screen.act: Cls PutAt
+//     PROC Cls()
+//     ...
+//
+// A member is linked in when the program uses a name it defines and the program does
+// not, and so on for the names the members linked in use. Each is a MODULE of its
+// own, so its PRIVATE names stay its own.
+
+use crate::ast::{collect_expression_names, collect_names, strip_lines, Definition, Procedure, Program};
+use crate::compile::{parse, tokenize, CompileOptions};
+use crate::error::{CompileError, Result};
+use std::collections::HashSet;
+
+const HEADER: &str = ";;; kz80_action library";
+const MEMBER: &str = ";;; member ";
+
+/// A source file in a library
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub defines: Vec<String>,  // Public globals and procedures
+    pub source: String,
+}
+
+/// The members of a library, in the order they were added
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Library {
+    pub members: Vec<Member>,
+}
+
+impl Library {
+    /// A library of (file name, source) pairs, each parsed to find what it defines
+    pub fn create(files: &[(String, String)], options: &CompileOptions) -> Result<Library> {
+        let mut members = Vec::new();
+        for (name, source) in files {
+            if source.lines().any(|line| line.starts_with(MEMBER) || line == HEADER) {
+                return Err(link_error(name, format!("has a line starting with {}", MEMBER.trim_end())));
+            }
+            let (program, _) = tokenize(source, options).and_then(|t| parse(t, options)).map_err(|e| link_error(name, e))?;
+            let private: HashSet<(&str, usize)> = program.private.iter().map(|(n, m)| (n.as_str(), *m)).collect();
+            let defines = program.definitions.iter()
+                .filter(|d| !private.contains(&(d.name.as_str(), d.module)))
+                .map(|d| d.name.clone())
+                .collect();
+            let mut source = source.clone();
+            if !source.ends_with('\n') {
+                source.push('\n');
+            }
+            members.push(Member { name: name.clone(), defines, source });
+        }
+        Ok(Library { members })
+    }
+
+    /// The library in the text of a .lib file
+    pub fn parse(text: &str) -> std::result::Result<Library, String> {
+        let mut lines = text.split_inclusive('\n');
+        if lines.next().map(str::trim_end) != Some(HEADER) {
+            return Err("not a kz80_action library".to_string());
+        }
+        let mut members: Vec<Member> = Vec::new();
+        for line in lines {
+            if let Some(heading) = line.strip_prefix(MEMBER) {
+                let (name, defines) = heading.trim_end().split_once(':').unwrap_or((heading.trim_end(), ""));
+                members.push(Member {
+                    name: name.to_string(),
+                    defines: defines.split_whitespace().map(str::to_string).collect(),
+                    source: String::new(),
+                });
+            } else if let Some(member) = members.last_mut() {
+                member.source.push_str(line);
+            } else if !line.trim().is_empty() {
+                return Err("source before the first member".to_string());
+            }
+        }
+        Ok(Library { members })
+    }
+
+    /// The text of the .lib file
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", HEADER);
+        for member in &self.members {
+            text.push_str(&format!("{}{}: {}\n", MEMBER, member.name, member.defines.join(" ")));
+            text.push_str(&member.source);
+        }
+        text
+    }
+}
+
+/// The members of libraries that program needs, in the order they are first needed;
+/// the earlier library wins when two define a name
+pub fn needed<'a>(program: &Program, libraries: &'a [Library], options: &CompileOptions) -> Result<Vec<(&'a Member, Program)>> {
+    let case = options.case_mode;
+    let mut defined: Vec<String> = program.definitions.iter().map(|d| d.name.clone()).collect();
+    let mut wanted: Vec<String> = uses(program).into_iter().map(str::to_string).collect();
+    let mut linked = Vec::new();
+    let mut i = 0;
+    while i < wanted.len() {
+        let name = wanted[i].clone();
+        i += 1;
+        if defined.iter().any(|d| case.matches(d, &name)) {
+            continue;
+        }
+        let Some(member) = libraries.iter().flat_map(|l| &l.members)
+            .find(|m| m.defines.iter().any(|d| case.matches(d, &name))) else {
+            continue;
+        };
+        let (parsed, _) = tokenize(&member.source, options).and_then(|t| parse(t, options))
+            .map_err(|e| link_error(&member.name, e))?;
+        defined.extend(parsed.definitions.iter().map(|d| d.name.clone()));
+        wanted.extend(uses(&parsed).into_iter().map(str::to_string));
+        linked.push((member, parsed));
+    }
+    Ok(linked)
+}
+
+/// The program with the library members it needs. Their code goes first, each member
+/// after those it uses, so calls are to code already placed; each has modules of its
+/// own, numbered after the program's.
+pub fn link(program: &Program, libraries: &[Library], options: &CompileOptions) -> Result<Program> {
+    let members = needed(program, libraries, options)?;
+    if members.is_empty() {
+        return Ok(program.clone());
+    }
+    if !program.procedures.iter().any(|p| options.case_mode.matches(&p.name, "Main")) {
+        return Err(CompileError::LinkError {
+            message: "a program linked with a library needs a PROC main to start at".to_string(),
+        });
+    }
+
+    // Each member after the ones it uses, unless they use each other
+    let case = options.case_mode;
+    let uses_of: Vec<Vec<usize>> = members.iter()
+        .map(|(_, parsed)| uses(parsed).into_iter()
+            .filter_map(|name| members.iter().position(|(m, _)| m.defines.iter().any(|d| case.matches(d, name))))
+            .collect())
+        .collect();
+    fn place(i: usize, uses_of: &[Vec<usize>], seen: &mut [bool], order: &mut Vec<usize>) {
+        if !seen[i] {
+            seen[i] = true;
+            for &used in &uses_of[i] {
+                place(used, uses_of, seen, order);
+            }
+            order.push(i);
+        }
+    }
+    let mut order = Vec::new();
+    let mut seen = vec![false; members.len()];
+    for i in 0..members.len() {
+        place(i, &uses_of, &mut seen, &mut order);
+    }
+
+    let mut linked = Program { modules: program.modules.clone(), ..Program::new() };
+    let mut parts: Vec<(Program, usize)> = Vec::new();
+    for i in order {
+        let mut member = members[i].1.clone();
+        let base = linked.modules.len() + 1;
+        linked.modules.push(1);
+        linked.modules.extend(&member.modules);
+        // Line markers would point into the program's source
+        for proc in &mut member.procedures {
+            strip_lines(&mut proc.body);
+        }
+        parts.push((member, base));
+    }
+    parts.push((program.clone(), 0));
+    for (part, base) in parts {
+        linked.globals.extend(part.globals);
+        linked.procedures.extend(part.procedures.into_iter().map(|p| Procedure { module: p.module + base, ..p }));
+        linked.declarations.extend(part.declarations.into_iter().map(|p| Procedure { module: p.module + base, ..p }));
+        linked.private.extend(part.private.into_iter().map(|(name, module)| (name, module + base)));
+        linked.definitions.extend(part.definitions.into_iter().map(|d| Definition { module: d.module + base, ..d }));
+    }
+    Ok(linked)
+}
+
+// Names the program's procedures and initial values use, other than their own locals
+// and parameters
+fn uses(program: &Program) -> Vec<&str> {
+    let mut names = Vec::new();
+    for var in &program.globals {
+        if let Some(value) = &var.initial_value {
+            collect_expression_names(value, &mut names);
+        }
+    }
+    for proc in &program.procedures {
+        let mut used = Vec::new();
+        for local in &proc.locals {
+            if let Some(value) = &local.initial_value {
+                collect_expression_names(value, &mut used);
+            }
+        }
+        collect_names(&proc.body, &mut used);
+        let own: HashSet<&str> = proc.locals.iter().map(|v| v.name.as_str())
+            .chain(proc.params.iter().map(|p| p.name.as_str()))
+            .collect();
+        names.extend(used.into_iter().filter(|name| !own.contains(name)));
+    }
+    names
+}
+
+fn link_error(member: &str, error: impl std::fmt::Display) -> CompileError {
+    CompileError::LinkError { message: format!("{}: {}", member, error) }
+}
+
+#[cfg(test)]
+mod tests;
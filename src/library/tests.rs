@@ -0,0 +1,67 @@
+// Libraries written, read back and searched for the members a program needs
+
+use super::*;
+use crate::compile::{compile_program, compile_source};
+use crate::emulator::Console;
+use crate::run::{check, Limits, Machine};
+
+fn library() -> Library {
+    let files = [
+        ("screen.act".to_string(), "PROC Cls()\nPutD(12)\nRETURN\nPROC Line(BYTE n)\nWHILE n > 0 DO PutD('-') n = n - 1 OD\nRETURN\n".to_string()),
+        ("math.act".to_string(), "PRIVATE BYTE last\nFUNC BYTE Max(BYTE a, BYTE b)\nlast = a\nIF b > a THEN last = b FI\nRETURN (last)\n".to_string()),
+        ("banner.act".to_string(), "PROC Banner()\nLine(3)\nPrint(\"hi\")\nRETURN".to_string()),
+    ];
+    Library::create(&files, &CompileOptions::default()).unwrap()
+}
+
+#[test]
+fn members_list_their_public_names() {
+    let library = library();
+    let defines: Vec<&[String]> = library.members.iter().map(|m| m.defines.as_slice()).collect();
+    assert_eq!(defines, [&["Cls", "Line"][..], &["Max"], &["Banner"]]);
+    assert_eq!(Library::parse(&library.to_text()), Ok(library));
+    assert!(Library::parse("PROC main()\nRETURN\n").is_err());
+}
+
+#[test]
+fn only_the_members_used_are_linked() {
+    let libraries = [library()];
+    let options = CompileOptions::default();
+    let names = |source: &str| -> Vec<String> {
+        let (program, _) = parse(tokenize(source, &options).unwrap(), &options).unwrap();
+        needed(&program, &libraries, &options).unwrap().iter().map(|(m, _)| m.name.clone()).collect()
+    };
+    assert!(names("PROC main()\nPrintE()\nRETURN\n").is_empty());
+    assert_eq!(names("PROC main()\nbanner()\nRETURN\n"), ["banner.act", "screen.act"]);
+    // The program's own Line comes before the library's
+    assert!(names("PROC Line(BYTE n)\nRETURN\nPROC main()\nLine(2)\nRETURN\n").is_empty());
+}
+
+#[test]
+fn members_go_before_the_program() {
+    // Banner needs Line, so screen.act comes before banner.act; the program keeps its
+    // own line numbers
+    let source = "\
+BYTE biggest
+PROC main()
+Banner()
+biggest = Max(3, 9)
+RETURN
+";
+    let options = CompileOptions::default();
+    let (program, _) = parse(tokenize(source, &options).unwrap(), &options).unwrap();
+    let linked = link(&program, &[library()], &options).unwrap();
+    let names: Vec<&str> = linked.procedures.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["Cls", "Line", "Banner", "Max", "main"]);
+
+    let [screen, math, banner] = [0, 1, 2].map(|i| library().members[i].source.clone());
+    let by_hand = format!("{}MODULE\n{}MODULE\n{}MODULE\n{}", screen, banner, math, source);
+    assert_eq!(compile_program(&linked, &options).unwrap().binary, compile_source(&by_hand, options.clone()).unwrap().binary);
+    let with_library = CompileOptions { libraries: vec![library()], ..options.clone() };
+    assert_eq!(compile_source(source, with_library).unwrap().binary, compile_source(&by_hand, options.clone()).unwrap().binary);
+    let test = format!("{}; expect: ---hi\n; expect-memory: biggest 09\n", by_hand);
+    assert_eq!(check(&test, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+
+    let (no_main, _) = parse(tokenize("PROC start()\nBanner()\nRETURN\n", &options).unwrap(), &options).unwrap();
+    assert!(link(&no_main, &[library()], &options).is_err());
+}
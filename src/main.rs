@@ -1,148 +1,1289 @@
 // Action! Compiler for Z80
 // A cross-compiler that generates Z80 machine code from Action! source
 
-mod lexer;
-mod token;
-mod ast;
-mod parser;
-mod codegen;
-mod runtime;
-mod error;
-
-use clap::Parser;
+use kz80_action::{
+    ast, bench, codegen, compile_patch, compile_source, emulator, format, library, manifest, meta, parse_source, parser, project, repl, run, runtime,
+    symbols, token, tokenize, upload, CompileError, CompileOptions,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::fs;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(name = "kz80_action")]
 #[command(about = "Action! language compiler for Z80", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Input Action! source file
-    #[arg(short, long)]
-    input: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input Action! source file; several are compiled one after the other as one program
+    #[arg(short, long, value_name = "FILE", required_unless_present = "files")]
+    input: Vec<PathBuf>,
+
+    /// More input files, after those given with --input
+    #[arg(value_name = "FILE")]
+    files: Vec<PathBuf>,
 
     /// Output binary file
     #[arg(short, long)]
     output: Option<PathBuf>,
 
+    /// Build for a system: sets the origin, console, RAM and output format together
+    #[arg(long, value_enum, value_name = "SYSTEM",
+          conflicts_with_all = ["org", "boot_rom", "uart", "console", "ram", "format"])]
+    target: Option<TargetKind>,
+
+    /// With --target rc2014 or rc2014-sio: a ROM image, or a program for RAM at 0x8000
+    /// loaded through the monitor as Intel HEX. With --target msx: a ROM cartridge, or
+    /// a BLOAD file
+    #[arg(long, value_enum, requires = "target")]
+    image: Option<ImageKind>,
+
     /// Origin address for code (default: 0x4200)
-    #[arg(long, default_value = "0x4200")]
-    org: String,
+    #[arg(long, default_value = "0x4200", value_parser = address_arg)]
+    org: u16,
+
+    /// Build a ROM image for 0x0000 with a reset stub and interrupt vectors
+    #[arg(long, conflicts_with = "org")]
+    boot_rom: bool,
+
+    /// Call PutD, PrintB, Print and PrintE through RST vectors (1 byte per call) in a boot ROM
+    #[arg(long, requires = "boot_rom")]
+    rst_calls: bool,
+
+    /// Initial stack pointer for --boot-rom (default: 0x0000, the top of memory)
+    #[arg(long, value_name = "ADDRESS", requires = "boot_rom", value_parser = address_arg)]
+    stack: Option<u16>,
+
+    /// Prefix the image with a stub that relocates it to wherever it is loaded
+    #[arg(long, conflicts_with = "boot_rom")]
+    relocatable: bool,
+
+    /// Return to the caller when main returns instead of halting, for programs started
+    /// from a monitor
+    #[arg(long = "return")]
+    return_to_caller: bool,
+
+    /// Fail if the output image is larger than this (the ROM or RAM set aside for it)
+    #[arg(long, value_name = "BYTES", value_parser = address_arg)]
+    max_size: Option<u16>,
+
+    /// Run address for initialized data (default: directly after code)
+    #[arg(long, value_parser = address_arg)]
+    data_addr: Option<u16>,
+
+    /// Serial chip of the console and other devices, set up at startup
+    #[arg(long, value_enum, default_value_t = UartKind::Simple)]
+    uart: UartKind,
+
+    /// Where console I/O goes: the UART, or the system the program runs under
+    #[arg(long, value_enum, default_value_t = ConsoleKind::Uart)]
+    console: ConsoleKind,
+
+    /// First RAM address, for the runtime's and the program's variables (default: 0x2000)
+    #[arg(long, value_name = "ADDRESS", value_parser = address_arg)]
+    ram: Option<u16>,
+
+    /// Output file formats; the first is written to the output file, the others next
+    /// to it with their own extension
+    #[arg(long, value_enum, value_name = "FORMAT,...", value_delimiter = ',', default_value = "bin")]
+    format: Vec<FormatKind>,
+
+    /// Replace NAME with TEXT wherever it appears in the source, like DEFINE
+    #[arg(short = 'D', long, value_name = "NAME=TEXT")]
+    define: Vec<String>,
+
+    /// Library (.lib) to take the procedures and globals the program uses but does not
+    /// define from; may be given more than once, and the first to define a name wins
+    #[arg(long = "lib", value_name = "FILE")]
+    libs: Vec<PathBuf>,
+
+    /// UART clock divide (1, 16 or 64) [default: 64]
+    #[arg(long)]
+    uart_divide: Option<u8>,
+
+    /// Queue console output in a ring buffer of this many bytes (a power of two up to 128),
+    /// sent as the UART becomes ready instead of waiting on it for every character
+    #[arg(long, value_name = "BYTES")]
+    tx_buffer: Option<u8>,
+
+    /// Keep expression temporaries on a stack of this many bytes in RAM instead of the
+    /// hardware stack, for systems with little stack to spare for interrupts
+    #[arg(long, value_name = "BYTES")]
+    eval_stack: Option<u16>,
+
+    /// Don't echo line input (InputS), for terminals that echo what is typed themselves
+    #[arg(long)]
+    no_echo: bool,
+
+    /// Key that ends a line of input (InputS) [default: cr]
+    #[arg(long, value_enum, value_name = "KEY")]
+    line_end: Option<LineEndKind>,
+
+    /// Character set the program's text is in, translated on output: atascii, petscii,
+    /// or a 256-byte file giving the byte to send for each code
+    #[arg(long, value_name = "NAME|FILE")]
+    charset: Option<String>,
+
+    /// Console (device 0) ports as DATA[,STATUS] (default: the usual ports of the UART)
+    #[arg(long, value_name = "PORTS")]
+    console_ports: Option<String>,
+
+    /// Printer (device 1) ports as DATA[,STATUS] (default: 0x02,0x03)
+    #[arg(long, value_name = "PORTS")]
+    printer_ports: Option<String>,
+
+    /// Aux serial (device 2) ports as DATA[,STATUS] (default: 0x04,0x05)
+    #[arg(long, value_name = "PORTS")]
+    aux_ports: Option<String>,
+
+    /// Procedure to call at startup before main, with interrupts disabled (default: SysInit if defined)
+    #[arg(long, value_name = "PROC")]
+    init: Option<String>,
+
+    /// Put a table of JP instructions to these procedures at the start of the image,
+    /// 3 bytes per entry in the order given, so other programs can call them at fixed addresses
+    #[arg(long, value_name = "PROC,...", value_delimiter = ',')]
+    jump_table: Vec<String>,
+
+    /// Include the XMODEM transfer routines (XRecv and XSend) in the runtime
+    #[arg(long)]
+    xmodem: bool,
+
+    /// Include the CRC routines (Crc16 and CheckSum) in the runtime
+    #[arg(long, value_enum, value_name = "METHOD", num_args = 0..=1, default_missing_value = "bitwise")]
+    crc: Option<CrcKind>,
+
+    /// Prefer smaller or faster code where there is a choice
+    #[arg(long, value_enum, value_name = "GOAL")]
+    opt_for: Option<OptFor>,
+
+    /// What + and - do when the result does not fit its type
+    #[arg(long, value_enum, value_name = "MODE", default_value = "wrap")]
+    overflow: OverflowKind,
+
+    /// Generate every procedure, including those the program never calls
+    #[arg(long)]
+    keep_unused: bool,
+
+    /// Leave out variables that are written but never read, and the stores to them
+    #[arg(long)]
+    drop_write_only: bool,
+
+    /// Let procedures that are never active at the same time share RAM for their locals
+    /// (non-recursive programs only)
+    #[arg(long)]
+    overlay_locals: bool,
+
+    /// Give every procedure with locals a stack frame, so each call has its own
+    /// (recursive procedures always get one)
+    #[arg(long)]
+    stack_locals: bool,
+
+    /// Check the generated code for signs of compiler bugs (bad jump targets, unbalanced stack)
+    #[arg(long)]
+    verify: bool,
 
     /// Generate listing file
     #[arg(short, long)]
     listing: bool,
 
+    /// Sections of the listing to write
+    #[arg(long, value_enum, value_name = "SECTION,...", value_delimiter = ',',
+          default_value = "code,data,symbols", requires = "listing")]
+    lst_sections: Vec<ListingSection>,
+
+    /// Leave the hex dump of the program code out of the listing
+    #[arg(long, requires = "listing")]
+    lst_no_hex: bool,
+
+    /// Leave the disassembled program and runtime library out of the listing
+    #[arg(long, requires = "listing")]
+    lst_no_disasm: bool,
+
+    /// Also write a machine-readable listing (.json or .csv)
+    #[arg(long, value_name = "FORMAT")]
+    listing_export: Option<ListingFormat>,
+
+    /// Also write these next to the output: meta (.meta.json, the entry address,
+    /// sections, runtime routines and variables, for emulators and debuggers); asm
+    /// (.asm, source for sjasmplus or z80asm that assembles to the image, with labels);
+    /// sections (.code.bin and .data.bin, listed with their addresses in .sections.json);
+    /// stack (.stack.txt, the stack each procedure uses); cfg (.cfg.dot, each
+    /// procedure's basic blocks as a Graphviz graph)
+    #[arg(long, value_enum, value_name = "KIND,...", value_delimiter = ',')]
+    emit: Vec<EmitKind>,
+
+    /// Write the addresses of the public procedures and globals to a symbol file (.json),
+    /// for another build to import
+    #[arg(long, value_name = "FILE")]
+    export_symbols: Option<PathBuf>,
+
+    /// Call the procedures and use the globals of a separately built image, as its
+    /// symbol file gives them (repeatable)
+    #[arg(long, value_name = "FILE")]
+    import_symbols: Vec<PathBuf>,
+
+    /// Compile only this procedure and write it over its old code in the image given by
+    /// --patch-into, at the address its --import-symbols file gives
+    #[arg(long, value_name = "PROC", requires_all = ["patch_into", "import_symbols"],
+          conflicts_with_all = ["export_symbols", "emit", "listing", "listing_export", "relocatable"])]
+    patch: Option<String>,
+
+    /// With --patch: the binary image to patch, which is also the output unless -o is given
+    #[arg(long, value_name = "FILE", requires = "patch")]
+    patch_into: Option<PathBuf>,
+
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
+
+    /// Require uppercase keywords and exact-case names
+    #[arg(long)]
+    strict_case: bool,
+
+    /// Number of elements for an ARRAY declared without a size [default: 256]
+    #[arg(long)]
+    default_array_size: Option<usize>,
+
+    /// Maximum nesting depth of expressions and blocks, up to 1000 [default: 200]
+    #[arg(long, value_parser = nesting_arg)]
+    max_nesting: Option<usize>,
+
+    /// Operator precedence to parse expressions with
+    #[arg(long, value_enum, value_name = "MODE", default_value = "modern")]
+    compat: CompatKind,
 }
 
-fn main() {
-    let args = Args::parse();
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CompatKind {
+    /// Shifts below + and -, XOR alongside OR
+    Modern,
+    /// The original Action!'s: shifts alongside *, XOR below OR
+    Strict,
+}
 
-    // Parse origin address
-    let org = if args.org.starts_with("0x") || args.org.starts_with("0X") {
-        u16::from_str_radix(&args.org[2..], 16).unwrap_or(0x4200)
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CrcKind {
+    /// A bit at a time, in a few dozen bytes
+    Bitwise,
+    /// A byte at a time from a 512-byte table
+    Table,
+}
+
+impl From<CrcKind> for runtime::Crc {
+    fn from(kind: CrcKind) -> Self {
+        match kind {
+            CrcKind::Bitwise => runtime::Crc::Bitwise,
+            CrcKind::Table => runtime::Crc::Table,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ListingSection {
+    /// Program code and the runtime library
+    Code,
+    /// Initialized data
+    Data,
+    /// Procedures, variables, the jump table and registers changed
+    Symbols,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ListingFormat {
+    Json,
+    Csv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum EmitKind {
+    /// Where the build put everything, as JSON
+    Meta,
+    /// The image as Z80 assembler source
+    Asm,
+    /// A file for each section, and a manifest of them
+    Sections,
+    /// The stack each procedure uses
+    Stack,
+    /// Each procedure's basic blocks, as a Graphviz graph
+    Cfg,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OptFor {
+    /// JR for loops, DJNZ for counted FOR loops
+    Size,
+    /// Shifts for multiplies by powers of two
+    Speed,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OverflowKind {
+    /// Keep the low bits, as the Z80 does
+    Wrap,
+    /// Stop with "?Overflow at $XXXX", the address of the operation
+    Check,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LineEndKind {
+    /// Carriage return ends a line, line feeds are ignored
+    Cr,
+    /// Line feed ends a line, carriage returns are ignored
+    Lf,
+}
+
+impl From<LineEndKind> for runtime::LineEnd {
+    fn from(kind: LineEndKind) -> Self {
+        match kind {
+            LineEndKind::Cr => runtime::LineEnd::Cr,
+            LineEndKind::Lf => runtime::LineEnd::Lf,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum UartKind {
+    /// Pre-initialized port, data 0x00 and status 0x01 (RetroShield)
+    Simple,
+    /// Motorola 6850 ACIA, data 0x81 and control 0x80
+    Acia,
+    /// Z80 SIO channel A, data 0x81 and control 0x80
+    Sio,
+    /// Intel 8251 USART, data 0x00 and control 0x01
+    #[value(name = "8251")]
+    I8251,
+}
+
+impl From<UartKind> for runtime::Uart {
+    fn from(kind: UartKind) -> Self {
+        match kind {
+            UartKind::Simple => runtime::Uart::Simple,
+            UartKind::Acia => runtime::Uart::Acia,
+            UartKind::Sio => runtime::Uart::Sio,
+            UartKind::I8251 => runtime::Uart::I8251,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ConsoleKind {
+    /// The UART chosen with --uart
+    Uart,
+    /// CP/M BDOS calls; the program returns to CP/M
+    Cpm,
+    /// ZX Spectrum ROM; the program returns to BASIC
+    Zx,
+    /// MSX BIOS; the program returns to BASIC
+    Msx,
+}
+
+impl From<ConsoleKind> for runtime::ConsoleBackend {
+    fn from(kind: ConsoleKind) -> Self {
+        match kind {
+            ConsoleKind::Uart => runtime::ConsoleBackend::Uart,
+            ConsoleKind::Cpm => runtime::ConsoleBackend::Cpm,
+            ConsoleKind::Zx => runtime::ConsoleBackend::Zx,
+            ConsoleKind::Msx => runtime::ConsoleBackend::Msx,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum FormatKind {
+    /// The image as is
+    Bin,
+    /// The image as is, as a CP/M .COM file
+    Com,
+    /// Intel HEX
+    Hex,
+    /// MSX BLOAD file
+    Msx,
+    /// MSX ROM cartridge (.rom), for --org 0x4010 or 0x8010 after its header
+    MsxRom,
+    /// ZX Spectrum tape file
+    Tap,
+    /// Checksummed chunks for loader/chunkload.asm (.chk), with the data where it runs
+    Chunks,
+}
+
+impl From<FormatKind> for format::Format {
+    fn from(kind: FormatKind) -> Self {
+        match kind {
+            FormatKind::Bin => format::Format::Bin,
+            FormatKind::Com => format::Format::Com,
+            FormatKind::Hex => format::Format::Hex,
+            FormatKind::Msx => format::Format::Msx,
+            FormatKind::MsxRom => format::Format::MsxRom,
+            FormatKind::Tap => format::Format::Tap,
+            FormatKind::Chunks => format::Format::Chunks,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ImageKind {
+    /// Boot ROM image
+    Rom,
+    /// Program in RAM, loaded and started through the ROM's monitor
+    Ram,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum TargetKind {
+    /// RetroShield Z80: code from 0x0000, simple UART, RAM from 0x2000
+    Retroshield,
+    /// RC2014: 8KB boot ROM with the 6850 ACIA, RAM from 0x8000
+    Rc2014,
+    /// RC2014 or Small Computer board with the SIO/2 (console on channel A), ROM and
+    /// RAM as rc2014
+    Rc2014Sio,
+    /// CP/M 2.2: .COM program at 0x0100, console through the BDOS, RAM from 0x8000
+    Cpm,
+    /// ZX Spectrum: tape file with code at 0x8000, RAM from 0xC000
+    Zx,
+    /// MSX: BLOAD file with code at 0x9000, RAM from 0xD000; or with --image rom, a
+    /// cartridge at 0x4000, RAM from 0xC000
+    Msx,
+    /// Golden-image tests: every setting the compiler has a default for is fixed, so
+    /// the output only changes when the compiler's code does
+    Test,
+}
+
+impl TargetKind {
+    // Set the options the target stands for
+    fn apply(self, args: &mut Args) {
+        let (org, console, ram, format) = match self {
+            TargetKind::Retroshield => (0x0000, ConsoleKind::Uart, 0x2000, FormatKind::Bin),
+            TargetKind::Rc2014 | TargetKind::Rc2014Sio => {
+                args.uart = if self == TargetKind::Rc2014 { UartKind::Acia } else { UartKind::Sio };
+                match args.image.unwrap_or(ImageKind::Rom) {
+                    // ROM from 0x0000 to 0x1FFF
+                    ImageKind::Rom => {
+                        args.boot_rom = true;
+                        args.max_size = Some(0x2000);
+                        (0x0000, ConsoleKind::Uart, 0x8000, FormatKind::Bin)
+                    }
+                    // Run with the monitor's G 8000, below the variables
+                    ImageKind::Ram => {
+                        args.return_to_caller = true;
+                        args.max_size = Some(0x4000);
+                        (0x8000, ConsoleKind::Uart, 0xC000, FormatKind::Hex)
+                    }
+                }
+            }
+            TargetKind::Cpm => (0x0100, ConsoleKind::Cpm, 0x8000, FormatKind::Com),
+            TargetKind::Zx => (0x8000, ConsoleKind::Zx, 0xC000, FormatKind::Tap),
+            TargetKind::Msx => match args.image.unwrap_or(ImageKind::Ram) {
+                // 16 or 32KB from 0x4000, started by the BIOS through the header
+                ImageKind::Rom => {
+                    args.max_size = Some(0x7FF0);
+                    (0x4010, ConsoleKind::Msx, 0xC000, FormatKind::MsxRom)
+                }
+                ImageKind::Ram => (0x9000, ConsoleKind::Msx, 0xD000, FormatKind::Msx),
+            },
+            // The settings left to the defaults are filled in as well
+            TargetKind::Test => {
+                let pinned = CompileOptions::pinned();
+                let runtime = &pinned.runtime;
+                args.uart = UartKind::Simple;
+                let ports = |(data, status): (u8, u8)| Some(format!("{},{}", data, status));
+                args.console_ports = args.console_ports.take().or_else(|| ports(runtime.devices.console));
+                args.printer_ports = args.printer_ports.take().or_else(|| ports(runtime.devices.printer));
+                args.aux_ports = args.aux_ports.take().or_else(|| ports(runtime.devices.aux));
+                args.uart_divide.get_or_insert(runtime.clock_divide);
+                args.line_end.get_or_insert(LineEndKind::Cr);
+                args.default_array_size.get_or_insert(pinned.default_array_size);
+                args.max_nesting.get_or_insert(pinned.max_nesting);
+                (pinned.origin, ConsoleKind::Uart, runtime.ram_start, FormatKind::Bin)
+            }
+        };
+        args.org = org;
+        args.console = console;
+        args.ram = Some(ram);
+        args.format = vec![format];
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Interactively run statements on the built-in Z80 emulator
+    Repl {
+        /// Require uppercase keywords and exact-case names
+        #[arg(long)]
+        strict_case: bool,
+    },
+    /// Compile a program and run it on the built-in Z80 emulator, with the console on
+    /// this terminal
+    Run {
+        /// Action! source file
+        program: PathBuf,
+
+        /// Map an 80x25 text screen at this address, shown when the program halts
+        #[arg(long, value_parser = address_arg)]
+        screen: Option<u16>,
+
+        /// Map banked memory: a bank select register, a window address and size, and
+        /// the number of banks
+        #[arg(long, value_name = "REGISTER,WINDOW,SIZE,COUNT")]
+        bank: Option<String>,
+
+        /// Type the console input from a script instead of this terminal
+        #[arg(long, value_name = "SCRIPT")]
+        input: Option<PathBuf>,
+
+        /// Watch the source file, and compile each procedure changed in it again and
+        /// patch it into memory while the program runs
+        #[arg(long)]
+        watch: bool,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+    /// Run test programs on the emulator and check their console output against
+    /// their `; expect:` comment lines
+    Test {
+        /// Action! source files (default: the tests listed in action.toml)
+        programs: Vec<PathBuf>,
+
+        /// Type the console input from a script
+        #[arg(long, value_name = "SCRIPT")]
+        input: Option<PathBuf>,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+    /// Send a compiled program to a board over a serial port
+    Upload {
+        /// Compiler output: an Intel HEX file (.hex), or an image to send as one
+        program: PathBuf,
+
+        /// Serial port, such as /dev/ttyUSB0 or COM3
+        #[arg(short, long)]
+        port: String,
+
+        /// Baud rate
+        #[arg(long, default_value_t = 115200)]
+        baud: u32,
+
+        /// How the board takes the program in
+        #[arg(long, value_enum, default_value_t = ProtocolKind::Hex)]
+        protocol: ProtocolKind,
+
+        /// Load address of an image sent as Intel HEX, and where --run starts it
+        /// (default: 0x0100 for CP/M, 0x8000 otherwise)
+        #[arg(long, value_parser = address_arg)]
+        org: Option<u16>,
+
+        /// Start the program once it is loaded: G with the load address at a monitor,
+        /// the program's name at CP/M
+        #[arg(long)]
+        run: bool,
+
+        /// Milliseconds to wait after each line of Intel HEX, for monitors without flow control
+        #[arg(long, value_name = "MS", default_value_t = 10)]
+        line_delay: u64,
+    },
+    /// Create a project directory with a manifest, a program and a test
+    New {
+        /// Name of the project and its directory
+        name: String,
+
+        /// System to build for
+        #[arg(long, value_enum, value_name = "SYSTEM")]
+        target: Option<TargetKind>,
+    },
+    /// Compile the project described by a manifest
+    Build {
+        /// Project manifest
+        #[arg(default_value = "action.toml")]
+        manifest: PathBuf,
+    },
+    /// Bundle source files into a library (.lib) that programs take members from with --lib
+    Lib {
+        /// Action! source files, one member each
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Library file to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Compile a directory of programs and report size and cycle counts
+    Bench {
+        /// Directory of .act programs
+        #[arg(default_value = "examples")]
+        dir: PathBuf,
+
+        /// Save the results as a baseline file
+        #[arg(long)]
+        save: Option<PathBuf>,
+
+        /// Compare against a saved baseline file
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ProtocolKind {
+    /// Intel HEX typed at a monitor such as SCM
+    Hex,
+    /// XMODEM to a program receiving it, such as one calling XRecv
+    Xmodem,
+    /// Intel HEX typed into PIP at the CP/M prompt, then made into a .COM file with LOAD
+    Cpm,
+}
+
+impl From<ProtocolKind> for upload::Protocol {
+    fn from(kind: ProtocolKind) -> Self {
+        match kind {
+            ProtocolKind::Hex => upload::Protocol::Hex,
+            ProtocolKind::Xmodem => upload::Protocol::Xmodem,
+            ProtocolKind::Cpm => upload::Protocol::Cpm,
+        }
+    }
+}
+
+/// Limits for programs run on the emulator
+#[derive(clap::Args, Debug)]
+struct LimitArgs {
+    /// Stop the program after this many T-states
+    #[arg(long)]
+    max_cycles: Option<u64>,
+
+    /// Stop the program after this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+}
+
+impl From<&LimitArgs> for run::Limits {
+    fn from(args: &LimitArgs) -> Self {
+        run::Limits {
+            max_cycles: args.max_cycles,
+            timeout: args.timeout.map(std::time::Duration::from_secs),
+        }
+    }
+}
+
+// An address or size option, in hex with 0x or in decimal
+fn address_arg(text: &str) -> Result<u16, String> {
+    let (digits, radix) = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None => (text, 10),
+    };
+    let value = u32::from_str_radix(digits, radix)
+        .map_err(|_| "expected a number in hex, as 0x4200, or in decimal".to_string())?;
+    u16::try_from(value).map_err(|_| "the value is past 0xFFFF".to_string())
+}
+
+// A nesting limit. The compiler recurses once a level, so its own stack, rather
+// than the language, bounds it: 1000 levels leave the main thread room to spare
+fn nesting_arg(text: &str) -> Result<usize, String> {
+    let depth: usize = text.parse().map_err(|_| "expected a number".to_string())?;
+    if depth > 1000 {
+        return Err("at most 1000 levels fit the compiler's stack".to_string());
+    }
+    Ok(depth)
+}
+
+// Data and status ports from "DATA[,STATUS]"; the status port defaults to DATA+1
+fn parse_ports(text: &str) -> Option<(u8, u8)> {
+    let port = |t: &str| address_arg(t.trim()).ok().and_then(|n| u8::try_from(n).ok());
+    match text.split_once(',') {
+        Some((data, status)) => Some((port(data)?, port(status)?)),
+        None => {
+            let data = port(text)?;
+            Some((data, data.checked_add(1)?))
+        }
+    }
+}
+
+// Output translation from a character set's name or a table file
+fn parse_charset(text: &str) -> runtime::Charset {
+    match text.to_ascii_lowercase().as_str() {
+        "atascii" => return runtime::Charset::Atascii,
+        "petscii" => return runtime::Charset::Petscii,
+        _ => {}
+    }
+    let table = fs::read(text).unwrap_or_else(|e| {
+        eprintln!("Error reading file {:?}: {}", text, e);
+        std::process::exit(1);
+    });
+    let table: [u8; 256] = table.as_slice().try_into().unwrap_or_else(|_| {
+        eprintln!("Error: --charset table {:?} must be 256 bytes, found {}", text, table.len());
+        std::process::exit(1);
+    });
+    runtime::Charset::Table(Box::new(table))
+}
+
+// Bank configuration from "REGISTER,WINDOW,SIZE,COUNT"
+fn parse_bank(text: &str) -> Option<run::BankConfig> {
+    let fields: Vec<u16> = text.split(',').map(|t| address_arg(t.trim())).collect::<Result<_, _>>().ok()?;
+    match fields[..] {
+        [register, window, size, count] if size > 0 && count > 0 => {
+            Some(run::BankConfig { register, window, size, count: count as usize })
+        }
+        _ => None,
+    }
+}
+
+// Write a library of the files, each a member under its file name
+fn create_library(files: &[PathBuf], output: &std::path::Path) -> Result<(), String> {
+    let mut sources = Vec::new();
+    for path in files {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading file {:?}: {}", path, e))?;
+        let name = path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+        sources.push((name, source));
+    }
+    let lib = library::Library::create(&sources, &CompileOptions::default()).map_err(|e| e.to_string())?;
+    fs::write(output, lib.to_text()).map_err(|e| format!("Error writing file {:?}: {}", output, e))?;
+    println!("Wrote {} members to {:?}", lib.members.len(), output);
+    Ok(())
+}
+
+// Console with the input typed from a script, if there is one
+fn scripted_console(input: Option<&std::path::Path>) -> Result<emulator::Console, String> {
+    let mut console = emulator::Console::new();
+    if let Some(path) = input {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading file {:?}: {}", path, e))?;
+        console.script = run::parse_input_script(&text)
+            .map_err(|e| format!("Error in input script {:?}: {}", path, e))?;
+    }
+    Ok(console)
+}
+
+fn run_program(
+    program: &std::path::Path,
+    screen: Option<u16>,
+    bank: Option<&str>,
+    input: Option<&std::path::Path>,
+    watch: bool,
+    limits: &run::Limits,
+) -> Result<(), String> {
+    let source = fs::read_to_string(program)
+        .map_err(|e| format!("Error reading file {:?}: {}", program, e))?;
+    let bank = bank.map(|text| parse_bank(text)
+        .ok_or_else(|| format!("Error: --bank expects REGISTER,WINDOW,SIZE,COUNT, found '{}'", text)))
+        .transpose()?;
+    let machine = run::Machine {
+        screen,
+        bank,
+    };
+
+    let mut console = scripted_console(input)?;
+    console.echo = true;
+    console.interactive = input.is_none();
+    let cpu = if watch {
+        // Look at the file at most a few times a second
+        let modified = || fs::metadata(program).and_then(|m| m.modified()).ok();
+        let mut last = modified();
+        let mut checked = std::time::Instant::now();
+        let mut changed = || {
+            if checked.elapsed() < std::time::Duration::from_millis(250) {
+                return None;
+            }
+            checked = std::time::Instant::now();
+            let now = modified();
+            if now == last {
+                return None;
+            }
+            last = now;
+            fs::read_to_string(program).ok()
+        };
+        run::run_watched(&source, &machine, limits, &mut console, &mut changed, &mut |message| eprintln!("[watch] {}", message))?
     } else {
-        args.org.parse().unwrap_or(0x4200)
+        run::run(&source, &machine, limits, &mut console)?
     };
+    if let Some(screen) = cpu.device::<emulator::TextScreen>() {
+        print!("{}", screen.render());
+    }
+    Ok(())
+}
+
+// Check each test program, reporting PASS or FAIL; true if all passed
+fn run_tests(programs: &[PathBuf], input: Option<&std::path::Path>, limits: &run::Limits) -> Result<bool, String> {
+    let mut failed = 0;
+    for program in programs {
+        let source = run::test_source(program)?;
+        let mut console = scripted_console(input)?;
+        match run::check(&source, &run::Machine::default(), limits, &mut console) {
+            Ok(dumps) => print!("PASS {}\n{}", program.display(), dumps),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}\n{}", program.display(), e);
+            }
+        }
+    }
+    println!("{} passed, {} failed", programs.len() - failed, failed);
+    Ok(failed == 0)
+}
+
+// What the upload subcommand was asked to send, and where
+struct UploadArgs<'a> {
+    program: &'a std::path::Path,
+    port: &'a str,
+    baud: u32,
+    protocol: upload::Protocol,
+    org: u16,
+    run: bool,
+    line_delay: std::time::Duration,
+}
+
+fn run_upload(args: &UploadArgs) -> Result<(), String> {
+    let file = fs::read(args.program)
+        .map_err(|e| format!("Error reading file {:?}: {}", args.program, e))?;
+    let is_hex = args.program.extension().is_some_and(|e| e.eq_ignore_ascii_case("hex"));
+    let hex = || if is_hex { file.clone() } else { format::Format::Hex.wrap(&file, args.org, "") };
+    let name: String = args.program.file_stem().map_or(String::new(), |s| s.to_string_lossy().to_uppercase())
+        .chars().filter(char::is_ascii_alphanumeric).take(8).collect();
+
+    if args.protocol == upload::Protocol::Xmodem && (is_hex || args.run) {
+        return Err("Error: XMODEM sends images, not .hex files, and cannot start them".to_string());
+    }
+
+    let mut line = serialport::new(args.port, args.baud)
+        .timeout(std::time::Duration::from_secs(1))
+        .open()
+        .map_err(|e| format!("Error opening {}: {}", args.port, e))?;
+    let io_error = |e: std::io::Error| format!("Error on {}: {}", args.port, e);
+    match args.protocol {
+        upload::Protocol::Hex => {
+            upload::send_hex(&mut line, &hex(), args.line_delay).map_err(io_error)?;
+            if args.run {
+                upload::type_command(&mut line, &format!("G {:04X}", args.org)).map_err(io_error)?;
+            }
+        }
+        upload::Protocol::Xmodem => {
+            upload::send_xmodem(&mut line, &file).map_err(|e| format!("Error: XMODEM {}", e))?;
+        }
+        upload::Protocol::Cpm => {
+            if name.is_empty() {
+                return Err(format!("Error: no CP/M file name in {:?}", args.program));
+            }
+            upload::send_cpm(&mut line, &name, &hex(), args.line_delay).map_err(io_error)?;
+            if args.run {
+                upload::type_command(&mut line, &name).map_err(io_error)?;
+            }
+        }
+    }
+    println!("Sent {:?} to {}", args.program, args.port);
+    Ok(())
+}
+
+fn run_bench(dir: &std::path::Path, save: Option<&std::path::Path>, baseline: Option<&std::path::Path>) -> std::io::Result<()> {
+    let results = bench::run(dir)?;
+    let baseline = baseline.map(bench::load).transpose()?;
+    print!("{}", bench::report(&results, baseline.as_ref()));
+    if let Some(path) = save {
+        bench::save(path, &results)?;
+        println!("Baseline saved to {:?}", path);
+    }
+    Ok(())
+}
 
-    // Read source file
-    let source = match fs::read_to_string(&args.input) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Error reading file {:?}: {}", args.input, e);
+fn main() {
+    let args = Args::parse();
+
+    match args.command {
+        Some(Command::Repl { strict_case }) => {
+            let case_mode = if strict_case {
+                token::CaseMode::Strict
+            } else {
+                token::CaseMode::Insensitive
+            };
+            if let Err(e) = repl::Repl::new(case_mode).run() {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Run { program, screen, bank, input, watch, limits }) => {
+            let limits = run::Limits::from(&limits);
+            if let Err(e) = run_program(&program, screen, bank.as_deref(), input.as_deref(), watch, &limits) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Test { mut programs, input, limits }) => {
+            if programs.is_empty() {
+                programs = manifest::load(std::path::Path::new("action.toml")).map(|m| m.tests()).unwrap_or_else(|e| {
+                    eprintln!("Error: no programs given, and no tests from action.toml: {}", e);
+                    std::process::exit(1);
+                });
+            }
+            match run_tests(&programs, input.as_deref(), &run::Limits::from(&limits)) {
+                Ok(true) => return,
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::Upload { program, port, baud, protocol, org, run, line_delay }) => {
+            let protocol = upload::Protocol::from(protocol);
+            let default_org = if protocol == upload::Protocol::Cpm { 0x0100 } else { 0x8000 };
+            let args = UploadArgs {
+                program: &program,
+                port: &port,
+                baud,
+                protocol,
+                org: org.unwrap_or(default_org),
+                run,
+                line_delay: std::time::Duration::from_millis(line_delay),
+            };
+            if let Err(e) = run_upload(&args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Lib { files, output }) => {
+            if let Err(e) = create_library(&files, &output) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Bench { dir, save, baseline }) => {
+            if let Err(e) = run_bench(&dir, save.as_deref(), baseline.as_deref()) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::New { name, target }) => {
+            // The extension of what the target builds, as the compiler would pick it
+            let mut defaults = Args::parse_from(["kz80_action", "-i", "main.act"]);
+            if let Some(target) = target {
+                target.apply(&mut defaults);
+            }
+            let extension = format::Format::from(defaults.format[0]).extension();
+            let target = target.and_then(|t| t.to_possible_value()).map(|v| v.get_name().to_string());
+            match project::create(std::path::Path::new(&name), &name, target.as_deref(), extension) {
+                Ok(files) => {
+                    for file in files {
+                        println!("Created {:?}", file);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Build { manifest }) => {
+            let (argv, sources) = manifest::load(&manifest).and_then(|m| m.to_args()).unwrap_or_else(|e| {
+                eprintln!("Error in {:?}: {}", manifest, e);
+                std::process::exit(1);
+            });
+            let args = Args::try_parse_from(argv).unwrap_or_else(|e| {
+                eprintln!("Error in {:?}: {}", manifest, e);
+                std::process::exit(1);
+            });
+            compile(args, &sources);
+            return;
+        }
+        None => {}
+    }
+    let sources: Vec<PathBuf> = args.input.iter().chain(&args.files).cloned().collect();
+    compile(args, &sources);
+}
+
+// Compile the sources, one after the other as one program, as the options say
+fn compile(mut args: Args, sources: &[PathBuf]) {
+    let input = &sources[0];
+    if let Some(target) = args.target {
+        if args.image.is_some() && !matches!(target, TargetKind::Rc2014 | TargetKind::Rc2014Sio | TargetKind::Msx) {
+            eprintln!("Error: --image only applies to --target rc2014, rc2014-sio and msx");
             std::process::exit(1);
         }
+        target.apply(&mut args);
+    }
+
+    // Parse origin address
+    let org = if args.boot_rom { 0x0000 } else { args.org };
+
+    // Read source files, noting the line each starts at
+    let mut source = String::new();
+    let mut starts = Vec::new();
+    for path in sources {
+        starts.push((path, source.lines().count() + 1));
+        match fs::read_to_string(path) {
+            Ok(s) => {
+                source.push_str(&s);
+                if !source.ends_with('\n') {
+                    source.push('\n');
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading file {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Where a line of the sources put together is, when there are several files
+    let place = |line: usize| match starts.iter().rev().find(|&&(_, start)| start <= line) {
+        Some((path, start)) if sources.len() > 1 => format!(" ({} line {})", path.display(), line - start + 1),
+        _ => String::new(),
     };
 
     if args.verbose {
-        println!("Compiling {:?}...", args.input);
+        println!("Compiling {:?}...", sources);
         println!("Origin address: 0x{:04X}", org);
     }
 
-    // Tokenize
-    let mut lexer = lexer::Lexer::new(&source);
-    let tokens = match lexer.tokenize() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("Lexer error: {}", e);
+    // Symbols of the images the program uses
+    let mut imports = Vec::new();
+    for path in &args.import_symbols {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Error reading file {:?}: {}", path, e);
+            std::process::exit(1);
+        });
+        imports.push(symbols::SymbolFile::parse(&text).unwrap_or_else(|e| {
+            eprintln!("Error in symbol file {:?}: {}", path, e);
+            std::process::exit(1);
+        }));
+    }
+
+    // Libraries whose members it uses
+    let mut libraries = Vec::new();
+    for path in &args.libs {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Error reading file {:?}: {}", path, e);
             std::process::exit(1);
+        });
+        libraries.push(library::Library::parse(&text).unwrap_or_else(|e| {
+            eprintln!("Error in library {:?}: {}", path, e);
+            std::process::exit(1);
+        }));
+    }
+
+    let uart = runtime::Uart::from(args.uart);
+    let mut runtime_options = runtime::RuntimeOptions {
+        console: args.console.into(),
+        ram_start: args.ram.unwrap_or(runtime::RAM_START),
+        uart,
+        clock_divide: args.uart_divide.unwrap_or(runtime::RuntimeOptions::default().clock_divide),
+        xmodem: args.xmodem,
+        crc: args.crc.map(Into::into),
+        eval_stack: args.eval_stack.unwrap_or(0),
+        tx_buffer: args.tx_buffer.unwrap_or(0),
+        echo: !args.no_echo,
+        line_end: args.line_end.map_or(runtime::LineEnd::default(), Into::into),
+        charset: args.charset.as_deref().map(parse_charset),
+        rst_calls: args.rst_calls,
+        cartridge: args.format.contains(&FormatKind::MsxRom),
+        ..Default::default()
+    };
+    runtime_options.devices.console = uart.default_ports();
+    for (text, ports, flag) in [
+        (&args.console_ports, &mut runtime_options.devices.console, "--console-ports"),
+        (&args.printer_ports, &mut runtime_options.devices.printer, "--printer-ports"),
+        (&args.aux_ports, &mut runtime_options.devices.aux, "--aux-ports"),
+    ] {
+        if let Some(text) = text {
+            *ports = parse_ports(text).unwrap_or_else(|| {
+                eprintln!("Error: {} expects DATA[,STATUS] port numbers, found '{}'", flag, text);
+                std::process::exit(1);
+            });
         }
+    }
+
+    let options = CompileOptions {
+        origin: org,
+        boot_rom: args.boot_rom,
+        stack: args.stack.unwrap_or(0x0000),
+        relocatable: args.relocatable,
+        max_size: args.max_size.map(usize::from),
+        runtime: runtime_options,
+        case_mode: if args.strict_case { token::CaseMode::Strict } else { token::CaseMode::Insensitive },
+        defines: args.define.iter()
+            .map(|define| {
+                let (name, text) = define.split_once('=').unwrap_or((define, ""));
+                (name.trim().to_string(), text.to_string())
+            })
+            .collect(),
+        default_array_size: args.default_array_size.unwrap_or(parser::DEFAULT_ARRAY_SIZE),
+        max_nesting: args.max_nesting.unwrap_or(parser::DEFAULT_MAX_DEPTH),
+        compat: match args.compat {
+            CompatKind::Modern => parser::Compat::Modern,
+            CompatKind::Strict => parser::Compat::Strict,
+        },
+        exit: match args.console {
+            ConsoleKind::Uart if args.return_to_caller => codegen::Exit::Return,
+            ConsoleKind::Uart => codegen::Exit::Halt,
+            ConsoleKind::Cpm => codegen::Exit::Jump(0x0000),  // Warm start
+            ConsoleKind::Zx | ConsoleKind::Msx => codegen::Exit::Return,
+        },
+        opt_for: args.opt_for.map(|opt_for| match opt_for {
+            OptFor::Size => codegen::OptFor::Size,
+            OptFor::Speed => codegen::OptFor::Speed,
+        }),
+        overflow: match args.overflow {
+            OverflowKind::Wrap => codegen::Overflow::Wrap,
+            OverflowKind::Check => codegen::Overflow::Check,
+        },
+        data_address: args.data_addr,
+        // Only when every file loads the data where it runs can the image leave it out
+        data_loaded: args.format.iter().all(|&f| f == FormatKind::Chunks),
+        init: args.init.clone(),
+        // Another build may call any procedure in a symbol file
+        keep_unused: args.keep_unused || args.export_symbols.is_some(),
+        drop_write_only: args.drop_write_only && args.export_symbols.is_none(),
+        overlay_locals: args.overlay_locals,
+        stack_locals: args.stack_locals,
+        verify: args.verify,
+        jump_table: args.jump_table.clone(),
+        imports,
+        include_dir: input.parent().map(PathBuf::from),
+        libraries,
+        ..CompileOptions::default()
+    };
+    let fail = |e: CompileError| -> ! {
+        report(&e, &place);
+        std::process::exit(1);
     };
 
     if args.verbose {
-        println!("Tokens: {}", tokens.len());
-        for tok in &tokens {
+        for tok in tokenize(&source, &options).unwrap_or_else(|e| fail(e)) {
             println!("  {:?}", tok);
         }
     }
-
-    // Parse
-    let mut parser = parser::Parser::new(tokens);
-    let program = match parser.parse() {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("Parser error: {}", e);
-            std::process::exit(1);
-        }
-    };
-
+    if let (Some(name), Some(path)) = (&args.patch, &args.patch_into) {
+        let (program, _) = parse_source(&source, &options).unwrap_or_else(|e| fail(e));
+        patch(&args, name, path, &program, &options, &place);
+        return;
+    }
+    let built = compile_source(&source, options.clone()).unwrap_or_else(|e| fail(e));
+    let program = &built.program;
     if args.verbose {
         println!("AST: {:?}", program);
     }
-
-    // Generate runtime library first, leaving space for initial JP instruction
-    let runtime_start = org + 3;  // JP instruction takes 3 bytes
-    let (runtime_code, runtime_symbols) = runtime::generate_runtime(runtime_start);
-    let code_start = runtime_symbols.end_address;
+    for warning in &built.warnings {
+        // The parser's come first, by line
+        let by_line = warning.strip_prefix("line ").and_then(|w| w.split_once(": "))
+            .and_then(|(line, w)| Some((line.parse::<usize>().ok()?, w)));
+        match by_line {
+            Some((line, warning)) => eprintln!("Warning at line {}{}: {}", line, place(line), warning),
+            None => eprintln!("Warning: {}", warning),
+        }
+    }
+    let codegen = &built.codegen;
 
     if args.verbose {
+        let symbols = &built.runtime_symbols;
         println!("Runtime: {} bytes (0x{:04X}-0x{:04X})",
-                 runtime_code.len(), runtime_start, code_start);
-        println!("  PrintB: 0x{:04X}", runtime_symbols.print_b);
-        println!("  PrintC: 0x{:04X}", runtime_symbols.print_c);
-        println!("  PrintE: 0x{:04X}", runtime_symbols.print_e);
-        println!("  Print:  0x{:04X}", runtime_symbols.print);
-    }
-
-    // Generate code
-    let mut codegen = codegen::CodeGenerator::new(code_start);
-    codegen.set_runtime_symbols(&runtime_symbols);
-    let program_code = match codegen.generate(&program) {
-        Ok(b) => b,
-        Err(e) => {
-            eprintln!("Code generation error: {}", e);
-            std::process::exit(1);
-        }
-    };
+                 built.runtime_size, built.runtime_start, symbols.end_address);
+        println!("  PrintB: 0x{:04X}", symbols.print_b);
+        println!("  PrintC: 0x{:04X}", symbols.print_c);
+        println!("  PrintE: 0x{:04X}", symbols.print_e);
+        println!("  Print:  0x{:04X}", symbols.print);
+    }
+    let binary = &built.binary;
 
-    // Build final binary:
-    // 1. JP to code_start (entry point with CALL main, HALT)
-    // 2. Runtime library
-    // 3. Program code
-    let mut binary = Vec::new();
-    binary.push(0xC3);  // JP
-    binary.push((code_start & 0xFF) as u8);
-    binary.push((code_start >> 8) as u8);
-    binary.extend(runtime_code);
-    binary.extend(program_code);
-
-    // Determine output filename
-    let output_path = args.output.unwrap_or_else(|| {
-        let mut p = args.input.clone();
-        p.set_extension("bin");
+    // Determine output filenames: the first format's is the one given, the others
+    // swap in their own extension
+    let formats: Vec<format::Format> = args.format.iter().map(|&f| f.into()).collect();
+    let output_path = args.output.clone().unwrap_or_else(|| {
+        let mut p = input.clone();
+        p.set_extension(formats[0].extension());
         p
     });
+    for (i, format) in formats.iter().enumerate() {
+        if formats[..i].iter().any(|f| f.extension() == format.extension()) {
+            eprintln!("Error: two output formats would both write a .{} file", format.extension());
+            std::process::exit(1);
+        }
+    }
+    let name = output_path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
 
     // Write output
-    if let Err(e) = fs::write(&output_path, &binary) {
-        eprintln!("Error writing output file {:?}: {}", output_path, e);
-        std::process::exit(1);
+    for (i, format) in formats.iter().enumerate() {
+        let path = if i == 0 { output_path.clone() } else { output_path.with_extension(format.extension()) };
+        let file = match format {
+            format::Format::Chunks => format::chunks(&built.segments(), org),
+            format => format.wrap(binary, org, &name),
+        };
+        if let Err(e) = fs::write(&path, &file) {
+            eprintln!("Error writing output file {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+        println!("Compiled {} bytes to {:?}", file.len(), path);
+    }
+    if args.verbose {
+        for entry in &built.jump_table {
+            println!("  Jump table: {} at 0x{:04X} -> 0x{:04X}", entry.name, entry.entry, entry.target);
+        }
+    }
+
+    if let Some(path) = &args.export_symbols {
+        let exported = symbols::SymbolFile::export(program, &built, &options);
+        if let Err(e) = fs::write(path, exported.to_json()) {
+            eprintln!("Error writing symbol file {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+        println!("Symbols written to {:?}", path);
     }
 
-    println!("Compiled {} bytes to {:?}", binary.len(), output_path);
+    if args.emit.contains(&EmitKind::Meta) {
+        let meta_path = output_path.with_extension("meta.json");
+        if let Err(e) = fs::write(&meta_path, meta::Metadata::new(program, &built, &options).to_json()) {
+            eprintln!("Error writing metadata file {:?}: {}", meta_path, e);
+            std::process::exit(1);
+        }
+        println!("Metadata written to {:?}", meta_path);
+    }
+    if args.emit.contains(&EmitKind::Asm) {
+        let asm_path = output_path.with_extension("asm");
+        if let Err(e) = fs::write(&asm_path, built.assembly(program)) {
+            eprintln!("Error writing assembler source {:?}: {}", asm_path, e);
+            std::process::exit(1);
+        }
+        println!("Assembler source written to {:?}", asm_path);
+    }
+    if args.emit.contains(&EmitKind::Sections) {
+        let segments = built.segments();
+        let file_path = |name: &str| output_path.with_extension(format!("{}.bin", name));
+        for segment in &segments {
+            let path = file_path(segment.name);
+            if let Err(e) = fs::write(&path, &segment.bytes) {
+                eprintln!("Error writing section file {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        let manifest_path = output_path.with_extension("sections.json");
+        let file_name = |name: &str| file_path(name).file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        if let Err(e) = fs::write(&manifest_path, meta::SectionManifest::new(&segments, org, file_name).to_json()) {
+            eprintln!("Error writing section manifest {:?}: {}", manifest_path, e);
+            std::process::exit(1);
+        }
+        println!("Sections written to {:?}", manifest_path);
+    }
+    if args.emit.contains(&EmitKind::Stack) {
+        let stack_path = output_path.with_extension("stack.txt");
+        if let Err(e) = fs::write(&stack_path, built.stack_report()) {
+            eprintln!("Error writing stack report {:?}: {}", stack_path, e);
+            std::process::exit(1);
+        }
+        println!("Stack report written to {:?}", stack_path);
+    }
+    if args.emit.contains(&EmitKind::Cfg) {
+        let cfg_path = output_path.with_extension("cfg.dot");
+        if let Err(e) = fs::write(&cfg_path, built.control_flow(program)) {
+            eprintln!("Error writing control-flow graph {:?}: {}", cfg_path, e);
+            std::process::exit(1);
+        }
+        println!("Control-flow graph written to {:?}", cfg_path);
+    }
 
     // Generate listing if requested
     if args.listing {
@@ -151,11 +1292,69 @@ fn main() {
             p.set_extension("lst");
             p
         };
-        let listing = codegen.generate_listing();
+        let options = codegen::ListingOptions {
+            code: args.lst_sections.contains(&ListingSection::Code),
+            data: args.lst_sections.contains(&ListingSection::Data),
+            symbols: args.lst_sections.contains(&ListingSection::Symbols),
+            hex: !args.lst_no_hex,
+            disassembly: !args.lst_no_disasm,
+        };
+        let listing = built.listing(&options);
         if let Err(e) = fs::write(&listing_path, listing) {
             eprintln!("Error writing listing file {:?}: {}", listing_path, e);
         } else {
             println!("Listing written to {:?}", listing_path);
         }
     }
+
+    // Generate machine-readable listing if requested
+    if let Some(format) = args.listing_export {
+        let (extension, listing) = match format {
+            ListingFormat::Json => ("json", codegen.generate_listing_json(&source)),
+            ListingFormat::Csv => ("csv", codegen.generate_listing_csv(&source)),
+        };
+        let export_path = output_path.with_extension(extension);
+        if let Err(e) = fs::write(&export_path, listing) {
+            eprintln!("Error writing listing file {:?}: {}", export_path, e);
+        } else {
+            println!("Listing written to {:?}", export_path);
+        }
+    }
+}
+
+// Report a compile error the way its stage words it, with where its line is
+fn report(e: &CompileError, place: &dyn Fn(usize) -> String) {
+    let at = e.line().map_or(String::new(), place);
+    match e {
+        CompileError::DefineError { name, message } => eprintln!("Error in --define {}: {}", name, message),
+        CompileError::LexerError { .. } => eprintln!("Lexer error: {}{}", e, at),
+        CompileError::ParserError { .. } | CompileError::UnexpectedToken { .. } => eprintln!("Parser error: {}{}", e, at),
+        CompileError::SemanticError { .. } | CompileError::TypeMismatch { .. } => eprintln!("{}{}", e, at),
+        CompileError::Errors { errors } => errors.iter().for_each(|e| report(e, place)),
+        CompileError::LinkError { message } => eprintln!("Error: {}", message),
+        CompileError::CodeGenError { .. } | CompileError::OptionError { .. } => eprintln!("{}", e),
+        e => eprintln!("Code generation error: {}", e),
+    }
+}
+
+// Compile one procedure into the image at path and write the image out again
+fn patch(args: &Args, name: &str, path: &PathBuf, program: &ast::Program, options: &CompileOptions,
+         place: &dyn Fn(usize) -> String) {
+    let image = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Error reading file {:?}: {}", path, e);
+        std::process::exit(1);
+    });
+    let patched = compile_patch(program, name, &image, options).unwrap_or_else(|e| {
+        report(&e, place);
+        std::process::exit(1);
+    });
+    let output_path = args.output.clone().unwrap_or_else(|| path.clone());
+    let format: format::Format = args.format[0].into();
+    let stem = output_path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let file = format.wrap(&patched, options.origin, &stem);
+    if let Err(e) = fs::write(&output_path, &file) {
+        eprintln!("Error writing output file {:?}: {}", output_path, e);
+        std::process::exit(1);
+    }
+    println!("Patched {} into {:?}", name, output_path);
 }
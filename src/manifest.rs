@@ -0,0 +1,114 @@
+// Project manifests (action.toml): the sources of a program and the options to build
+// it with, so `kz80_action build` can stand in for a shell script of compiler flags.
+//
+//     sources = ["src/main.act", "src/screen.act"]   # Compiled one after the other
+//     output = "build/game.bin"
+//     target = "rc2014-sio"
+//     org = 0x8000
+//     formats = ["bin", "hex"]
+//     libraries = ["lib/screen.lib"]                 # Searched for what the sources use
+//     options = ["--opt-for", "size"]                # Any other command line options
+//     tests = ["tests/hello.act"]                    # Run by `kz80_action test`
+//
+//     [defines]
+//     WIDTH = 40
+//     GREETING = '"hello"'
+//
+// The manifest becomes a command line, so it is checked like one. Paths are relative
+// to the manifest.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    sources: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    target: Option<String>,
+    image: Option<String>,
+    org: Option<u16>,
+    ram: Option<u16>,
+    data_addr: Option<u16>,
+    console: Option<String>,
+    uart: Option<String>,
+    #[serde(default)]
+    formats: Vec<String>,
+    #[serde(default)]
+    libraries: Vec<PathBuf>,
+    #[serde(default)]
+    options: Vec<String>,
+    #[serde(default)]
+    defines: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    tests: Vec<PathBuf>,
+    #[serde(skip)]
+    dir: PathBuf,  // Where the manifest is, for relative paths
+}
+
+/// Read a manifest file
+pub fn load(path: &Path) -> Result<Manifest, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut manifest = parse(&text)?;
+    manifest.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    Ok(manifest)
+}
+
+/// A manifest from its text, with paths relative to the current directory
+pub fn parse(text: &str) -> Result<Manifest, String> {
+    toml::from_str(text).map_err(|e| e.message().to_string())
+}
+
+impl Manifest {
+    /// The project's test programs
+    pub fn tests(&self) -> Vec<PathBuf> {
+        self.tests.iter().map(|t| self.dir.join(t)).collect()
+    }
+
+    /// The command line the manifest stands for, and the source files in order
+    pub fn to_args(&self) -> Result<(Vec<String>, Vec<PathBuf>), String> {
+        let sources: Vec<PathBuf> = self.sources.iter().map(|s| self.dir.join(s)).collect();
+        let Some(first) = sources.first() else {
+            return Err("no sources".to_string());
+        };
+
+        let mut args = vec!["kz80_action".to_string(), "-i".to_string(), first.display().to_string()];
+        let mut option = |name: &str, value: String| {
+            args.push(format!("--{}", name));
+            args.push(value);
+        };
+        if let Some(output) = &self.output {
+            option("output", self.dir.join(output).display().to_string());
+        }
+        for (name, value) in [("target", &self.target), ("image", &self.image), ("console", &self.console), ("uart", &self.uart)] {
+            if let Some(value) = value {
+                option(name, value.clone());
+            }
+        }
+        for (name, value) in [("org", self.org), ("ram", self.ram), ("data-addr", self.data_addr)] {
+            if let Some(value) = value {
+                option(name, format!("0x{:04X}", value));
+            }
+        }
+        if !self.formats.is_empty() {
+            option("format", self.formats.join(","));
+        }
+        for library in &self.libraries {
+            option("lib", self.dir.join(library).display().to_string());
+        }
+        for (name, value) in &self.defines {
+            let text = match value {
+                toml::Value::String(text) => text.clone(),
+                toml::Value::Integer(n) => n.to_string(),
+                _ => return Err(format!("define {} must be a string or an integer", name)),
+            };
+            option("define", format!("{}={}", name, text));
+        }
+        args.extend(self.options.iter().cloned());
+        Ok((args, sources))
+    }
+}
+
+#[cfg(test)]
+mod tests;
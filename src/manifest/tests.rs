@@ -0,0 +1,51 @@
+// Manifests turned into command lines
+
+use super::*;
+
+fn args(text: &str) -> Result<Vec<String>, String> {
+    parse(text)?.to_args().map(|(args, _)| args)
+}
+
+#[test]
+fn every_setting_becomes_an_option() {
+    let text = r#"
+sources = ["main.act", "lib.act"]
+output = "out/game.bin"
+target = "rc2014-sio"
+image = "ram"
+ram = 0xC000
+formats = ["bin", "hex"]
+options = ["--opt-for", "size"]
+
+[defines]
+WIDTH = 40
+NAME = '"kz80"'
+"#;
+    let (args, sources) = parse(text).unwrap().to_args().unwrap();
+    assert_eq!(args, [
+        "kz80_action", "-i", "main.act", "--output", "out/game.bin",
+        "--target", "rc2014-sio", "--image", "ram", "--ram", "0xC000",
+        "--format", "bin,hex",
+        "--define", "NAME=\"kz80\"", "--define", "WIDTH=40",
+        "--opt-for", "size",
+    ]);
+    assert_eq!(sources, [PathBuf::from("main.act"), PathBuf::from("lib.act")]);
+}
+
+#[test]
+fn paths_are_relative_to_the_manifest() {
+    let mut manifest = parse("sources = [\"a.act\"]\norg = 256\nlibraries = [\"lib/io.lib\"]\n").unwrap();
+    manifest.dir = PathBuf::from("project");
+    let (args, sources) = manifest.to_args().unwrap();
+    assert_eq!(args, ["kz80_action", "-i", "project/a.act", "--org", "0x0100", "--lib", "project/lib/io.lib"]);
+    assert_eq!(sources, [PathBuf::from("project/a.act")]);
+}
+
+#[test]
+fn mistakes_are_reported() {
+    assert_eq!(args("sources = []").unwrap_err(), "no sources");
+    assert!(args("sources = [\"a.act\"]\norigin = 1\n").unwrap_err().contains("unknown field `origin`"));
+    assert!(args("sources = [\"a.act\"]\norg = 70000\n").is_err());
+    assert_eq!(args("sources = [\"a.act\"]\n[defines]\nX = 1.5\n").unwrap_err(),
+               "define X must be a string or an integer");
+}
@@ -0,0 +1,177 @@
+// Build metadata (.meta.json): where everything in one build ended up, for emulators,
+// uploaders and debuggers to read rather than each working it out from the options.
+//
+//     {
+//       "origin": 16896,
+//       "entry": 16896,
+//       "main": 17410,
+//       "sections": [
+//         { "name": "startup", "start": 16896, "size": 3 },
+//         { "name": "runtime", "start": 16899, "size": 480 },
+//         { "name": "code", "start": 17379, "size": 96 },
+//         { "name": "data", "start": 17475, "size": 6, "run": 8200 },
+//         { "name": "variables", "start": 8192, "size": 14 }
+//       ],
+//       "runtime": [{ "name": "PrintB", "address": 16913 }, ...],
+//       "procedures": [{ "name": "main", "address": 17410, "size": 40 }],
+//       "globals": [{ "name": "count", "address": 8196, "type": "BYTE" }]
+//     }
+//
+// Addresses are where the image was linked to run; "run" is given for data copied
+// elsewhere at startup. The manifest of --emit sections is here too, listing the file
+// each section was written to.
+
+use crate::ast::Program;
+use crate::compile::{CompileOptions, CompileOutput};
+use crate::format::Segment;
+use crate::relocate;
+use crate::symbols::type_name;
+use serde::Serialize;
+
+/// A range of addresses the build uses
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Section {
+    pub name: &'static str,
+    pub start: u16,
+    pub size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run: Option<u16>,  // Where the section is copied to run, if not where it is loaded
+}
+
+/// A runtime routine or a procedure
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Routine {
+    pub name: String,
+    pub address: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+}
+
+/// A global variable
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Global {
+    pub name: String,
+    pub address: u16,
+    #[serde(rename = "type")]
+    pub data_type: String,
+}
+
+/// What one build put where
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Metadata {
+    pub origin: u16,        // Load address of the image
+    pub entry: u16,         // Where to start it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub main: Option<u16>,  // The procedure the startup code calls
+    pub sections: Vec<Section>,
+    pub runtime: Vec<Routine>,
+    pub procedures: Vec<Routine>,
+    pub globals: Vec<Global>,
+}
+
+impl Metadata {
+    /// The layout of a compiled program
+    pub fn new(program: &Program, output: &CompileOutput, options: &CompileOptions) -> Metadata {
+        let codegen = &output.codegen;
+        let symbols = &output.runtime_symbols;
+        let linked = output.image_origin();
+        let table_start = output.runtime_start - 3 * output.jump_table.len() as u16;
+        let (data_load, data_run, data_size) = codegen.data_layout();
+        let ram_start = options.runtime.ram_start;
+
+        let mut sections = Vec::new();
+        if linked != output.origin {
+            sections.push(Section { name: "relocator", start: output.origin, size: relocate::STUB_SIZE as usize, run: None });
+        }
+        sections.push(Section { name: "startup", start: linked, size: table_start.wrapping_sub(linked) as usize, run: None });
+        if !output.jump_table.is_empty() {
+            sections.push(Section { name: "jump table", start: table_start, size: 3 * output.jump_table.len(), run: None });
+        }
+        sections.extend([
+            Section { name: "runtime", start: output.runtime_start, size: output.runtime_size, run: None },
+            Section { name: "code", start: symbols.end_address, size: data_load.wrapping_sub(symbols.end_address) as usize, run: None },
+            Section { name: "data", start: data_load, size: data_size, run: Some(data_run).filter(|&run| run != data_load) },
+            Section { name: "variables", start: ram_start, size: codegen.variables_end().wrapping_sub(ram_start) as usize, run: None },
+        ]);
+
+        let mut runtime: Vec<Routine> = symbols.routines().into_iter()
+            .map(|(name, address)| Routine { name: name.to_string(), address, size: None })
+            .collect();
+        runtime.sort_by_key(|routine| routine.address);
+        let procedures = program.procedures.iter()
+            .filter_map(|proc| Some(Routine {
+                name: proc.name.clone(),
+                address: codegen.procedure_address(&proc.name)?,
+                size: codegen.procedure_size(&proc.name),
+            }))
+            .collect();
+        let globals = program.globals.iter()
+            .filter_map(|var| Some(Global {
+                name: var.name.clone(),
+                address: codegen.global_address(&var.name)?,
+                data_type: type_name(&var.data_type),
+            }))
+            .collect();
+
+        Metadata {
+            origin: output.origin,
+            entry: output.origin,
+            main: codegen.entry_address(),
+            sections,
+            runtime,
+            procedures,
+            globals,
+        }
+    }
+
+    /// The text of the metadata file
+    pub fn to_json(&self) -> String {
+        let mut text = serde_json::to_string_pretty(self).expect("metadata serializes");
+        text.push('\n');
+        text
+    }
+}
+
+/// One file of --emit sections
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SectionFile {
+    pub name: &'static str,
+    pub file: String,
+    pub address: u16,       // Where the file loads
+    pub size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run: Option<u16>,   // Where the startup code copies it to, if it does
+}
+
+/// The manifest of --emit sections: the files to load and where to start
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SectionManifest {
+    pub entry: u16,
+    pub sections: Vec<SectionFile>,
+}
+
+impl SectionManifest {
+    /// The manifest for segments each written to the file file_name gives for its name
+    pub fn new(segments: &[Segment], entry: u16, file_name: impl Fn(&str) -> String) -> SectionManifest {
+        let sections = segments.iter()
+            .map(|segment| SectionFile {
+                name: segment.name,
+                file: file_name(segment.name),
+                address: segment.address,
+                size: segment.bytes.len(),
+                run: segment.run,
+            })
+            .collect();
+        SectionManifest { entry, sections }
+    }
+
+    /// The text of the manifest file
+    pub fn to_json(&self) -> String {
+        let mut text = serde_json::to_string_pretty(self).expect("manifest serializes");
+        text.push('\n');
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests;
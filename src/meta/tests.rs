@@ -0,0 +1,82 @@
+// The layout written to .meta.json files
+
+use super::*;
+use crate::compile::compile_source;
+
+const SOURCE: &str = "\
+BYTE count
+CARD ARRAY table(3)
+PROC show()
+PrintE(\"hi\")
+RETURN
+PROC main()
+show()
+RETURN
+";
+
+fn metadata(options: CompileOptions) -> Metadata {
+    let program = crate::test_support::parse(SOURCE).unwrap();
+    let output = compile_source(SOURCE, options.clone()).unwrap();
+    Metadata::new(&program, &output, &options)
+}
+
+#[test]
+fn sections_cover_the_image_in_order() {
+    let options = CompileOptions::pinned();
+    let output = compile_source(SOURCE, options.clone()).unwrap();
+    let meta = metadata(options);
+    assert_eq!((meta.origin, meta.entry), (0x4200, 0x4200));
+    assert_eq!(meta.main, output.codegen.procedure_address("main"));
+
+    let names: Vec<&str> = meta.sections.iter().map(|s| s.name).collect();
+    assert_eq!(names, ["startup", "runtime", "code", "data", "variables"]);
+    let image = &meta.sections[..4];
+    for pair in image.windows(2) {
+        assert_eq!(pair[0].start as usize + pair[0].size, pair[1].start as usize, "{:?}", pair);
+    }
+    assert_eq!(image.iter().map(|s| s.size).sum::<usize>(), output.binary.len());
+    assert_eq!(meta.sections[3].run, None);
+    assert_eq!(meta.sections[4].start, 0x2000);
+
+    assert!(meta.runtime.iter().any(|r| r.name == "PrintE" && r.address == output.runtime_symbols.print_e));
+    assert!(meta.runtime.windows(2).all(|pair| pair[0].address <= pair[1].address));
+    assert_eq!(meta.procedures.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["show", "main"]);
+    assert_eq!(meta.globals[1], Global {
+        name: "table".to_string(),
+        address: output.codegen.global_address("table").unwrap(),
+        data_type: "CARD ARRAY(3)".to_string(),
+    });
+}
+
+#[test]
+fn moved_data_and_relocation_stubs_are_shown() {
+    let moved = metadata(CompileOptions { data_address: Some(0x3000), ..CompileOptions::pinned() });
+    let data = moved.sections.iter().find(|s| s.name == "data").unwrap();
+    assert_eq!(data.run, Some(0x3000));
+    assert!(moved.to_json().contains("\"run\": 12288"));
+
+    let relocatable = metadata(CompileOptions { relocatable: true, ..CompileOptions::pinned() });
+    assert_eq!(relocatable.sections[0], Section { name: "relocator", start: 0x4200, size: relocate::STUB_SIZE as usize, run: None });
+    assert_eq!(relocatable.sections[1].start, 0x4200 + relocate::STUB_SIZE);
+}
+
+#[test]
+fn section_files_split_the_image_at_its_data() {
+    let source = "BYTE ARRAY msg = \"hi\"\nPROC main()\nPrint(msg)\nRETURN\n";
+    let options = CompileOptions { data_address: Some(0x3000), ..CompileOptions::pinned() };
+    let output = compile_source(source, options).unwrap();
+    let segments = output.segments();
+    assert_eq!(segments.iter().flat_map(|s| s.bytes.clone()).collect::<Vec<u8>>(), output.binary);
+
+    let manifest = SectionManifest::new(&segments, output.origin, |name| format!("game.{}.bin", name));
+    let (load, _, size) = output.codegen.data_layout();
+    assert_eq!(manifest.entry, 0x4200);
+    assert_eq!(manifest.sections, [
+        SectionFile { name: "code", file: "game.code.bin".to_string(), address: 0x4200, size: output.binary.len() - size, run: None },
+        SectionFile { name: "data", file: "game.data.bin".to_string(), address: load, size: 3, run: Some(0x3000) },
+    ]);
+    assert!(manifest.to_json().contains("\"run\": 12288"));
+
+    let relocatable = compile_source(SOURCE, CompileOptions { relocatable: true, ..CompileOptions::pinned() }).unwrap();
+    assert_eq!(relocatable.segments().iter().map(|s| s.name).collect::<Vec<_>>(), ["image"]);
+}
@@ -3,15 +3,100 @@
 use crate::token::{Token, TokenInfo};
 use crate::ast::*;
 use crate::error::{CompileError, Result};
+use std::path::{Path, PathBuf};
+
+/// Default limit on nested expressions and blocks
+pub const DEFAULT_MAX_DEPTH: usize = 200;
+
+/// Default number of elements for an ARRAY declared without a size
+pub const DEFAULT_ARRAY_SIZE: usize = 256;
+
+/// Which operator precedence expressions follow
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compat {
+    #[default]
+    Modern,  // Shifts below + and -, and XOR alongside OR, as in C
+    Strict,  // The original Action!'s: shifts alongside *, and XOR below OR
+}
+
+// Builds the node for a binary operator from its operands
+type BinaryOperation = fn(Box<Expression>, Box<Expression>) -> Expression;
 
 pub struct Parser {
     tokens: Vec<TokenInfo>,
     pos: usize,
+    depth: usize,
+    max_depth: usize,
+    compat: Compat,
+    default_array_size: usize,
+    include_dir: PathBuf,            // Where INCBIN paths start from
+    warnings: Vec<(usize, String)>,  // (line, message)
 }
 
 impl Parser {
     pub fn new(tokens: Vec<TokenInfo>) -> Self {
-        Parser { tokens, pos: 0 }
+        Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            compat: Compat::default(),
+            default_array_size: DEFAULT_ARRAY_SIZE,
+            include_dir: PathBuf::from("."),
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Parse expressions with the original Action! precedence, or this compiler's
+    pub fn set_compat(&mut self, compat: Compat) {
+        self.compat = compat;
+    }
+
+    /// Read INCBIN files relative to this directory instead of the current one
+    pub fn set_include_dir(&mut self, dir: &Path) {
+        self.include_dir = dir.to_path_buf();
+    }
+
+    pub fn set_default_array_size(&mut self, size: usize) {
+        self.default_array_size = size;
+    }
+
+    /// Warnings collected while parsing, as (line, message)
+    pub fn warnings(&self) -> &[(usize, String)] {
+        &self.warnings
+    }
+
+    // Run a recursive parse step, failing cleanly once nesting exceeds the limit
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= self.max_depth {
+            return Err(CompileError::ParserError {
+                line: self.current_line(),
+                message: format!("Nesting too deep (limit is {})", self.max_depth),
+            });
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    // Take a binary operator, which nests what it has so far one level deeper in the
+    // tree, however flat the source: links counts those the caller has taken
+    fn operator(&mut self, links: &mut usize) -> Result<()> {
+        if self.depth >= self.max_depth {
+            return Err(CompileError::ParserError {
+                line: self.current_line(),
+                message: format!("Expression too deep: its operators and the nesting around them pass the limit of {}", self.max_depth),
+            });
+        }
+        self.depth += 1;
+        *links += 1;
+        self.advance();
+        Ok(())
     }
 
     fn current(&self) -> &Token {
@@ -70,6 +155,16 @@ impl Parser {
 
     // Parse data type
     fn parse_type(&mut self) -> Result<DataType> {
+        let (base_type, array) = self.parse_type_spec()?;
+        Ok(match array {
+            Some(size) => Self::array_of(base_type, size.unwrap_or(self.default_array_size)),
+            None => base_type,
+        })
+    }
+
+    // Parse a base type and optional ARRAY suffix.
+    // Returns the base type and, for arrays, the declared size if one was given.
+    fn parse_type_spec(&mut self) -> Result<(DataType, Option<Option<usize>>)> {
         self.skip_newlines();
         let base_type = match self.current() {
             Token::Byte => { self.advance(); DataType::Byte }
@@ -92,294 +187,181 @@ impl Parser {
 
             // Optional array size in parentheses
             let size = if self.current() == &Token::LeftParen {
-                self.advance();
-                let size = self.parse_number()?;
-                self.expect(Token::RightParen)?;
-                size as usize
+                Some(self.parse_array_size()?)
             } else {
-                256 // Default array size
+                None
             };
 
-            Ok(match base_type {
-                DataType::Byte | DataType::Char => DataType::ByteArray(size),
-                DataType::Card => DataType::CardArray(size),
-                DataType::Int => DataType::IntArray(size),
-                _ => base_type,
-            })
+            Ok((base_type, Some(size)))
         } else {
-            Ok(base_type)
+            Ok((base_type, None))
         }
     }
 
-    fn parse_number(&mut self) -> Result<i32> {
-        self.skip_newlines();
-        if let Token::Number(n) = self.current() {
-            let n = *n;
-            self.advance();
-            Ok(n)
-        } else {
-            Err(CompileError::UnexpectedToken {
-                expected: "number".to_string(),
-                found: format!("{:?}", self.current()),
-            })
+    // Parse a parenthesized array size, which must be a positive constant expression
+    fn parse_array_size(&mut self) -> Result<usize> {
+        self.expect(Token::LeftParen)?;
+        let line = self.current_line();
+        let size = self.parse_expression()?.const_value().ok_or(CompileError::ParserError {
+            line,
+            message: "Array size must be a constant expression".to_string(),
+        })?;
+        if size < 1 {
+            return Err(CompileError::ParserError {
+                line,
+                message: format!("Array size must be at least 1, found {}", size),
+            });
+        }
+        self.expect(Token::RightParen)?;
+        Ok(size as usize)
+    }
+
+    fn array_of(base_type: DataType, size: usize) -> DataType {
+        match base_type {
+            DataType::Byte | DataType::Char => DataType::ByteArray(size),
+            DataType::Card => DataType::CardArray(size),
+            DataType::Int => DataType::IntArray(size),
+            _ => base_type,
         }
     }
 
-    // Parse primary expression (atoms)
+    // Parse primary expression (atoms). What nests goes to a function of its own,
+    // keeping this frame, which every level of nesting adds, small
     fn parse_primary(&mut self) -> Result<Expression> {
         self.skip_newlines();
-        match self.current().clone() {
-            Token::Number(n) => {
-                self.advance();
-                Ok(Expression::Number(n))
-            }
-            Token::String(s) => {
-                self.advance();
-                Ok(Expression::String(s))
-            }
-            Token::Char(c) => {
-                self.advance();
-                Ok(Expression::Char(c))
-            }
-            Token::Identifier(name) => {
-                self.advance();
-                self.skip_newlines();
-
-                // Check for array access or function call
-                match self.current() {
-                    Token::LeftBracket => {
-                        self.advance();
-                        let index = self.parse_expression()?;
-                        self.expect(Token::RightBracket)?;
-                        Ok(Expression::ArrayAccess {
-                            array: name,
-                            index: Box::new(index),
-                        })
-                    }
-                    Token::LeftParen => {
-                        self.advance();
-                        let args = self.parse_argument_list()?;
-                        self.expect(Token::RightParen)?;
-                        Ok(Expression::FunctionCall { name, args })
-                    }
-                    _ => Ok(Expression::Variable(name)),
-                }
-            }
-            Token::LeftParen => {
-                self.advance();
-                let expr = self.parse_expression()?;
-                self.expect(Token::RightParen)?;
-                Ok(expr)
-            }
+        let expr = match self.current() {
+            Token::Number(n) => Expression::Number(*n),
+            Token::String(s) => Expression::String(s.clone()),
+            Token::Char(c) => Expression::Char(*c),
+            Token::Identifier(_) => return self.parse_name(),
+            Token::LeftParen => return self.parse_parenthesized(),
             Token::At => {
                 self.advance();
-                let name = self.expect_identifier()?;
-                Ok(Expression::AddressOf(name))
-            }
-            Token::Caret => {
-                self.advance();
-                let expr = self.parse_primary()?;
-                Ok(Expression::Dereference(Box::new(expr)))
-            }
-            Token::Minus => {
-                self.advance();
-                let expr = self.parse_unary()?;
-                Ok(Expression::Negate(Box::new(expr)))
-            }
-            Token::Not => {
-                self.advance();
-                let expr = self.parse_unary()?;
-                Ok(Expression::Not(Box::new(expr)))
+                return Ok(Expression::AddressOf(self.expect_identifier()?));
             }
-            _ => Err(CompileError::ParserError {
-                line: self.current_line(),
-                message: format!("Unexpected token in expression: {:?}", self.current()),
-            }),
+            Token::Caret => return self.parse_dereference(),
+            Token::Minus | Token::Not => return self.parse_unary(),
+            _ => return Err(self.unexpected()),
+        };
+        self.advance();
+        Ok(expr)
+    }
+
+    // The error for a token no expression starts with, built out of parse_primary's frame
+    fn unexpected(&self) -> CompileError {
+        CompileError::ParserError {
+            line: self.current_line(),
+            message: format!("Unexpected token in expression: {:?}", self.current()),
         }
     }
 
-    fn parse_unary(&mut self) -> Result<Expression> {
+    // Parse a variable, or an array element or function call by name
+    fn parse_name(&mut self) -> Result<Expression> {
+        let name = self.expect_identifier()?;
         self.skip_newlines();
+
+        // Check for array access or function call
         match self.current() {
-            Token::Minus => {
+            Token::LeftBracket => {
                 self.advance();
-                let expr = self.parse_unary()?;
-                Ok(Expression::Negate(Box::new(expr)))
+                let index = self.nested(|p| p.parse_expression())?;
+                self.expect(Token::RightBracket)?;
+                Ok(Expression::ArrayAccess {
+                    array: name,
+                    index: Box::new(index),
+                })
             }
-            Token::Not => {
+            Token::LeftParen => {
                 self.advance();
-                let expr = self.parse_unary()?;
-                Ok(Expression::Not(Box::new(expr)))
-            }
-            _ => self.parse_primary(),
-        }
-    }
-
-    // Parse multiplication/division
-    fn parse_multiplicative(&mut self) -> Result<Expression> {
-        let mut left = self.parse_unary()?;
-
-        loop {
-            self.skip_newlines();
-            match self.current() {
-                Token::Star => {
-                    self.advance();
-                    let right = self.parse_unary()?;
-                    left = Expression::Multiply(Box::new(left), Box::new(right));
-                }
-                Token::Slash => {
-                    self.advance();
-                    let right = self.parse_unary()?;
-                    left = Expression::Divide(Box::new(left), Box::new(right));
-                }
-                Token::Mod => {
-                    self.advance();
-                    let right = self.parse_unary()?;
-                    left = Expression::Modulo(Box::new(left), Box::new(right));
-                }
-                _ => break,
+                let args = self.nested(|p| p.parse_argument_list())?;
+                self.expect(Token::RightParen)?;
+                Ok(Expression::FunctionCall { name, args })
             }
+            _ => Ok(Expression::Variable(name)),
         }
-
-        Ok(left)
     }
 
-    // Parse addition/subtraction
-    fn parse_additive(&mut self) -> Result<Expression> {
-        let mut left = self.parse_multiplicative()?;
-
-        loop {
-            self.skip_newlines();
-            match self.current() {
-                Token::Plus => {
-                    self.advance();
-                    let right = self.parse_multiplicative()?;
-                    left = Expression::Add(Box::new(left), Box::new(right));
-                }
-                Token::Minus => {
-                    self.advance();
-                    let right = self.parse_multiplicative()?;
-                    left = Expression::Subtract(Box::new(left), Box::new(right));
-                }
-                _ => break,
-            }
-        }
-
-        Ok(left)
+    fn parse_parenthesized(&mut self) -> Result<Expression> {
+        self.expect(Token::LeftParen)?;
+        let expr = self.nested(|p| p.parse_expression())?;
+        self.expect(Token::RightParen)?;
+        Ok(expr)
     }
 
-    // Parse shift operations
-    fn parse_shift(&mut self) -> Result<Expression> {
-        let mut left = self.parse_additive()?;
-
-        loop {
-            self.skip_newlines();
-            match self.current() {
-                Token::Lsh => {
-                    self.advance();
-                    let right = self.parse_additive()?;
-                    left = Expression::LeftShift(Box::new(left), Box::new(right));
-                }
-                Token::Rsh => {
-                    self.advance();
-                    let right = self.parse_additive()?;
-                    left = Expression::RightShift(Box::new(left), Box::new(right));
-                }
-                _ => break,
-            }
-        }
-
-        Ok(left)
+    // Parse - and NOT before an operand
+    fn parse_unary(&mut self) -> Result<Expression> {
+        self.skip_newlines();
+        let operation: fn(Box<Expression>) -> Expression = match self.current() {
+            Token::Minus => Expression::Negate,
+            Token::Not => Expression::Not,
+            _ => return self.parse_primary(),
+        };
+        self.advance();
+        let expr = self.nested(|p| p.parse_unary())?;
+        Ok(operation(Box::new(expr)))
     }
 
-    // Parse comparison operations
-    fn parse_comparison(&mut self) -> Result<Expression> {
-        let mut left = self.parse_shift()?;
-
-        loop {
-            self.skip_newlines();
-            match self.current() {
-                Token::Equal => {
-                    self.advance();
-                    let right = self.parse_shift()?;
-                    left = Expression::Equal(Box::new(left), Box::new(right));
-                }
-                Token::NotEqual => {
-                    self.advance();
-                    let right = self.parse_shift()?;
-                    left = Expression::NotEqual(Box::new(left), Box::new(right));
-                }
-                Token::Less => {
-                    self.advance();
-                    let right = self.parse_shift()?;
-                    left = Expression::Less(Box::new(left), Box::new(right));
-                }
-                Token::LessEqual => {
-                    self.advance();
-                    let right = self.parse_shift()?;
-                    left = Expression::LessEqual(Box::new(left), Box::new(right));
-                }
-                Token::Greater => {
-                    self.advance();
-                    let right = self.parse_shift()?;
-                    left = Expression::Greater(Box::new(left), Box::new(right));
-                }
-                Token::GreaterEqual => {
-                    self.advance();
-                    let right = self.parse_shift()?;
-                    left = Expression::GreaterEqual(Box::new(left), Box::new(right));
-                }
-                _ => break,
-            }
-        }
-
-        Ok(left)
+    fn parse_dereference(&mut self) -> Result<Expression> {
+        self.expect(Token::Caret)?;
+        let expr = self.nested(|p| p.parse_primary())?;
+        Ok(Expression::Dereference(Box::new(expr)))
     }
 
-    // Parse logical AND
-    fn parse_and(&mut self) -> Result<Expression> {
-        let mut left = self.parse_comparison()?;
-
-        loop {
-            self.skip_newlines();
-            if self.current() == &Token::And {
-                self.advance();
-                let right = self.parse_comparison()?;
-                left = Expression::And(Box::new(left), Box::new(right));
-            } else {
-                break;
-            }
-        }
-
-        Ok(left)
+    // The binary operator at the current token, with how tightly it binds: OR and
+    // XOR lowest, then AND, the comparisons, the shifts, + and -, and * / MOD. The
+    // original precedence puts XOR below OR and the shifts with *
+    fn binary_operator(&self) -> Option<(u8, BinaryOperation)> {
+        let strict = self.compat == Compat::Strict;
+        let xor = if strict { 0 } else { 1 };
+        let shift = if strict { 6 } else { 4 };
+        Some(match self.current() {
+            Token::Xor => (xor, Expression::Xor),
+            Token::BitXor => (xor, Expression::BitXor),
+            Token::Or => (1, Expression::Or),
+            Token::BitOr => (1, Expression::BitOr),
+            Token::And => (2, Expression::And),
+            Token::BitAnd => (2, Expression::BitAnd),
+            Token::Equal => (3, Expression::Equal),
+            Token::NotEqual => (3, Expression::NotEqual),
+            Token::Less => (3, Expression::Less),
+            Token::LessEqual => (3, Expression::LessEqual),
+            Token::Greater => (3, Expression::Greater),
+            Token::GreaterEqual => (3, Expression::GreaterEqual),
+            Token::Lsh => (shift, Expression::LeftShift),
+            Token::Rsh => (shift, Expression::RightShift),
+            Token::Plus => (5, Expression::Add),
+            Token::Minus => (5, Expression::Subtract),
+            Token::Star => (6, Expression::Multiply),
+            Token::Slash => (6, Expression::Divide),
+            Token::Mod => (6, Expression::Modulo),
+            _ => return None,
+        })
     }
 
-    // Parse logical OR/XOR
-    fn parse_or(&mut self) -> Result<Expression> {
-        let mut left = self.parse_and()?;
+    // Parse binary operators binding at least as tightly as min, left to right.
+    // A run of operators at one precedence is a loop rather than a call each, so
+    // only a rise in precedence or a parenthesis costs a stack frame
+    fn parse_binary(&mut self, min: u8) -> Result<Expression> {
+        let mut left = self.parse_unary()?;
+        let mut links = 0;
 
         loop {
             self.skip_newlines();
-            match self.current() {
-                Token::Or => {
-                    self.advance();
-                    let right = self.parse_and()?;
-                    left = Expression::Or(Box::new(left), Box::new(right));
-                }
-                Token::Xor => {
-                    self.advance();
-                    let right = self.parse_and()?;
-                    left = Expression::Xor(Box::new(left), Box::new(right));
-                }
+            let (precedence, operation) = match self.binary_operator() {
+                Some((precedence, operation)) if precedence >= min => (precedence, operation),
                 _ => break,
-            }
+            };
+            self.operator(&mut links)?;
+            let right = self.parse_binary(precedence + 1)?;
+            left = operation(Box::new(left), Box::new(right));
         }
+        self.depth -= links;
 
         Ok(left)
     }
 
     fn parse_expression(&mut self) -> Result<Expression> {
-        self.parse_or()
+        self.parse_binary(0)
     }
 
     fn parse_argument_list(&mut self) -> Result<Vec<Expression>> {
@@ -402,9 +384,15 @@ impl Parser {
 
     // Parse variable declaration
     fn parse_var_decl(&mut self) -> Result<Variable> {
-        let data_type = self.parse_type()?;
+        let line = self.current_line();
+        let (base_type, mut array) = self.parse_type_spec()?;
         let name = self.expect_identifier()?;
 
+        // The size may also follow the name: BYTE ARRAY buf(100)
+        if array == Some(None) && self.current() == &Token::LeftParen {
+            array = Some(Some(self.parse_array_size()?));
+        }
+
         let initial_value = if self.current() == &Token::Equal {
             self.advance();
             Some(self.parse_expression()?)
@@ -412,6 +400,22 @@ impl Parser {
             None
         };
 
+        let data_type = match array {
+            Some(Some(size)) => Self::array_of(base_type, size),
+            // An unsized array initialized from a string takes the string's size
+            Some(None) => match &initial_value {
+                Some(Expression::String(s)) => Self::array_of(base_type, s.len() + 1),
+                _ => {
+                    self.warnings.push((line, format!(
+                        "ARRAY '{}' has no size; using the default of {} elements",
+                        name, self.default_array_size,
+                    )));
+                    Self::array_of(base_type, self.default_array_size)
+                }
+            },
+            None => base_type,
+        };
+
         Ok(Variable {
             name,
             data_type,
@@ -419,146 +423,52 @@ impl Parser {
         })
     }
 
-    // Parse statement
+    // Parse statement. Those holding blocks go to functions of their own, leaving
+    // the rest, and their larger frame, out of the recursion through the blocks
     fn parse_statement(&mut self) -> Result<Option<Statement>> {
         self.skip_newlines();
 
-        match self.current().clone() {
-            Token::Eof | Token::Od | Token::Fi | Token::Until => {
-                Ok(None)
-            }
+        let parse: fn(&mut Self) -> Result<Statement> = match self.current() {
+            Token::If => Self::parse_if,
+            Token::While => Self::parse_while,
+            Token::Do => Self::parse_until,
+            Token::For => Self::parse_for,
+            _ => return self.parse_simple_statement(),
+        };
+        parse(self).map(Some)
+    }
+
+    // Parse a statement holding no block
+    fn parse_simple_statement(&mut self) -> Result<Option<Statement>> {
+        let statement = match self.current() {
+            Token::Eof | Token::Od | Token::Fi | Token::Until => return Ok(None),
 
             // Variable declaration
             Token::Byte | Token::Card | Token::Int | Token::Char_ => {
-                let var = self.parse_var_decl()?;
-                Ok(Some(Statement::VarDecl(var)))
-            }
-
-            // IF statement
-            Token::If => {
-                self.advance();
-                let condition = self.parse_expression()?;
-                self.skip_newlines();
-
-                // THEN is optional in some Action! variants
-                if self.current() == &Token::Then {
-                    self.advance();
-                }
-
-                let then_block = self.parse_block()?;
-
-                let else_block = if self.current() == &Token::Else {
-                    self.advance();
-                    Some(self.parse_block()?)
-                } else {
-                    None
-                };
-
-                self.expect(Token::Fi)?;
-                Ok(Some(Statement::If {
-                    condition,
-                    then_block,
-                    else_block,
-                }))
-            }
-
-            // WHILE statement
-            Token::While => {
-                self.advance();
-                let condition = self.parse_expression()?;
-                self.expect(Token::Do)?;
-                let body = self.parse_block()?;
-                self.expect(Token::Od)?;
-                Ok(Some(Statement::While { condition, body }))
-            }
-
-            // FOR statement
-            Token::For => {
-                self.advance();
-                let var = self.expect_identifier()?;
-                self.expect(Token::Equal)?;
-                let start = self.parse_expression()?;
-                self.expect(Token::To)?;
-                let end = self.parse_expression()?;
-
-                let step = if self.current() == &Token::Step {
-                    self.advance();
-                    Some(self.parse_expression()?)
-                } else {
-                    None
-                };
-
-                self.expect(Token::Do)?;
-                let body = self.parse_block()?;
-                self.expect(Token::Od)?;
-
-                Ok(Some(Statement::For {
-                    var,
-                    start,
-                    end,
-                    step,
-                    body,
-                }))
+                Statement::VarDecl(self.parse_var_decl()?)
             }
 
             // EXIT
             Token::Exit => {
                 self.advance();
-                Ok(Some(Statement::Exit))
+                Statement::Exit
             }
 
             // RETURN
             Token::Return => {
                 self.advance();
-                self.skip_newlines();
 
-                // Check if there's a return value
+                // Check if there's a return value on the same line
                 let value = match self.current() {
                     Token::Newline | Token::Eof | Token::Od | Token::Fi => None,
                     _ => Some(self.parse_expression()?),
                 };
 
-                Ok(Some(Statement::Return(value)))
+                Statement::Return(value)
             }
 
             // Assignment or procedure call
-            Token::Identifier(name) => {
-                self.advance();
-                self.skip_newlines();
-
-                match self.current() {
-                    // Array assignment
-                    Token::LeftBracket => {
-                        self.advance();
-                        let index = self.parse_expression()?;
-                        self.expect(Token::RightBracket)?;
-                        self.expect(Token::Equal)?;
-                        let value = self.parse_expression()?;
-                        Ok(Some(Statement::ArrayAssignment {
-                            array: name,
-                            index,
-                            value,
-                        }))
-                    }
-                    // Assignment
-                    Token::Equal => {
-                        self.advance();
-                        let value = self.parse_expression()?;
-                        Ok(Some(Statement::Assignment { target: name, value }))
-                    }
-                    // Procedure call
-                    Token::LeftParen => {
-                        self.advance();
-                        let args = self.parse_argument_list()?;
-                        self.expect(Token::RightParen)?;
-                        Ok(Some(Statement::ProcCall { name, args }))
-                    }
-                    // Bare procedure call (no parens)
-                    _ => {
-                        Ok(Some(Statement::ProcCall { name, args: vec![] }))
-                    }
-                }
-            }
+            Token::Identifier(_) => self.parse_named_statement()?,
 
             // Pointer dereference assignment
             Token::Caret => {
@@ -566,22 +476,154 @@ impl Parser {
                 let pointer = self.parse_primary()?;
                 self.expect(Token::Equal)?;
                 let value = self.parse_expression()?;
-                Ok(Some(Statement::PointerAssignment { pointer, value }))
+                Statement::PointerAssignment { pointer, value }
             }
 
             Token::Newline => {
                 self.advance();
-                self.parse_statement()
+                return self.parse_statement();
             }
 
-            _ => Err(CompileError::ParserError {
-                line: self.current_line(),
-                message: format!("Unexpected token: {:?}", self.current()),
-            }),
+            _ => {
+                return Err(CompileError::ParserError {
+                    line: self.current_line(),
+                    message: format!("Unexpected token: {:?}", self.current()),
+                });
+            }
+        };
+        Ok(Some(statement))
+    }
+
+    // IF condition [THEN] block [ELSE block] FI
+    fn parse_if(&mut self) -> Result<Statement> {
+        self.expect(Token::If)?;
+        let condition = self.parse_expression()?;
+        self.skip_newlines();
+
+        // THEN is optional in some Action! variants
+        if self.current() == &Token::Then {
+            self.advance();
+        }
+
+        let then_block = self.parse_block()?;
+
+        let else_block = if self.current() == &Token::Else {
+            self.advance();
+            Some(self.parse_block()?)
+        } else {
+            None
+        };
+
+        self.expect(Token::Fi)?;
+        Ok(Statement::If {
+            condition,
+            then_block,
+            else_block,
+        })
+    }
+
+    // WHILE condition DO block OD
+    fn parse_while(&mut self) -> Result<Statement> {
+        self.expect(Token::While)?;
+        let condition = self.parse_expression()?;
+        self.expect(Token::Do)?;
+        let body = self.parse_block()?;
+        self.expect(Token::Od)?;
+        Ok(Statement::While { condition, body })
+    }
+
+    // DO block UNTIL condition OD
+    fn parse_until(&mut self) -> Result<Statement> {
+        self.expect(Token::Do)?;
+        let body = self.parse_block()?;
+        self.expect(Token::Until)?;
+        let condition = self.parse_expression()?;
+        self.expect(Token::Od)?;
+        Ok(Statement::Until { condition, body })
+    }
+
+    // FOR var = start TO end [STEP step] DO block OD
+    fn parse_for(&mut self) -> Result<Statement> {
+        self.expect(Token::For)?;
+        let var = self.expect_identifier()?;
+        self.expect(Token::Equal)?;
+        let start = self.parse_expression()?;
+        self.expect(Token::To)?;
+        let end = self.parse_expression()?;
+
+        let step = if self.current() == &Token::Step {
+            self.advance();
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+
+        self.expect(Token::Do)?;
+        let body = self.parse_block()?;
+        self.expect(Token::Od)?;
+
+        Ok(Statement::For {
+            var,
+            start,
+            end,
+            step,
+            body,
+        })
+    }
+
+    // Assignment or procedure call
+    fn parse_named_statement(&mut self) -> Result<Statement> {
+        let name = self.expect_identifier()?;
+        self.skip_newlines();
+
+        match self.current() {
+            // Array assignment
+            Token::LeftBracket => {
+                self.advance();
+                let index = self.nested(|p| p.parse_expression())?;
+                self.expect(Token::RightBracket)?;
+                self.expect(Token::Equal)?;
+                let value = self.parse_expression()?;
+                Ok(Statement::ArrayAssignment {
+                    array: name,
+                    index,
+                    value,
+                })
+            }
+            // Assignment
+            Token::Equal => {
+                self.advance();
+                let value = self.parse_expression()?;
+                Ok(Statement::Assignment { target: name, value })
+            }
+            // Procedure call, or array element assignment: name(i) = value
+            Token::LeftParen => {
+                self.advance();
+                let mut args = self.parse_argument_list()?;
+                self.expect(Token::RightParen)?;
+                if self.current() == &Token::Equal && args.len() == 1 {
+                    self.advance();
+                    let value = self.parse_expression()?;
+                    return Ok(Statement::ArrayAssignment {
+                        array: name,
+                        index: args.remove(0),
+                        value,
+                    });
+                }
+                Ok(Statement::ProcCall { name, args })
+            }
+            // Bare procedure call (no parens)
+            _ => {
+                Ok(Statement::ProcCall { name, args: vec![] })
+            }
         }
     }
 
     fn parse_block(&mut self) -> Result<Vec<Statement>> {
+        self.nested(|p| p.parse_block_inner())
+    }
+
+    fn parse_block_inner(&mut self) -> Result<Vec<Statement>> {
         let mut statements = Vec::new();
         self.skip_newlines();
 
@@ -591,7 +633,9 @@ impl Parser {
                     break;
                 }
                 _ => {
+                    let line = self.current_line();
                     if let Some(stmt) = self.parse_statement()? {
+                        statements.push(Statement::Line(line));
                         statements.push(stmt);
                     } else {
                         break;
@@ -604,8 +648,64 @@ impl Parser {
         Ok(statements)
     }
 
-    // Parse procedure/function
-    fn parse_procedure(&mut self) -> Result<Procedure> {
+    // Parse a global variable into the program
+    fn parse_global(&mut self, program: &mut Program) -> Result<()> {
+        let line = self.current_line();
+        let var = self.parse_var_decl()?;
+        program.definitions.push(Definition { name: var.name.clone(), line, module: program.modules.len(), global: true });
+        program.globals.push(var);
+        Ok(())
+    }
+
+    // INCBIN "file" or INCBIN name = "file": a BYTE ARRAY of the file's bytes in the
+    // data section, named after the file unless a name is given, and a CARD with
+    // _len after the name holding its length
+    fn parse_incbin(&mut self, program: &mut Program) -> Result<()> {
+        let line = self.current_line();
+        self.advance();
+        let name = match self.current().clone() {
+            Token::Identifier(name) => {
+                self.advance();
+                self.expect(Token::Equal)?;
+                Some(name)
+            }
+            _ => None,
+        };
+        let Token::String(file) = self.current().clone() else {
+            return Err(CompileError::ParserError {
+                line,
+                message: format!("INCBIN expects a file name in quotes, found {:?}", self.current()),
+            });
+        };
+        self.advance();
+        let error = |message: String| CompileError::ParserError { line, message };
+        let path = self.include_dir.join(&file);
+        let bytes = std::fs::read(&path).map_err(|e| error(format!("cannot read INCBIN file \"{}\": {}", path.display(), e)))?;
+        if bytes.is_empty() || bytes.len() > 0xFFFF {
+            return Err(error(format!("INCBIN file \"{}\" is {} bytes; it must have 1 to 65535", file, bytes.len())));
+        }
+        let name = name.unwrap_or_else(|| {
+            let stem = Path::new(&file).file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+            let name: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+            if name.starts_with(|c: char| c.is_ascii_alphabetic()) { name } else { format!("_{}", name) }
+        });
+
+        let length = bytes.len();
+        let module = program.modules.len();
+        for var in [
+            Variable { name: name.clone(), data_type: DataType::ByteArray(length), initial_value: Some(Expression::Bytes(bytes)) },
+            Variable { name: format!("{}_len", name), data_type: DataType::Card, initial_value: Some(Expression::Number(length as i32)) },
+        ] {
+            program.definitions.push(Definition { name: var.name.clone(), line, module, global: true });
+            program.globals.push(var);
+        }
+        Ok(())
+    }
+
+    // Parse procedure/function into the program, or only its declaration when the
+    // heading ends in FORWARD
+    fn parse_procedure(&mut self, program: &mut Program) -> Result<()> {
+        let line = self.current_line();
         let is_func = self.current() == &Token::Func;
         self.advance();
 
@@ -617,6 +717,23 @@ impl Parser {
 
         let name = self.expect_identifier()?;
 
+        // A fixed address for the procedure: PROC Handler = $F000()
+        let address = if self.current() == &Token::Equal {
+            self.advance();
+            let line = self.current_line();
+            let value = self.parse_expression()?.const_value().ok_or(CompileError::ParserError {
+                line,
+                message: "Procedure address must be a constant expression".to_string(),
+            })?;
+            let address = u16::try_from(value).map_err(|_| CompileError::ParserError {
+                line,
+                message: format!("Procedure address must be 0-$FFFF, found {}", value),
+            })?;
+            Some(address)
+        } else {
+            None
+        };
+
         // Parse parameters
         let params = if self.current() == &Token::LeftParen {
             self.advance();
@@ -627,6 +744,35 @@ impl Parser {
             Vec::new()
         };
 
+        // Arguments in registers, asked for after the parameters: PROC Add(BYTE b) FASTCALL
+        let fast_call = matches!(self.current(), Token::Identifier(word) if word.eq_ignore_ascii_case("FASTCALL"));
+        if fast_call {
+            self.advance();
+        }
+
+        // Run by an interrupt rather than called: PROC Tick() INTERRUPT
+        let interrupt = matches!(self.current(), Token::Identifier(word) if word.eq_ignore_ascii_case("INTERRUPT"));
+        if interrupt {
+            self.advance();
+        }
+
+        // A declaration, for calls that come before the body: PROC Add(BYTE b) FORWARD
+        if matches!(self.current(), Token::Identifier(word) if word.eq_ignore_ascii_case("FORWARD")) {
+            self.advance();
+            program.declarations.push(Procedure {
+                name,
+                params,
+                return_type,
+                locals: Vec::new(),
+                body: Vec::new(),
+                address,
+                fast_call,
+                interrupt,
+                module: program.modules.len(),
+            });
+            return Ok(());
+        }
+
         self.skip_newlines();
 
         // Parse locals and body
@@ -645,23 +791,31 @@ impl Parser {
         }
 
         // Parse body until RETURN
-        let mut body = self.parse_block()?;
+        let mut body = self.parse_block_inner()?;
 
         // Handle RETURN at end
         self.skip_newlines();
         if self.current() == &Token::Return {
+            let line = self.current_line();
             if let Some(stmt) = self.parse_statement()? {
+                body.push(Statement::Line(line));
                 body.push(stmt);
             }
         }
 
-        Ok(Procedure {
+        program.definitions.push(Definition { name: name.clone(), line, module: program.modules.len(), global: false });
+        program.procedures.push(Procedure {
             name,
             params,
             return_type,
             locals,
             body,
-        })
+            address,
+            fast_call,
+            interrupt,
+            module: program.modules.len(),
+        });
+        Ok(())
     }
 
     fn parse_parameter_list(&mut self) -> Result<Vec<Parameter>> {
@@ -674,8 +828,20 @@ impl Parser {
 
         loop {
             let data_type = self.parse_type()?;
+            // VAR parameters take the caller's variable: PROC MinMax(BYTE VAR lo, BYTE VAR hi)
+            let by_ref = matches!(self.current(), Token::Identifier(word) if word.eq_ignore_ascii_case("VAR"));
+            if by_ref {
+                if matches!(data_type, DataType::ByteArray(_) | DataType::CardArray(_) | DataType::IntArray(_)) {
+                    return Err(CompileError::ParserError {
+                        line: self.current_line(),
+                        message: "ARRAY parameters are passed by address already; VAR is for BYTE, CHAR, CARD and INT"
+                            .to_string(),
+                    });
+                }
+                self.advance();
+            }
             let name = self.expect_identifier()?;
-            params.push(Parameter { name, data_type });
+            params.push(Parameter { name, data_type, by_ref });
 
             self.skip_newlines();
             if self.current() == &Token::Comma {
@@ -699,19 +865,51 @@ impl Parser {
 
                 // Global variable
                 Token::Byte | Token::Card | Token::Int | Token::Char_ => {
-                    let var = self.parse_var_decl()?;
-                    program.globals.push(var);
+                    self.parse_global(&mut program)?;
                 }
 
                 // Procedure or function
                 Token::Proc | Token::Func => {
-                    let proc = self.parse_procedure()?;
-                    program.procedures.push(proc);
+                    self.parse_procedure(&mut program)?;
+                }
+
+                // A global or procedure only its own module may use, or one any may
+                Token::Identifier(word) if word.eq_ignore_ascii_case("PRIVATE") || word.eq_ignore_ascii_case("PUBLIC") => {
+                    let private = word.eq_ignore_ascii_case("PRIVATE");
+                    self.advance();
+                    let name = match self.current() {
+                        Token::Byte | Token::Card | Token::Int | Token::Char_ => {
+                            self.parse_global(&mut program)?;
+                            program.globals.last().map(|v| v.name.clone()).unwrap_or_default()
+                        }
+                        Token::Proc | Token::Func => {
+                            let (procedures, declarations) = (program.procedures.len(), program.declarations.len());
+                            self.parse_procedure(&mut program)?;
+                            let proc = program.procedures.get(procedures).or(program.declarations.get(declarations));
+                            proc.map(|p| p.name.clone()).unwrap_or_default()
+                        }
+                        other => {
+                            return Err(CompileError::ParserError {
+                                line: self.current_line(),
+                                message: format!("Expected a global or procedure after {}, found {:?}",
+                                                 if private { "PRIVATE" } else { "PUBLIC" }, other),
+                            });
+                        }
+                    };
+                    if private {
+                        program.private.push((name, program.modules.len()));
+                    }
+                }
+
+                // A file's contents as a BYTE ARRAY, with its length
+                Token::Identifier(word) if word.eq_ignore_ascii_case("INCBIN") => {
+                    self.parse_incbin(&mut program)?;
                 }
 
+                // The start of a section of the program with names of its own
                 Token::Module => {
+                    program.modules.push(self.current_line());
                     self.advance();
-                    // Skip module declaration for now
                 }
 
                 _ => {
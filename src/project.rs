@@ -0,0 +1,54 @@
+// New projects: a manifest, a program that prints a greeting and a test of its output,
+// ready for `kz80_action build` and `kz80_action test`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Create a project called name in dir, which must not exist yet, built for target
+/// (a --target name) if given, into a file with the given extension. Returns the
+/// files created
+pub fn create(dir: &Path, name: &str, target: Option<&str>, extension: &str) -> io::Result<Vec<PathBuf>> {
+    if dir.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} already exists", dir)));
+    }
+    let greeting = format!("Hello from {}!", name);
+    let target = target.map_or(String::new(), |t| format!("target = \"{}\"\n", t));
+    let files = [
+        ("action.toml", format!("\
+# Build with `kz80_action build`, run the tests on the emulator with `kz80_action test`
+sources = [\"src/main.act\"]
+output = \"build/{name}.{extension}\"
+{target}tests = [\"tests/hello.act\"]
+")),
+        ("src/main.act", format!("\
+; {name}
+
+PROC main()
+  Print(\"{greeting}\")
+  PrintE()
+RETURN
+")),
+        ("tests/hello.act", format!("\
+; Runs src/main.act on the built-in emulator, and passes if the console output is
+; the text of the lines starting with expect:
+; source: ../src/main.act
+; expect: {greeting}
+")),
+        ("build/.gitignore", "*\n".to_string()),
+    ];
+
+    let mut created = Vec::new();
+    for (path, text) in files {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, text)?;
+        created.push(path);
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,55 @@
+// A new project builds and its test of the program passes
+
+use super::*;
+use crate::emulator::Console;
+use crate::manifest;
+use crate::run::{check, test_source, Limits, Machine};
+
+// A directory for the test to create a project in, gone afterwards
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("kz80_action_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Scratch(dir)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn new_project_has_a_passing_test() {
+    let scratch = Scratch::new("new_project");
+    let dir = scratch.0.join("demo");
+    create(&dir, "demo", Some("cpm"), "com").unwrap();
+
+    let manifest = manifest::load(&dir.join("action.toml")).unwrap();
+    let (args, sources) = manifest.to_args().unwrap();
+    assert_eq!(args[3..], ["--output", &dir.join("build/demo.com").display().to_string(), "--target", "cpm"]);
+    assert_eq!(sources, [dir.join("src/main.act")]);
+
+    let tests = manifest.tests();
+    assert_eq!(tests, [dir.join("tests/hello.act")]);
+    let source = test_source(&tests[0]).unwrap();
+    check(&source, &Machine::default(), &Limits::default(), &mut Console::new()).unwrap();
+
+    // The test checks the program, so it fails once the program prints something else
+    let main = dir.join("src/main.act");
+    fs::write(&main, fs::read_to_string(&main).unwrap().replace("Hello", "Goodbye")).unwrap();
+    let source = test_source(&tests[0]).unwrap();
+    let failure = check(&source, &Machine::default(), &Limits::default(), &mut Console::new()).unwrap_err();
+    assert!(failure.contains("Goodbye from demo!"), "{}", failure);
+}
+
+#[test]
+fn new_project_needs_a_new_directory() {
+    let scratch = Scratch::new("existing");
+    fs::create_dir_all(&scratch.0).unwrap();
+    let error = create(&scratch.0, "demo", None, "bin").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+}
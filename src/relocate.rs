@@ -0,0 +1,129 @@
+// Self-relocating output: a position-independent stub, the image and a relocation bitmap.
+//
+// The image is built twice, PROBE_SHIFT bytes apart. Every word that differs by exactly
+// PROBE_SHIFT is an address inside the image and gets a bit in the bitmap; anything else
+// that differs means the image cannot be relocated. At startup the stub works out where
+// it was loaded, adds the distance from the build address to each marked word and jumps
+// to the image.
+
+use crate::runtime::RAM_START;
+
+/// Bytes of stub in front of the image
+pub const STUB_SIZE: u16 = 69;
+
+/// Distance between the two builds; it changes both bytes of every address
+pub const PROBE_SHIFT: u16 = 0x0101;
+
+/// Offsets of the words in image that are addresses, given the same image built
+/// PROBE_SHIFT bytes higher
+pub fn relocations(image: &[u8], shifted: &[u8]) -> Result<Vec<usize>, String> {
+    if image.len() != shifted.len() {
+        return Err("image size depends on its address".to_string());
+    }
+    let mut offsets = Vec::new();
+    let mut i = 0;
+    while i < image.len() {
+        if image[i] == shifted[i] {
+            i += 1;
+            continue;
+        }
+        let word = |bytes: &[u8]| bytes.get(i + 1).map(|&hi| u16::from_le_bytes([bytes[i], hi]));
+        match (word(image), word(shifted)) {
+            (Some(a), Some(b)) if b == a.wrapping_add(PROBE_SHIFT) => {
+                offsets.push(i);
+                i += 2;
+            }
+            _ => return Err(format!("byte at offset {} depends on the address but is not part of one", i)),
+        }
+    }
+    Ok(offsets)
+}
+
+/// Stub, image and bitmap for an image built to run at org + STUB_SIZE, loadable anywhere
+/// outside the variable area
+pub fn make_relocatable(org: u16, image: &[u8], shifted: &[u8]) -> Result<Vec<u8>, String> {
+    let mut bitmap = vec![0u8; image.len().div_ceil(8)];
+    for offset in relocations(image, shifted)? {
+        bitmap[offset / 8] |= 0x80 >> (offset % 8);
+    }
+
+    let mut binary = generate_stub(org, image.len() as u16, bitmap.len() as u16);
+    binary.extend_from_slice(image);
+    binary.extend(bitmap);
+    Ok(binary)
+}
+
+// The stub finds its own address by calling a RET it leaves at RAM_START (runtime
+// state that is set up again later) and reading the return address back off the stack
+fn generate_stub(org: u16, image_len: u16, bitmap_len: u16) -> Vec<u8> {
+    let word = |w: u16| [(w & 0xFF) as u8, (w >> 8) as u8];
+    let mut code = Vec::new();
+    code.push(0xF3);  // DI
+    code.extend([0x3E, 0xC9]);  // LD A, $C9 (RET)
+    code.push(0x32);  // LD (RAM_START), A
+    code.extend(word(RAM_START));
+    code.push(0xCD);  // CALL RAM_START
+    code.extend(word(RAM_START));
+    // here:
+    code.push(0x3B);  // DEC SP
+    code.push(0x3B);  // DEC SP
+    code.push(0xE1);  // POP HL (HL = load address of here)
+    code.push(0x11);  // LD DE, STUB_SIZE - here
+    code.extend(word(STUB_SIZE - 9));
+    code.push(0x19);  // ADD HL, DE (HL = image)
+    code.push(0xE5);  // PUSH HL (entry point, for the end)
+    code.push(0xE5);  // PUSH HL
+    code.push(0x11);  // LD DE, -(build address of the image)
+    code.extend(word(0u16.wrapping_sub(org.wrapping_add(STUB_SIZE))));
+    code.push(0x19);  // ADD HL, DE
+    code.push(0x44);  // LD B, H
+    code.push(0x4D);  // LD C, L (BC = distance moved)
+    code.push(0xE1);  // POP HL
+    code.push(0xE5);  // PUSH HL
+    code.push(0x11);  // LD DE, image_len
+    code.extend(word(image_len));
+    code.push(0x19);  // ADD HL, DE
+    code.push(0xEB);  // EX DE, HL (DE = bitmap)
+    code.push(0xE1);  // POP HL (HL = image)
+    code.push(0xD9);  // EXX
+    code.push(0x01);  // LD BC, bitmap_len (bitmap bytes to go, in BC')
+    code.extend(word(bitmap_len));
+    code.push(0xD9);  // EXX
+    // next_byte:
+    code.push(0xD9);  // EXX
+    code.push(0x78);  // LD A, B
+    code.push(0xB1);  // OR C
+    code.extend([0x28, 0x18]);  // JR Z, done (+24)
+    code.push(0x0B);  // DEC BC
+    code.push(0xD9);  // EXX
+    code.push(0x1A);  // LD A, (DE)
+    code.push(0x13);  // INC DE
+    code.push(0x37);  // SCF
+    code.push(0x17);  // RLA (bit 7 into carry, a marker bit into bit 0)
+    // next_bit:
+    code.extend([0x30, 0x0A]);  // JR NC, skip (+10)
+    code.push(0x08);  // EX AF, AF'
+    code.push(0x7E);  // LD A, (HL)
+    code.push(0x81);  // ADD A, C
+    code.push(0x77);  // LD (HL), A
+    code.push(0x23);  // INC HL
+    code.push(0x7E);  // LD A, (HL)
+    code.push(0x88);  // ADC A, B
+    code.push(0x77);  // LD (HL), A
+    code.push(0x2B);  // DEC HL
+    code.push(0x08);  // EX AF, AF'
+    // skip:
+    code.push(0x23);  // INC HL
+    code.push(0x87);  // ADD A, A (next bit into carry; zero once the marker is out)
+    code.extend([0x20, 0xF0]);  // JR NZ, next_bit (-16)
+    code.extend([0x18, 0xE3]);  // JR next_byte (-29)
+    // done:
+    code.push(0xD9);  // EXX
+    code.push(0xE1);  // POP HL
+    code.push(0xE9);  // JP (HL)
+    debug_assert_eq!(code.len(), STUB_SIZE as usize);
+    code
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,48 @@
+// Relocatable images run on the built-in emulator at addresses other than their own
+
+use super::*;
+use crate::emulator::{Console, Cpu, StopReason};
+use crate::test_support::{compile_program, ORG};
+
+const SOURCE: &str = "\
+BYTE ARRAY msg = \"moved\"
+PROC show()
+Print(msg)
+RETURN
+PROC main()
+show()
+RETURN
+";
+
+fn relocatable(source: &str) -> Vec<u8> {
+    let image = compile_program(source, ORG + STUB_SIZE).unwrap();
+    let shifted = compile_program(source, ORG + STUB_SIZE + PROBE_SHIFT).unwrap();
+    make_relocatable(ORG, &image, &shifted).unwrap()
+}
+
+fn run_at(binary: &[u8], addr: u16) -> Vec<u8> {
+    let mut cpu = Cpu::new();
+    cpu.load(addr, binary);
+    cpu.pc = addr;
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(1_000_000)), StopReason::Halted);
+    console.output
+}
+
+#[test]
+fn runs_wherever_it_is_loaded() {
+    let binary = relocatable(SOURCE);
+    for addr in [ORG, 0x8000, 0x9123, 0xC001] {
+        assert_eq!(run_at(&binary, addr), b"moved", "loaded at ${:04X}", addr);
+    }
+}
+
+#[test]
+fn marks_only_addresses() {
+    let image = [0x3E, 0x05, 0xC3, 0x00, 0x42, 0x21, 0x00, 0x20];
+    let shifted = [0x3E, 0x05, 0xC3, 0x01, 0x43, 0x21, 0x00, 0x20];
+    assert_eq!(relocations(&image, &shifted), Ok(vec![3]));
+
+    let shifted = [0x3E, 0x06, 0xC3, 0x01, 0x43, 0x21, 0x00, 0x20];
+    assert!(relocations(&image, &shifted).is_err());
+}
@@ -0,0 +1,224 @@
+// Interactive REPL for Action!
+// Each entry is compiled together with earlier declarations and run on the emulator
+
+use crate::ast::{Expression, Procedure, Program, Statement, Variable};
+use crate::compile::{compile_program, parse, tokenize, CompileOptions};
+use crate::emulator::{Console, Cpu, StopReason};
+use crate::error::Result;
+use crate::token::{CaseMode, Token, TokenInfo};
+use std::io::{self, Write};
+
+const ORG: u16 = 0x4200;
+const ENTRY: &str = "ReplStatement";
+const RESULT_VAR: &str = "ReplResult";
+const MAX_CYCLES: u64 = 100_000_000;
+
+const HELP: &str = "\
+Enter Action! statements to run them, declarations (BYTE x, PROC ...) to keep them,
+or `? expr` to print the value of an expression.
+  :help    show this help
+  :reset   forget all declarations and clear memory
+  :quit    leave the REPL";
+
+pub struct Repl {
+    globals: Vec<Variable>,
+    procedures: Vec<Procedure>,
+    cpu: Cpu,
+    case_mode: CaseMode,
+}
+
+impl Repl {
+    pub fn new(case_mode: CaseMode) -> Self {
+        Repl {
+            globals: vec![Self::result_var()],
+            procedures: Vec::new(),
+            cpu: Cpu::new(),
+            case_mode,
+        }
+    }
+
+    // How entries compile: to run the entry procedure, with the REPL's case policy
+    fn options(&self) -> CompileOptions {
+        CompileOptions {
+            origin: ORG,
+            case_mode: self.case_mode,
+            entry: Some(ENTRY.to_string()),
+            ..CompileOptions::default()
+        }
+    }
+
+    fn tokenize(&self, text: &str) -> Result<Vec<TokenInfo>> {
+        tokenize(text, &self.options())
+    }
+
+    fn result_var() -> Variable {
+        Variable {
+            name: RESULT_VAR.to_string(),
+            data_type: crate::ast::DataType::Card,
+            initial_value: None,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        println!("Action! REPL - type :help for help");
+        loop {
+            let Some(entry) = self.read_entry()? else {
+                println!();
+                return Ok(());
+            };
+            let trimmed = entry.trim();
+            match trimmed {
+                "" => continue,
+                ":q" | ":quit" => return Ok(()),
+                ":help" => println!("{}", HELP),
+                ":reset" => *self = Repl::new(self.case_mode),
+                _ if trimmed.starts_with(':') => println!("Unknown command: {}", trimmed),
+                _ => {
+                    if let Err(e) = self.eval(trimmed) {
+                        println!("Error: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Read one complete entry, continuing over lines until blocks are closed
+    fn read_entry(&self) -> io::Result<Option<String>> {
+        let mut entry = String::new();
+        loop {
+            print!("{}", if entry.is_empty() { "> " } else { "... " });
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line)? == 0 {
+                return Ok(if entry.is_empty() { None } else { Some(entry) });
+            }
+            entry.push_str(&line);
+            if self.is_complete(&entry) {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    // An entry is complete once its IF/DO blocks are closed and any PROC has reached RETURN
+    fn is_complete(&self, text: &str) -> bool {
+        let Ok(tokens) = self.tokenize(text.trim_start_matches('?')) else {
+            return true;
+        };
+        let tokens: Vec<&Token> = tokens.iter().map(|t| &t.token).collect();
+        let count = |kinds: &[Token]| tokens.iter().filter(|t| kinds.contains(t)).count();
+        let opened = count(&[Token::If, Token::Do]);
+        let closed = count(&[Token::Fi, Token::Od]);
+        // A FOR or WHILE header may be followed by DO on the next line
+        let awaiting_do = count(&[Token::For, Token::While]) > count(&[Token::Do]);
+        if opened > closed || awaiting_do {
+            return false;
+        }
+        if matches!(tokens.first(), Some(Token::Proc | Token::Func)) {
+            // The last line must start with RETURN
+            let last_line = tokens.iter().rev()
+                .skip_while(|t| matches!(t, Token::Newline | Token::Eof))
+                .take_while(|t| **t != &Token::Newline)
+                .last();
+            return last_line == Some(&&Token::Return);
+        }
+        true
+    }
+
+    fn parse(&self, source: &str) -> Result<Program> {
+        parse(self.tokenize(source)?, &self.options()).map(|(program, _)| program)
+    }
+
+    fn eval(&mut self, text: &str) -> Result<()> {
+        let mut globals = self.globals.clone();
+        let mut procedures = self.procedures.clone();
+        let mut body = Vec::new();
+        let mut show_result = false;
+
+        if let Some(expr) = text.strip_prefix('?') {
+            let program = self.parse(&format!("PROC {}()\n{} = {}\nRETURN\n", ENTRY, RESULT_VAR, expr))?;
+            body = program.procedures.into_iter().next().map(|p| p.body).unwrap_or_default();
+            show_result = true;
+        } else if self.is_declaration(text) {
+            let program = self.parse(text)?;
+            for mut var in program.globals {
+                // Scalar initializers become assignments so the value lives in RAM; strings and
+                // TABLEs stay as the ARRAY's contents
+                let table = var.initial_value.as_ref().is_some_and(|v| v.table(self.case_mode).is_some());
+                if !table && !matches!(var.initial_value, None | Some(Expression::String(_))) {
+                    body.push(Statement::Assignment {
+                        target: var.name.clone(),
+                        value: var.initial_value.take().unwrap(),
+                    });
+                }
+                globals.retain(|g| !self.case_mode.matches(&g.name, &var.name));
+                globals.push(var);
+            }
+            for proc in program.procedures {
+                procedures.retain(|p| !self.case_mode.matches(&p.name, &proc.name));
+                procedures.push(proc);
+            }
+        } else {
+            let program = self.parse(&format!("PROC {}()\n{}\nRETURN\n", ENTRY, text))?;
+            if let Some(proc) = program.procedures.into_iter().next() {
+                globals.extend(proc.locals);
+                body = proc.body;
+            }
+        }
+
+        let mut program = Program::new();
+        program.globals = globals.clone();
+        program.procedures = procedures.clone();
+        program.procedures.push(Procedure {
+            name: ENTRY.to_string(),
+            params: Vec::new(),
+            return_type: None,
+            locals: Vec::new(),
+            body,
+            address: None,
+            fast_call: false,
+            interrupt: false,
+            module: 0,
+        });
+        let (image, result_addr) = self.compile(&program)?;
+
+        self.globals = globals;
+        self.procedures = procedures;
+        self.execute(&image);
+
+        if show_result {
+            println!("= {}", self.cpu.read_word(result_addr));
+        }
+        Ok(())
+    }
+
+    fn is_declaration(&self, text: &str) -> bool {
+        matches!(
+            self.tokenize(text).ok().and_then(|t| t.first().map(|t| t.token.clone())),
+            Some(Token::Byte | Token::Card | Token::Int | Token::Char_ | Token::Proc | Token::Func)
+        )
+    }
+
+    // Compile to an image, also returning the address of the result variable
+    fn compile(&self, program: &Program) -> Result<(Vec<u8>, u16)> {
+        let output = compile_program(program, &self.options())?;
+        let result_addr = output.codegen.global_address(RESULT_VAR).unwrap_or_default();
+        Ok((output.binary, result_addr))
+    }
+
+    // Run a freshly compiled image; RAM outside the image keeps its contents
+    fn execute(&mut self, image: &[u8]) {
+        self.cpu.load(ORG, image);
+        self.cpu.pc = ORG;
+        self.cpu.sp = 0x0000;
+        self.cpu.halted = false;
+
+        let mut console = Console::new();
+        console.echo = true;
+        console.interactive = true;
+        if self.cpu.run(&mut console, Some(MAX_CYCLES)) == StopReason::CycleLimit {
+            println!("\nStopped after {} cycles at PC=${:04X}", MAX_CYCLES, self.cpu.pc);
+        } else if console.output.last().is_some_and(|&b| b != b'\n') {
+            println!();
+        }
+    }
+}
@@ -0,0 +1,388 @@
+// Run a program on the emulator, with the console on the host's terminal and optional
+// memory-mapped devices around the CPU, or check its output against the expectations
+// written in its comments. A watched run patches procedures changed in the source into
+// memory while the program runs.
+
+use crate::ast::{strip_lines, Procedure, Program};
+use crate::codegen::CodeGenerator;
+use crate::compile::{self, compile_patch, compile_program, CompileOptions, CompileOutput};
+use crate::emulator::{BankSwitch, Console, Cpu, StopReason, TextScreen};
+use crate::symbols::SymbolFile;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const ORG: u16 = 0x4200;
+
+// T-states run between checks of the timeout, and of the source of a watched run
+const TIMEOUT_SLICE: u64 = 1_000_000;
+
+// Stack words searched for return addresses in a backtrace
+const BACKTRACE_DEPTH: u16 = 32;
+
+/// Comment prefixes in a test program: a line of expected output, expected bytes in
+/// memory (ADDRESS BYTES...), memory to show after the run (ADDRESS LENGTH), and a
+/// source file to check, relative to the test
+const EXPECT: &str = "; expect:";
+const EXPECT_MEMORY: &str = "; expect-memory:";
+const DUMP: &str = "; dump:";
+const SOURCE: &str = "; source:";
+
+/// Banked memory window: REGISTER,WINDOW,SIZE,COUNT
+#[derive(Debug, Clone, Copy)]
+pub struct BankConfig {
+    pub register: u16,
+    pub window: u16,
+    pub size: u16,
+    pub count: usize,
+}
+
+/// Devices mapped into memory for a run
+#[derive(Debug, Default, Clone)]
+pub struct Machine {
+    pub screen: Option<u16>,  // Base address of an 80x25 text screen
+    pub bank: Option<BankConfig>,
+}
+
+impl Machine {
+    fn cpu(&self) -> Cpu {
+        let mut cpu = Cpu::new();
+        if let Some(base) = self.screen {
+            cpu.map_device(Box::new(TextScreen::new(base)));
+        }
+        if let Some(bank) = self.bank {
+            cpu.map_device(Box::new(BankSwitch::new(bank.register, bank.window, bank.size, bank.count)));
+        }
+        cpu
+    }
+}
+
+/// When to give up on a program that does not halt
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    pub max_cycles: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+// Procedure and runtime routine addresses, for naming addresses in diagnostics
+struct Symbols {
+    image: std::ops::Range<u16>,
+    names: Vec<(u16, String)>,  // Sorted by address
+}
+
+impl Symbols {
+    // Nearest symbol at or below addr, as "name+offset"
+    fn describe(&self, addr: u16) -> String {
+        if !self.image.contains(&addr) {
+            return format!("${:04X}", addr);
+        }
+        match self.names.iter().rev().find(|(start, _)| *start <= addr) {
+            Some((start, name)) if *start == addr => format!("${:04X} {}", addr, name),
+            Some((start, name)) => format!("${:04X} {}+{}", addr, name, addr - start),
+            None => format!("${:04X}", addr),
+        }
+    }
+}
+
+fn options() -> CompileOptions {
+    CompileOptions { origin: ORG, ..CompileOptions::default() }
+}
+
+// The program in source, as the options parse it
+fn parse_source(source: &str) -> crate::error::Result<Program> {
+    compile::parse_source(source, &options()).map(|(program, _)| program)
+}
+
+fn compile(source: &str) -> crate::error::Result<(Program, CompileOutput, Symbols)> {
+    let program = parse_source(source)?;
+    let output = compile_program(&program, &options())?;
+
+    let code_start = output.runtime_symbols.end_address;
+    let mut names: Vec<(u16, String)> = output.runtime_symbols.routines().into_iter()
+        .map(|(name, addr)| (addr, name.to_string()))
+        .chain(output.codegen.procedure_addresses().map(|(name, addr)| (addr, name.to_string())))
+        .collect();
+    names.push((code_start, "startup".to_string()));
+    // Built-ins come before the internal routines they share an address with
+    names.sort_by_key(|&(addr, _)| addr);
+    names.dedup_by_key(|&mut (addr, _)| addr);
+    let image = ORG..ORG.wrapping_add(output.binary.len() as u16);
+    Ok((program, output, Symbols { image, names }))
+}
+
+/// Compile and run a program until it halts, returning the CPU for its final state, or
+/// a diagnostic with the registers and a backtrace if it hits a limit first
+pub fn run(source: &str, machine: &Machine, limits: &Limits, console: &mut Console) -> Result<Cpu, String> {
+    let (_, output, symbols) = compile(source).map_err(|e| e.to_string())?;
+    execute(&output.binary, &symbols, machine, limits, console, None)
+}
+
+/// Run a program as run does, calling changed between slices of the run for the new
+/// source, if the program's has changed. Each procedure changed in it is compiled again
+/// and patched into memory, and report told what was reloaded or why not.
+pub fn run_watched(
+    source: &str,
+    machine: &Machine,
+    limits: &Limits,
+    console: &mut Console,
+    changed: &mut dyn FnMut() -> Option<String>,
+    report: &mut dyn FnMut(&str),
+) -> Result<Cpu, String> {
+    let (program, output, symbols) = compile(source).map_err(|e| e.to_string())?;
+    let mut reload = HotReload::new(&program, &output);
+    let mut between = |cpu: &mut Cpu| {
+        if let Some(source) = changed() {
+            for message in reload.reload(&source, cpu) {
+                report(&message);
+            }
+        }
+    };
+    execute(&output.binary, &symbols, machine, limits, console, Some(&mut between))
+}
+
+// The procedures of a running program, as last compiled, and the image they are in
+struct HotReload {
+    procedures: HashMap<String, String>,  // Name to the procedure without line markers, printed
+    globals: String,
+    image: Vec<u8>,
+    options: CompileOptions,              // Importing the image's own symbols
+}
+
+impl HotReload {
+    fn new(program: &Program, output: &CompileOutput) -> HotReload {
+        let options = options();
+        let symbols = SymbolFile::export(program, output, &options);
+        HotReload {
+            procedures: Self::procedures(program),
+            globals: format!("{:?}", program.globals),
+            image: output.binary.clone(),
+            options: CompileOptions { imports: vec![symbols], ..options },
+        }
+    }
+
+    fn procedures(program: &Program) -> HashMap<String, String> {
+        program.procedures.iter().map(|proc| (options().case_mode.key(&proc.name), Self::printed(proc))).collect()
+    }
+
+    // What the procedure does, whichever lines it is on
+    fn printed(proc: &Procedure) -> String {
+        let mut proc = proc.clone();
+        strip_lines(&mut proc.body);
+        format!("{:?}", proc)
+    }
+
+    // Patch the procedures changed in source into cpu's memory, with a message for
+    // each; the others keep their code, and a procedure running at the time returns
+    // into its new code
+    fn reload(&mut self, source: &str, cpu: &mut Cpu) -> Vec<String> {
+        let program = match parse_source(source) {
+            Ok(program) => program,
+            Err(e) => return vec![format!("not reloaded: {}", e)],
+        };
+        let mut messages = Vec::new();
+        if format!("{:?}", program.globals) != self.globals {
+            messages.push("the globals changed; restart to use them".to_string());
+        }
+        for proc in &program.procedures {
+            let (name, key, text) = (&proc.name, options().case_mode.key(&proc.name), Self::printed(proc));
+            match self.procedures.get(&key) {
+                Some(old) if *old == text => continue,
+                Some(_) => match compile_patch(&program, name, &self.image, &self.options) {
+                    Ok(patched) => {
+                        for (i, (&new, &old)) in patched.iter().zip(&self.image).enumerate() {
+                            if new != old {
+                                cpu.write(ORG.wrapping_add(i as u16), new);
+                            }
+                        }
+                        self.image = patched;
+                        self.procedures.insert(key, text);
+                        messages.push(format!("reloaded {}", name));
+                    }
+                    Err(e) => messages.push(format!("{} not reloaded: {}", name, e)),
+                },
+                None => messages.push(format!("{} is new; restart to use it", name)),
+            }
+        }
+        messages.sort();
+        messages
+    }
+}
+
+fn execute(
+    binary: &[u8],
+    symbols: &Symbols,
+    machine: &Machine,
+    limits: &Limits,
+    console: &mut Console,
+    mut between: Option<&mut dyn FnMut(&mut Cpu)>,
+) -> Result<Cpu, String> {
+    let mut cpu = machine.cpu();
+    cpu.load(ORG, binary);
+    cpu.pc = ORG;
+
+    let started = Instant::now();
+    loop {
+        let left = limits.max_cycles.map(|max| max.saturating_sub(cpu.cycles));
+        let slice = match (left, limits.timeout.is_some() || between.is_some()) {
+            (Some(left), true) => Some(left.min(TIMEOUT_SLICE)),
+            (None, true) => Some(TIMEOUT_SLICE),
+            (left, false) => left,
+        };
+        if cpu.run(console, slice) == StopReason::Halted {
+            return Ok(cpu);
+        }
+        if let Some(between) = between.as_mut() {
+            between(&mut cpu);
+        }
+        if limits.max_cycles.is_some_and(|max| cpu.cycles >= max) {
+            return Err(diagnostic(&cpu, symbols, "cycle limit"));
+        }
+        if limits.timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            return Err(diagnostic(&cpu, symbols, "timeout"));
+        }
+    }
+}
+
+// Why and where a program was stopped, with its registers and the return addresses
+// found on the stack
+fn diagnostic(cpu: &Cpu, symbols: &Symbols, reason: &str) -> String {
+    let mut text = format!(
+        "stopped by {} after {} T-states at {}\n",
+        reason, cpu.cycles, symbols.describe(cpu.pc),
+    );
+    text.push_str(&format!(
+        "  AF={:04X} BC={:04X} DE={:04X} HL={:04X} IX={:04X} IY={:04X} SP={:04X}\n",
+        cpu.af(), cpu.bc(), cpu.de(), cpu.hl(), cpu.ix, cpu.iy, cpu.sp,
+    ));
+    text.push_str("backtrace:");
+    for i in 0..BACKTRACE_DEPTH {
+        let at = cpu.sp.wrapping_add(2 * i);
+        if at < cpu.sp {
+            break;  // Wrapped past the top of memory
+        }
+        let addr = cpu.read_word(at);
+        // A return address follows a CALL or conditional CALL in the image
+        let call = cpu.read(addr.wrapping_sub(3));
+        if symbols.image.contains(&addr) && (call == 0xCD || call & 0xC7 == 0xC4) {
+            text.push_str(&format!("\n  {}", symbols.describe(addr.wrapping_sub(3))));
+        }
+    }
+    text
+}
+
+/// Parse an input script: each line is typed followed by CR, or without it if it ends
+/// in a backslash. A line starting with @CYCLES is typed once the program has run that
+/// many T-states, and later lines follow it. Lines starting with # are comments.
+pub fn parse_input_script(text: &str) -> Result<VecDeque<(u64, Vec<u8>)>, String> {
+    let mut script = VecDeque::new();
+    let mut at = 0;
+    for (n, line) in text.lines().enumerate() {
+        let mut line = line.trim_end_matches('\r');
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(timed) = line.strip_prefix('@') {
+            let (cycles, rest) = timed.split_once(' ').unwrap_or((timed, ""));
+            at = cycles.parse().map_err(|_| format!("line {}: bad cycle count '{}'", n + 1, cycles))?;
+            line = rest;
+        }
+        let keys = match line.strip_suffix('\\') {
+            Some(keys) => keys.as_bytes().to_vec(),
+            None => [line.as_bytes(), b"\r"].concat(),
+        };
+        script.push_back((at, keys));
+    }
+    Ok(script)
+}
+
+// Hex bytes, 16 to a line, each line starting with its address
+fn hex_dump(start: u16, bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        text.push_str(&format!("  {:04X}: {}\n", start.wrapping_add(16 * i as u16), hex.join(" ")));
+    }
+    text
+}
+
+// Address of a test directive: a number ($hex, 0xhex or decimal) or a global variable
+fn directive_address(text: &str, codegen: &CodeGenerator) -> Option<u16> {
+    let hex = text.strip_prefix('$').or_else(|| text.strip_prefix("0x"));
+    match hex {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None if text.starts_with(|c: char| c.is_ascii_digit()) => text.parse().ok(),
+        None => codegen.global_address(text),
+    }
+}
+
+/// Read the test program at path for check: the files named by its `; source:` lines,
+/// in order, then its own text, so a test can check a program kept in another file
+pub fn test_source(path: &Path) -> Result<String, String> {
+    let read = |path: &Path| fs::read_to_string(path).map_err(|e| format!("Error reading file {:?}: {}", path, e));
+    let test = read(path)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut source = String::new();
+    for name in test.lines().filter_map(|line| line.trim_start().strip_prefix(SOURCE)) {
+        source.push_str(&read(&dir.join(name.trim()))?);
+        source.push('\n');
+    }
+    source.push_str(&test);
+    Ok(source)
+}
+
+/// Run a test program and compare its console output with its `; expect:` lines and
+/// its memory with its `; expect-memory:` lines. Returns the regions asked for by
+/// `; dump:` lines, after a description of what did not match if anything did.
+pub fn check(source: &str, machine: &Machine, limits: &Limits, console: &mut Console) -> Result<String, String> {
+    let directives = |prefix: &'static str| source.lines()
+        .filter_map(move |line| line.trim_start().strip_prefix(prefix))
+        .map(|line| line.strip_prefix(' ').unwrap_or(line));
+    let expected: Vec<&str> = directives(EXPECT).collect();
+    let (_, output, symbols) = compile(source).map_err(|e| e.to_string())?;
+    let codegen = &output.codegen;
+    let cpu = execute(&output.binary, &symbols, machine, limits, console, None)?;
+
+    let mut failures = Vec::new();
+    let actual = String::from_utf8_lossy(&console.output).replace("\r\n", "\n");
+    let expected = expected.join("\n");
+    if actual.trim_end() != expected.trim_end() {
+        failures.push(format!("expected output:\n{}\nactual output:\n{}", expected.trim_end(), actual.trim_end()));
+    }
+
+    let read = |start: u16, len: usize| -> Vec<u8> {
+        (0..len).map(|i| cpu.read(start.wrapping_add(i as u16))).collect()
+    };
+    for line in directives(EXPECT_MEMORY) {
+        let mut fields = line.split_whitespace();
+        let place = fields.next().unwrap_or_default();
+        let start = directive_address(place, codegen)
+            .ok_or_else(|| format!("expect-memory: unknown address '{}'", place))?;
+        let bytes = fields.map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("expect-memory: bad bytes in '{}'", line))?;
+        let found = read(start, bytes.len());
+        if found != bytes {
+            failures.push(format!(
+                "expected memory at {}:\n{}\nactual memory:\n{}",
+                place, hex_dump(start, &bytes).trim_end(), hex_dump(start, &found).trim_end(),
+            ));
+        }
+    }
+    let mut dumps = String::new();
+    for line in directives(DUMP) {
+        let (place, len) = line.split_once(' ').unwrap_or((line, "16"));
+        let start = directive_address(place.trim(), codegen)
+            .ok_or_else(|| format!("dump: unknown address '{}'", place))?;
+        let len = len.trim().parse().map_err(|_| format!("dump: bad length in '{}'", line))?;
+        dumps.push_str(&format!("{}:\n{}", place.trim(), hex_dump(start, &read(start, len))));
+    }
+    if failures.is_empty() {
+        Ok(dumps)
+    } else {
+        Err(format!("{}\n{}", failures.join("\n"), dumps).trim_end().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,460 @@
+// Scripted input and output checks for programs run on the emulator
+
+use super::*;
+
+const ECHO: &str = "\
+PROC main()
+BYTE c
+c = GetD()
+PutD(c)
+c = GetD()
+PutD(c)
+RETURN
+";
+
+fn scripted(text: &str) -> Console {
+    let mut console = Console::new();
+    console.script = parse_input_script(text).unwrap();
+    console
+}
+
+#[test]
+fn script_lines_and_keystrokes() {
+    let script = parse_input_script("# comment\nab\n@1000 c\\\n@20\nd\n").unwrap();
+    assert_eq!(script, [
+        (0, b"ab\r".to_vec()),
+        (1000, b"c".to_vec()),
+        (20, b"\r".to_vec()),
+        (20, b"d\r".to_vec()),
+    ]);
+    assert!(parse_input_script("@soon x").is_err());
+}
+
+#[test]
+fn timed_input_waits_for_its_cycle() {
+    let mut console = scripted("x\\\n@300000 y\\\n");
+    let cpu = run(ECHO, &Machine::default(), &Limits::default(), &mut console).unwrap();
+    assert_eq!(console.output, b"xy");
+    assert!(cpu.cycles >= 300_000);
+}
+
+#[test]
+fn check_compares_output_with_expect_lines() {
+    let source = format!("{}; expect: ab\n", ECHO);
+    assert_eq!(check(&source, &Machine::default(), &Limits::default(), &mut scripted("ab\\")), Ok(String::new()));
+    assert!(check(&source, &Machine::default(), &Limits::default(), &mut scripted("ba\\")).is_err());
+}
+
+#[test]
+fn cycle_limit_stops_a_program_waiting_for_input() {
+    let limits = Limits { max_cycles: Some(100_000), timeout: None };
+    let Err(err) = run(ECHO, &Machine::default(), &limits, &mut Console::new()) else {
+        panic!("the program halted");
+    };
+    assert!(err.starts_with("stopped by cycle limit after 1000"), "{}", err);
+    // Polling for input (GetD jumps to in_char), called from main, called by the startup code
+    let backtrace: Vec<&str> = err.lines().skip_while(|l| *l != "backtrace:").skip(1).collect();
+    assert_eq!(backtrace.len(), 2, "{}", err);
+    assert!(backtrace[0].to_lowercase().ends_with(" main"), "{}", err);
+    assert!(backtrace[1].contains(" startup+"), "{}", err);
+    assert!(err.lines().next().unwrap().contains(" in_char+"), "{}", err);
+}
+
+#[test]
+fn check_compares_and_dumps_memory() {
+    let source = "\
+BYTE ARRAY counts(4)
+CARD total
+PROC main()
+counts(1) = 7
+counts(3) = 9
+total = 513
+RETURN
+; expect-memory: counts 00 07 00 09
+; expect-memory: total 01 02
+; dump: counts 4
+";
+    let run_check = |source: &str| check(source, &Machine::default(), &Limits::default(), &mut Console::new());
+    let dumps = run_check(source).unwrap();
+    assert!(dumps.starts_with("counts:\n  "), "{}", dumps);
+    assert!(dumps.ends_with(": 00 07 00 09\n"), "{}", dumps);
+
+    let err = run_check(&source.replace("total 01 02", "total 02 01")).unwrap_err();
+    assert!(err.starts_with("expected memory at total:"), "{}", err);
+    assert!(err.contains(": 01 02\n"), "{}", err);
+    assert!(run_check(&source.replace("counts 00", "nothing 00")).is_err());
+}
+
+#[test]
+fn until_loops_run_their_body_first() {
+    let source = "\
+BYTE i
+BYTE j
+
+PROC main()
+i = 5
+DO
+  PutD(i + '0')
+  i = i + 1
+UNTIL i > 3
+OD
+j = 0
+DO
+  j = j + 1
+  IF j = 3 THEN EXIT FI
+UNTIL 0
+OD
+PutD(j + '0')
+RETURN
+; expect: 53
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn exit_leaves_the_innermost_loop() {
+    let source = "\
+BYTE i
+BYTE j
+PROC main()
+FOR i = 1 TO 3 DO
+  j = 0
+  WHILE 1 DO
+    j = j + 1
+    IF j = i THEN EXIT FI
+  OD
+  PutD(j + '0')
+  IF i = 2 THEN EXIT FI
+OD
+RETURN
+; expect: 12
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn strings_compare_by_their_characters() {
+    let source = "\
+BYTE ARRAY name(8)
+BYTE ARRAY other = \"abd\"
+PROC main()
+SSub(name, \"xabcx\", 1, 3)
+IF name = \"abc\" THEN PutD('1') FI
+IF name <> \"ab\" THEN PutD('2') FI
+IF name = other THEN PutD('x') FI
+IF name <> other THEN PutD('3') FI
+PrintB(SCompare(name, other)) PutD(' ')
+PrintB(SCompare(other, name)) PutD(' ')
+PrintB(SCompare(\"\", \"\"))
+RETURN
+; expect: 123255 1 0
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn characters_are_classified_and_converted() {
+    let source = "\
+BYTE ARRAY text = \"a1Z@[`{ 9\"
+BYTE i
+BYTE c
+PROC main()
+FOR i = 0 TO 8 DO
+  c = text(i)
+  PutD(IsDigit(c) + '0') PutD(IsAlpha(c) + '0') PutD(ToUpper(c)) PutD(ToLower(c))
+OD
+RETURN
+; expect: 01Aa101101Zz00@@00[[00``00{{00  1099
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn tables_are_computed_for_each_element() {
+    let source = "\
+BYTE ARRAY squares(6) = TABLE(i, i * i)
+CARD ARRAY cubes(4) = TABLE(n, n * n * n + 1000)
+BYTE ARRAY sine(8) = TABLE(a, 128 + Sin(a * 32, 100))
+PROC main()
+BYTE ARRAY local(3) = TABLE(i, 10 - i)
+PrintB(squares(5) + local(2))
+RETURN
+; expect: 33
+; expect-memory: squares 00 01 04 09 10 19
+; expect-memory: cubes E8 03 E9 03 F0 03 03 04
+; expect-memory: sine 80 C7 E4 C7 80 39 1C 39
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+
+    let errors = ["BYTE ARRAY big(20) = TABLE(i, i * i)", "BYTE x = TABLE(i, i)", "BYTE ARRAY odd(2) = TABLE(i, i + j)"]
+        .map(|decl| check(&format!("{}\nPROC main()\nRETURN\n", decl), &Machine::default(), &Limits::default(), &mut Console::new()).unwrap_err());
+    assert!(errors[0].contains("TABLE entry 16 of 'big' is 256"), "{}", errors[0]);
+    assert!(errors[1].contains("'x' is not one"), "{}", errors[1]);
+    assert!(errors[2].contains("TABLE entry 0 of 'odd' is not a constant"), "{}", errors[2]);
+}
+
+#[test]
+fn register_arguments_reach_the_procedure() {
+    let source = "\
+PROC show(BYTE c, CARD n)
+BYTE d
+d = n MOD 256 + '0'
+PutD(c)
+PutD(d)
+RETURN
+PROC twice(BYTE c) FASTCALL
+show(c, 1)
+show(c, 2)
+RETURN
+PROC main()
+twice('x')
+RETURN
+; expect: x1x2
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn var_parameters_change_the_callers_variable() {
+    // AddTwice passes its own VAR parameter on, so Add changes total
+    let source = "\
+CARD total
+BYTE seen
+PROC Add(CARD VAR sum, BYTE n)
+sum = sum + n
+RETURN
+PROC AddTwice(CARD VAR sum) FASTCALL
+Add(sum, 200)
+Add(sum, 100)
+RETURN
+PROC Mark(BYTE VAR flag)
+flag = 'y'
+RETURN
+PROC main()
+total = 1000
+AddTwice(total)
+Mark(seen)
+PutD(seen)
+RETURN
+; expect: y
+; expect-memory: total 14 05
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn division_and_modulo() {
+    let source = "\
+BYTE b
+BYTE q
+BYTE r
+CARD c
+CARD cq
+CARD cr
+PROC main()
+b = 200
+c = 50000
+q = b / 7
+r = b MOD 7
+cq = c / b
+cr = c MOD 300
+RETURN
+; expect-memory: q 1C
+; expect-memory: r 04
+; expect-memory: cq FA 00
+; expect-memory: cr C8 00
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn private_names_of_each_module_are_their_own() {
+    let source = "\
+MODULE
+PRIVATE BYTE count
+PROC first()
+count = count + 1
+PutD(count + '0')
+RETURN
+MODULE
+PRIVATE BYTE count
+PROC second()
+count = count + 5
+PutD(count + '0')
+RETURN
+MODULE
+BYTE count
+PROC main()
+count = 2
+first()
+second()
+first()
+PutD(count + '0')
+RETURN
+; expect: 1522
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn functions_return_their_type() {
+    let source = "\
+CARD c
+BYTE b
+INT i
+
+FUNC CARD twice(CARD n)
+RETURN (n + n)
+
+FUNC CARD widen(BYTE n)
+RETURN (n)
+
+FUNC BYTE low(CARD n)
+RETURN (n MOD 256)
+
+FUNC INT less(INT a) FASTCALL
+PutD('x')
+RETURN (a - 300)
+
+PROC main()
+c = twice(1000) + 1
+b = low(c)
+c = c + widen(b)
+i = less(5)
+RETURN
+; expect: x
+; expect-memory: c A2 08
+; expect-memory: b D1
+; expect-memory: i D9 FE
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn recursive_procedures_have_locals_of_their_own() {
+    let source = "\
+CARD total
+
+PROC digits(BYTE v) FASTCALL
+BYTE d
+d = v MOD 10
+IF v >= 10 THEN digits(v / 10) FI
+PutD(d + '0')
+RETURN
+
+FUNC CARD sum(BYTE n) FASTCALL
+BYTE m = 0
+CARD r = 0
+m = m + n
+IF n > 0 THEN r = sum(n - 1) FI
+RETURN (r + m)
+
+PROC main()
+digits(234)
+total = sum(10)
+RETURN
+; expect: 234
+; expect-memory: total 37 00
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn programs_replace_the_console_routines() {
+    // Everything printed, by the runtime too, goes through the program's PutD, and
+    // InputS reads through its GetD
+    let source = "\
+BYTE ARRAY out(8)
+BYTE ARRAY line(8)
+BYTE sent
+BYTE typed
+
+PROC PutD(BYTE c) FASTCALL
+out(sent) = c
+sent = sent + 1
+RETURN
+
+FUNC BYTE GetD()
+BYTE c
+typed = typed + 1
+c = 13
+IF typed = 1 THEN c = 'o' FI
+IF typed = 2 THEN c = 'k' FI
+RETURN (c)
+
+PROC main()
+Print(\"hi\")
+PrintB(42)
+InputS(line, 8)
+RETURN
+; expect-memory: out 68 69 34 32 6F 6B
+; expect-memory: line 6F 6B 00
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn procedures_receive_their_arguments() {
+    // fill gets its array in HL, first in A and the others on the stack; twice has a
+    // stack frame and reads acc where its caller pushed it
+    let source = "\
+BYTE ARRAY digits(4)
+CARD total
+BYTE doubled
+
+PROC fill(BYTE ARRAY a, BYTE first, BYTE gap, BYTE n)
+BYTE i = 0
+WHILE i < n DO
+  a(i) = first
+  first = first + gap
+  i = i + 1
+OD
+RETURN
+
+FUNC CARD add3(CARD x, CARD y, BYTE z)
+RETURN (x + y + z)
+
+FUNC BYTE twice(BYTE n, BYTE acc)
+BYTE r
+r = acc
+IF n > 0 THEN r = twice(n - 1, acc + 2) FI
+RETURN (r)
+
+PROC main()
+fill(digits, 3, 4, 4)
+total = add3(1000, 234, 5)
+doubled = twice(5, 1)
+RETURN
+; expect-memory: digits 03 07 0B 0F
+; expect-memory: total D7 04
+; expect-memory: doubled 0B
+";
+    assert_eq!(check(source, &Machine::default(), &Limits::default(), &mut Console::new()), Ok(String::new()));
+}
+
+#[test]
+fn watched_runs_reload_changed_procedures() {
+    let source = "\
+PROC Show()
+PutD('a')
+RETURN
+PROC main()
+BYTE i
+BYTE j
+Show()
+FOR i = 1 TO 200 DO
+  FOR j = 1 TO 200 DO OD
+OD
+Show()
+RETURN
+";
+    // Show changes on its own line, and Extra is new
+    let changed = source.replace("PutD('a')", "PutD('b')").replacen("PROC Show", "PROC Extra()\nRETURN\nPROC Show", 1);
+    let mut edits = vec![changed];
+    let mut messages = Vec::new();
+    let mut console = Console::new();
+    run_watched(source, &Machine::default(), &Limits::default(), &mut console,
+                &mut || edits.pop(), &mut |message| messages.push(message.to_string())).unwrap();
+    assert_eq!(messages, ["Extra is new; restart to use it", "reloaded Show"]);
+    assert_eq!(console.output, b"ab");
+}
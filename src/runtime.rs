@@ -1,255 +1,1581 @@
 // Z80 Runtime library for Action! compiler
 // Provides built-in procedures and functions
 
-/// Generate the runtime library code
+mod asm;
+
+use crate::token::CaseMode;
+use asm::{Alu::*, Asm, Cond, Target, R16::*, R8::*};
+
+/// Start of RAM; the runtime's own variables come first, then the program's globals
+pub const RAM_START: u16 = 0x2000;
+
+/// Device number of the console for I/O routines taking a device argument
+pub const DEVICE_CONSOLE: u8 = 0;
+
+/// Device number of the printer, used by the LPrint routines
+pub const DEVICE_PRINTER: u8 = 1;
+
+/// (data, status) I/O ports of each device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DevicePorts {
+    pub console: (u8, u8),  // Device 0
+    pub printer: (u8, u8),  // Device 1
+    pub aux: (u8, u8),      // Device 2, a second UART
+}
+
+impl Default for DevicePorts {
+    fn default() -> Self {
+        DevicePorts {
+            console: Uart::default().default_ports(),
+            printer: (0x02, 0x03),
+            aux: (0x04, 0x05),
+        }
+    }
+}
+
+/// Serial chip behind the devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Uart {
+    #[default]
+    Simple,  // Already set up; receive ready in status bit 0, never busy (RetroShield)
+    Acia,    // Motorola 6850
+    Sio,     // Z80 SIO, channel A
+    I8251,   // Intel 8251
+}
+
+impl Uart {
+    /// Usual (data, status/control) ports of the chip
+    pub fn default_ports(self) -> (u8, u8) {
+        match self {
+            Uart::Simple | Uart::I8251 => (0x00, 0x01),
+            Uart::Acia | Uart::Sio => (0x81, 0x80),  // RC2014 layout
+        }
+    }
+
+    // Status bit set when a received character is waiting
+    fn rx_ready(self) -> u8 {
+        match self {
+            Uart::I8251 => 0x02,
+            _ => 0x01,
+        }
+    }
+
+    // Status bit set when the transmitter can take a character, if it has to be checked
+    fn tx_ready(self) -> Option<u8> {
+        match self {
+            Uart::Simple => None,
+            Uart::Acia => Some(0x02),
+            Uart::Sio => Some(0x04),
+            Uart::I8251 => Some(0x01),
+        }
+    }
+
+    // Bytes written to the control port to set the chip up for 8N1 at the given clock divide
+    fn init_sequence(self, clock_divide: u8) -> Vec<u8> {
+        match self {
+            Uart::Simple => Vec::new(),
+            Uart::Acia => {
+                let divide = match clock_divide { 1 => 0x00, 16 => 0x01, _ => 0x02 };
+                vec![
+                    0x03,           // Master reset
+                    0x14 | divide,  // 8N1, RTS low, no interrupts
+                ]
+            }
+            Uart::Sio => {
+                let clock = match clock_divide { 1 => 0x00, 16 => 0x40, _ => 0xC0 };
+                vec![
+                    0x18,                 // Channel reset
+                    0x04, clock | 0x04,   // WR4: clock mode, 1 stop bit, no parity
+                    0x01, 0x00,           // WR1: no interrupts
+                    0x03, 0xC1,           // WR3: receive 8 bits, enabled
+                    0x05, 0xEA,           // WR5: DTR, transmit 8 bits, enabled, RTS
+                ]
+            }
+            Uart::I8251 => {
+                let factor = match clock_divide { 1 => 0x01, 16 => 0x02, _ => 0x03 };
+                vec![
+                    0x00, 0x00, 0x00,  // Get to the command register whatever state it is in
+                    0x40,              // Internal reset
+                    0x4C | factor,     // Mode: async, 8 bits, no parity, 1 stop bit
+                    0x37,              // Command: RTS, error reset, receive and transmit enabled, DTR
+                ]
+            }
+        }
+    }
+}
+
+/// Where console I/O goes: the UART at the console ports, or the system the program
+/// runs under. The system consoles ignore device selection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsoleBackend {
+    #[default]
+    Uart,
+    Cpm,  // BDOS calls
+    Zx,   // ZX Spectrum ROM: RST 10h to the upper screen, keys from LAST-K
+    Msx,  // MSX BIOS CHPUT and CHGET
+}
+
+/// How Crc16 works out the CRC: a bit at a time, or a byte at a time from a 512-byte
+/// table in the runtime, several times faster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crc {
+    Bitwise,
+    Table,
+}
+
+/// CRC-16 polynomial x^16 + x^12 + x^5 + 1, as in XMODEM-CRC and CCITT
+const CRC16_POLY: u16 = 0x1021;
+
+// Shift eight bits out of a CRC
+fn crc16_step(mut crc: u16) -> u16 {
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 { crc << 1 ^ CRC16_POLY } else { crc << 1 };
+    }
+    crc
+}
+
+/// Key that ends a line of input; the other of CR and LF is ignored, so terminals
+/// sending CR LF pairs read one line, not a line and an empty one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnd {
+    #[default]
+    Cr,
+    Lf,
+}
+
+/// Character set a program's text is written in, translated through a 256-byte table
+/// in the runtime as out_char sends it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Charset {
+    Atascii,                // Atari 8-bit, to ASCII
+    Petscii,                // Commodore, upper and lower case, to ASCII
+    Table(Box<[u8; 256]>),  // The byte to send for each code
+}
+
+impl Charset {
+    /// The byte sent for each code. CR and LF pass through every built-in table, as
+    /// the runtime ends lines with them, and codes with no ASCII counterpart become '?'
+    pub fn table(&self) -> [u8; 256] {
+        match self {
+            Charset::Atascii => std::array::from_fn(|code| atascii(code as u8)),
+            Charset::Petscii => std::array::from_fn(|code| petscii(code as u8)),
+            Charset::Table(table) => **table,
+        }
+    }
+}
+
+fn atascii(code: u8) -> u8 {
+    match code {
+        0x0A | 0x0D => code,
+        0x20..=0x5F | 0x61..=0x7A | 0x7C => code,
+        0x7D => 0x0C,         // Clear screen: form feed
+        0x7E | 0xFE => 0x08,  // Delete back, delete character: backspace
+        0x7F => 0x09,         // Tab
+        0x9B => 0x0A,         // EOL
+        0xFD => 0x07,         // Bell
+        0x9C..=0x9F | 0xFF => b'?',  // Line and tab editing
+        0x80..=0xFF if !matches!(code & 0x7F, 0x0A | 0x0D | 0x7D..=0x7F) => atascii(code & 0x7F),  // Inverse video
+        _ => b'?',            // Graphics and cursor movement
+    }
+}
+
+fn petscii(code: u8) -> u8 {
+    match code {
+        0x07 | 0x0A | 0x0D => code,
+        0x14 => 0x08,  // Delete: backspace
+        0x8D => 0x0D,  // Shifted return
+        0x93 => 0x0C,  // Clear screen: form feed
+        0x20..=0x40 | 0x5B | 0x5D => code,
+        0x41..=0x5A => code + 0x20,  // Lower case
+        0x61..=0x7A | 0xC1..=0xDA => (code & 0x1F) | 0x40,  // Upper case
+        0x5E => b'^',  // Up arrow
+        0x5F => b'_',  // Left arrow
+        0xA0 => b' ',  // Shifted space
+        _ => b'?',
+    }
+}
+
+/// What to include in the runtime library and how it talks to the hardware
+#[derive(Debug, Clone)]
+pub struct RuntimeOptions {
+    pub console: ConsoleBackend,
+    pub ram_start: u16,    // First RAM address for the runtime's and the program's variables
+    pub devices: DevicePorts,
+    pub uart: Uart,
+    pub clock_divide: u8,  // UART clock divide: 1, 16 or 64
+    pub xmodem: bool,      // Include XRecv and XSend
+    pub crc: Option<Crc>,  // Include Crc16 and CheckSum, computing the CRC this way
+    pub eval_stack: u16,   // Bytes of RAM for expression temporaries, 0 to keep them on the hardware stack
+    pub tx_buffer: u8,     // Console output queue size, a power of two; 0 sends each character straight away
+    pub echo: bool,        // Echo line input and its editing back to the terminal
+    pub line_end: LineEnd, // Key that ends a line of input
+    pub charset: Option<Charset>,  // Translate output from this character set
+    pub rst_calls: bool,   // Reach the busiest routines through RST vectors (boot ROMs only)
+    pub cartridge: bool,   // MSX ROM cartridge: map its second 16KB in at 0x8000 at startup
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        RuntimeOptions {
+            console: ConsoleBackend::Uart,
+            ram_start: RAM_START,
+            devices: DevicePorts::default(),
+            uart: Uart::default(),
+            clock_divide: 64,
+            xmodem: false,
+            crc: None,
+            eval_stack: 0,
+            tx_buffer: 0,
+            echo: true,
+            line_end: LineEnd::Cr,
+            charset: None,
+            rst_calls: false,
+            cartridge: false,
+        }
+    }
+}
+
+impl RuntimeOptions {
+    /// The settings of --target test, written out rather than taken from the defaults,
+    /// so a runtime built with them stays the same when a default changes
+    pub fn pinned() -> Self {
+        RuntimeOptions {
+            console: ConsoleBackend::Uart,
+            ram_start: 0x2000,
+            devices: DevicePorts { console: (0x00, 0x01), printer: (0x02, 0x03), aux: (0x04, 0x05) },
+            uart: Uart::Simple,
+            clock_divide: 64,
+            xmodem: false,
+            crc: None,
+            eval_stack: 0,
+            tx_buffer: 0,
+            echo: true,
+            line_end: LineEnd::Cr,
+            charset: None,
+            rst_calls: false,
+            cartridge: false,
+        }
+    }
+}
+
+/// Generate the runtime library code with the default options
 /// Returns (code bytes, symbol table with addresses)
 pub fn generate_runtime(base_address: u16) -> (Vec<u8>, RuntimeSymbols) {
-    let mut code = Vec::new();
+    generate_runtime_with_options(base_address, &RuntimeOptions::default())
+}
+
+/// Address of the runtime in a boot ROM, after the restart and NMI vectors
+pub const BOOT_RUNTIME_START: u16 = 0x0068;
+
+/// Routines reached through RST vectors with rst_calls, busiest first
+const RST_ROUTINES: [(&str, u8); 4] = [
+    ("PutD", 0x08),
+    ("PrintB", 0x10),
+    ("Print", 0x18),
+    ("PrintE", 0x20),
+];
+
+/// Built-ins that are unsafe to call from interrupt code while main code may be inside
+/// them or depend on the state they change: the XMODEM routines keep their state and
+/// block buffer in fixed RAM, and the device and printer variants switch the device
+/// every other I/O routine uses. The rest work in registers and on the stack only.
+const NON_REENTRANT: [&str; 11] = [
+    "XRecv", "XSend",
+    "PrintD", "PrintBD", "PrintCD", "PrintED", "InputSD",
+    "LPrint", "LPrintB", "LPrintC", "LPrintE",
+];
+
+/// Whether a built-in (by canonical name) can be called from interrupt code
+pub fn is_reentrant(builtin: &str) -> bool {
+    !NON_REENTRANT.contains(&builtin)
+}
+
+/// Reset stub and vector area at 0x0000 for a boot ROM, BOOT_RUNTIME_START bytes long:
+/// disable interrupts, set SP and jump to start. Restarts the runtime uses jump to
+/// their routines; other RST 08h-30h return straight away, RST 38h (mode 1
+/// interrupts) re-enables interrupts and returns, and the NMI at 66h returns with
+/// RETN, so a stray restart or interrupt does no harm
+pub fn generate_boot_vectors(stack_top: u16, start: u16, symbols: &RuntimeSymbols) -> Vec<u8> {
+    let mut code = vec![0xFF; BOOT_RUNTIME_START as usize];  // Unused space, as in an erased ROM
+    let stub = [
+        0xF3,  // DI
+        0x31, (stack_top & 0xFF) as u8, (stack_top >> 8) as u8,  // LD SP, stack_top
+        0xC3, (start & 0xFF) as u8, (start >> 8) as u8,          // JP start
+    ];
+    code[..stub.len()].copy_from_slice(&stub);
+    for rst in (0x08..0x38).step_by(8) {
+        code[rst] = 0xC9;  // RET
+    }
+    for &(vector, routine) in &symbols.rst_vectors {
+        let at = vector as usize;
+        code[at..at + 3].copy_from_slice(&[0xC3, (routine & 0xFF) as u8, (routine >> 8) as u8]);  // JP routine
+    }
+    code[0x38..0x3B].copy_from_slice(&[0xFB, 0xED, 0x4D]);  // EI; RETI
+    code[0x66..0x68].copy_from_slice(&[0xED, 0x45]);        // RETN
+    code
+}
+
+// out_char and in_char through the console routines of the system the program runs under
+fn system_console(a: &mut Asm, console: ConsoleBackend, symbols: &mut RuntimeSymbols) {
+    match console {
+        ConsoleBackend::Cpm => {
+            const BDOS: u16 = 0x0005;
+            a.push(BC);
+            a.push(DE);
+            a.push(HL);
+            a.push(AF);
+            a.ld(E, A);
+            a.ld_n(C, 2);  // Console output
+            a.call(BDOS);
+            a.pop(AF);
+            a.pop(HL);
+            a.pop(DE);
+            a.pop(BC);
+            a.ret();
+
+            symbols.in_char = a.addr();
+            a.push(BC);
+            a.push(DE);
+            a.push(HL);
+            let wait = a.here();
+            a.ld_n(E, 0xFF);
+            a.ld_n(C, 6);  // Direct console input: a key without echo, or 0
+            a.call(BDOS);
+            a.alu(Or, A);
+            a.jr_if(Cond::Z, wait);
+            a.pop(HL);
+            a.pop(DE);
+            a.pop(BC);
+            a.ret();
+        }
+        ConsoleBackend::Zx => {
+            const LAST_K: u16 = 0x5C08;  // Last key pressed
+            const FLAGS: u16 = 0x5C3B;   // Bit 5 set when LAST_K holds a new key
+            a.alu_n(Cp, 0x0A);
+            a.ret_if(Cond::Z);  // CR alone starts a new line, LF would print as '?'
+            a.push(BC);
+            a.push(DE);
+            a.push(HL);
+            a.push(AF);
+            a.rst(0x10);  // Print A on the stream uart_init opened
+            a.pop(AF);
+            a.pop(HL);
+            a.pop(DE);
+            a.pop(BC);
+            a.ret();
+
+            // The ROM's interrupt routine scans the keyboard
+            symbols.in_char = a.addr();
+            let done = a.label();
+            a.push(HL);
+            a.ld_nn(HL, FLAGS);
+            let wait = a.here();
+            a.bit(5, M);
+            a.jr_if(Cond::Z, wait);
+            a.res(5, M);
+            a.ld_a_mem(LAST_K);
+            a.pop(HL);
+            a.alu_n(Cp, 0x0C);  // DELETE
+            a.jr_if(Cond::NZ, done);
+            a.ld_n(A, 0x08);  // Backspace, as elsewhere
+            a.bind(done);
+            a.ret();
+        }
+        ConsoleBackend::Msx => {
+            a.jp(0x00A2);  // CHPUT, which changes no registers
+            symbols.in_char = a.addr();
+            a.jp(0x009F);  // CHGET
+        }
+        ConsoleBackend::Uart => unreachable!("the UART console is generated inline"),
+    }
+}
+
+// HL = buf + A
+fn point_hl_into(a: &mut Asm, buf: impl Into<Target>) {
+    let no_carry = a.label();
+    a.ld_nn(HL, buf);
+    a.alu(Add, L);
+    a.ld(L, A);
+    a.jr_if(Cond::NC, no_carry);
+    a.inc(H);
+    a.bind(no_carry);
+}
+
+/// Generate the runtime library code for the given options
+pub fn generate_runtime_with_options(base_address: u16, options: &RuntimeOptions) -> (Vec<u8>, RuntimeSymbols) {
+    let devices = &options.devices;
+    let mut a = Asm::new(base_address);
     let mut symbols = RuntimeSymbols::new();
 
-    let mut addr = base_address;
+    // (data, status) ports per device number; unknown devices use the console
+    let device_ports: [(u8, u8); 3] = [
+        devices.console,  // 0: console
+        devices.printer,  // 1: printer
+        devices.aux,      // 2: aux serial
+    ];
+    let rx_ready = options.uart.rx_ready();
+
+    // Runtime variables: ports of the selected device
+    let dev_data = options.ram_start;
+    let dev_status = options.ram_start + 1;
+    symbols.ram_end = options.ram_start + 2;
+
+    // Console output queue, when the UART has a transmitter status to drain it by: head
+    // is where the next character goes and tail the next one to send, so the queue is
+    // empty when they are equal and holds at most tx_size - 1 characters
+    let uart_console = options.console == ConsoleBackend::Uart;
+    let tx_size = if uart_console && options.uart.tx_ready().is_some() { options.tx_buffer } else { 0 };
+    assert!(tx_size == 0 || (tx_size >= 2 && tx_size.is_power_of_two()), "bad console queue size {}", tx_size);
+    let tx_head = symbols.ram_end;
+    let tx_tail = symbols.ram_end + 1;
+    let tx_buf = symbols.ram_end + 2;
+    if tx_size != 0 {
+        symbols.ram_end = tx_buf + tx_size as u16;
+    }
+    let tx_drain = a.label();
+    let tx_flush = a.label();
+    let mut put_d_inline = None;
+    let charset_table = a.label();
+
+    // ============================================================
+    // out_char - Output a character to the selected device
+    // Input: A = character (preserved)
+    // ============================================================
+    symbols.out_char = a.addr();
+    if options.charset.is_some() {
+        // Send the character's entry in the table instead
+        let send = a.label();
+        a.push(HL);
+        a.push(AF);
+        point_hl_into(&mut a, charset_table);
+        a.ld(A, M);
+        a.call(send);
+        a.pop(AF);
+        a.pop(HL);
+        a.ret();
+        a.bind(send);
+    }
+    if !uart_console {
+        system_console(&mut a, options.console, &mut symbols);
+    } else {
+        a.push(BC);
+        a.ld(B, A);
+        if tx_size != 0 {
+            // Console output goes through the queue
+            let direct = a.label();
+            a.ld_a_mem(dev_data);
+            a.alu_n(Cp, devices.console.0);
+            a.jr_if(Cond::NZ, direct);
+            a.push(HL);
+            let wait_room = a.here();
+            a.call(tx_drain);
+            a.ld_a_mem(tx_head);
+            a.inc(A);
+            a.alu_n(And, tx_size - 1);
+            a.ld(C, A);  // Head after this character
+            a.ld_a_mem(tx_tail);
+            a.alu(Cp, C);
+            a.jr_if(Cond::Z, wait_room);  // Full
+            a.ld_a_mem(tx_head);
+            point_hl_into(&mut a, tx_buf);
+            a.ld(M, B);
+            a.ld(A, C);
+            a.ld_mem_a(tx_head);
+            a.call(tx_drain);  // Start sending if the UART is free
+            a.pop(HL);
+            a.ld(A, B);
+            a.pop(BC);
+            a.ret();
+            a.bind(direct);
+        }
+        if let Some(tx_ready) = options.uart.tx_ready() {
+            let out_wait = a.here();
+            a.ld_a_mem(dev_status);
+            a.ld(C, A);
+            a.in_c(A);
+            a.alu_n(And, tx_ready);
+            a.jr_if(Cond::Z, out_wait);
+        }
+        a.ld_a_mem(dev_data);
+        a.ld(C, A);
+        a.out_c(B);
+        a.ld(A, B);
+        a.pop(BC);
+        if tx_size == 0 && options.charset.is_none() {
+            // Without the queue or a table it neither calls nor jumps out, so it can be copied
+            put_d_inline = Some(symbols.out_char..a.addr());
+        }
+        a.ret();
+
+        // ============================================================
+        // in_char - Get a character from the selected device (blocking)
+        // Output: A = character read
+        // ============================================================
+        symbols.in_char = a.addr();
+        if tx_size != 0 {
+            a.call(tx_flush);  // Show any prompt before waiting
+        }
+        a.push(BC);
+        let in_wait = a.here();
+        a.ld_a_mem(dev_status);
+        a.ld(C, A);
+        a.in_c(A);
+        a.alu_n(And, rx_ready);
+        a.jr_if(Cond::Z, in_wait);
+        a.ld_a_mem(dev_data);
+        a.ld(C, A);
+        a.in_c(A);
+        a.pop(BC);
+        a.ret();
+    }
+
+    if let Some(tx_ready) = options.uart.tx_ready().filter(|_| tx_size != 0) {
+        let (data, status) = devices.console;
+
+        // ------------------------------------------------------------
+        // tx_drain - Send queued console output while the UART can take it
+        // Changes A only
+        // ------------------------------------------------------------
+        let drain_done = a.label();
+        a.bind(tx_drain);
+        a.push(HL);
+        let drain_loop = a.here();
+        a.in_n(status);
+        a.alu_n(And, tx_ready);
+        a.jr_if(Cond::Z, drain_done);  // Busy
+        a.ld_a_mem(tx_tail);
+        a.ld_nn(HL, tx_head);
+        a.alu(Cp, M);
+        a.jr_if(Cond::Z, drain_done);  // Empty
+        point_hl_into(&mut a, tx_buf);
+        a.ld(A, M);
+        a.out_n(data);
+        a.ld_a_mem(tx_tail);
+        a.inc(A);
+        a.alu_n(And, tx_size - 1);
+        a.ld_mem_a(tx_tail);
+        a.jr(drain_loop);
+        a.bind(drain_done);
+        a.pop(HL);
+        a.ret();
 
-    // Console I/O port addresses (RetroShield compatible)
-    const CONSOLE_DATA: u8 = 0x00;
-    const CONSOLE_STATUS: u8 = 0x01;
+        // ------------------------------------------------------------
+        // tx_flush - Wait until all queued console output is sent
+        // Changes A only
+        // ------------------------------------------------------------
+        symbols.tx_flush = a.addr();
+        a.bind(tx_flush);
+        a.push(HL);
+        let flush_loop = a.here();
+        a.call(tx_drain);
+        a.ld_a_mem(tx_tail);
+        a.ld_nn(HL, tx_head);
+        a.alu(Cp, M);
+        a.jr_if(Cond::NZ, flush_loop);
+        a.pop(HL);
+        a.ret();
+    }
+
+    // ============================================================
+    // set_device - Select the device used by out_char and in_char
+    // Input: A = device number (all registers preserved)
+    // ============================================================
+    symbols.set_device = a.addr();
+    let known = a.label();
+    let no_carry = a.label();
+    let port_table = a.label();
+    a.push(HL);
+    a.push(AF);
+    a.alu_n(Cp, device_ports.len() as u8);
+    a.jr_if(Cond::C, known);
+    a.alu(Xor, A);  // Unknown device: console
+    a.bind(known);
+    a.alu(Add, A);  // Two ports per device
+    a.ld_nn(HL, port_table);
+    a.alu(Add, L);
+    a.ld(L, A);
+    a.jr_if(Cond::NC, no_carry);
+    a.inc(H);
+    a.bind(no_carry);
+    a.ld(A, M);
+    a.ld_mem_a(dev_data);
+    a.inc16(HL);
+    a.ld(A, M);
+    a.ld_mem_a(dev_status);
+    a.pop(AF);
+    a.pop(HL);
+    a.ret();
+
+    a.bind(port_table);
+    let table_start = a.addr();
+    for (data, status) in device_ports {
+        a.bytes(&[data, status]);
+    }
+    symbols.tables.push(table_start..a.addr());
+
+    // ============================================================
+    // reset_device - Select the console again (all registers preserved)
+    // ============================================================
+    symbols.reset_device = a.addr();
+    a.push(AF);
+    a.ld_n(A, DEVICE_CONSOLE);
+    a.call(symbols.set_device);
+    a.pop(AF);
+    a.ret();
+
+    // ============================================================
+    // uart_init - Set up the console UART, called once at startup
+    // ============================================================
+    let init_sequence = options.uart.init_sequence(options.clock_divide);
+    if options.console == ConsoleBackend::Zx {
+        symbols.uart_init = a.addr();
+        a.ld_n(A, 2);  // Upper screen
+        a.jp(0x1601);  // CHAN-OPEN
+    } else if uart_console && !init_sequence.is_empty() {
+        symbols.uart_init = a.addr();
+        for value in init_sequence {
+            a.ld_n(A, value);
+            a.out_n(devices.console.1);  // Control port
+        }
+        a.ret();
+    }
+
+    // ============================================================
+    // slot_init - Map the cartridge's slot in at 0x8000-0xBFFF as well as 0x4000-0x7FFF,
+    // where the BIOS put it, called before anything else at startup
+    // ============================================================
+    if options.cartridge {
+        const ENASLT: u16 = 0x0024;  // Select slot A for the page at H
+        const RSLREG: u16 = 0x0138;  // Read the primary slot register
+        const EXPTBL: u16 = 0xFCC1;  // Per primary slot: bit 7 set if it is expanded
+        const SLTTBL: u16 = 0xFCC5;  // Per primary slot: its secondary slot register
+        symbols.slot_init = a.addr();
+        a.call(RSLREG);
+        a.bytes(&[0x0F, 0x0F]);  // RRCA, RRCA: primary slot of 0x4000 to bits 0-1
+        a.alu_n(And, 0x03);
+        a.ld(C, A);
+        a.ld_n(B, 0);
+        a.ld_nn(HL, EXPTBL);
+        a.add_hl(BC);
+        a.ld(A, M);
+        a.alu_n(And, 0x80);
+        a.alu(Or, C);
+        a.ld(C, A);
+        a.ld_nn(HL, SLTTBL);
+        a.add_hl(BC);  // B is 0, so only the primary slot in C counts
+        a.ld(A, M);
+        a.alu_n(And, 0x0C);  // Secondary slot of 0x4000
+        a.alu(Or, C);
+        a.ld_n(H, 0x80);
+        a.call(ENASLT);
+        a.bytes(&[0xFB]);  // EI: ENASLT leaves interrupts off, and CHGET needs them
+        a.ret();
+    }
+
+    // The console routines print through PutD, and InputS reads through GetD, so a
+    // program's own PutD or GetD takes their place everywhere (see RuntimeSymbols::weak)
+    let put_d = a.label();
+    let get_d = a.label();
 
     // ============================================================
     // PrintB - Print byte as decimal number (0-255)
     // Input: A = byte to print
     // ============================================================
-    symbols.print_b = addr;
-    // Save the value
-    code.push(0xF5);  // PUSH AF
-    addr += 1;
-
-    // Convert to decimal and print
-    // Divide by 100
-    code.push(0x06); code.push(100);  // LD B, 100
-    addr += 2;
-    code.push(0xCD); // CALL div8
-    let div8_call1 = code.len();
-    code.push(0x00); code.push(0x00);  // placeholder
-    addr += 3;
-
-    // If quotient > 0, print it
-    code.push(0xB7);  // OR A
-    addr += 1;
-    code.push(0x28); code.push(0x06);  // JR Z, skip_hundreds (+6 bytes to skip)
-    addr += 2;
-    code.push(0xC6); code.push(0x30);  // ADD A, '0'
-    addr += 2;
-    code.push(0xD3); code.push(CONSOLE_DATA);  // OUT (CONSOLE_DATA), A
-    addr += 2;
-    code.push(0x3E); code.push(0x01);  // LD A, 1 (flag: printed something)
-    addr += 2;
-    // skip_hundreds:
-
-    // Get remainder, divide by 10
-    code.push(0x79);  // LD A, C (remainder)
-    addr += 1;
-    code.push(0x06); code.push(10);  // LD B, 10
-    addr += 2;
-    code.push(0xCD);  // CALL div8
-    let div8_call2 = code.len();
-    code.push(0x00); code.push(0x00);  // placeholder
-    addr += 3;
-
-    // Print tens digit (always if we printed hundreds, or if > 0)
-    code.push(0xC6); code.push(0x30);  // ADD A, '0'
-    addr += 2;
-    code.push(0xD3); code.push(CONSOLE_DATA);  // OUT (CONSOLE_DATA), A
-    addr += 2;
-
-    // Print ones digit
-    code.push(0x79);  // LD A, C (remainder)
-    addr += 1;
-    code.push(0xC6); code.push(0x30);  // ADD A, '0'
-    addr += 2;
-    code.push(0xD3); code.push(CONSOLE_DATA);  // OUT (CONSOLE_DATA), A
-    addr += 2;
-
-    code.push(0xF1);  // POP AF
-    addr += 1;
-    code.push(0xC9);  // RET
-    addr += 1;
+    symbols.print_b = a.addr();
+    let div8 = a.label();
+    let skip_hundreds = a.label();
+    let ones = a.label();
+    a.push(AF);
+
+    // Hundreds digit, if there is one; E stays nonzero once a digit is printed
+    a.ld_n(B, 100);
+    a.call(div8);
+    a.ld(E, A);
+    a.alu(Or, A);
+    a.jr_if(Cond::Z, skip_hundreds);
+    a.alu_n(Add, b'0');
+    a.call(put_d);
+    a.bind(skip_hundreds);
+
+    // Tens digit, if it is not a leading zero
+    a.ld(A, C);
+    a.ld_n(B, 10);
+    a.call(div8);
+    a.ld(B, A);
+    a.alu(Or, E);
+    a.jr_if(Cond::Z, ones);
+    a.ld(A, B);
+    a.alu_n(Add, b'0');
+    a.call(put_d);
+
+    // Ones digit, always
+    a.bind(ones);
+    a.ld(A, C);
+    a.alu_n(Add, b'0');
+    a.call(put_d);
+
+    a.pop(AF);
+    a.ret();
 
     // ============================================================
     // PrintC - Print CARD (16-bit) as decimal number
     // Input: HL = value to print
     // ============================================================
-    symbols.print_c = addr;
-    code.push(0xE5);  // PUSH HL
-    addr += 1;
-    code.push(0xD5);  // PUSH DE
-    addr += 1;
-    code.push(0xC5);  // PUSH BC
-    addr += 1;
-
-    // We'll use a simple repeated subtraction approach
-    // For each power of 10 (10000, 1000, 100, 10, 1)
-    // Note: This is a simplified version
-
-    // Print HL as 5-digit decimal (with leading zero suppression)
-    // For now, just print low byte
-    code.push(0x7D);  // LD A, L
-    addr += 1;
-    code.push(0xCD);  // CALL PrintB
-    code.push((symbols.print_b & 0xFF) as u8);
-    code.push((symbols.print_b >> 8) as u8);
-    addr += 3;
-
-    code.push(0xC1);  // POP BC
-    addr += 1;
-    code.push(0xD1);  // POP DE
-    addr += 1;
-    code.push(0xE1);  // POP HL
-    addr += 1;
-    code.push(0xC9);  // RET
-    addr += 1;
+    symbols.print_c = a.addr();
+    let div10 = a.label();
+    a.push(HL);
+    a.push(BC);
+    a.ld_n(B, 0);  // Digits on the stack, last first
+    symbols.push_loops.push((a.addr(), 8));  // Five digits at most
+    let printc_divide = a.here();
+    a.call(div10);
+    a.alu_n(Add, b'0');
+    a.push(AF);
+    a.inc(B);
+    a.ld(A, H);
+    a.alu(Or, L);
+    a.jr_if(Cond::NZ, printc_divide);
+    let printc_print = a.here();
+    a.pop(AF);
+    a.call(put_d);
+    a.djnz(printc_print);
+    a.pop(BC);
+    a.pop(HL);
+    a.ret();
 
     // ============================================================
     // PrintE - Print end of line (CR+LF)
     // ============================================================
-    symbols.print_e = addr;
-    code.push(0x3E); code.push(0x0D);  // LD A, 13 (CR)
-    addr += 2;
-    code.push(0xD3); code.push(CONSOLE_DATA);  // OUT (CONSOLE_DATA), A
-    addr += 2;
-    code.push(0x3E); code.push(0x0A);  // LD A, 10 (LF)
-    addr += 2;
-    code.push(0xD3); code.push(CONSOLE_DATA);  // OUT (CONSOLE_DATA), A
-    addr += 2;
-    code.push(0xC9);  // RET
-    addr += 1;
+    symbols.print_e = a.addr();
+    a.ld_n(A, 0x0D);  // CR
+    a.call(put_d);
+    a.ld_n(A, 0x0A);  // LF
+    a.call(put_d);
+    a.ret();
+
+    // ============================================================
+    // PrintBE / PrintCE - Print a number followed by end of line
+    // Input: A = byte (PrintBE) or HL = card (PrintCE)
+    // ============================================================
+    symbols.print_be = a.addr();
+    a.call(symbols.print_b);
+    a.jp(symbols.print_e);
+
+    symbols.print_ce = a.addr();
+    a.call(symbols.print_c);
+    a.jp(symbols.print_e);
 
     // ============================================================
     // Print - Print a null-terminated string
     // Input: HL = pointer to string
     // ============================================================
-    symbols.print = addr;
-    code.push(0x7E);  // print_loop: LD A, (HL)
-    addr += 1;
-    code.push(0xB7);  // OR A
-    addr += 1;
-    code.push(0xC8);  // RET Z (if null terminator)
-    addr += 1;
-    code.push(0xD3); code.push(CONSOLE_DATA);  // OUT (CONSOLE_DATA), A
-    addr += 2;
-    code.push(0x23);  // INC HL
-    addr += 1;
-    code.push(0x18); code.push(0xF7);  // JR print_loop (-9)
-    addr += 2;
-
-    // ============================================================
-    // GetD - Get a character from console (blocking)
+    symbols.print = a.addr();
+    let print_loop = a.here();
+    a.ld(A, M);
+    a.alu(Or, A);
+    a.ret_if(Cond::Z);  // Null terminator
+    a.call(put_d);
+    a.inc16(HL);
+    a.jr(print_loop);
+
+    // ============================================================
+    // GetD - Get a character from the selected device (blocking)
     // Output: A = character read
     // ============================================================
-    symbols.get_d = addr;
-    code.push(0xDB); code.push(CONSOLE_STATUS);  // IN A, (CONSOLE_STATUS)
-    addr += 2;
-    code.push(0xE6); code.push(0x01);  // AND 1 (check RX ready)
-    addr += 2;
-    code.push(0x28); code.push(0xFA);  // JR Z, GetD (loop until ready)
-    addr += 2;
-    code.push(0xDB); code.push(CONSOLE_DATA);  // IN A, (CONSOLE_DATA)
-    addr += 2;
-    code.push(0xC9);  // RET
-    addr += 1;
+    symbols.get_d = a.addr();
+    a.bind(get_d);
+    a.jp(symbols.in_char);
 
     // ============================================================
-    // PutD - Output a character to console
+    // PutD - Output a character to the selected device
     // Input: A = character to output
     // ============================================================
-    symbols.put_d = addr;
-    code.push(0xD3); code.push(CONSOLE_DATA);  // OUT (CONSOLE_DATA), A
-    addr += 2;
-    code.push(0xC9);  // RET
-    addr += 1;
+    symbols.put_d = a.addr();
+    a.bind(put_d);
+    a.jp(symbols.out_char);
+
+    // ============================================================
+    // Evaluation stack (optional) - expression temporaries in RAM rather than on the
+    // hardware stack, which then only holds return addresses and a register or two.
+    // It grows upwards; a push claims its slot before filling it, and a pop empties
+    // its slot before giving it up, so interrupt code can use the stack too.
+    // ============================================================
+    if options.eval_stack != 0 {
+        assert!(options.eval_stack.is_multiple_of(2), "odd evaluation stack size {}", options.eval_stack);
+        let eval_sp = symbols.ram_end;        // Address of the next free slot
+        let eval_base = symbols.ram_end + 2;
+        symbols.ram_end = eval_base + options.eval_stack;
+
+        // eval_init - Empty the stack, called once at startup (changes HL)
+        symbols.eval_init = a.addr();
+        a.ld_nn(HL, eval_base);
+        a.ld_mem_rr(eval_sp, HL);
+        a.ret();
+
+        // eval_push - Push HL (all registers preserved)
+        symbols.eval_push = a.addr();
+        a.push(DE);
+        a.ex_de_hl();
+        a.ld_rr_mem(HL, eval_sp);
+        a.inc16(HL);
+        a.inc16(HL);
+        a.ld_mem_rr(eval_sp, HL);
+        a.dec16(HL);
+        a.ld(M, D);
+        a.dec16(HL);
+        a.ld(M, E);
+        a.ex_de_hl();
+        a.pop(DE);
+        a.ret();
+
+        // eval_pop_hl, eval_pop_de, eval_pop_bc - Pop into a register pair (the others
+        // preserved)
+        for (routine, rr) in [(&mut symbols.eval_pop_hl, HL), (&mut symbols.eval_pop_de, DE), (&mut symbols.eval_pop_bc, BC)] {
+            *routine = a.addr();
+            let saved = if rr == HL { DE } else { HL };
+            a.push(saved);
+            a.ld_rr_mem(HL, eval_sp);
+            a.dec16(HL);
+            a.ld(D, M);
+            a.dec16(HL);
+            a.ld(E, M);
+            a.ld_mem_rr(eval_sp, HL);
+            match rr {
+                HL => a.ex_de_hl(),
+                BC => {
+                    a.ld(B, D);
+                    a.ld(C, E);
+                }
+                _ => {}
+            }
+            a.pop(saved);
+            a.ret();
+        }
+    }
 
     // ============================================================
     // Multiply - 16-bit multiply (HL = HL * DE)
     // Input: HL, DE = 16-bit values
-    // Output: HL = result (low 16 bits)
-    // ============================================================
-    symbols.multiply = addr;
-    code.push(0xC5);  // PUSH BC
-    addr += 1;
-    code.push(0x44);  // LD B, H
-    addr += 1;
-    code.push(0x4D);  // LD C, L
-    addr += 1;
-    code.push(0x21); code.push(0x00); code.push(0x00);  // LD HL, 0
-    addr += 3;
-    code.push(0x06); code.push(16);  // LD B, 16 (bit counter)
-    addr += 2;
-    // mult_loop:
-    let mult_loop = addr;
-    code.push(0x29);  // ADD HL, HL (shift result left)
-    addr += 1;
-    code.push(0xCB); code.push(0x23);  // SLA E
-    addr += 2;
-    code.push(0xCB); code.push(0x12);  // RL D (shift DE left, carry = high bit)
-    addr += 2;
-    code.push(0x30); code.push(0x01);  // JR NC, skip_add
-    addr += 2;
-    code.push(0x09);  // ADD HL, BC
-    addr += 1;
-    // skip_add:
-    code.push(0x10);  // DJNZ mult_loop
-    let offset = (mult_loop as i32 - addr as i32 - 1) as i8;
-    code.push(offset as u8);
-    addr += 2;
-    code.push(0xC1);  // POP BC
-    addr += 1;
-    code.push(0xC9);  // RET
-    addr += 1;
+    // Output: HL = result (low 16 bits); A and DE are changed
+    // ============================================================
+    symbols.multiply = a.addr();
+    let skip_add = a.label();
+    a.push(BC);
+    a.ld(B, H);
+    a.ld(C, L);
+    a.ld_nn(HL, 0);
+    a.ld_n(A, 16);  // Bit counter; B holds half the multiplicand
+    let mult_loop = a.here();
+    a.add_hl(HL);  // Shift result left
+    a.sla(E);
+    a.rl(D);  // Shift DE left, carry = high bit
+    a.jr_if(Cond::NC, skip_add);
+    a.add_hl(BC);
+    a.bind(skip_add);
+    a.dec(A);
+    a.jr_if(Cond::NZ, mult_loop);
+    a.pop(BC);
+    a.ret();
 
     // ============================================================
     // div8 - 8-bit division
     // Input: A = dividend, B = divisor
     // Output: A = quotient, C = remainder
     // ============================================================
-    symbols.div8 = addr;
-    // Patch the earlier calls
-    let div8_addr = addr;
-    code[div8_call1] = (div8_addr & 0xFF) as u8;
-    code[div8_call1 + 1] = (div8_addr >> 8) as u8;
-    code[div8_call2] = (div8_addr & 0xFF) as u8;
-    code[div8_call2 + 1] = (div8_addr >> 8) as u8;
+    symbols.div8 = a.addr();
+    a.bind(div8);
+    let div8_done = a.label();
 
     // Correct division algorithm:
     // C = dividend (becomes remainder)
     // D = quotient
-    code.push(0x4F);  // LD C, A (C = dividend)
-    addr += 1;
-    code.push(0x16); code.push(0x00);  // LD D, 0 (quotient = 0)
-    addr += 2;
-    // div8_loop:
-    let div8_loop = addr;
-    code.push(0x79);  // LD A, C (A = current dividend)
-    addr += 1;
-    code.push(0xB8);  // CP B (compare with divisor)
-    addr += 1;
-    code.push(0x38); code.push(0x05);  // JR C, div8_done (if A < B, done)
-    addr += 2;
-    code.push(0x90);  // SUB B (A = A - B)
-    addr += 1;
-    code.push(0x4F);  // LD C, A (update remainder)
-    addr += 1;
-    code.push(0x14);  // INC D (quotient++)
-    addr += 1;
-    code.push(0x18);  // JR div8_loop
-    let offset2 = (div8_loop as i32 - addr as i32 - 1) as i8;
-    code.push(offset2 as u8);
-    addr += 2;
-    // div8_done:
-    code.push(0x7A);  // LD A, D (return quotient in A)
-    addr += 1;
-    code.push(0xC9);  // RET
-    addr += 1;
-
-    symbols.end_address = addr;
+    a.ld(C, A);
+    a.ld_n(D, 0);
+    let div8_loop = a.here();
+    a.ld(A, C);  // A = current dividend
+    a.alu(Cp, B);
+    a.jr_if(Cond::C, div8_done);  // A < B: done
+    a.alu(Sub, B);
+    a.ld(C, A);  // Update remainder
+    a.inc(D);  // Quotient++
+    a.jr(div8_loop);
+    a.bind(div8_done);
+    a.ld(A, D);  // Return quotient in A
+    a.ret();
+
+    // ============================================================
+    // div16 - 16-bit unsigned division, for / and MOD
+    // Input: HL = dividend, DE = divisor
+    // Output: HL = quotient, DE = remainder; A is changed
+    // Dividing by 0 gives a quotient of $FFFF and the dividend as remainder
+    // ============================================================
+    symbols.div16 = a.addr();
+    let div16_carry = a.label();
+    let div16_fits = a.label();
+    let div16_next = a.label();
+    a.push(BC);
+    a.ld(A, H);  // AC = dividend, shifted out at the top as quotient bits come in below
+    a.ld(C, L);
+    a.ld_nn(HL, 0);  // Remainder
+    a.ld_n(B, 16);
+    let div16_loop = a.here();
+    a.sla(C);
+    a.rl(A);
+    a.adc_hl(HL);
+    a.jr_if(Cond::C, div16_carry);  // Past 16 bits: more than any divisor
+    a.sbc_hl(DE);  // Carry is clear
+    a.jr_if(Cond::NC, div16_fits);
+    a.add_hl(DE);
+    a.jr(div16_next);
+    a.bind(div16_carry);
+    a.alu(Or, A);
+    a.sbc_hl(DE);
+    a.bind(div16_fits);
+    a.inc(C);
+    a.bind(div16_next);
+    a.djnz(div16_loop);
+    a.ex_de_hl();
+    a.ld(H, A);
+    a.ld(L, C);
+    a.pop(BC);
+    a.ret();
+
+    // ============================================================
+    // SIndex - Find a character in a null-terminated string
+    // Input: HL = string, C = character
+    // Output: A = index of the first match, or $FF if not found
+    // ============================================================
+    symbols.s_index = a.addr();
+    let sindex_found = a.label();
+    let sindex_none = a.label();
+    a.ld_n(B, 0);  // Index
+    let sindex_loop = a.here();
+    a.ld(A, M);
+    a.alu(Or, A);
+    a.jr_if(Cond::Z, sindex_none);
+    a.alu(Cp, C);
+    a.jr_if(Cond::Z, sindex_found);
+    a.inc16(HL);
+    a.inc(B);
+    a.jr(sindex_loop);
+    a.bind(sindex_found);
+    a.ld(A, B);
+    a.ret();
+    a.bind(sindex_none);
+    a.ld_n(A, 0xFF);
+    a.ret();
+
+    // ============================================================
+    // SSub - Copy a substring into a buffer, null-terminated
+    // Input: HL = source string, DE = destination, B = start, C = length
+    // Copying stops early at the end of the source string
+    // ============================================================
+    symbols.s_sub = a.addr();
+    let ssub_copy = a.label();
+    let ssub_done = a.label();
+    a.ld(A, B);
+    a.alu(Or, A);
+    a.jr_if(Cond::Z, ssub_copy);
+    let ssub_skip = a.here();
+    a.ld(A, M);
+    a.alu(Or, A);
+    a.jr_if(Cond::Z, ssub_done);  // Source ended before start
+    a.inc16(HL);
+    a.djnz(ssub_skip);
+    a.bind(ssub_copy);
+    a.ld(A, C);
+    a.alu(Or, A);
+    a.jr_if(Cond::Z, ssub_done);
+    a.ld(A, M);
+    a.alu(Or, A);
+    a.jr_if(Cond::Z, ssub_done);
+    a.ld_ind_a(DE);
+    a.inc16(HL);
+    a.inc16(DE);
+    a.dec(C);
+    a.jr(ssub_copy);
+    a.bind(ssub_done);
+    a.alu(Xor, A);
+    a.ld_ind_a(DE);  // Terminate destination
+    a.ret();
+
+    // ============================================================
+    // SCompare - Compare two null-terminated strings
+    // Input: HL = first string, DE = second string
+    // Output: A = 0 if they are the same, 1 if the first sorts after the second,
+    //         $FF if it sorts before
+    // ============================================================
+    symbols.s_compare = a.addr();
+    let scompare_differ = a.label();
+    let scompare_loop = a.here();
+    a.ld_a_ind(DE);
+    a.alu(Cp, M);
+    a.jr_if(Cond::NZ, scompare_differ);
+    a.alu(Or, A);
+    a.ret_if(Cond::Z);  // Both ended together
+    a.inc16(HL);
+    a.inc16(DE);
+    a.jr(scompare_loop);
+    a.bind(scompare_differ);
+    a.ld_n(A, 1);
+    a.ret_if(Cond::C);  // The second string's character is lower
+    a.ld_n(A, 0xFF);
+    a.ret();
+
+    // ============================================================
+    // div10 - 16-bit division by 10
+    // Input: HL = dividend
+    // Output: HL = quotient, A = remainder
+    // ============================================================
+    symbols.div10 = a.addr();
+    a.bind(div10);
+    let div10_skip = a.label();
+    a.push(BC);
+    a.ld_n(B, 16);
+    a.alu(Xor, A);
+    let div10_loop = a.here();
+    a.add_hl(HL);  // Next dividend bit into A, a quotient bit of 0 into L
+    a.rl(A);
+    a.alu_n(Cp, 10);
+    a.jr_if(Cond::C, div10_skip);
+    a.alu_n(Sub, 10);
+    a.inc(L);
+    a.bind(div10_skip);
+    a.djnz(div10_loop);
+    a.pop(BC);
+    a.ret();
 
+    // ============================================================
+    // StrC - Write a CARD as decimal into a buffer, null-terminated
+    // Input: HL = value, DE = buffer (at least 6 bytes)
+    // StrB is the same routine: the byte is passed as a CARD
+    // ============================================================
+    symbols.str_c = a.addr();
+    a.push(BC);
+    a.push(DE);
+    a.push(HL);
+    a.ld_n(B, 0);  // Digits on the stack, last first
+    symbols.push_loops.push((a.addr(), 8));
+    let strc_divide = a.here();
+    a.call(symbols.div10);
+    a.alu_n(Add, b'0');
+    a.push(AF);
+    a.inc(B);
+    a.ld(A, H);
+    a.alu(Or, L);
+    a.jr_if(Cond::NZ, strc_divide);
+    let strc_store = a.here();
+    a.pop(AF);
+    a.ld_ind_a(DE);
+    a.inc16(DE);
+    a.djnz(strc_store);
+    a.alu(Xor, A);
+    a.ld_ind_a(DE);
+    a.pop(HL);
+    a.pop(DE);
+    a.pop(BC);
+    a.ret();
+
+    // ============================================================
+    // ValC - Read a decimal number from the start of a string
+    // Input: HL = string
+    // Output: HL = value (modulo 65536), A = its low byte for ValB
+    // Reading stops at the first character that is not a digit
+    // ============================================================
+    symbols.val_c = a.addr();
+    let valc_done = a.label();
+    a.push(BC);
+    a.push(DE);
+    a.ld(D, H);
+    a.ld(E, L);
+    a.ld_nn(HL, 0);
+    let valc_loop = a.here();
+    a.ld_a_ind(DE);
+    a.alu_n(Sub, b'0');
+    a.jr_if(Cond::C, valc_done);
+    a.alu_n(Cp, 10);
+    a.jr_if(Cond::NC, valc_done);
+    a.ld(B, H);  // HL = HL * 10 + digit
+    a.ld(C, L);
+    a.add_hl(HL);
+    a.add_hl(HL);
+    a.add_hl(BC);
+    a.add_hl(HL);
+    a.ld(C, A);
+    a.ld_n(B, 0);
+    a.add_hl(BC);
+    a.inc16(DE);
+    a.jr(valc_loop);
+    a.bind(valc_done);
+    a.ld(A, L);
+    a.pop(DE);
+    a.pop(BC);
+    a.ret();
+
+    // ============================================================
+    // InputS - Read a line from the selected device into a buffer, null-terminated
+    // Input: HL = buffer, C = buffer size including the terminator
+    // Output: A = length of the line
+    // Backspace and DEL remove the last character and other control characters are
+    // ignored; with echo, typing into a full buffer rings the bell
+    // ============================================================
+    symbols.input_s = a.addr();
+    let line_end = match options.line_end {
+        LineEnd::Cr => 0x0D,
+        LineEnd::Lf => 0x0A,
+    };
+    let is_erase = a.label();
+    let is_end = a.label();
+    a.ld_n(B, 0);  // Length
+    a.ld(A, C);
+    a.alu(Or, A);
+    a.ret_if(Cond::Z);  // No room even for the terminator
+    a.dec(C);  // Room for characters
+    let is_loop = a.here();
+    let is_full = if options.echo { a.label() } else { is_loop };
+    a.call(symbols.get_d);
+    a.alu_n(Cp, line_end);
+    a.jr_if(Cond::Z, is_end);
+    a.alu_n(Cp, 0x08);
+    a.jr_if(Cond::Z, is_erase);
+    a.alu_n(Cp, 0x7F);
+    a.jr_if(Cond::Z, is_erase);
+    a.alu_n(Cp, b' ');
+    a.jr_if(Cond::C, is_loop);  // Other control characters, the other line end among them
+    a.ld(D, A);
+    a.ld(A, B);
+    a.alu(Cp, C);
+    a.jr_if(Cond::NC, is_full);
+    a.ld(M, D);
+    a.inc16(HL);
+    a.inc(B);
+    if options.echo {
+        a.ld(A, D);
+        a.call(symbols.put_d);
+    }
+    a.jr(is_loop);
+    a.bind(is_erase);
+    a.ld(A, B);
+    a.alu(Or, A);
+    a.jr_if(Cond::Z, is_loop);
+    a.dec16(HL);
+    a.dec(B);
+    if options.echo {
+        for c in [0x08, b' ', 0x08] {  // Rub out the character on screen
+            a.ld_n(A, c);
+            a.call(symbols.put_d);
+        }
+    }
+    a.jr(is_loop);
+    if options.echo {
+        a.bind(is_full);
+        a.ld_n(A, 0x07);  // BEL
+        a.call(symbols.put_d);
+        a.jr(is_loop);
+    }
+    a.bind(is_end);
+    a.ld_n(M, 0);
+    if options.echo {
+        a.call(symbols.print_e);
+    }
+    a.ld(A, B);
+    a.ret();
+
+    // ============================================================
+    // XMODEM module (optional) - checksum XMODEM over the selected device
+    // ============================================================
+    if options.xmodem {
+        const SOH: u8 = 0x01;
+        const EOT: u8 = 0x04;
+        const ACK: u8 = 0x06;
+        const NAK: u8 = 0x15;
+        const CAN: u8 = 0x18;
+        const SUB: u8 = 0x1A;  // Pads the last block
+        const BLOCK_SIZE: u8 = 128;
+        const RETRIES: u8 = 10;
+
+        // Runtime variables for a transfer
+        let xm_block = symbols.ram_end;       // Expected or current block number
+        let xm_retry = symbols.ram_end + 1;   // Retries left
+        let xm_ptr = symbols.ram_end + 2;     // Next byte in the caller's buffer
+        let xm_left = symbols.ram_end + 4;    // Room left (XRecv) or bytes left (XSend)
+        let xm_count = symbols.ram_end + 6;   // Bytes received
+        let xm_buf = symbols.ram_end + 8;     // One received block
+        symbols.ram_end = xm_buf + BLOCK_SIZE as u16;
+
+        // ------------------------------------------------------------
+        // in_timeout - Get a character, giving up after about B seconds at 4MHz
+        // Output: A = character and carry clear, or carry set on timeout
+        // ------------------------------------------------------------
+        let in_timeout = a.addr();
+        let it_ready = a.label();
+        let it_done = a.label();
+        if tx_size != 0 {
+            a.call(tx_flush);  // The other end answers only once it has everything
+        }
+        a.push(BC);
+        a.push(DE);
+        let it_outer = a.here();
+        a.ld_nn(DE, 0);  // 65536 polls
+        let it_poll = a.here();
+        a.ld_a_mem(dev_status);
+        a.ld(C, A);
+        a.in_c(A);
+        a.alu_n(And, rx_ready);
+        a.jr_if(Cond::NZ, it_ready);
+        a.dec16(DE);
+        a.ld(A, D);
+        a.alu(Or, E);
+        a.jr_if(Cond::NZ, it_poll);
+        a.djnz(it_outer);
+        a.scf();  // Timed out
+        a.jr(it_done);
+        a.bind(it_ready);
+        a.call(symbols.in_char);
+        a.alu(Or, A);  // Clear carry
+        a.bind(it_done);
+        a.pop(DE);
+        a.pop(BC);
+        a.ret();
+
+        // ------------------------------------------------------------
+        // XRecv - Receive a file into a buffer
+        // Input: HL = buffer, BC = buffer size
+        // Output: HL = bytes received (a multiple of 128), or 0 on failure
+        // ------------------------------------------------------------
+        symbols.xmodem_recv = a.addr();
+        let xr_error = a.label();
+        let xr_fail = a.label();
+        let xr_header = a.label();
+        let xr_eot = a.label();
+        let xr_new = a.label();
+        a.ld_mem_rr(xm_ptr, HL);
+        a.ld_mem_rr(xm_left, BC);
+        a.ld_nn(HL, 0);
+        a.ld_mem_rr(xm_count, HL);
+        a.ld_n(A, 1);
+        a.ld_mem_a(xm_block);
+        a.ld_n(A, RETRIES);
+        a.ld_mem_a(xm_retry);
+        a.ld_n(A, NAK);  // Asks the sender to start
+        // xr_reply: send A, then wait for the next block
+        let xr_reply = a.here();
+        a.call(symbols.out_char);
+        let xr_wait = a.here();
+        a.ld_n(B, 10);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xr_error);
+        a.alu_n(Cp, SOH);
+        a.jr_if(Cond::Z, xr_header);
+        a.alu_n(Cp, EOT);
+        a.jr_if(Cond::Z, xr_eot);
+        a.alu_n(Cp, CAN);
+        a.jr_if(Cond::Z, xr_fail);
+        a.jr(xr_wait);  // Ignore noise
+        // xr_error: timeout or bad block, wait for the line to go quiet and NAK
+        a.bind(xr_error);
+        a.ld_n(B, 1);
+        a.call(in_timeout);
+        a.jr_if(Cond::NC, xr_error);
+        a.ld_nn(HL, xm_retry);
+        a.dec(M);
+        a.jr_if(Cond::Z, xr_fail);
+        a.ld_n(A, NAK);
+        a.jr(xr_reply);
+        // xr_eot: end of file
+        a.bind(xr_eot);
+        a.ld_n(A, ACK);
+        a.call(symbols.out_char);
+        a.ld_rr_mem(HL, xm_count);
+        a.ret();
+        // xr_fail: cancel the transfer
+        a.bind(xr_fail);
+        a.ld_n(A, CAN);
+        a.call(symbols.out_char);
+        a.ld_nn(HL, 0);
+        a.ret();
+        // xr_header: D = block number, checked against its complement
+        a.bind(xr_header);
+        a.ld_n(B, 1);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xr_error);
+        a.ld(D, A);
+        a.ld_n(B, 1);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xr_error);
+        a.cpl();
+        a.alu(Cp, D);
+        a.jr_if(Cond::NZ, xr_error);
+        // Read the data into xm_buf, C = checksum, E = bytes to go
+        a.ld_nn(HL, xm_buf);
+        a.ld_n(E, BLOCK_SIZE);
+        a.ld_n(C, 0);
+        let xr_data = a.here();
+        a.ld_n(B, 1);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xr_error);
+        a.ld(M, A);
+        a.inc16(HL);
+        a.alu(Add, C);
+        a.ld(C, A);
+        a.dec(E);
+        a.jr_if(Cond::NZ, xr_data);
+        a.ld_n(B, 1);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xr_error);
+        a.alu(Cp, C);
+        a.jr_if(Cond::NZ, xr_error);
+        // A good block: the expected one, or the previous one again if our ACK was lost
+        a.ld_a_mem(xm_block);
+        a.alu(Cp, D);
+        a.jr_if(Cond::Z, xr_new);
+        a.dec(A);
+        a.alu(Cp, D);
+        a.ld_n(A, ACK);
+        a.jp_if(Cond::Z, xr_reply);  // Duplicate, acknowledge it again
+        a.jr(xr_fail);  // Out of sequence
+        // xr_new: copy the block to the caller's buffer if it fits
+        a.bind(xr_new);
+        a.ld_rr_mem(HL, xm_left);
+        a.ld_nn(DE, BLOCK_SIZE as u16);
+        a.alu(Or, A);
+        a.sbc_hl(DE);
+        a.jr_if(Cond::C, xr_fail);  // Buffer full
+        a.ld_mem_rr(xm_left, HL);
+        a.ld_rr_mem(HL, xm_count);
+        a.add_hl(DE);
+        a.ld_mem_rr(xm_count, HL);
+        a.ld_rr_mem(DE, xm_ptr);
+        a.ld_nn(HL, xm_buf);
+        a.ld_nn(BC, BLOCK_SIZE as u16);
+        a.ldir();
+        a.ld_mem_rr(xm_ptr, DE);
+        a.ld_nn(HL, xm_block);
+        a.inc(M);
+        a.ld_n(A, RETRIES);
+        a.ld_mem_a(xm_retry);
+        a.ld_n(A, ACK);
+        a.jp(xr_reply);
+
+        // ------------------------------------------------------------
+        // XSend - Send a buffer as a file, padding the last block with SUB
+        // Input: HL = buffer, BC = length
+        // Output: A = 1 if the receiver acknowledged everything, 0 on failure
+        // ------------------------------------------------------------
+        symbols.xmodem_send = a.addr();
+        let xs_fail = a.label();
+        let xs_eot = a.label();
+        let xs_pad = a.label();
+        let xs_retry = a.label();
+        let xs_acked = a.label();
+        let xs_eot_retry = a.label();
+        a.ld_mem_rr(xm_ptr, HL);
+        a.ld_mem_rr(xm_left, BC);
+        a.ld_n(A, 1);
+        a.ld_mem_a(xm_block);
+        a.ld_n(A, RETRIES);
+        a.ld_mem_a(xm_retry);
+        // xs_start: wait for the receiver's NAK
+        let xs_start = a.here();
+        a.ld_n(B, 60);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xs_fail);
+        a.alu_n(Cp, CAN);
+        a.jr_if(Cond::Z, xs_fail);
+        a.alu_n(Cp, NAK);
+        a.jr_if(Cond::NZ, xs_start);
+        // xs_block: send the block at xm_ptr, or EOT when nothing is left
+        let xs_block = a.here();
+        a.ld_rr_mem(DE, xm_left);
+        a.ld(A, D);
+        a.alu(Or, E);
+        a.jr_if(Cond::Z, xs_eot);
+        a.ld_n(A, SOH);
+        a.call(symbols.out_char);
+        a.ld_a_mem(xm_block);
+        a.call(symbols.out_char);
+        a.cpl();
+        a.call(symbols.out_char);
+        a.ld_rr_mem(HL, xm_ptr);
+        a.ld_nn(BC, (BLOCK_SIZE as u16) << 8);  // B = count, C = checksum
+        let xs_data = a.here();
+        a.ld(A, D);
+        a.alu(Or, E);
+        a.ld_n(A, SUB);
+        a.jr_if(Cond::Z, xs_pad);
+        a.ld(A, M);
+        a.inc16(HL);
+        a.dec16(DE);
+        a.bind(xs_pad);
+        a.call(symbols.out_char);
+        a.alu(Add, C);
+        a.ld(C, A);
+        a.djnz(xs_data);
+        a.ld(A, C);
+        a.call(symbols.out_char);
+        a.ld_n(B, 10);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xs_retry);
+        a.alu_n(Cp, CAN);
+        a.jr_if(Cond::Z, xs_fail);
+        a.alu_n(Cp, ACK);
+        a.jr_if(Cond::Z, xs_acked);
+        // xs_retry: NAK, timeout or noise, send the block again
+        a.bind(xs_retry);
+        a.ld_nn(HL, xm_retry);
+        a.dec(M);
+        a.jr_if(Cond::Z, xs_fail);
+        a.jr(xs_block);
+        // xs_acked: move on to the next block
+        a.bind(xs_acked);
+        a.ld_mem_rr(xm_ptr, HL);
+        a.ld_mem_rr(xm_left, DE);
+        a.ld_nn(HL, xm_block);
+        a.inc(M);
+        a.ld_n(A, RETRIES);
+        a.ld_mem_a(xm_retry);
+        a.jr(xs_block);
+        // xs_eot: end of file, repeated until acknowledged
+        a.bind(xs_eot);
+        a.ld_n(A, EOT);
+        a.call(symbols.out_char);
+        a.ld_n(B, 10);
+        a.call(in_timeout);
+        a.jr_if(Cond::C, xs_eot_retry);
+        a.alu_n(Cp, ACK);
+        a.ld_n(A, 1);
+        a.ret_if(Cond::Z);
+        a.bind(xs_eot_retry);
+        a.ld_nn(HL, xm_retry);
+        a.dec(M);
+        a.jr_if(Cond::NZ, xs_eot);
+        a.bind(xs_fail);
+        a.alu(Xor, A);
+        a.ret();
+    }
+
+    // ============================================================
+    // CRC module (optional) - CRC-16 and 8-bit checksums of a buffer
+    // ============================================================
+    if let Some(crc) = options.crc {
+        // ------------------------------------------------------------
+        // Crc16 - CRC-16 (XMODEM: polynomial $1021, starting at 0) of a buffer
+        // Input: HL = buffer, BC = length
+        // Output: HL = CRC; BC is changed
+        // ------------------------------------------------------------
+        symbols.crc16 = a.addr();
+        let crc_table = a.label();
+        let crc_done = a.label();
+        a.push(DE);
+        a.ld_nn(DE, 0);
+        let crc_loop = a.here();
+        a.ld(A, B);
+        a.alu(Or, C);
+        a.jr_if(Cond::Z, crc_done);
+        a.ld(A, M);
+        a.alu(Xor, D);
+        match crc {
+            Crc::Bitwise => {
+                let no_poly = a.label();
+                a.ld(D, A);  // CRC ^= byte << 8
+                a.push(BC);
+                a.ld_n(B, 8);
+                let crc_bit = a.here();
+                a.sla(E);
+                a.rl(D);
+                a.jr_if(Cond::NC, no_poly);
+                a.ld(A, D);
+                a.alu_n(Xor, (CRC16_POLY >> 8) as u8);
+                a.ld(D, A);
+                a.ld(A, E);
+                a.alu_n(Xor, CRC16_POLY as u8);
+                a.ld(E, A);
+                a.bind(no_poly);
+                a.djnz(crc_bit);
+                a.pop(BC);
+            }
+            Crc::Table => {
+                // CRC = CRC << 8 ^ table(CRC >> 8 ^ byte)
+                a.push(HL);
+                a.push(BC);
+                a.ld(L, A);
+                a.ld_n(H, 0);
+                a.add_hl(HL);
+                a.ld_nn(BC, crc_table);
+                a.add_hl(BC);
+                a.ld(A, E);
+                a.inc16(HL);
+                a.alu(Xor, M);
+                a.ld(D, A);
+                a.dec16(HL);
+                a.ld(E, M);
+                a.pop(BC);
+                a.pop(HL);
+            }
+        }
+        a.inc16(HL);
+        a.dec16(BC);
+        a.jr(crc_loop);
+        a.bind(crc_done);
+        a.ld(H, D);
+        a.ld(L, E);
+        a.pop(DE);
+        a.ret();
+
+        if crc == Crc::Table {
+            a.bind(crc_table);
+            let table_start = a.addr();
+            for i in 0..=255u16 {
+                a.bytes(&crc16_step(i << 8).to_le_bytes());
+            }
+            symbols.tables.push(table_start..a.addr());
+        }
+
+        // ------------------------------------------------------------
+        // CheckSum - 8-bit sum of a buffer
+        // Input: HL = buffer, BC = length
+        // Output: A = sum (modulo 256); BC and HL are changed
+        // ------------------------------------------------------------
+        symbols.check_sum = a.addr();
+        let sum_done = a.label();
+        a.push(DE);
+        a.ld_n(E, 0);
+        let sum_loop = a.here();
+        a.ld(A, B);
+        a.alu(Or, C);
+        a.jr_if(Cond::Z, sum_done);
+        a.ld(A, E);
+        a.alu(Add, M);
+        a.ld(E, A);
+        a.inc16(HL);
+        a.dec16(BC);
+        a.jr(sum_loop);
+        a.bind(sum_done);
+        a.ld(A, E);
+        a.pop(DE);
+        a.ret();
+    }
+
+    if options.rst_calls {
+        for (name, vector) in RST_ROUTINES {
+            let routine = symbols.get_function(name, CaseMode::Strict).map(|(_, addr)| addr).unwrap_or_default();
+            symbols.rst_vectors.push((vector, routine));
+        }
+    }
+
+    if let Some(charset) = &options.charset {
+        a.bind(charset_table);
+        let table_start = a.addr();
+        a.bytes(&charset.table());
+        symbols.tables.push(table_start..a.addr());
+    }
+
+    symbols.end_address = a.addr();
+
+    let code = a.finish();
+    if let Some(body) = put_d_inline {
+        symbols.put_d_inline = code[(body.start - base_address) as usize..(body.end - base_address) as usize].to_vec();
+    }
     (code, symbols)
 }
 
@@ -258,12 +1584,49 @@ pub struct RuntimeSymbols {
     pub print_b: u16,      // Print byte as decimal
     pub print_c: u16,      // Print CARD as decimal
     pub print_e: u16,      // Print end of line
+    pub print_be: u16,     // Print byte and end of line
+    pub print_ce: u16,     // Print CARD and end of line
     pub print: u16,        // Print string
     pub get_d: u16,        // Get character
     pub put_d: u16,        // Put character
     pub multiply: u16,     // 16-bit multiply
     pub div8: u16,         // 8-bit divide
+    pub div10: u16,        // 16-bit divide by 10
+    pub div16: u16,        // 16-bit divide, with remainder
+    pub str_c: u16,        // Number to decimal string
+    pub val_c: u16,        // Decimal string to number
+    pub s_index: u16,      // Find character in string
+    pub s_sub: u16,        // Copy substring
+    pub s_compare: u16,    // Compare strings
+    pub input_s: u16,      // Read a line with editing
+    pub out_char: u16,     // Output character to selected device
+    pub in_char: u16,      // Input character from selected device
+    pub set_device: u16,   // Select device for I/O
+    pub reset_device: u16, // Select the console again
+    pub uart_init: u16,    // Set up the console UART, 0 if it needs no setup
+    pub tx_flush: u16,     // Send all queued console output, 0 without a queue
+    pub slot_init: u16,    // Map in the rest of an MSX cartridge, 0 unless it is one
+    pub xmodem_recv: u16,  // XMODEM receive, 0 without the XMODEM module
+    pub xmodem_send: u16,  // XMODEM send, 0 without the XMODEM module
+    pub crc16: u16,        // CRC-16 of a buffer, 0 without the CRC module
+    pub eval_init: u16,    // Empty the evaluation stack, 0 without one
+    pub eval_push: u16,    // Push HL on the evaluation stack
+    pub eval_pop_hl: u16,  // Pop the evaluation stack into HL
+    pub eval_pop_de: u16,  // Pop the evaluation stack into DE
+    pub eval_pop_bc: u16,  // Pop the evaluation stack into BC
+    pub check_sum: u16,    // 8-bit sum of a buffer, 0 without the CRC module
+    pub rst_vectors: Vec<(u8, u16)>,  // (RST vector, routine) pairs for calls through RST
+    pub tables: Vec<std::ops::Range<u16>>,  // Data in the runtime, not code
+    pub push_loops: Vec<(u16, u16)>,  // Loops that push on each pass, with the most bytes pushed on coming back round
+    pub put_d_inline: Vec<u8>,  // out_char without its RET, to copy in place of a PutD call, or empty
     pub end_address: u16,  // Address after runtime
+    pub ram_end: u16,      // First RAM address after runtime variables
+}
+
+impl Default for RuntimeSymbols {
+    fn default() -> Self {
+        RuntimeSymbols::new()
+    }
 }
 
 impl RuntimeSymbols {
@@ -272,25 +1635,139 @@ impl RuntimeSymbols {
             print_b: 0,
             print_c: 0,
             print_e: 0,
+            print_be: 0,
+            print_ce: 0,
             print: 0,
             get_d: 0,
             put_d: 0,
             multiply: 0,
             div8: 0,
+            div10: 0,
+            div16: 0,
+            str_c: 0,
+            val_c: 0,
+            s_index: 0,
+            s_sub: 0,
+            s_compare: 0,
+            input_s: 0,
+            out_char: 0,
+            in_char: 0,
+            set_device: 0,
+            reset_device: 0,
+            uart_init: 0,
+            tx_flush: 0,
+            slot_init: 0,
+            xmodem_recv: 0,
+            xmodem_send: 0,
+            crc16: 0,
+            eval_init: 0,
+            eval_push: 0,
+            eval_pop_hl: 0,
+            eval_pop_de: 0,
+            eval_pop_bc: 0,
+            check_sum: 0,
+            rst_vectors: Vec::new(),
+            tables: Vec::new(),
+            push_loops: Vec::new(),
+            put_d_inline: Vec::new(),
             end_address: 0,
+            ram_end: RAM_START,
         }
     }
 
-    /// Get the address of a runtime function by name
-    pub fn get_function(&self, name: &str) -> Option<u16> {
-        match name.to_uppercase().as_str() {
-            "PRINTB" => Some(self.print_b),
-            "PRINTC" => Some(self.print_c),
-            "PRINTE" => Some(self.print_e),
-            "PRINT" => Some(self.print),
-            "GETD" => Some(self.get_d),
-            "PUTD" => Some(self.put_d),
-            _ => None,
-        }
+    /// The RST instruction that reaches a routine, if it has a vector
+    pub fn rst_for(&self, routine: u16) -> Option<u8> {
+        self.rst_vectors.iter()
+            .find(|&&(_, r)| r == routine)
+            .map(|&(vector, _)| 0xC7 | vector)  // RST vector
+    }
+
+    /// Routines a program may replace with a procedure of the same name: each is a JP
+    /// whose target the linker points at the program's own, and the rest of the runtime
+    /// reaches it only through that JP
+    pub fn weak(&self) -> [(&'static str, u16); 2] {
+        [("PutD", self.put_d), ("GetD", self.get_d)]
+    }
+
+    /// Names and addresses of the routines in the runtime, including internal ones
+    pub fn routines(&self) -> Vec<(&'static str, u16)> {
+        let routines = [
+            ("PrintB", self.print_b),
+            ("PrintC", self.print_c),
+            ("PrintE", self.print_e),
+            ("PrintBE", self.print_be),
+            ("PrintCE", self.print_ce),
+            ("Print", self.print),
+            ("GetD", self.get_d),
+            ("PutD", self.put_d),
+            ("multiply", self.multiply),
+            ("div8", self.div8),
+            ("div16", self.div16),
+            ("SIndex", self.s_index),
+            ("SSub", self.s_sub),
+            ("SCompare", self.s_compare),
+            ("InputS", self.input_s),
+            ("div10", self.div10),
+            ("StrC", self.str_c),
+            ("ValC", self.val_c),
+            ("out_char", self.out_char),
+            ("in_char", self.in_char),
+            ("set_device", self.set_device),
+            ("reset_device", self.reset_device),
+            ("uart_init", self.uart_init),
+            ("tx_flush", self.tx_flush),
+            ("slot_init", self.slot_init),
+            ("XRecv", self.xmodem_recv),
+            ("XSend", self.xmodem_send),
+            ("Crc16", self.crc16),
+            ("CheckSum", self.check_sum),
+            ("eval_init", self.eval_init),
+            ("eval_push", self.eval_push),
+            ("eval_pop_hl", self.eval_pop_hl),
+            ("eval_pop_de", self.eval_pop_de),
+            ("eval_pop_bc", self.eval_pop_bc),
+        ];
+        routines.into_iter().filter(|&(_, addr)| addr != 0).collect()
+    }
+
+    /// Get the canonical name and address of a runtime function
+    pub fn get_function(&self, name: &str, case_mode: CaseMode) -> Option<(&'static str, u16)> {
+        let builtins = [
+            ("PrintB", self.print_b),
+            ("PrintC", self.print_c),
+            ("PrintE", self.print_e),
+            ("PrintBE", self.print_be),
+            ("PrintCE", self.print_ce),
+            ("Print", self.print),
+            ("GetD", self.get_d),
+            ("PutD", self.put_d),
+            ("Put", self.put_d),
+            ("Get", self.get_d),
+            ("PrintD", self.print),
+            ("PrintBD", self.print_b),
+            ("PrintCD", self.print_c),
+            ("PrintED", self.print_e),
+            ("LPrint", self.print),
+            ("LPrintB", self.print_b),
+            ("LPrintC", self.print_c),
+            ("LPrintE", self.print_e),
+            ("SIndex", self.s_index),
+            ("SSub", self.s_sub),
+            ("SCompare", self.s_compare),
+            ("InputS", self.input_s),
+            ("InputSD", self.input_s),
+            ("StrB", self.str_c),
+            ("StrC", self.str_c),
+            ("ValB", self.val_c),
+            ("ValC", self.val_c),
+            ("XRecv", self.xmodem_recv),
+            ("XSend", self.xmodem_send),
+            ("Crc16", self.crc16),
+            ("CheckSum", self.check_sum),
+        ];
+        builtins.into_iter().find(|(builtin, _)| case_mode.matches(builtin, name))
     }
 }
+
+#[cfg(test)]
+mod tests;
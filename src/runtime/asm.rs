@@ -0,0 +1,377 @@
+// A small Z80 assembler for the runtime library: instructions by mnemonic, labels, and
+// jump and address fixups filled in when the code is finished.
+//
+// Only the instructions the runtime uses are here. Mistakes in the runtime itself (a
+// label never placed, a relative jump out of range) panic: they are compiler bugs, not
+// errors in the program being compiled.
+
+/// 8-bit registers in the Z80's encoding; M is the byte at (HL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum R8 { B, C, D, E, H, L, M, A }
+
+/// Register pairs in the Z80's encoding; AF takes the place of SP, so it is only for
+/// PUSH and POP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum R16 { BC, DE, HL, AF }
+
+/// Conditions of JR, JP and RET
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond { NZ, Z, NC, C }
+
+/// 8-bit arithmetic and logic operations on A
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alu {
+    Add = 0,
+    Sub = 2,
+    And = 4,
+    Xor = 5,
+    Or = 6,
+    Cp = 7,
+}
+
+/// A place in the code, placed with bind or here
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// Where a jump, call or 16-bit load goes: a known address or a label
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    Address(u16),
+    Label(Label),
+}
+
+impl From<u16> for Target {
+    fn from(addr: u16) -> Self {
+        Target::Address(addr)
+    }
+}
+
+impl From<Label> for Target {
+    fn from(label: Label) -> Self {
+        Target::Label(label)
+    }
+}
+
+// A label reference waiting for the label's address
+struct Fixup {
+    at: usize,        // Code index of the offset byte or the address word
+    label: Label,
+    relative: bool,   // JR or DJNZ offset rather than an absolute address
+}
+
+/// Code being assembled to run at a base address
+pub struct Asm {
+    base: u16,
+    code: Vec<u8>,
+    labels: Vec<Option<u16>>,
+    fixups: Vec<Fixup>,
+}
+
+impl Asm {
+    pub fn new(base: u16) -> Self {
+        Asm { base, code: Vec::new(), labels: Vec::new(), fixups: Vec::new() }
+    }
+
+    /// Address of the next instruction
+    pub fn addr(&self) -> u16 {
+        self.base.wrapping_add(self.code.len() as u16)
+    }
+
+    /// A label to place later
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Place a label at the next instruction
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "runtime label placed twice");
+        self.labels[label.0] = Some(self.addr());
+    }
+
+    /// A label placed at the next instruction
+    pub fn here(&mut self) -> Label {
+        let label = self.label();
+        self.bind(label);
+        label
+    }
+
+    /// The code with every label reference filled in
+    pub fn finish(mut self) -> Vec<u8> {
+        for fixup in &self.fixups {
+            let target = self.labels[fixup.label.0].expect("runtime label never placed");
+            if fixup.relative {
+                let from = self.base.wrapping_add(fixup.at as u16 + 1);
+                let offset = i8::try_from(target.wrapping_sub(from) as i16).expect("runtime jump out of range");
+                self.code[fixup.at] = offset as u8;
+            } else {
+                self.code[fixup.at..fixup.at + 2].copy_from_slice(&target.to_le_bytes());
+            }
+        }
+        self.code
+    }
+
+    /// Raw bytes, for tables
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn word(&mut self, target: impl Into<Target>) {
+        match target.into() {
+            Target::Address(addr) => self.code.extend_from_slice(&addr.to_le_bytes()),
+            Target::Label(label) => {
+                self.fixups.push(Fixup { at: self.code.len(), label, relative: false });
+                self.code.extend_from_slice(&[0x00, 0x00]);
+            }
+        }
+    }
+
+    fn relative(&mut self, opcode: u8, label: Label) {
+        self.code.push(opcode);
+        self.fixups.push(Fixup { at: self.code.len(), label, relative: true });
+        self.code.push(0x00);
+    }
+
+    // Loads
+
+    /// LD r, r'
+    pub fn ld(&mut self, dst: R8, src: R8) {
+        assert!(!(dst == R8::M && src == R8::M), "LD (HL), (HL) is HALT");
+        self.code.push(0x40 | (dst as u8) << 3 | src as u8);
+    }
+
+    /// LD r, n
+    pub fn ld_n(&mut self, r: R8, n: u8) {
+        self.code.extend_from_slice(&[0x06 | (r as u8) << 3, n]);
+    }
+
+    /// LD rr, nn
+    pub fn ld_nn(&mut self, rr: R16, nn: impl Into<Target>) {
+        assert!(rr != R16::AF, "no LD AF, nn");
+        self.code.push(0x01 | (rr as u8) << 4);
+        self.word(nn);
+    }
+
+    /// LD A, (nn)
+    pub fn ld_a_mem(&mut self, addr: u16) {
+        self.code.push(0x3A);
+        self.word(addr);
+    }
+
+    /// LD (nn), A
+    pub fn ld_mem_a(&mut self, addr: u16) {
+        self.code.push(0x32);
+        self.word(addr);
+    }
+
+    /// LD rr, (nn)
+    pub fn ld_rr_mem(&mut self, rr: R16, addr: u16) {
+        match rr {
+            R16::HL => self.code.push(0x2A),
+            R16::AF => panic!("no LD AF, (nn)"),
+            _ => self.code.extend_from_slice(&[0xED, 0x4B | (rr as u8) << 4]),
+        }
+        self.word(addr);
+    }
+
+    /// LD (nn), rr
+    pub fn ld_mem_rr(&mut self, addr: u16, rr: R16) {
+        match rr {
+            R16::HL => self.code.push(0x22),
+            R16::AF => panic!("no LD (nn), AF"),
+            _ => self.code.extend_from_slice(&[0xED, 0x43 | (rr as u8) << 4]),
+        }
+        self.word(addr);
+    }
+
+    /// LD (BC), A or LD (DE), A
+    pub fn ld_ind_a(&mut self, rr: R16) {
+        assert!(matches!(rr, R16::BC | R16::DE), "no LD (rr), A through {:?}", rr);
+        self.code.push(0x02 | (rr as u8) << 4);
+    }
+
+    /// LD A, (BC) or LD A, (DE)
+    pub fn ld_a_ind(&mut self, rr: R16) {
+        assert!(matches!(rr, R16::BC | R16::DE), "no LD A, (rr) through {:?}", rr);
+        self.code.push(0x0A | (rr as u8) << 4);
+    }
+
+    /// LDIR
+    pub fn ldir(&mut self) {
+        self.code.extend_from_slice(&[0xED, 0xB0]);
+    }
+
+    /// EX DE, HL
+    pub fn ex_de_hl(&mut self) {
+        self.code.push(0xEB);
+    }
+
+    /// PUSH rr
+    pub fn push(&mut self, rr: R16) {
+        self.code.push(0xC5 | (rr as u8) << 4);
+    }
+
+    /// POP rr
+    pub fn pop(&mut self, rr: R16) {
+        self.code.push(0xC1 | (rr as u8) << 4);
+    }
+
+    // Arithmetic
+
+    /// ADD, SUB, AND, XOR, OR or CP with a register
+    pub fn alu(&mut self, op: Alu, r: R8) {
+        self.code.push(0x80 | (op as u8) << 3 | r as u8);
+    }
+
+    /// ADD, SUB, AND, XOR, OR or CP with a constant
+    pub fn alu_n(&mut self, op: Alu, n: u8) {
+        self.code.extend_from_slice(&[0xC6 | (op as u8) << 3, n]);
+    }
+
+    /// INC r
+    pub fn inc(&mut self, r: R8) {
+        self.code.push(0x04 | (r as u8) << 3);
+    }
+
+    /// DEC r
+    pub fn dec(&mut self, r: R8) {
+        self.code.push(0x05 | (r as u8) << 3);
+    }
+
+    /// INC rr
+    pub fn inc16(&mut self, rr: R16) {
+        assert!(rr != R16::AF, "no INC AF");
+        self.code.push(0x03 | (rr as u8) << 4);
+    }
+
+    /// DEC rr
+    pub fn dec16(&mut self, rr: R16) {
+        assert!(rr != R16::AF, "no DEC AF");
+        self.code.push(0x0B | (rr as u8) << 4);
+    }
+
+    /// ADD HL, rr
+    pub fn add_hl(&mut self, rr: R16) {
+        assert!(rr != R16::AF, "no ADD HL, AF");
+        self.code.push(0x09 | (rr as u8) << 4);
+    }
+
+    /// ADC HL, rr
+    pub fn adc_hl(&mut self, rr: R16) {
+        assert!(rr != R16::AF, "no ADC HL, AF");
+        self.code.extend_from_slice(&[0xED, 0x4A | (rr as u8) << 4]);
+    }
+
+    /// SBC HL, rr
+    pub fn sbc_hl(&mut self, rr: R16) {
+        assert!(rr != R16::AF, "no SBC HL, AF");
+        self.code.extend_from_slice(&[0xED, 0x42 | (rr as u8) << 4]);
+    }
+
+    /// SLA r
+    pub fn sla(&mut self, r: R8) {
+        self.code.extend_from_slice(&[0xCB, 0x20 | r as u8]);
+    }
+
+    /// RL r
+    pub fn rl(&mut self, r: R8) {
+        self.code.extend_from_slice(&[0xCB, 0x10 | r as u8]);
+    }
+
+    /// BIT n, r
+    pub fn bit(&mut self, n: u8, r: R8) {
+        assert!(n < 8, "no bit {}", n);
+        self.code.extend_from_slice(&[0xCB, 0x40 | n << 3 | r as u8]);
+    }
+
+    /// RES n, r
+    pub fn res(&mut self, n: u8, r: R8) {
+        assert!(n < 8, "no bit {}", n);
+        self.code.extend_from_slice(&[0xCB, 0x80 | n << 3 | r as u8]);
+    }
+
+    /// CPL
+    pub fn cpl(&mut self) {
+        self.code.push(0x2F);
+    }
+
+    /// SCF
+    pub fn scf(&mut self) {
+        self.code.push(0x37);
+    }
+
+    // Jumps, calls and returns
+
+    /// JR label
+    pub fn jr(&mut self, label: Label) {
+        self.relative(0x18, label);
+    }
+
+    /// JR cc, label
+    pub fn jr_if(&mut self, cond: Cond, label: Label) {
+        self.relative(0x20 | (cond as u8) << 3, label);
+    }
+
+    /// DJNZ label
+    pub fn djnz(&mut self, label: Label) {
+        self.relative(0x10, label);
+    }
+
+    /// JP nn
+    pub fn jp(&mut self, target: impl Into<Target>) {
+        self.code.push(0xC3);
+        self.word(target);
+    }
+
+    /// JP cc, nn
+    pub fn jp_if(&mut self, cond: Cond, target: impl Into<Target>) {
+        self.code.push(0xC2 | (cond as u8) << 3);
+        self.word(target);
+    }
+
+    /// CALL nn
+    pub fn call(&mut self, target: impl Into<Target>) {
+        self.code.push(0xCD);
+        self.word(target);
+    }
+
+    /// RST p
+    pub fn rst(&mut self, vector: u8) {
+        assert!(vector & !0x38 == 0, "no RST {:02X}h", vector);
+        self.code.push(0xC7 | vector);
+    }
+
+    /// RET
+    pub fn ret(&mut self) {
+        self.code.push(0xC9);
+    }
+
+    /// RET cc
+    pub fn ret_if(&mut self, cond: Cond) {
+        self.code.push(0xC0 | (cond as u8) << 3);
+    }
+
+    // Input and output
+
+    /// IN r, (C)
+    pub fn in_c(&mut self, r: R8) {
+        assert!(r != R8::M, "no IN (HL), (C)");
+        self.code.extend_from_slice(&[0xED, 0x40 | (r as u8) << 3]);
+    }
+
+    /// OUT (C), r
+    pub fn out_c(&mut self, r: R8) {
+        assert!(r != R8::M, "no OUT (C), (HL)");
+        self.code.extend_from_slice(&[0xED, 0x41 | (r as u8) << 3]);
+    }
+
+    /// IN A, (n)
+    pub fn in_n(&mut self, port: u8) {
+        self.code.extend_from_slice(&[0xDB, port]);
+    }
+
+    /// OUT (n), A
+    pub fn out_n(&mut self, port: u8) {
+        self.code.extend_from_slice(&[0xD3, port]);
+    }
+}
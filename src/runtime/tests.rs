@@ -0,0 +1,535 @@
+// Runtime routines run on the built-in emulator
+
+use crate::emulator::{Console, Cpu, IoBus, StopReason};
+use crate::runtime::{generate_runtime_with_options, Charset, ConsoleBackend, Crc, DevicePorts, LineEnd, RuntimeOptions, RuntimeSymbols, Uart, BOOT_RUNTIME_START, RAM_START};
+use crate::test_support::{compile_boot_rom, compile_program_with, ORG};
+
+const MAX_CYCLES: u64 = 20_000_000;
+
+// Run a program with the XMODEM module, returning the console output and final memory
+fn run_xmodem(source: &str, input: &[u8]) -> (Vec<u8>, Cpu) {
+    let options = RuntimeOptions { xmodem: true, ..Default::default() };
+    let image = compile_program_with(source, ORG, &options).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load(ORG, &image);
+    cpu.pc = ORG;
+    let mut console = Console::new();
+    console.input.extend(input);
+    assert_eq!(cpu.run(&mut console, Some(MAX_CYCLES)), StopReason::Halted);
+    (console.output, cpu)
+}
+
+// An XMODEM block as a sender puts it on the line
+fn block(number: u8, data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    padded.resize(128, 0x1A);
+    let mut bytes = vec![0x01, number, !number];
+    bytes.extend(&padded);
+    bytes.push(padded.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)));
+    bytes
+}
+
+#[test]
+fn xsend_sends_padded_blocks() {
+    let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let source = "BYTE ARRAY buf(200)\nPROC main()\nBYTE i\nFOR i = 0 TO 199 DO buf(i) = i OD\nPutD(XSend(buf, 200) + '0')\nRETURN\n";
+    // Receiver: NAK to start, ACK both blocks and the EOT
+    let (output, _) = run_xmodem(source, &[0x15, 0x06, 0x06, 0x06]);
+    let mut expected = block(1, &data[..128]);
+    expected.extend(block(2, &data[128..]));
+    expected.push(0x04);
+    expected.extend(b"1");
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn xsend_resends_a_block_after_nak() {
+    let source = "BYTE ARRAY buf(3) = \"abc\"\nPROC main()\nPutD(XSend(buf, 3) + '0')\nRETURN\n";
+    let (output, _) = run_xmodem(source, &[0x15, 0x15, 0x06, 0x06]);
+    let mut expected = block(1, b"abc");
+    expected.extend(block(1, b"abc"));
+    expected.push(0x04);
+    expected.extend(b"1");
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn xrecv_receives_blocks() {
+    let source = "BYTE ARRAY buf(256)\nCARD n\nPROC main()\nn = XRecv(buf, 256)\nRETURN\n";
+    let first: Vec<u8> = (0..128).collect();
+    let mut input = block(1, &first);
+    input.extend(block(2, b"tail"));
+    input.extend(block(2, b"tail"));  // Repeated as if our ACK was lost
+    input.push(0x04);
+    let (output, cpu) = run_xmodem(source, &input);
+    assert_eq!(output, [0x15, 0x06, 0x06, 0x06, 0x06]);
+    // Globals follow the device ports and the XMODEM variables
+    let buf = RAM_START as usize + 2 + 136;
+    assert_eq!(&cpu.mem[buf..buf + 128], &first[..]);
+    assert_eq!(&cpu.mem[buf + 128..buf + 132], b"tail");
+    assert_eq!(cpu.read_word((buf + 256) as u16), 256);
+}
+
+#[test]
+fn xrecv_cancels_when_the_buffer_is_full() {
+    let source = "BYTE ARRAY buf(100)\nCARD n\nPROC main()\nn = 1\nn = XRecv(buf, 100)\nRETURN\n";
+    let (output, cpu) = run_xmodem(source, &block(1, b"too big"));
+    assert_eq!(output, [0x15, 0x18]);
+    let n = RAM_START as usize + 2 + 136 + 100;
+    assert_eq!(cpu.read_word(n as u16), 0);
+}
+
+// A 6850 ACIA at the RC2014 ports whose transmitter is busy on every other status read
+#[derive(Default)]
+struct Acia {
+    control: Vec<u8>,
+    output: Vec<u8>,
+    polls: usize,
+}
+
+impl IoBus for Acia {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            0x80 => {
+                self.polls += 1;
+                if self.polls.is_multiple_of(2) { 0x02 } else { 0x00 }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            0x80 => self.control.push(value),
+            0x81 => {
+                assert!(self.polls.is_multiple_of(2), "wrote while the transmitter was busy");
+                self.output.push(value);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn acia_is_set_up_and_polled() {
+    let mut options = RuntimeOptions { uart: Uart::Acia, clock_divide: 16, ..Default::default() };
+    options.devices.console = Uart::Acia.default_ports();
+    let image = compile_program_with("PROC main()\nPrint(\"ok\")\nRETURN\n", ORG, &options).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load(ORG, &image);
+    cpu.pc = ORG;
+    let mut acia = Acia::default();
+    assert_eq!(cpu.run(&mut acia, Some(MAX_CYCLES)), StopReason::Halted);
+    assert_eq!(acia.control, [0x03, 0x15]);
+    assert_eq!(acia.output, b"ok");
+}
+
+#[test]
+fn queued_output_reaches_the_acia_in_order() {
+    // The queue is shorter than the line, so it fills up and wraps around
+    let mut options = RuntimeOptions { uart: Uart::Acia, tx_buffer: 8, ..Default::default() };
+    options.devices.console = Uart::Acia.default_ports();
+    let text = "longer than the queue, twice over";
+    let source = format!("PROC main()\nPrint(\"{}\")\nPrintB(42)\nPrintE()\nRETURN\n", text);
+    let image = compile_program_with(&source, ORG, &options).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load(ORG, &image);
+    cpu.pc = ORG;
+    let mut acia = Acia::default();
+    assert_eq!(cpu.run(&mut acia, Some(MAX_CYCLES)), StopReason::Halted);
+    assert_eq!(String::from_utf8(acia.output).unwrap(), format!("{}42\r\n", text));
+}
+
+#[test]
+fn expression_temporaries_on_the_evaluation_stack() {
+    let source = "CARD x\nCARD y\nBYTE ARRAY buf(8)\nPROC main()\nx = 1000\ny = x + (x + (x + 5))\nStrC(y, buf)\nPrint(buf)\nRETURN\n";
+    for eval_stack in [0, 16] {
+        let options = RuntimeOptions { eval_stack, ..Default::default() };
+        let image = compile_program_with(source, ORG, &options).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load(ORG, &image);
+        cpu.pc = ORG;
+        let mut console = Console::new();
+        assert_eq!(cpu.run(&mut console, Some(MAX_CYCLES)), StopReason::Halted);
+        assert_eq!(console.output, b"3005");
+        if eval_stack != 0 {
+            // Empty again: the pointer after the device ports points at the first slot
+            assert_eq!(cpu.read_word(RAM_START + 2), RAM_START + 4);
+        }
+    }
+}
+
+#[test]
+fn boot_rom_copies_data_to_ram() {
+    let source = "BYTE ARRAY msg = \"hi\"\nPROC main()\nmsg(0) = 'H'\nPrint(msg)\nRETURN\n";
+    let image = compile_boot_rom(source, 0x8000, &RuntimeOptions::default()).unwrap();
+    assert_eq!(&image[..7], [0xF3, 0x31, 0x00, 0x80, 0xC3, image[5], image[6]]);
+
+    let mut cpu = Cpu::new();
+    cpu.load(0x0000, &image);
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(MAX_CYCLES)), StopReason::Halted);
+    assert_eq!(console.output, b"Hi");
+    assert_eq!(cpu.sp, 0x8000);
+    // The copy in ROM is untouched; the program changed the one in RAM
+    let rom = &cpu.mem[..image.len()];
+    assert!(rom.ends_with(b"hi\0"));
+    let ram = &cpu.mem[RAM_START as usize..0x8000];
+    assert!(ram.windows(3).any(|w| w == b"Hi\0"));
+}
+
+#[test]
+fn boot_rom_calls_through_restarts() {
+    let source = "PROC main()\nPutD('a')\nPrintB(5)\nPrint(\"c\")\nPrintE()\nRETURN\n";
+    let options = RuntimeOptions { rst_calls: true, ..Default::default() };
+    let image = compile_boot_rom(source, 0x0000, &options).unwrap();
+    let plain = compile_boot_rom(source, 0x0000, &RuntimeOptions::default()).unwrap();
+    // Each RST saves 2 bytes, less the RET the plain build saves by jumping to PrintE
+    assert_eq!(plain.len() - image.len(), 4 * 2 - 1);
+    for rst in [0xCF, 0xD7, 0xDF, 0xE7] {
+        assert!(image[BOOT_RUNTIME_START as usize..].contains(&rst));
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.load(0x0000, &image);
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(MAX_CYCLES)), StopReason::Halted);
+    assert_eq!(console.output, b"a5c\r\n");
+}
+
+#[test]
+fn non_reentrant_routines_are_builtins() {
+    let (_, symbols) = crate::runtime::generate_runtime(ORG + 3);
+    for name in super::NON_REENTRANT {
+        assert!(symbols.get_function(name, crate::token::CaseMode::Strict).is_some(), "{}", name);
+        assert!(!super::is_reentrant(name));
+    }
+    assert!(super::is_reentrant("PrintB"));
+}
+
+// Runtime routines called on their own: the runtime alone is loaded at RUNTIME_BASE and
+// the routine returns to a HALT at 0000
+
+const RUNTIME_BASE: u16 = 0x0100;
+
+// A CPU about to enter a routine, with the console selected
+fn enter(routine: fn(&RuntimeSymbols) -> u16) -> Cpu {
+    enter_with(&RuntimeOptions::default(), routine)
+}
+
+fn enter_with(options: &RuntimeOptions, routine: fn(&RuntimeSymbols) -> u16) -> Cpu {
+    let (code, symbols) = generate_runtime_with_options(RUNTIME_BASE, options);
+    let mut cpu = Cpu::new();
+    cpu.load(RUNTIME_BASE, &code);
+    cpu.load(0x0000, &[0x76]);  // HALT
+    let (data, status) = DevicePorts::default().console;
+    cpu.load(RAM_START, &[data, status]);
+    cpu.sp = 0x7FFE;
+    cpu.write_word(cpu.sp, 0x0000);
+    cpu.pc = routine(&symbols);
+    cpu
+}
+
+// Run the routine to its return, giving its console output
+fn call(cpu: &mut Cpu) -> Vec<u8> {
+    call_typing(cpu, b"")
+}
+
+fn call_typing(cpu: &mut Cpu, keys: &[u8]) -> Vec<u8> {
+    let mut console = Console::new();
+    console.input.extend(keys);
+    assert_eq!(cpu.run(&mut console, Some(MAX_CYCLES)), StopReason::Halted);
+    console.output
+}
+
+#[test]
+fn print_b_boundaries() {
+    for (value, expected) in [(0, "0"), (7, "7"), (9, "9"), (10, "10"), (42, "42"), (99, "99"), (100, "100"), (105, "105"), (255, "255")] {
+        let mut cpu = enter(|s| s.print_b);
+        cpu.a = value;
+        assert_eq!(String::from_utf8(call(&mut cpu)).unwrap(), expected, "PrintB({})", value);
+        assert_eq!(cpu.a, value);
+    }
+}
+
+#[test]
+fn div8_edge_cases() {
+    for (dividend, divisor) in [(0u8, 1u8), (1, 1), (7, 8), (8, 8), (200, 7), (255, 1), (255, 16), (255, 255)] {
+        let mut cpu = enter(|s| s.div8);
+        cpu.a = dividend;
+        cpu.b = divisor;
+        call(&mut cpu);
+        assert_eq!((cpu.a, cpu.c), (dividend / divisor, dividend % divisor), "{} / {}", dividend, divisor);
+        assert_eq!(cpu.b, divisor);
+    }
+}
+
+#[test]
+fn div8_by_zero_never_returns() {
+    let mut cpu = enter(|s| s.div8);
+    cpu.a = 5;
+    cpu.b = 0;
+    assert_eq!(cpu.run(&mut Console::new(), Some(100_000)), StopReason::CycleLimit);
+}
+
+#[test]
+fn multiply_keeps_the_low_16_bits() {
+    for (x, y) in [(0u16, 0xFFFFu16), (3, 5), (255, 255), (0x1234, 0x10), (300, 300), (0x8000, 2), (0xFFFF, 0xFFFF)] {
+        let mut cpu = enter(|s| s.multiply);
+        cpu.set_hl(x);
+        cpu.set_de(y);
+        cpu.set_bc(0xBEEF);
+        call(&mut cpu);
+        assert_eq!(cpu.hl(), x.wrapping_mul(y), "{} * {}", x, y);
+        assert_eq!(cpu.bc(), 0xBEEF);
+    }
+}
+
+#[test]
+fn print_c_prints_all_16_bits() {
+    for (value, expected) in [(0u16, "0"), (9, "9"), (255, "255"), (256, "256"), (1234, "1234"), (10000, "10000"), (65535, "65535")] {
+        let mut cpu = enter(|s| s.print_c);
+        cpu.set_hl(value);
+        cpu.set_bc(0xBEEF);
+        cpu.set_de(0xCAFE);
+        assert_eq!(String::from_utf8(call(&mut cpu)).unwrap(), expected, "PrintC({})", value);
+        assert_eq!((cpu.hl(), cpu.bc(), cpu.de()), (value, 0xBEEF, 0xCAFE));
+    }
+}
+
+#[test]
+fn div10_covers_16_bits() {
+    for value in [0u16, 9, 10, 255, 1000, 12345, 65535] {
+        let mut cpu = enter(|s| s.div10);
+        cpu.set_hl(value);
+        cpu.set_bc(0xBEEF);
+        call(&mut cpu);
+        assert_eq!((cpu.hl(), cpu.a), (value / 10, (value % 10) as u8), "{} / 10", value);
+        assert_eq!(cpu.bc(), 0xBEEF);
+    }
+}
+
+#[test]
+fn div16_gives_quotient_and_remainder() {
+    for (dividend, divisor) in [(0u16, 1u16), (100, 7), (255, 255), (1000, 3), (65535, 10), (65535, 0x8001), (40000, 0xFFFF), (12, 0)] {
+        let mut cpu = enter(|s| s.div16);
+        cpu.set_hl(dividend);
+        cpu.set_de(divisor);
+        cpu.set_bc(0xBEEF);
+        call(&mut cpu);
+        let expected = dividend.checked_div(divisor).map_or((0xFFFF, dividend), |q| (q, dividend % divisor));
+        assert_eq!((cpu.hl(), cpu.de()), expected, "{} / {}", dividend, divisor);
+        assert_eq!(cpu.bc(), 0xBEEF);
+    }
+}
+
+#[test]
+fn str_c_writes_decimal() {
+    const BUF: u16 = 0x3000;
+    for value in [0u16, 7, 10, 255, 1000, 65535] {
+        let mut cpu = enter(|s| s.str_c);
+        cpu.load(BUF, &[0xFF; 8]);
+        cpu.set_hl(value);
+        cpu.set_de(BUF);
+        call(&mut cpu);
+        let text: Vec<u8> = (0..7).map(|i| cpu.read(BUF + i)).take_while(|&b| b != 0).collect();
+        assert_eq!(String::from_utf8(text).unwrap(), value.to_string());
+        assert_eq!((cpu.hl(), cpu.de()), (value, BUF));
+    }
+}
+
+#[test]
+fn val_c_reads_leading_digits() {
+    const BUF: u16 = 0x3000;
+    for (text, expected) in [("0", 0u16), ("42", 42), ("65535", 65535), ("65536", 0), ("123abc", 123), ("", 0), ("x1", 0)] {
+        let mut cpu = enter(|s| s.val_c);
+        cpu.load(BUF, text.as_bytes());
+        cpu.write(BUF + text.len() as u16, 0);
+        cpu.set_hl(BUF);
+        call(&mut cpu);
+        assert_eq!((cpu.hl(), cpu.a), (expected, expected as u8), "ValC({:?})", text);
+    }
+}
+
+// Run Crc16 or CheckSum over bytes, giving HL and A
+fn crc_routine(crc: Crc, routine: fn(&RuntimeSymbols) -> u16, bytes: &[u8]) -> (u16, u8) {
+    const BUF: u16 = 0x3000;
+    let options = RuntimeOptions { crc: Some(crc), ..Default::default() };
+    let mut cpu = enter_with(&options, routine);
+    cpu.load(BUF, bytes);
+    cpu.set_hl(BUF);
+    cpu.set_bc(bytes.len() as u16);
+    cpu.set_de(0xBEEF);
+    call(&mut cpu);
+    assert_eq!(cpu.de(), 0xBEEF);
+    (cpu.hl(), cpu.a)
+}
+
+#[test]
+fn crc16_matches_the_xmodem_check_value() {
+    let data: Vec<u8> = (0..=255).collect();
+    for crc in [Crc::Bitwise, Crc::Table] {
+        assert_eq!(crc_routine(crc, |s| s.crc16, b"123456789").0, 0x31C3, "{:?}", crc);
+        assert_eq!(crc_routine(crc, |s| s.crc16, b"").0, 0x0000, "{:?}", crc);
+        assert_eq!(crc_routine(crc, |s| s.crc16, &data).0, 0x7E55, "{:?}", crc);
+    }
+}
+
+#[test]
+fn check_sum_adds_bytes() {
+    let data: Vec<u8> = (0..=255).collect();
+    assert_eq!(crc_routine(Crc::Bitwise, |s| s.check_sum, &data).1, 0x80);
+    assert_eq!(crc_routine(Crc::Bitwise, |s| s.check_sum, b"").1, 0x00);
+}
+
+// Read a line with InputS into a buffer of the given size, giving the line and the echo
+fn input_s(options: &RuntimeOptions, size: u8, keys: &[u8]) -> (String, Vec<u8>) {
+    const BUFFER: u16 = 0x3000;
+    let mut cpu = enter_with(options, |s| s.input_s);
+    cpu.set_hl(BUFFER);
+    cpu.c = size;
+    let echo = call_typing(&mut cpu, keys);
+    let line: Vec<u8> = cpu.mem[BUFFER as usize..].iter().take_while(|&&b| b != 0).copied().collect();
+    assert_eq!(cpu.a as usize, line.len());
+    (String::from_utf8(line).unwrap(), echo)
+}
+
+#[test]
+fn input_s_edits_and_echoes() {
+    let (line, echo) = input_s(&RuntimeOptions::default(), 10, b"ab\x08c\x7Fd\x01\n\r");
+    assert_eq!(line, "ad");
+    assert_eq!(echo, b"ab\x08 \x08c\x08 \x08d\r\n");
+}
+
+#[test]
+fn input_s_rings_the_bell_when_full() {
+    let (line, echo) = input_s(&RuntimeOptions::default(), 3, b"abc\x08d\r");
+    assert_eq!(line, "ad");
+    assert_eq!(echo, b"ab\x07\x08 \x08d\r\n");
+}
+
+#[test]
+fn input_s_without_echo_ending_lines_with_lf() {
+    let options = RuntimeOptions { echo: false, line_end: LineEnd::Lf, ..Default::default() };
+    let (line, echo) = input_s(&options, 10, b"x\ry\n");
+    assert_eq!(line, "xy");
+    assert!(echo.is_empty());
+}
+
+#[test]
+fn charsets_translate_output() {
+    let mut reversed = [0u8; 256];
+    reversed.iter_mut().enumerate().for_each(|(code, byte)| *byte = 255 - code as u8);
+    for (charset, code, sent) in [
+        (Charset::Atascii, b'A', b'A'),
+        (Charset::Atascii, 0xC1, b'A'),  // Inverse video
+        (Charset::Atascii, 0x9B, b'\n'),  // EOL
+        (Charset::Atascii, 0xFD, 0x07),  // Bell
+        (Charset::Atascii, 0x10, b'?'),  // Graphics
+        (Charset::Atascii, b'\r', b'\r'),
+        (Charset::Petscii, 0x41, b'a'),
+        (Charset::Petscii, 0xC1, b'A'),
+        (Charset::Petscii, 0x93, 0x0C),  // Clear screen
+        (Charset::Table(Box::new(reversed)), 0x00, 0xFF),
+    ] {
+        let options = RuntimeOptions { charset: Some(charset.clone()), ..Default::default() };
+        let mut cpu = enter_with(&options, |s| s.out_char);
+        cpu.a = code;
+        cpu.set_hl(0x1234);
+        assert_eq!(call(&mut cpu), [sent], "{:?} ${:02X}", charset, code);
+        assert_eq!((cpu.a, cpu.hl()), (code, 0x1234));
+    }
+}
+
+// System consoles, with stand-ins for the system's routines that use the console ports
+
+#[test]
+fn cpm_console_goes_through_the_bdos() {
+    let options = RuntimeOptions { console: ConsoleBackend::Cpm, ..Default::default() };
+    let bdos: &[u8] = &[
+        0x79, 0xFE, 0x02, 0x20, 0x04,  // LD A,C  CP 2  JR NZ,input
+        0x7B, 0xD3, 0x00, 0xC9,        // LD A,E  OUT (0),A  RET
+        0xDB, 0x01, 0xE6, 0x01, 0xC8,  // input: IN A,(1)  AND 1  RET Z
+        0xDB, 0x00, 0xC9,              // IN A,(0)  RET
+    ];
+    let mut cpu = enter_with(&options, |s| s.input_s);
+    cpu.load(0x0005, bdos);
+    cpu.set_hl(0x3000);
+    cpu.c = 10;
+    assert_eq!(call_typing(&mut cpu, b"hi\r"), b"hi\r\n");
+    assert_eq!(&cpu.mem[0x3000..0x3003], b"hi\0");
+}
+
+#[test]
+fn msx_console_goes_through_the_bios() {
+    let options = RuntimeOptions { console: ConsoleBackend::Msx, ..Default::default() };
+    let mut cpu = enter_with(&options, |s| s.input_s);
+    cpu.load(0x0080, &[0xDB, 0x01, 0xE6, 0x01, 0x28, 0xFA, 0xDB, 0x00, 0xC9]);  // Waiting IN
+    cpu.load(0x009F, &[0xC3, 0x80, 0x00]);  // CHGET
+    cpu.load(0x00A2, &[0xD3, 0x00, 0xC9]);  // CHPUT
+    cpu.set_hl(0x3000);
+    cpu.c = 10;
+    assert_eq!(call_typing(&mut cpu, b"ok\x08\r"), b"ok\x08 \x08\r\n");
+    assert_eq!(&cpu.mem[0x3000..0x3002], b"o\0");
+}
+
+#[test]
+fn zx_console_prints_through_rst_10h_and_reads_last_k() {
+    let options = RuntimeOptions { console: ConsoleBackend::Zx, ..Default::default() };
+    let mut cpu = enter_with(&options, |s| s.print_e);
+    cpu.load(0x0010, &[0xD3, 0x00, 0xC9]);
+    assert_eq!(call(&mut cpu), b"\r");
+
+    // DELETE is the backspace other consoles send
+    let mut cpu = enter_with(&options, |s| s.in_char);
+    cpu.load(0x5C08, &[0x0C]);
+    cpu.load(0x5C3B, &[0x20]);
+    call(&mut cpu);
+    assert_eq!(cpu.a, 0x08);
+    assert_eq!(cpu.mem[0x5C3B], 0x00);
+}
+
+#[test]
+fn system_consoles_leave_ram_to_the_program() {
+    let options = RuntimeOptions { console: ConsoleBackend::Cpm, ram_start: 0x8000, ..Default::default() };
+    let (_, symbols) = generate_runtime_with_options(RUNTIME_BASE, &options);
+    assert_eq!(symbols.ram_end, 0x8002);
+    assert_eq!(symbols.uart_init, 0);
+}
+
+#[test]
+fn assembler_fills_in_labels() {
+    use super::asm::{Asm, Cond, R16, R8};
+    let mut a = Asm::new(0x1000);
+    let skip = a.label();
+    let table = a.label();
+    let top = a.here();
+    a.jr_if(Cond::Z, skip);
+    a.ld_nn(R16::HL, table);
+    a.djnz(top);
+    a.bind(skip);
+    a.ld(R8::A, R8::M);
+    a.call(0x1234);
+    a.bind(table);
+    a.bytes(&[0xAA]);
+    assert_eq!(a.finish(), [
+        0x28, 0x05,        // JR Z, skip
+        0x21, 0x0B, 0x10,  // LD HL, table
+        0x10, 0xF9,        // DJNZ top
+        0x7E,              // skip: LD A, (HL)
+        0xCD, 0x34, 0x12,  // CALL $1234
+        0xAA,              // table
+    ]);
+}
+
+#[test]
+#[should_panic(expected = "runtime jump out of range")]
+fn assembler_rejects_a_relative_jump_out_of_range() {
+    use super::asm::Asm;
+    let mut a = Asm::new(0x1000);
+    let far = a.label();
+    a.jr(far);
+    a.bytes(&[0x00; 128]);
+    a.bind(far);
+    a.finish();
+}
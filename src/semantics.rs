@@ -0,0 +1,445 @@
+// Checks on a parsed program before code generation: every name it uses is defined
+// and used as what it is, calls give the program's procedures the arguments they
+// take, and statements are only where they can be. All the errors are found, each
+// with its line, rather than the first one code generation runs into.
+//
+// Expressions are typed as Action! promotes them: an operation on two BYTEs is a
+// BYTE, one with an INT is an INT, and otherwise it is a CARD; a constant takes the
+// type of the other operand when it fits. Storing a CARD or INT where a BYTE goes,
+// which drops the high byte, and comparing an INT with a BYTE or CARD, which mixes
+// signed and unsigned order, are type mismatches. Comparisons and the logical and
+// bit operations give a BYTE, and so does n MOD 256, the way to store a word in one.
+// The types go in a side table over the program's expression arena, each procedure's
+// worked out once its locals are known, going through its IDs in reverse so that
+// operands come before what uses them.
+//
+// Names are compared by their symbol key under the compiler's case policy. Which
+// module may see a PRIVATE name is left to the code generator.
+//
+// An INTERRUPT procedure runs between any two instructions of the main code, so a
+// built-in that keeps state in fixed RAM or switches the device is unsafe for both to
+// call; that is a warning rather than an error, as the program may keep interrupts
+// off around the main code's calls.
+
+use crate::ast::arena::{ExprArena, ExprId, SideTable};
+use crate::ast::calls::{self, CallGraph};
+use crate::ast::{DataType, Expression, Parameter, Procedure, Program, Statement};
+use crate::error::CompileError;
+use crate::codegen;
+use crate::runtime::{self, RuntimeSymbols};
+use crate::symbols::SymbolFile;
+use crate::token::CaseMode;
+use std::collections::HashMap;
+
+// What a name stands for
+#[derive(Debug, Clone)]
+enum Symbol {
+    Variable(DataType),
+    Procedure { params: Vec<Parameter>, returns: Option<DataType> },
+}
+
+// The static type of an expression
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Byte,
+    Card,   // Also addresses: pointers, arrays and strings
+    Int,
+    Const(i32),  // Of whatever type the value fits
+    Unknown,     // Builtins and names in error, which match anything
+}
+
+impl Type {
+    fn of(data_type: &DataType) -> Type {
+        match data_type {
+            DataType::Byte | DataType::Char => Type::Byte,
+            DataType::Int => Type::Int,
+            _ => Type::Card,
+        }
+    }
+
+    // The type an operation on the two gives
+    fn promote(self, other: Type) -> Type {
+        match (self, other) {
+            (Type::Unknown, _) | (_, Type::Unknown) => Type::Unknown,
+            (Type::Const(n), t) | (t, Type::Const(n)) => t.widen(n),
+            (Type::Int, _) | (_, Type::Int) => Type::Int,
+            (Type::Card, _) | (_, Type::Card) => Type::Card,
+            _ => Type::Byte,
+        }
+    }
+
+    // The type that holds self's values and n
+    fn widen(self, n: i32) -> Type {
+        match self {
+            Type::Byte if n > 255 => Type::Card,
+            Type::Byte | Type::Card if n < 0 => Type::Int,
+            Type::Const(m) => Type::Byte.widen(m).promote(Type::Byte.widen(n)),
+            t => t,
+        }
+    }
+
+    fn is_unsigned(self) -> bool {
+        matches!(self, Type::Byte | Type::Card)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Type::Byte => "BYTE",
+            Type::Card => "CARD",
+            Type::Int => "INT",
+            Type::Const(n) if (0..=255).contains(&n) => "BYTE",
+            Type::Const(n) if n < 0 => "INT",
+            Type::Const(_) => "CARD",
+            Type::Unknown => "unknown",
+        }
+    }
+}
+
+/// Everything wrong with the program, in source order, given the symbols it imports
+/// from other images
+pub fn check(program: &Program, case_mode: CaseMode, imports: &[SymbolFile]) -> Vec<CompileError> {
+    let arena = ExprArena::new(program);
+    let mut checker = Checker {
+        case_mode,
+        types: SideTable::new(&arena),
+        arena,
+        globals: HashMap::new(),
+        locals: HashMap::new(),
+        proc: None,
+        line: 0,
+        loops: 0,
+        errors: Vec::new(),
+    };
+    for import in imports {
+        for var in &import.globals {
+            checker.globals.insert(case_mode.key(&var.name), Symbol::Variable(var.data_type()));
+        }
+        for proc in &import.procedures {
+            let symbol = Symbol::Procedure { params: proc.parameters(), returns: proc.return_type() };
+            checker.globals.insert(case_mode.key(&proc.name), symbol);
+        }
+    }
+    for var in &program.globals {
+        checker.globals.insert(case_mode.key(&var.name), Symbol::Variable(var.data_type.clone()));
+    }
+    for proc in program.declarations.iter().chain(&program.procedures) {
+        let symbol = Symbol::Procedure { params: proc.params.clone(), returns: proc.return_type.clone() };
+        checker.globals.insert(case_mode.key(&proc.name), symbol);
+    }
+
+    let ids = checker.arena.globals();
+    checker.infer(ids);
+    for (var, line) in program.globals.iter().zip(program.definitions.iter().filter(|d| d.global).map(|d| d.line)) {
+        if let Some(value) = &var.initial_value {
+            checker.line = line;
+            checker.check_store(&var.data_type, value, &var.name);
+        }
+    }
+    let lines = program.definitions.iter().filter(|d| !d.global).map(|d| d.line);
+    for (index, (proc, line)) in program.procedures.iter().zip(lines).enumerate() {
+        checker.line = line;
+        checker.check_procedure(index, proc);
+    }
+    checker.errors
+}
+
+/// A warning for each built-in that is not reentrant and that both an INTERRUPT
+/// procedure and the main code can call, directly or through other procedures
+pub fn interrupt_warnings(program: &Program, case_mode: CaseMode) -> Vec<String> {
+    let key = |name: &str| case_mode.key(name);
+    let graph = CallGraph::new(program, key);
+    let handlers: Vec<&Procedure> = program.procedures.iter().filter(|p| p.interrupt).collect();
+    let handler_keys: Vec<String> = handlers.iter().map(|p| key(&p.name)).collect();
+    let in_interrupt = graph.reachable(handler_keys.iter().map(String::as_str));
+    let main: Vec<String> = program.procedures.iter().map(|p| key(&p.name)).filter(|k| !in_interrupt.contains(k.as_str())).collect();
+    let in_main = graph.reachable(main.iter().map(String::as_str));
+
+    // The non-reentrant built-ins each procedure calls itself; one of the program's own
+    // of the same name replaces the built-in
+    let unsafe_calls = |proc: &Procedure| -> Vec<&'static str> {
+        calls::called(&proc.body).iter()
+            .filter(|name| !program.procedures.iter().any(|p| case_mode.matches(&p.name, name)))
+            .filter_map(|name| RuntimeSymbols::default().get_function(name, case_mode).map(|(builtin, _)| builtin))
+            .filter(|builtin| !runtime::is_reentrant(builtin))
+            .collect()
+    };
+    let main_calls: Vec<(&str, &str)> = program.procedures.iter()
+        .filter(|p| in_main.contains(key(&p.name).as_str()))
+        .flat_map(|p| unsafe_calls(p).into_iter().map(move |builtin| (builtin, p.name.as_str())))
+        .collect();
+
+    let mut warnings = Vec::new();
+    for handler in handlers {
+        let reached = graph.reachable([key(&handler.name).as_str()]);
+        let mut seen = Vec::new();
+        for proc in program.procedures.iter().filter(|p| reached.contains(key(&p.name).as_str())) {
+            for builtin in unsafe_calls(proc) {
+                if seen.contains(&builtin) {
+                    continue;
+                }
+                seen.push(builtin);
+                if let Some((_, caller)) = main_calls.iter().find(|(b, _)| *b == builtin) {
+                    warnings.push(format!("INTERRUPT {} calls {}, which is not reentrant and which main code calls too, in {}",
+                                          handler.name, builtin, caller));
+                }
+            }
+        }
+    }
+    warnings
+}
+
+struct Checker<'a> {
+    case_mode: CaseMode,
+    arena: ExprArena<'a>,
+    types: SideTable<Type>,           // Of each expression in the arena
+    globals: HashMap<String, Symbol>,
+    locals: HashMap<String, Symbol>,  // Of the procedure being checked
+    proc: Option<&'a Procedure>,
+    line: usize,                      // Of the statement being checked
+    loops: usize,                     // Loops the statement is in
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Checker<'a> {
+    // The procedure at index in the program
+    fn check_procedure(&mut self, index: usize, proc: &'a Procedure) {
+        self.proc = Some(proc);
+        self.locals.clear();
+        if proc.interrupt && (!proc.params.is_empty() || proc.return_type.is_some()) {
+            self.error(format!("INTERRUPT {} has parameters or a result, which no interrupt passes or takes", proc.name));
+        }
+        let names = proc.params.iter().map(|p| (&p.name, &p.data_type))
+            .chain(proc.locals.iter().map(|v| (&v.name, &v.data_type)));
+        for (name, data_type) in names {
+            let key = self.case_mode.key(name);
+            if self.locals.insert(key, Symbol::Variable(data_type.clone())).is_some() {
+                self.error(format!("{} has two parameters or locals named {}", proc.name, name));
+            }
+        }
+        let ids = self.arena.procedure(index);
+        self.infer(ids);
+        for local in &proc.locals {
+            // A TABLE's entry is worked out by the compiler, with its own index
+            if let Some(value) = local.initial_value.as_ref().filter(|v| v.table(self.case_mode).is_none()) {
+                self.check_expression(value);
+                self.check_store(&local.data_type, value, &local.name);
+            }
+        }
+        self.check_block(&proc.body);
+    }
+
+    fn check_block(&mut self, stmts: &'a [Statement]) {
+        for stmt in stmts {
+            self.check_statement(stmt);
+        }
+    }
+
+    fn check_statement(&mut self, stmt: &'a Statement) {
+        match stmt {
+            Statement::Line(line) => self.line = *line,
+            Statement::VarDecl(var) => {
+                self.error(format!("{} is declared after the first statement; locals come before the statements", var.name));
+            }
+            Statement::Assignment { target, value } => {
+                self.check_variable(target);
+                if let Some(Symbol::Variable(data_type)) = self.lookup(target) {
+                    self.check_store(&data_type, value, target);
+                }
+            }
+            Statement::ArrayAssignment { array, value, .. } => {
+                self.check_variable(array);
+                if let Some(Symbol::Variable(data_type)) = self.lookup(array) {
+                    self.check_store(&element(&data_type), value, &format!("an element of {}", array));
+                }
+            }
+            Statement::PointerAssignment { pointer, value } => {
+                if let Some(DataType::Pointer(target)) = self.pointer_type(pointer) {
+                    self.check_store(&target, value, &format!("what {} points to", pointer.name().unwrap_or_default()));
+                }
+            }
+            Statement::For { var, start, end, step, .. } => {
+                self.check_variable(var);
+                if let Some(Symbol::Variable(data_type)) = self.lookup(var) {
+                    for bound in [Some(start), Some(end), step.as_ref()].into_iter().flatten() {
+                        self.check_store(&data_type, bound, var);
+                    }
+                }
+            }
+            Statement::ProcCall { name, args } => self.check_call(name, args, false),
+            Statement::Exit if self.loops == 0 => self.error("EXIT is not in a loop".to_string()),
+            Statement::Return(Some(value)) => match self.proc.map(|p| (p, &p.return_type)) {
+                Some((proc, None)) => self.error(format!("PROC {} returns a value; only a FUNC can", proc.name)),
+                Some((proc, Some(data_type))) => self.check_store(data_type, value, &format!("the result of {}", proc.name)),
+                None => {}
+            },
+            _ => {}
+        }
+        for expr in stmt.expressions() {
+            self.check_expression(expr);
+        }
+        let is_loop = matches!(stmt, Statement::While { .. } | Statement::Until { .. } | Statement::For { .. });
+        self.loops += is_loop as usize;
+        for nested in stmt.nested() {
+            self.check_statement(nested);
+        }
+        self.loops -= is_loop as usize;
+    }
+
+    fn check_expression(&mut self, expr: &'a Expression) {
+        match expr {
+            Expression::Variable(name) | Expression::AddressOf(name) | Expression::ArrayAccess { array: name, .. } => {
+                self.check_variable(name);
+            }
+            Expression::FunctionCall { name, args } => self.check_call(name, args, true),
+            Expression::Equal(l, r) | Expression::NotEqual(l, r) | Expression::Less(l, r)
+            | Expression::LessEqual(l, r) | Expression::Greater(l, r) | Expression::GreaterEqual(l, r) => {
+                let (left, right) = (self.type_of(l), self.type_of(r));
+                if left == Type::Int && right.is_unsigned() || left.is_unsigned() && right == Type::Int {
+                    self.mismatch(format!("{} on both sides of the comparison", left.name()), right.name().to_string());
+                }
+            }
+            _ => {}
+        }
+        for child in expr.children() {
+            self.check_expression(child);
+        }
+    }
+
+    // A use of name as a variable
+    fn check_variable(&mut self, name: &str) {
+        match self.lookup(name) {
+            Some(Symbol::Variable(_)) => {}
+            Some(Symbol::Procedure { .. }) => self.error(format!("{} is a procedure, not a variable", name)),
+            None => self.error(format!("{} is not defined", name)),
+        }
+    }
+
+    // A call of name, for its value if in_expression; name(i) on an ARRAY is an element
+    fn check_call(&mut self, name: &str, args: &[Expression], in_expression: bool) {
+        let builtin = self.case_mode.matches("PrintF", name)
+            || codegen::intrinsic(name, self.case_mode).is_some()
+            || RuntimeSymbols::default().get_function(name, self.case_mode).is_some();
+        match self.lookup(name) {
+            Some(Symbol::Variable(DataType::ByteArray(_) | DataType::CardArray(_) | DataType::IntArray(_)))
+                if in_expression && args.len() == 1 => {}
+            Some(Symbol::Variable(_)) => self.error(format!("{} is a variable, not a procedure", name)),
+            Some(Symbol::Procedure { .. }) | None if builtin => {}
+            Some(Symbol::Procedure { params, returns }) => {
+                if args.len() != params.len() {
+                    self.error(format!("{} expects {} arguments, found {}", name, params.len(), args.len()));
+                }
+                if in_expression && returns.is_none() {
+                    self.error(format!("{} is a PROC, so it has no value to use", name));
+                }
+                for (param, arg) in params.iter().zip(args).filter(|(p, _)| !p.by_ref) {
+                    self.check_store(&param.data_type, arg, &format!("{} of {}", param.name, name));
+                }
+            }
+            None => self.error(format!("{} is not defined", name)),
+        }
+    }
+
+    // A store of value into what, of data_type; a constant may be anything the
+    // high byte of which is 0 or all ones
+    fn check_store(&mut self, data_type: &DataType, value: &Expression, what: &str) {
+        if Type::of(data_type) != Type::Byte {
+            return;
+        }
+        match self.type_of(value) {
+            found @ (Type::Card | Type::Int) => self.mismatch(format!("BYTE for {}", what), found.name().to_string()),
+            Type::Const(n) if !(-128..=255).contains(&n) => self.mismatch(format!("BYTE for {}", what), n.to_string()),
+            _ => {}
+        }
+    }
+
+    // Type the expressions of ids, which are all those of a procedure or of the globals
+    fn infer(&mut self, ids: impl DoubleEndedIterator<Item = ExprId>) {
+        for id in ids.rev() {
+            let found = self.infer_type(self.arena.get(id));
+            self.types.insert(id, found);
+        }
+    }
+
+    fn type_of(&self, expr: &Expression) -> Type {
+        self.arena.id(expr).and_then(|id| self.types.get(id)).copied().unwrap_or(Type::Unknown)
+    }
+
+    // The type of expr from those of its operands, which are already in the table
+    fn infer_type(&self, expr: &Expression) -> Type {
+        if let Some(n) = expr.const_value() {
+            return Type::Const(n);
+        }
+        let symbol_type = |name: &str| match self.lookup(name) {
+            Some(Symbol::Variable(data_type)) => Some(data_type),
+            _ => None,
+        };
+        match expr {
+            Expression::String(_) | Expression::Bytes(_) | Expression::AddressOf(_) => Type::Card,
+            Expression::Variable(name) => symbol_type(name).map_or(Type::Unknown, |t| Type::of(&t)),
+            Expression::ArrayAccess { array, .. } => symbol_type(array).map_or(Type::Unknown, |t| Type::of(&element(&t))),
+            Expression::FunctionCall { name, .. } => match self.lookup(name) {
+                Some(Symbol::Variable(data_type)) => Type::of(&element(&data_type)),
+                Some(Symbol::Procedure { returns: Some(data_type), .. }) => Type::of(&data_type),
+                _ if codegen::intrinsic(name, self.case_mode).is_some() => Type::Byte,
+                _ => Type::Unknown,
+            },
+            Expression::Dereference(pointer) => match self.pointer_type(pointer) {
+                Some(DataType::Pointer(target)) => Type::of(&target),
+                _ => Type::Unknown,
+            },
+            Expression::Negate(value) => match self.type_of(value) {
+                Type::Unknown => Type::Unknown,
+                _ => Type::Int,
+            },
+            Expression::Not(value) => self.type_of(value),
+            Expression::LeftShift(value, _) | Expression::RightShift(value, _) => match self.type_of(value) {
+                Type::Const(_) => Type::Byte,
+                t => t,
+            },
+            // The remainder of a division by at most 256 fits a byte, the way to store a word in one
+            Expression::Modulo(_, r) if r.const_value().is_some_and(|n| (1..=256).contains(&n)) => Type::Byte,
+            Expression::Equal(..) | Expression::NotEqual(..) | Expression::Less(..)
+            | Expression::LessEqual(..) | Expression::Greater(..) | Expression::GreaterEqual(..)
+            | Expression::And(..) | Expression::Or(..) | Expression::Xor(..)
+            | Expression::BitAnd(..) | Expression::BitOr(..) | Expression::BitXor(..) => Type::Byte,
+            _ => {
+                let children = expr.children();
+                self.type_of(children[0]).promote(self.type_of(children[1]))
+            }
+        }
+    }
+
+    // The type of a pointer expression, when it names a pointer variable
+    fn pointer_type(&self, pointer: &Expression) -> Option<DataType> {
+        match self.lookup(pointer.name()?) {
+            Some(Symbol::Variable(data_type @ DataType::Pointer(_))) if matches!(pointer, Expression::Variable(_)) => Some(data_type),
+            _ => None,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        let key = self.case_mode.key(name);
+        self.locals.get(&key).or_else(|| self.globals.get(&key)).cloned()
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(CompileError::SemanticError { line: self.line, message });
+    }
+
+    fn mismatch(&mut self, expected: String, found: String) {
+        self.errors.push(CompileError::TypeMismatch { line: self.line, expected, found });
+    }
+}
+
+// The type of an array's elements, or the type itself for anything else
+fn element(data_type: &DataType) -> DataType {
+    match data_type {
+        DataType::ByteArray(_) => DataType::Byte,
+        DataType::CardArray(_) => DataType::Card,
+        DataType::IntArray(_) => DataType::Int,
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests;
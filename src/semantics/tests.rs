@@ -0,0 +1,138 @@
+// Programs checked before code generation, with the errors found in them
+
+use super::*;
+use crate::test_support::parse;
+
+fn errors(source: &str) -> Vec<String> {
+    check(&parse(source).unwrap(), CaseMode::Insensitive, &[]).iter().map(|e| e.to_string()).collect()
+}
+
+#[test]
+fn correct_programs_pass() {
+    let source = "\
+BYTE ARRAY buffer(4)
+CARD total
+FUNC BYTE twice(BYTE n)
+RETURN (n * 2)
+PROC main()
+BYTE i
+FOR i = 0 TO 3 DO
+  buffer(i) = twice(i)
+  IF i = 2 THEN EXIT FI
+OD
+total = buffer(1) + @total
+later(total)
+PrintB(GetD())
+RETURN
+PROC later(CARD c)
+PrintC(c)
+RETURN
+";
+    assert!(errors(source).is_empty(), "{:?}", errors(source));
+}
+
+#[test]
+fn every_error_is_found_with_its_line() {
+    let source = "\
+BYTE x
+PROC show(BYTE n)
+RETURN
+PROC main()
+show()
+y = 2
+EXIT
+x = show(1) + Missing(2)
+RETURN (x)
+";
+    assert_eq!(errors(source), [
+        "Error at line 5: show expects 1 arguments, found 0",
+        "Error at line 6: y is not defined",
+        "Error at line 7: EXIT is not in a loop",
+        "Error at line 8: show is a PROC, so it has no value to use",
+        "Error at line 8: Missing is not defined",
+        "Error at line 9: PROC main returns a value; only a FUNC can",
+    ]);
+}
+
+#[test]
+fn names_are_used_as_what_they_are() {
+    let source = "\
+BYTE count
+PROC tick(BYTE count, BYTE count)
+count()
+RETURN
+PROC main()
+tick = 1
+RETURN
+";
+    assert_eq!(errors(source), [
+        "Error at line 2: tick has two parameters or locals named count",
+        "Error at line 3: count is a variable, not a procedure",
+        "Error at line 6: tick is a procedure, not a variable",
+    ]);
+}
+
+#[test]
+fn types_are_promoted_and_mismatches_found() {
+    let source = "\
+BYTE b = 300
+CARD c
+INT i
+BYTE ARRAY bytes(4)
+FUNC BYTE low(CARD n)
+RETURN (n)
+PROC show(BYTE x)
+RETURN
+PROC main()
+b = b + 1
+b = c MOD 256 + 'A'
+i = -c
+c = b * 300
+b = c
+bytes(0) = c + 1
+show(c)
+b = low(c)
+IF i < 10 THEN b = 1 FI
+IF i < c THEN b = 2 FI
+IF b >= i OR c = 0 THEN b = 3 FI
+RETURN
+";
+    assert_eq!(errors(source), [
+        "Type mismatch at line 1: expected BYTE for b, found 300",
+        "Type mismatch at line 6: expected BYTE for the result of low, found CARD",
+        "Type mismatch at line 14: expected BYTE for b, found CARD",
+        "Type mismatch at line 15: expected BYTE for an element of bytes, found CARD",
+        "Type mismatch at line 16: expected BYTE for x of show, found CARD",
+        "Type mismatch at line 19: expected INT on both sides of the comparison, found CARD",
+        "Type mismatch at line 20: expected BYTE on both sides of the comparison, found INT",
+    ]);
+}
+
+#[test]
+fn interrupts_and_main_code_sharing_a_builtin_that_is_not_reentrant() {
+    let source = "\
+BYTE ticks
+PROC Log()
+LPrintB(ticks)
+RETURN
+PROC Tick() INTERRUPT
+ticks = ticks + 1
+Log()
+PrintB(ticks)
+RETURN
+PROC main()
+Log()
+PrintB(ticks)
+RETURN
+";
+    let program = parse(source).unwrap();
+    assert_eq!(interrupt_warnings(&program, CaseMode::Insensitive),
+               ["INTERRUPT Tick calls LPrintB, which is not reentrant and which main code calls too, in Log"]);
+
+    // Only the interrupt calling it is safe
+    let program = parse(&source.replace("PROC main()\nLog()", "PROC main()")).unwrap();
+    assert!(interrupt_warnings(&program, CaseMode::Insensitive).is_empty());
+
+    assert_eq!(errors("PROC Tick(BYTE b) INTERRUPT\nRETURN\nPROC main()\nRETURN\n"),
+               ["Error at line 1: INTERRUPT Tick has parameters or a result, which no interrupt passes or takes"]);
+}
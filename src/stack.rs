@@ -0,0 +1,231 @@
+// Stack each procedure and runtime routine uses, found by following its code.
+//
+// Every path from a routine's entry is decoded up to its returns, counting the bytes
+// pushed below its return address, and calls add the return address and what the
+// routine called uses in turn. A stack frame is set up with LD SP,HL after ADD HL,SP
+// and dropped with LD SP,IX after ADD IX,SP, so the values those two registers were
+// given relative to SP are tracked. A path is followed again each time it comes to an
+// instruction with the stack at another depth, so a loop that pushes on each pass
+// would be followed without end: one whose passes are bounded is given with the most
+// it pushes, and any other leaves the depth unknown, as do anything else that moves
+// SP and a jump that cannot be followed. A loop that pops on each pass is followed
+// until it would pop the return address.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::clobber::{decode, Flow, Registers};
+
+/// Bytes a routine may put on the stack below its return address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    Bytes(u16),
+    AtLeast(u16),  // Besides what routines outside the image use
+    Recursive,     // No bound: it calls itself, directly or not
+    Unknown,       // It moves SP in a way that cannot be followed
+}
+
+impl Depth {
+    // The deeper of two paths; a recursive one is the worst, and unknowns come next
+    fn max(self, other: Depth) -> Depth {
+        match (self, other) {
+            (Depth::Recursive, _) | (_, Depth::Recursive) => Depth::Recursive,
+            (Depth::Unknown, _) | (_, Depth::Unknown) => Depth::Unknown,
+            (Depth::Bytes(a), Depth::Bytes(b)) => Depth::Bytes(a.max(b)),
+            (Depth::Bytes(a) | Depth::AtLeast(a), Depth::Bytes(b) | Depth::AtLeast(b)) => Depth::AtLeast(a.max(b)),
+        }
+    }
+
+    // This deeper by bytes
+    fn plus(self, bytes: u16) -> Depth {
+        match self {
+            Depth::Bytes(n) => Depth::Bytes(n.saturating_add(bytes)),
+            Depth::AtLeast(n) => Depth::AtLeast(n.saturating_add(bytes)),
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for Depth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Depth::Bytes(n) => f.pad(&n.to_string()),
+            Depth::AtLeast(n) => f.pad(&format!("{}+", n)),
+            Depth::Recursive => f.pad("recursive"),
+            Depth::Unknown => f.pad("unknown"),
+        }
+    }
+}
+
+/// Stack a routine uses: in its own code, counting the return address of each call,
+/// and in all, with what the routines it calls use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub own: Depth,
+    pub worst: Depth,
+}
+
+// A register pair's value, where it is known to be an address on the stack
+#[derive(Clone, Copy, PartialEq)]
+enum Value {
+    Constant(u16),
+    Stack(i32),  // SP as it is at this depth
+    Unknown,
+}
+
+// What an instruction does to SP and the register pairs a frame is set up with
+enum Effect {
+    Depth(i32),       // PUSH, POP, INC SP, DEC SP
+    LoadHl(u16),      // LD HL,nn
+    LoadIx(u16),      // LD IX,nn
+    AddSpToHl,        // ADD HL,SP
+    AddSpToIx,        // ADD IX,SP
+    SpFromHl,         // LD SP,HL
+    SpFromIx,         // LD SP,IX
+    SpElsewhere,      // LD SP,nn and the like
+    Other,
+}
+
+fn effect(fetch: impl Fn(u16) -> Option<u8>, pc: u16) -> Effect {
+    let word = |at: u16| fetch(at).zip(fetch(at.wrapping_add(1))).map(|(lo, hi)| u16::from_le_bytes([lo, hi]));
+    match fetch(pc) {
+        Some(0xC5 | 0xD5 | 0xE5 | 0xF5) => Effect::Depth(2),
+        Some(0xC1 | 0xD1 | 0xE1 | 0xF1) => Effect::Depth(-2),
+        Some(0x33) => Effect::Depth(-1),
+        Some(0x3B) => Effect::Depth(1),
+        Some(0x21) => word(pc.wrapping_add(1)).map_or(Effect::Other, Effect::LoadHl),
+        Some(0x39) => Effect::AddSpToHl,
+        Some(0xF9) => Effect::SpFromHl,
+        Some(0x31) => Effect::SpElsewhere,
+        Some(0xED) if fetch(pc.wrapping_add(1)) == Some(0x7B) => Effect::SpElsewhere,  // LD SP,(nn)
+        Some(prefix @ (0xDD | 0xFD)) => match fetch(pc.wrapping_add(1)) {
+            Some(0xE5) => Effect::Depth(2),
+            Some(0xE1) => Effect::Depth(-2),
+            Some(0x21) if prefix == 0xDD => word(pc.wrapping_add(2)).map_or(Effect::Other, Effect::LoadIx),
+            Some(0x39) if prefix == 0xDD => Effect::AddSpToIx,
+            Some(0xF9) if prefix == 0xDD => Effect::SpFromIx,
+            Some(0xF9 | 0x31) => Effect::SpElsewhere,
+            _ => Effect::Other,
+        },
+        _ => Effect::Other,
+    }
+}
+
+/// Stack used by routines in an image, remembered per entry address
+pub struct Analysis<'a> {
+    image: &'a [u8],
+    base: u16,
+    done: HashMap<u16, Usage>,
+    in_progress: HashSet<u16>,
+    loops: HashMap<u16, u16>,  // Loop start -> most bytes pushed on coming back round to it
+}
+
+// Deepest a path goes before it is taken for a loop that runs away
+const RUNAWAY: i32 = 0x1000;
+
+impl<'a> Analysis<'a> {
+    /// Analysis of an image loaded at base
+    pub fn new(image: &'a [u8], base: u16) -> Self {
+        Analysis { image, base, done: HashMap::new(), in_progress: HashSet::new(), loops: HashMap::new() }
+    }
+
+    /// Take the loop starting at head to push at most bytes before it comes back round
+    pub fn bound_loop(&mut self, head: u16, bytes: u16) {
+        self.loops.insert(head, bytes);
+    }
+
+    fn fetch(&self, addr: u16) -> Option<u8> {
+        self.image.get(addr.checked_sub(self.base)? as usize).copied()
+    }
+
+    /// Stack the routine at entry may use before it returns
+    pub fn usage(&mut self, entry: u16) -> Usage {
+        if let Some(&usage) = self.done.get(&entry) {
+            return usage;
+        }
+        if !self.in_progress.insert(entry) {
+            return Usage { own: Depth::Recursive, worst: Depth::Recursive };
+        }
+        let usage = self.follow(entry);
+        self.in_progress.remove(&entry);
+        self.done.insert(entry, usage);
+        usage
+    }
+
+    fn follow(&mut self, entry: u16) -> Usage {
+        let unknown = Usage { own: Depth::Unknown, worst: Depth::Unknown };
+        let mut own = 0;
+        let mut worst = Depth::Bytes(0);
+        let mut seen: HashSet<(u16, i32)> = HashSet::new();
+        let mut entered: HashMap<u16, i32> = HashMap::new();  // Depth on first coming to a bounded loop
+        let mut pending = vec![(entry, 0, Value::Unknown, Value::Unknown)];
+        while let Some((pc, depth, mut hl, mut ix)) = pending.pop() {
+            if !seen.insert((pc, depth)) {
+                continue;
+            }
+            if let Some(&bytes) = self.loops.get(&pc) {
+                if depth > *entered.entry(pc).or_insert(depth) + bytes as i32 {
+                    continue;  // More passes than the loop makes
+                }
+            }
+            if depth < 0 {
+                continue;  // Into the return address, as a loop that pops goes once it has popped all it pushed
+            }
+            if depth > RUNAWAY {
+                return unknown;
+            }
+            let Some((len, written, flow)) = decode(|addr| self.fetch(addr), pc) else {
+                return unknown;
+            };
+            let mut depth = depth;
+            let at_depth = |value: Value, depth: i32| match value {
+                Value::Constant(n) => Value::Stack(depth - n as i16 as i32),
+                _ => Value::Unknown,
+            };
+            let effect = effect(|addr| self.fetch(addr), pc);
+            match effect {
+                Effect::Depth(bytes) => depth += bytes,
+                Effect::LoadHl(n) => hl = Value::Constant(n),
+                Effect::LoadIx(n) => ix = Value::Constant(n),
+                Effect::AddSpToHl => hl = at_depth(hl, depth),
+                Effect::AddSpToIx => ix = at_depth(ix, depth),
+                Effect::SpFromHl | Effect::SpFromIx => {
+                    let Value::Stack(at) = (if matches!(effect, Effect::SpFromHl) { hl } else { ix }) else {
+                        return unknown;
+                    };
+                    depth = at;
+                }
+                Effect::SpElsewhere => return unknown,
+                Effect::Other => {}
+            }
+            if written.intersects(Registers::HL) && !matches!(effect, Effect::LoadHl(_) | Effect::AddSpToHl) {
+                hl = Value::Unknown;
+            }
+            own = own.max(depth);
+
+            let next = pc.wrapping_add(len);
+            match flow {
+                Flow::Next => pending.push((next, depth, hl, ix)),
+                Flow::Jump(target) => pending.push((target, depth, hl, ix)),
+                Flow::Branch(target) => pending.extend([(target, depth, hl, ix), (next, depth, hl, ix)]),
+                Flow::Call(target) => {
+                    own = own.max(depth + 2);
+                    let called = if self.fetch(target).is_some() {
+                        self.usage(target).worst
+                    } else {
+                        Depth::AtLeast(0)  // A system routine
+                    };
+                    worst = worst.max(called.plus(depth.max(0) as u16 + 2));
+                    pending.push((next, depth, Value::Unknown, ix));
+                }
+                Flow::Return => {}
+                Flow::Unknown => return unknown,
+            }
+        }
+        let own = Depth::Bytes(own.max(0) as u16);
+        Usage { own, worst: worst.max(own) }
+    }
+}
+
+#[cfg(test)]
+mod tests;
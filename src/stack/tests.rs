@@ -0,0 +1,94 @@
+// Stack use of hand-assembled routines and compiled procedures
+
+use super::*;
+use crate::compile::{compile_source, CompileOptions};
+
+#[test]
+fn counts_pushes_and_calls() {
+    let code = [
+        0xC5,              // 4200: PUSH BC
+        0x28, 0x04,        // 4201: JR Z, +4
+        0xD5,              // 4203: PUSH DE
+        0xCD, 0x0B, 0x42,  // 4204: CALL 420B
+        0xD1,              // 4207: POP DE
+        0xC1,              // 4208: POP BC
+        0xC9,              // 4209: RET
+        0x00,              // 420A: (skipped)
+        0xE5,              // 420B: PUSH HL
+        0xE1,              // 420C: POP HL
+        0xC9,              // 420D: RET
+    ];
+    let mut analysis = Analysis::new(&code, 0x4200);
+    assert_eq!(analysis.usage(0x420B), Usage { own: Depth::Bytes(2), worst: Depth::Bytes(2) });
+    assert_eq!(analysis.usage(0x4200), Usage { own: Depth::Bytes(6), worst: Depth::Bytes(8) });
+}
+
+#[test]
+fn follows_stack_frames() {
+    let code = [
+        0xDD, 0xE5,              // 4200: PUSH IX
+        0xDD, 0x21, 0x00, 0x00,  // 4202: LD IX, 0
+        0xDD, 0x39,              // 4206: ADD IX, SP
+        0x21, 0xFA, 0xFF,        // 4208: LD HL, -6
+        0x39,                    // 420B: ADD HL, SP
+        0xF9,                    // 420C: LD SP, HL
+        0xC5,                    // 420D: PUSH BC
+        0xC1,                    // 420E: POP BC
+        0xDD, 0xF9,              // 420F: LD SP, IX
+        0xDD, 0xE1,              // 4211: POP IX
+        0xC9,                    // 4213: RET
+    ];
+    assert_eq!(Analysis::new(&code, 0x4200).usage(0x4200).worst, Depth::Bytes(10));
+}
+
+#[test]
+fn loops_that_push_need_a_bound() {
+    let code = [
+        0x06, 0x05,  // 4200: LD B, 5
+        0xF5,        // 4202: PUSH AF
+        0x10, 0xFD,  // 4203: DJNZ 4202
+        0xF1,        // 4205: POP AF
+        0x10, 0xFD,  // 4206: DJNZ 4205
+        0xC9,        // 4208: RET
+    ];
+    assert_eq!(Analysis::new(&code, 0x4200).usage(0x4200).worst, Depth::Unknown);
+    let mut analysis = Analysis::new(&code, 0x4200);
+    analysis.bound_loop(0x4202, 8);
+    assert_eq!(analysis.usage(0x4200).worst, Depth::Bytes(10));
+}
+
+#[test]
+fn recursion_and_system_calls_have_no_exact_depth() {
+    let code = [
+        0xC8,              // 4200: RET Z
+        0xCD, 0x00, 0x42,  // 4201: CALL 4200
+        0xC9,              // 4204: RET
+        0xCD, 0x05, 0x00,  // 4205: CALL 0005
+        0xC9,              // 4208: RET
+    ];
+    let mut analysis = Analysis::new(&code, 0x4200);
+    assert_eq!(analysis.usage(0x4200).worst, Depth::Recursive);
+    assert_eq!(analysis.usage(0x4205).worst, Depth::AtLeast(2));
+}
+
+#[test]
+fn report_gives_frames_and_depths() {
+    let source = "\
+PROC count(BYTE n)
+BYTE i
+IF n > 0 THEN count(n - 1) FI
+RETURN
+PROC show(BYTE a)
+PrintB(a)
+RETURN
+PROC main()
+show(1)
+count(3)
+RETURN
+";
+    let report = compile_source(source, CompileOptions::default()).unwrap().stack_report();
+    let row = |name: &str| report.lines().find(|line| line.starts_with(name)).unwrap().split_whitespace().skip(2).collect::<Vec<_>>();
+    assert_eq!(row("COUNT"), ["4", "6", "recursive"], "{}", report);
+    assert_eq!(row("SHOW"), ["-", "4", "6"], "{}", report);
+    assert!(report.ends_with("main reaches a recursive procedure.\n"), "{}", report);
+}
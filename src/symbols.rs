@@ -0,0 +1,205 @@
+// Symbol files (.json): where one build put its public procedures and globals, so a
+// build made separately can call and use them, as a program loaded into RAM does with
+// a resident library in ROM. Each file also gives the image's and the variables'
+// addresses, so the other build can keep clear of both.
+//
+//     {
+//       "image": { "start": 0, "size": 2048 },
+//       "variables": { "start": 32768, "size": 12 },
+//       "procedures": [
+//         { "name": "Beep", "address": 612, "size": 38, "params": [{ "name": "n", "type": "BYTE" }] },
+//         { "name": "Ticks", "address": 650, "size": 12, "params": [], "returns": "CARD" }
+//       ],
+//       "globals": [{ "name": "volume", "address": 32770, "type": "BYTE" }]
+//     }
+//
+// Types are written as in Action!: BYTE, CHAR, CARD, INT, BYTE ARRAY(8), or a type
+// followed by POINTER.
+
+use crate::ast::{DataType, Parameter, Program};
+use crate::compile::{CompileOptions, CompileOutput};
+use serde::{Deserialize, Serialize};
+
+/// Addresses a build takes up
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Area {
+    pub start: u16,
+    pub size: usize,
+}
+
+impl Area {
+    /// Whether the two share any address
+    pub fn overlaps(&self, other: &Area) -> bool {
+        let (a, b) = (self.start as usize, other.start as usize);
+        a < b + other.size && b < a + self.size
+    }
+
+    /// "$start-$last", for messages
+    pub fn describe(&self) -> String {
+        format!("${:04X}-${:04X}", self.start, (self.start as usize + self.size).saturating_sub(1))
+    }
+}
+
+/// A procedure another build can call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcedureSymbol {
+    pub name: String,
+    pub address: u16,
+    pub size: usize,  // Bytes of code, which a patch of the procedure may take
+    pub params: Vec<ParameterSymbol>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub returns: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fastcall: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fixed: bool,  // Placed at its address in the source, so it takes its arguments on the stack
+}
+
+/// A parameter of an exported procedure
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterSymbol {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub data_type: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub var: bool,
+}
+
+/// A global variable another build can use
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GlobalSymbol {
+    pub name: String,
+    pub address: u16,
+    #[serde(rename = "type")]
+    pub data_type: String,
+}
+
+/// The symbols of one build
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SymbolFile {
+    pub image: Area,
+    pub variables: Area,
+    pub procedures: Vec<ProcedureSymbol>,
+    pub globals: Vec<GlobalSymbol>,
+}
+
+impl SymbolFile {
+    /// The public procedures and globals of a compiled program, other than its main,
+    /// which each build has its own of
+    pub fn export(program: &Program, output: &CompileOutput, options: &CompileOptions) -> SymbolFile {
+        let codegen = &output.codegen;
+        let ram_start = options.runtime.ram_start;
+        let procedures = program.procedures.iter()
+            .filter(|proc| !codegen.is_private(&proc.name) && !options.case_mode.matches(&proc.name, "Main"))
+            .filter_map(|proc| Some(ProcedureSymbol {
+                name: proc.name.clone(),
+                address: codegen.procedure_address(&proc.name)?,
+                size: codegen.procedure_size(&proc.name)?,
+                params: proc.params.iter().map(|p| ParameterSymbol {
+                    name: p.name.clone(),
+                    data_type: type_name(&p.data_type),
+                    var: p.by_ref,
+                }).collect(),
+                returns: proc.return_type.as_ref().map(type_name),
+                fastcall: proc.fast_call,
+                fixed: proc.address.is_some(),
+            }))
+            .collect();
+        let globals = program.globals.iter()
+            .filter(|var| !codegen.is_private(&var.name))
+            .filter_map(|var| Some(GlobalSymbol {
+                name: var.name.clone(),
+                address: codegen.global_address(&var.name)?,
+                data_type: type_name(&var.data_type),
+            }))
+            .collect();
+        SymbolFile {
+            image: Area { start: output.origin, size: output.binary.len() },
+            variables: Area { start: ram_start, size: codegen.variables_end().wrapping_sub(ram_start) as usize },
+            procedures,
+            globals,
+        }
+    }
+
+    /// The symbols in the text of a symbol file
+    pub fn parse(text: &str) -> Result<SymbolFile, String> {
+        let file: SymbolFile = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let types = file.procedures.iter()
+            .flat_map(|p| p.params.iter().map(|param| &param.data_type).chain(&p.returns))
+            .chain(file.globals.iter().map(|g| &g.data_type));
+        for name in types {
+            parse_type(name).ok_or_else(|| format!("unknown type '{}'", name))?;
+        }
+        Ok(file)
+    }
+
+    /// The text of the symbol file
+    pub fn to_json(&self) -> String {
+        let mut text = serde_json::to_string_pretty(self).expect("symbols serialize");
+        text.push('\n');
+        text
+    }
+}
+
+impl ProcedureSymbol {
+    /// The parameters as the procedure declared them
+    pub fn parameters(&self) -> Vec<Parameter> {
+        self.params.iter()
+            .map(|p| Parameter { name: p.name.clone(), data_type: parse_type(&p.data_type).unwrap_or(DataType::Byte), by_ref: p.var })
+            .collect()
+    }
+
+    pub fn return_type(&self) -> Option<DataType> {
+        self.returns.as_deref().and_then(parse_type)
+    }
+}
+
+impl GlobalSymbol {
+    pub fn data_type(&self) -> DataType {
+        parse_type(&self.data_type).unwrap_or(DataType::Byte)
+    }
+}
+
+/// A type as Action! writes it
+pub fn type_name(data_type: &DataType) -> String {
+    match data_type {
+        DataType::Byte => "BYTE".to_string(),
+        DataType::Char => "CHAR".to_string(),
+        DataType::Card => "CARD".to_string(),
+        DataType::Int => "INT".to_string(),
+        DataType::ByteArray(n) => format!("BYTE ARRAY({})", n),
+        DataType::CardArray(n) => format!("CARD ARRAY({})", n),
+        DataType::IntArray(n) => format!("INT ARRAY({})", n),
+        DataType::Pointer(target) => format!("{} POINTER", type_name(target)),
+    }
+}
+
+fn parse_type(text: &str) -> Option<DataType> {
+    let text = text.trim();
+    if let Some(target) = text.strip_suffix("POINTER") {
+        return Some(DataType::Pointer(Box::new(parse_type(target)?)));
+    }
+    if let Some((element, size)) = text.split_once("ARRAY") {
+        let size = size.trim().strip_prefix('(')?.strip_suffix(')')?.trim().parse().ok()?;
+        return match element.trim() {
+            "BYTE" | "CHAR" => Some(DataType::ByteArray(size)),
+            "CARD" => Some(DataType::CardArray(size)),
+            "INT" => Some(DataType::IntArray(size)),
+            _ => None,
+        };
+    }
+    match text {
+        "BYTE" => Some(DataType::Byte),
+        "CHAR" => Some(DataType::Char),
+        "CARD" => Some(DataType::Card),
+        "INT" => Some(DataType::Int),
+        _ => None,
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[cfg(test)]
+mod tests;
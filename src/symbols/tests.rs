@@ -0,0 +1,89 @@
+// Symbol files written by one build and read by another
+
+use super::*;
+use crate::compile::{compile_source, CompileOptions};
+use crate::emulator::{Console, Cpu, StopReason};
+use crate::runtime::RuntimeOptions;
+
+// A resident library at $0000 with its variables at $3000
+const ROM: &str = "\
+BYTE count
+PRIVATE BYTE spare
+PROC Stars(BYTE n)
+count = count + n
+WHILE n > 0 DO PutD('*') n = n - 1 OD
+RETURN
+FUNC CARD Twice(CARD c)
+RETURN (c + c)
+PROC main()
+RETURN
+";
+
+fn rom() -> (SymbolFile, Vec<u8>) {
+    let options = CompileOptions {
+        origin: 0x0000,
+        runtime: RuntimeOptions { ram_start: 0x3000, ..RuntimeOptions::default() },
+        keep_unused: true,  // As with --export-symbols
+        ..CompileOptions::default()
+    };
+    let source = crate::test_support::parse(ROM).unwrap();
+    let output = compile_source(ROM, options.clone()).unwrap();
+    (SymbolFile::export(&source, &output, &options), output.binary)
+}
+
+fn with_imports(imports: &[SymbolFile]) -> CompileOptions {
+    CompileOptions { imports: imports.to_vec(), ..CompileOptions::default() }
+}
+
+#[test]
+fn public_symbols_are_exported() {
+    let (symbols, binary) = rom();
+    assert_eq!(symbols.image, Area { start: 0, size: binary.len() });
+    assert_eq!(symbols.variables.start, 0x3000);
+    let names: Vec<&str> = symbols.procedures.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["Stars", "Twice"]);
+    assert_eq!(symbols.procedures[1].returns.as_deref(), Some("CARD"));
+    assert_eq!(symbols.globals.iter().map(|g| g.name.as_str()).collect::<Vec<_>>(), ["count"]);
+    assert_eq!(SymbolFile::parse(&symbols.to_json()), Ok(symbols));
+
+    assert!(SymbolFile::parse("{}").is_err());
+    let bad = r#"{"image":{"start":0,"size":1},"variables":{"start":0,"size":0},
+        "procedures":[],"globals":[{"name":"x","address":0,"type":"WORD"}]}"#;
+    assert_eq!(SymbolFile::parse(bad), Err("unknown type 'WORD'".to_string()));
+}
+
+#[test]
+fn a_separate_build_calls_the_library() {
+    let (symbols, rom) = rom();
+    let source = "\
+CARD total
+PROC main()
+Stars(3)
+Stars(count)
+total = Twice(1000)
+RETURN
+";
+    let output = compile_source(source, with_imports(std::slice::from_ref(&symbols))).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load(0x0000, &rom);
+    cpu.load(output.origin, &output.binary);
+    cpu.pc = output.origin;
+    let mut console = Console::new();
+    assert_eq!(cpu.run(&mut console, Some(1_000_000)), StopReason::Halted);
+    assert_eq!(console.output, b"******");
+    assert_eq!(cpu.read(symbols.globals[0].address), 6);
+    assert_eq!(cpu.read_word(output.codegen.global_address("total").unwrap()), 2000);
+}
+
+#[test]
+fn clashes_with_the_library_fail() {
+    let (symbols, _) = rom();
+    let imports = [symbols];
+    let redefined = "PROC Stars(BYTE n)\nRETURN\nPROC main()\nRETURN\n";
+    assert!(compile_source(redefined, with_imports(&imports)).is_err());
+    let wrong_call = "PROC main()\nStars()\nRETURN\n";
+    assert!(compile_source(wrong_call, with_imports(&imports)).is_err());
+    let overlapping = CompileOptions { origin: 0x0100, ..with_imports(&imports) };
+    let error = compile_source("PROC main()\nRETURN\n", overlapping).err().unwrap().to_string();
+    assert!(error.contains("overlaps"), "{}", error);
+}
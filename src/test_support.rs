@@ -0,0 +1,101 @@
+// Test helpers: compile snippets of Action! source and return the emitted bytes
+
+use crate::ast::{Expression, Program, Statement};
+use crate::codegen::CodeGenerator;
+use crate::compile::{self, CompileOptions};
+use crate::error::Result;
+use crate::runtime;
+
+/// Origin used for snippets, that of --target test
+pub const ORG: u16 = 0x4200;
+
+const SNIPPET: &str = "Snippet";
+
+pub fn parse(source: &str) -> Result<Program> {
+    let options = CompileOptions::pinned();
+    compile::parse(compile::tokenize(source, &options)?, &options).map(|(program, _)| program)
+}
+
+/// Compile a whole program into an image loaded at org, laid out like the command line
+/// output, with the runtime of --target test so the bytes do not follow the defaults
+pub fn compile_program(source: &str, org: u16) -> Result<Vec<u8>> {
+    compile_program_with(source, org, &runtime::RuntimeOptions::pinned())
+}
+
+/// Like compile_program, with the given runtime options
+pub fn compile_program_with(source: &str, org: u16, options: &runtime::RuntimeOptions) -> Result<Vec<u8>> {
+    let options = CompileOptions { origin: org, runtime: options.clone(), ..CompileOptions::pinned() };
+    Ok(compile::compile_source(source, options)?.binary)
+}
+
+/// Compile a whole program into a boot ROM image for 0x0000, laid out like --boot-rom output
+pub fn compile_boot_rom(source: &str, stack_top: u16, options: &runtime::RuntimeOptions) -> Result<Vec<u8>> {
+    let options = CompileOptions { boot_rom: true, stack: stack_top, runtime: options.clone(), ..CompileOptions::pinned() };
+    Ok(compile::compile_source(source, options)?.binary)
+}
+
+/// Bytes generated for a whole program (startup code, procedures and data), without the runtime
+pub fn program_bytes(source: &str, configure: impl FnOnce(&mut CodeGenerator)) -> Result<Vec<u8>> {
+    let (_, runtime_symbols) = runtime::generate_runtime_with_options(ORG + 3, &runtime::RuntimeOptions::pinned());
+    let mut codegen = CodeGenerator::new(runtime_symbols.end_address);
+    codegen.set_runtime_symbols(&runtime_symbols);
+    configure(&mut codegen);
+    codegen.generate(&parse(source)?)
+}
+
+// A generator that has already compiled the declarations, so snippets can refer to them
+fn generator(decls: &str) -> Result<CodeGenerator> {
+    let (_, runtime_symbols) = runtime::generate_runtime_with_options(ORG + 3, &runtime::RuntimeOptions::pinned());
+    let mut codegen = CodeGenerator::new(runtime_symbols.end_address);
+    codegen.set_runtime_symbols(&runtime_symbols);
+    codegen.generate(&parse(decls)?)?;
+    Ok(codegen)
+}
+
+// Parse source as the body of a procedure, without the RETURN closing it
+fn parse_body(source: &str) -> Result<Vec<Statement>> {
+    let program = parse(&format!("PROC {}()\n{}\nRETURN\n", SNIPPET, source))?;
+    let mut body = program.procedures.into_iter().next().map(|p| p.body).unwrap_or_default();
+    if matches!(body.last(), Some(Statement::Return(None))) {
+        body.pop();
+        body.pop();  // Its line marker
+    }
+    Ok(body)
+}
+
+/// Bytes emitted for the statements in source, compiled after decls
+pub fn statement_bytes(decls: &str, source: &str) -> Result<Vec<u8>> {
+    let body = parse_body(source)?;
+    ast_statement_bytes(decls, &Statement::Block(body))
+}
+
+/// Bytes emitted for a statement built directly, compiled after decls
+pub fn ast_statement_bytes(decls: &str, stmt: &Statement) -> Result<Vec<u8>> {
+    generator(decls)?.snippet_statement(stmt)
+}
+
+/// Bytes emitted for an expression and whether its result is a word, compiled after decls
+pub fn expression_bytes(decls: &str, source: &str) -> Result<(Vec<u8>, bool)> {
+    let body = parse_body(&format!("{}({})", SNIPPET, source))?;
+    match body.into_iter().find(|s| !matches!(s, Statement::Line(_))) {
+        Some(Statement::ProcCall { mut args, .. }) if args.len() == 1 => {
+            ast_expression_bytes(decls, &args.remove(0))
+        }
+        _ => panic!("not a single expression: {}", source),
+    }
+}
+
+/// Bytes emitted for an expression built directly, compiled after decls
+pub fn ast_expression_bytes(decls: &str, expr: &Expression) -> Result<(Vec<u8>, bool)> {
+    generator(decls)?.snippet_expression(expr)
+}
+
+/// Hex dump with offsets, 16 bytes per line
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes.chunks(16).enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{:04X}: {}\n", i * 16, hex.join(" "))
+        })
+        .collect()
+}
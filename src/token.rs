@@ -97,3 +97,31 @@ impl TokenInfo {
         TokenInfo { token, line, column }
     }
 }
+
+/// Case policy applied to keywords and names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaseMode {
+    /// Keywords and names match regardless of case (Action! default)
+    #[default]
+    Insensitive,
+    /// Keywords must be uppercase and names must match exactly
+    Strict,
+}
+
+impl CaseMode {
+    /// Normalize a keyword or name into the form used for matching
+    pub fn key(&self, name: &str) -> String {
+        match self {
+            CaseMode::Insensitive => name.to_uppercase(),
+            CaseMode::Strict => name.to_string(),
+        }
+    }
+
+    /// Compare two names under this policy
+    pub fn matches(&self, a: &str, b: &str) -> bool {
+        match self {
+            CaseMode::Insensitive => a.eq_ignore_ascii_case(b),
+            CaseMode::Strict => a == b,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// Sending compiled programs to a board over a serial line, the way its monitor or
+// system takes them in: Intel HEX typed at a monitor, XMODEM to a program receiving
+// it, or a HEX file typed into CP/M's PIP and made into a .COM file by LOAD.
+//
+// The line is anything that reads and writes; reads that time out count as silence.
+
+use std::io::{self, Read, Write};
+use std::thread::sleep;
+use std::time::Duration;
+
+const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CTRL_Z: u8 = 0x1A;  // End of a file typed into PIP
+
+/// Times a block is sent before giving up on the receiver
+const RETRIES: usize = 10;
+
+/// Read timeouts to wait for the receiver to ask for the first block
+const START_TIMEOUTS: usize = 60;
+
+/// How the board takes in the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Hex,     // Intel HEX typed at a monitor's prompt
+    Xmodem,  // XMODEM with checksums, to a receiving program
+    Cpm,     // PIP NAME.HEX=CON:, the HEX file, then LOAD NAME
+}
+
+// The next byte from the line, or None if it stays quiet
+fn read_byte(line: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match line.read(&mut byte) {
+        Ok(1) => Ok(Some(byte[0])),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Drop what the board sends (echo, prompts) until it goes quiet
+fn settle(line: &mut impl Read) -> io::Result<()> {
+    while read_byte(line)?.is_some() {}
+    Ok(())
+}
+
+/// Type Intel HEX text a line at a time, waiting line_delay after each for monitors
+/// without flow control
+pub fn send_hex(line: &mut (impl Read + Write), hex: &[u8], line_delay: Duration) -> io::Result<()> {
+    for record in hex.split(|&b| b == b'\n') {
+        let record = record.strip_suffix(b"\r").unwrap_or(record);
+        if record.is_empty() {
+            continue;
+        }
+        line.write_all(record)?;
+        line.write_all(b"\r")?;
+        line.flush()?;
+        sleep(line_delay);
+    }
+    settle(line)
+}
+
+/// Type HEX into a file with PIP, then make it into NAME.COM with LOAD
+pub fn send_cpm(line: &mut (impl Read + Write), name: &str, hex: &[u8], line_delay: Duration) -> io::Result<()> {
+    type_command(line, &format!("PIP {}.HEX=CON:", name))?;
+    send_hex(line, hex, line_delay)?;
+    line.write_all(&[CTRL_Z])?;
+    line.flush()?;
+    settle(line)?;
+    type_command(line, &format!("LOAD {}", name))
+}
+
+/// Type a command line and wait for it to finish printing
+pub fn type_command(line: &mut (impl Read + Write), command: &str) -> io::Result<()> {
+    line.write_all(command.as_bytes())?;
+    line.write_all(b"\r")?;
+    line.flush()?;
+    settle(line)
+}
+
+/// Send data with XMODEM, padding the last block with ^Z
+pub fn send_xmodem(line: &mut (impl Read + Write), data: &[u8]) -> Result<(), String> {
+    let io_error = |e: io::Error| format!("serial error: {}", e);
+
+    // The receiver asks for the first block with a NAK
+    let mut waited = 0;
+    loop {
+        match read_byte(line).map_err(io_error)? {
+            Some(NAK) => break,
+            Some(CAN) => return Err("the receiver cancelled".to_string()),
+            Some(_) => {}
+            None if waited < START_TIMEOUTS => waited += 1,
+            None => return Err("the receiver never asked for the first block".to_string()),
+        }
+    }
+
+    for (i, chunk) in data.chunks(128).enumerate() {
+        let number = (i + 1) as u8;
+        let mut block = vec![SOH, number, !number];
+        block.extend_from_slice(chunk);
+        block.resize(3 + 128, CTRL_Z);
+        block.push(block[3..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+        send_until_ack(line, &block).map_err(|e| format!("block {}: {}", number, e))?;
+    }
+    send_until_ack(line, &[EOT]).map_err(|e| format!("end of transfer: {}", e))
+}
+
+// Send until the receiver ACKs; a NAK, noise or silence means send again
+fn send_until_ack(line: &mut (impl Read + Write), bytes: &[u8]) -> Result<(), String> {
+    for _ in 0..RETRIES {
+        line.write_all(bytes).and_then(|_| line.flush()).map_err(|e| format!("serial error: {}", e))?;
+        match read_byte(line).map_err(|e| format!("serial error: {}", e))? {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => return Err("the receiver cancelled".to_string()),
+            _ => {}
+        }
+    }
+    Err(format!("no ACK after {} tries", RETRIES))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,91 @@
+// Uploads over a scripted line: what the board answers is queued up front, and reads
+// time out once it runs out
+
+use super::*;
+use std::collections::VecDeque;
+
+#[derive(Default)]
+struct Line {
+    answers: VecDeque<u8>,
+    sent: Vec<u8>,
+}
+
+impl Line {
+    fn answering(answers: &[u8]) -> Self {
+        Line { answers: answers.iter().copied().collect(), sent: Vec::new() }
+    }
+}
+
+impl Read for Line {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.answers.pop_front() {
+            Some(b) if !buf.is_empty() => {
+                buf[0] = b;
+                Ok(1)
+            }
+            _ => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const HEX: &[u8] = b":0280000000FF7F\r\n:00000001FF\r\n";
+
+#[test]
+fn hex_is_typed_a_record_per_line() {
+    let mut line = Line::default();
+    send_hex(&mut line, HEX, Duration::ZERO).unwrap();
+    assert_eq!(line.sent, b":0280000000FF7F\r:00000001FF\r");
+}
+
+#[test]
+fn cpm_upload_goes_through_pip_and_load() {
+    let mut line = Line::default();
+    send_cpm(&mut line, "HELLO", HEX, Duration::ZERO).unwrap();
+    assert_eq!(line.sent, b"PIP HELLO.HEX=CON:\r:0280000000FF7F\r:00000001FF\r\x1ALOAD HELLO\r");
+}
+
+#[test]
+fn xmodem_resends_after_nak() {
+    let data: Vec<u8> = (0..130).map(|i| i as u8).collect();
+    // Noise before the start, then a NAK for the second block
+    let mut line = Line::answering(&[b'?', NAK, ACK, NAK, ACK, ACK]);
+    send_xmodem(&mut line, &data).unwrap();
+
+    let block = |number: u8, chunk: &[u8]| {
+        let mut padded = chunk.to_vec();
+        padded.resize(128, CTRL_Z);
+        let mut bytes = vec![SOH, number, !number];
+        bytes.extend(&padded);
+        bytes.push(padded.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)));
+        bytes
+    };
+    let mut expected = block(1, &data[..128]);
+    expected.extend(block(2, &data[128..]));
+    expected.extend(block(2, &data[128..]));
+    expected.push(EOT);
+    assert_eq!(line.sent, expected);
+}
+
+#[test]
+fn xmodem_gives_up_on_a_silent_receiver() {
+    let mut line = Line::answering(&[NAK]);
+    assert_eq!(send_xmodem(&mut line, b"x").unwrap_err(), "block 1: no ACK after 10 tries");
+    assert_eq!(line.sent.len(), RETRIES * 132);
+}
+
+#[test]
+fn xmodem_stops_when_the_receiver_cancels() {
+    let mut line = Line::answering(&[CAN]);
+    assert_eq!(send_xmodem(&mut line, b"x").unwrap_err(), "the receiver cancelled");
+}